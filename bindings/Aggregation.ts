@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * What an `Aggregate` computes over the members of a collection. The fields are paths relative
+ * to each member, like those of a `Filter`, and members whose field isn't a number are skipped.
+ */
+export type Aggregation = "Count" | { "Sum": Array<string> } | { "Min": Array<string> } | { "Max": Array<string> };
@@ -0,0 +1,16 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Aggregation } from "./Aggregation";
+import type { Credentials } from "./Credentials";
+import type { Encoding } from "./Encoding";
+import type { Envelope } from "./Envelope";
+import type { Filter } from "./Filter";
+import type { Operation } from "./Operation";
+import type { Ref } from "./Ref";
+import type { JsonValue } from "./serde_json/JsonValue";
+
+export type ClientMessage = { "Hello": { protocol_version: number, features: Array<string>, encoding: Encoding, 
+/**
+ * The session from the `Welcome` of a connection that dropped, to resume its
+ * subscriptions
+ */
+session?: string, } } | { "Get": Ref } | { "GetMany": Array<Ref> } | { "GetExpanded": [Ref, number] } | { "GetShallow": [Ref, number] } | { "GetFields": [Ref, Array<Array<string>>] } | { "GetChunked": Ref } | { "GetMetadata": Ref } | { "GetHistory": Ref } | { "GetAt": [Ref, number] } | { "Search": [Ref, string] } | { "Query": [Ref, Filter] } | { "ListKeys": Ref } | { "Aggregate": [Ref, Aggregation] } | { "Insert": [Ref, JsonValue] } | { "Update": [Ref, JsonValue] } | { "Remove": Ref } | { "Subscribe": Ref } | { "SubscribeDebounced": [Ref, number] } | { "SubscribeFrom": { key: Ref, token: number | null, } } | { "SubscribePattern": Ref } | { "Unsubscribe": Ref } | { "Follow": Ref } | { "Join": [Ref, JsonValue] } | { "Leave": Ref } | { "DescribeSchema": Ref } | { "CanI": [Operation, Ref] } | { "Call": { name: string, args: unknown, } } | { "SignUp": { username: string, password: string, } } | { "SignIn": Credentials } | "SignOut" | { "Auth": string } | { "Envelope": Envelope } | { "Durable": ClientMessage } | { "Validate": ClientMessage };
@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * How a `Filter::Compare` compares a field to its value. Ordering compares numbers with numbers
+ * and strings with strings, and fails for anything else.
+ */
+export type Comparison = "Eq" | "Ne" | "Lt" | "Le" | "Gt" | "Ge" | "Prefix";
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * What a client signs in to an account with
+ */
+export type Credentials = { "Password": { username: string, password: string, } } | "Anonymous" | { "Token": string };
@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * How messages are encoded on a WebSocket connection. Clients may send requests in either
+ * encoding, as JSON in text frames or MessagePack in binary frames, but the server sends
+ * everything from its `Welcome` onwards in the one picked in the `Hello`.
+ */
+export type Encoding = "Json" | "MessagePack";
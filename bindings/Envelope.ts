@@ -0,0 +1,15 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ClientMessage } from "./ClientMessage";
+
+/**
+ * A write or function call tagged with a single-use nonce and the time it was sent (unix millis),
+ * so the server can reject replays of captured traffic
+ */
+export type Envelope = { nonce: string, timestamp: number, 
+/**
+ * The hex HMAC-SHA256, keyed with the session token from the connection's `Welcome`, of the
+ * nonce, the timestamp, and the message written as JSON with the keys of every object
+ * sorted, each on its own line. Binds them together, so a captured message can't be wrapped
+ * again with a fresh nonce.
+ */
+signature: string, message: ClientMessage, };
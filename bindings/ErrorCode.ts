@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type ErrorCode = "PermissionDenied" | "KeyNotFound" | "InvalidPath" | "SchemaMismatch" | "RateLimited" | "LimitExceeded" | "ReadOnly" | "Maintenance" | "Replay" | "InvalidRequest" | "IncompatibleProtocol" | "Disconnected" | "FunctionFailed" | "InvalidCredentials" | "Internal";
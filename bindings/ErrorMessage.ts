@@ -0,0 +1,16 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ErrorCode } from "./ErrorCode";
+import type { Ref } from "./Ref";
+
+/**
+ * Why a request failed, in a form clients can act on without parsing prose
+ */
+export type ErrorMessage = { code: ErrorCode, 
+/**
+ * The ref the error is about, if it's about one
+ */
+path: Ref | null, 
+/**
+ * An explanation for people, which may change between versions
+ */
+message: string | null, };
@@ -0,0 +1,15 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { RuleCheck } from "./RuleCheck";
+
+/**
+ * How the server handled a request, to help while building an app against it
+ */
+export type Explanation = { 
+/**
+ * The schema node the request's path matched, or why it matched none
+ */
+schema: string, 
+/**
+ * What the permission rules were asked and what they answered, if the request needed them
+ */
+rule: RuleCheck | null, };
@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Comparison } from "./Comparison";
+
+/**
+ * Which members of a collection a `Query` reads. Fields are checked against the schema before
+ * anything is read.
+ */
+export type Filter = { "And": Array<Filter> } | { "Or": Array<Filter> } | { "Not": Filter } | { "Compare": { field: Array<string>, op: Comparison, value: unknown, } };
@@ -0,0 +1,12 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Ref } from "./Ref";
+
+export type RuleCheck = { 
+/**
+ * The operation the rules were asked about, e.g. "read"
+ */
+op: string, path: Ref, user: string | null, allowed: boolean, 
+/**
+ * Why the rules failed to answer, which counts as a denial
+ */
+error?: string, };
@@ -0,0 +1,17 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { ErrorMessage } from "./ErrorMessage";
+import type { Explanation } from "./Explanation";
+import type { Ref } from "./Ref";
+import type { JsonValue } from "./serde_json/JsonValue";
+
+export type ServerMessage = { "Welcome": { protocol_version: number, features: Array<string>, 
+/**
+ * Sent in the `Hello` of a later connection to resume this one's subscriptions, if this
+ * one drops
+ */
+session: string, 
+/**
+ * Whether the session the `Hello` asked for was resumed. If it was, its subscriptions
+ * carry on, and an update for each item changed while disconnected follows.
+ */
+resumed: boolean, } } | { "Value": JsonValue } | { "ValueChunk": { members: JsonValue, last: boolean, } } | { "Error": ErrorMessage } | { "SubscriptionUpdate": [Ref, string | null] } | { "ResumableUpdate": { key: Ref, value: JsonValue, token: number, } } | { "PatternUpdate": { pattern: Ref, key: Ref, value: string | null, } } | { "SubscriptionExpired": Ref } | { "PatternExpired": { pattern: Ref, key: Ref, } } | "ServerShutdown" | "SchemaChanged" | { "Explain": Explanation };
@@ -1,26 +1,132 @@
+// The version of the wire protocol this client speaks
+const PROTOCOL_VERSION = 1;
+// Asked for when connecting, so servers can tell iceload clients apart
+const SUBPROTOCOL = "iceload.v1";
+// Where the token of an anonymous user is kept between visits
+const ANONYMOUS_TOKEN_KEY = "iceload.anonymous_token";
+// Stands for the signed-in user in a key, e.g. `["users", "$uid", "inbox"]`
+const USER_PLACEHOLDER = "$uid";
+
 class IceloadClient {
-  constructor(socket) {
+  constructor(socket, options = {}) {
     this.socket = socket;
-    this.socket.onmessage = (e) => this.#message_recv(e);
+    this.envelope_writes = options.envelope_writes ?? false;
+    if (socket) {
+      socket.binaryType = "arraybuffer";
+      socket.onmessage = (e) => this.#message_recv(e);
+    }
     this.next_value = null;
     this.subscribers = {};
+    this.pattern_subscribers = {};
+    // The message that started each subscription, to make it again if a session can't be resumed
+    this.requests = {};
+    this.requested_features = options.features ?? [];
+    // Asks the server to compress what it sends, if it's set up to
+    if (options.compression) {
+      this.requested_features = [...this.requested_features, "deflate"];
+    }
+    // Compressed messages are inflated one after another, so none overtakes another
+    this.inflating = Promise.resolve();
+    this.features = [];
+    // Passed when reconnecting, to resume this connection's subscriptions
+    this.session = null;
+    // The account signed in to, and the token to sign in with again after reconnecting
+    this.user = null;
+    this.token = null;
+    // The ID token authenticated with instead, if there is one
+    this.id_token = null;
+    // Keeps anonymous users' tokens, with `getItem` and `setItem` like localStorage
+    this.token_storage = options.token_storage ?? globalThis.localStorage ?? null;
+    this.on_shutdown = options.on_shutdown ?? null;
+    // Called when an operator replaces the schema; `describeSchema` reads the new one
+    this.on_schema_changed = options.on_schema_changed ?? null;
+    // Called with the explanation that precedes each response when the server is in dev mode
+    this.on_explain = options.on_explain ?? null;
   }
 
-  static async connect(url) {
-    const socket = new WebSocket(url);
-    const client = new IceloadClient(socket);
-    return await new Promise((resolve) => {
-      socket.onopen = () => resolve(client);
+  static async connect(url, options = {}) {
+    const client = new IceloadClient(null, options);
+    await client.#open(url, null);
+    return client;
+  }
+
+  // Connect again after the connection drops, resuming its session so that subscriptions carry
+  // on and anything they missed is sent straight away. If the session has expired, the
+  // subscriptions are made again instead.
+  async reconnect(url) {
+    const resumed = await this.#open(url, this.session);
+    if (!resumed) {
+      // Sign in first, so the subscriptions are made as the same user
+      if (this.token) {
+        await this.signIn({ Token: this.token });
+      } else if (this.id_token) {
+        await this.auth(this.id_token);
+      }
+      for (const request of Object.values(this.requests)) {
+        this.socket.send(JSON.stringify(request));
+      }
+    }
+  }
+
+  // Open a connection and complete the handshake, resolving to whether `session` was resumed
+  async #open(url, session) {
+    const socket = new WebSocket(url, SUBPROTOCOL);
+    socket.binaryType = "arraybuffer";
+    this.socket = socket;
+    socket.onmessage = (e) => this.#message_recv(e);
+    await new Promise((resolve) => {
+      socket.onopen = resolve;
     });
+    // The server answers the Hello with Welcome, or with an error before closing the connection
+    const hello = { protocol_version: PROTOCOL_VERSION, features: this.requested_features };
+    if (session) {
+      hello.session = session;
+    }
+    socket.send(JSON.stringify({ Hello: hello }));
+    const { value: resumed } = await this.#wait_next_value();
+    return resumed;
   }
 
   #message_recv(e) {
-    const data = JSON.parse(e.data);
-    if (data.SubscriptionUpdate) {
+    if (typeof e.data === "string") {
+      this.#dispatch(JSON.parse(e.data));
+    } else {
+      this.inflating = this.inflating.then(async () => {
+        this.#dispatch(JSON.parse(await inflate(e.data)));
+      });
+    }
+  }
+
+  #dispatch(data) {
+    if (data === "ServerShutdown") {
+      this.on_shutdown?.();
+    } else if (data === "SchemaChanged") {
+      this.on_schema_changed?.();
+    } else if (data.Welcome) {
+      this.features = data.Welcome.features;
+      this.session = data.Welcome.session;
+      this.next_value?.({ value: data.Welcome.resumed });
+    } else if (data.Explain) {
+      this.on_explain?.(data.Explain);
+    } else if (data.SubscriptionUpdate) {
       const [key, value] = data.SubscriptionUpdate;
       for (const subscriber of this.subscribers[key]) {
         subscriber(value);
       }
+    } else if (data.PatternUpdate) {
+      const { pattern, key, value } = data.PatternUpdate;
+      for (const subscriber of this.pattern_subscribers[pattern]) {
+        subscriber(key, value);
+      }
+    } else if (data.SubscriptionExpired) {
+      for (const subscriber of this.subscribers[data.SubscriptionExpired]) {
+        subscriber(null, { expired: true });
+      }
+    } else if (data.PatternExpired) {
+      const { pattern, key } = data.PatternExpired;
+      for (const subscriber of this.pattern_subscribers[pattern]) {
+        subscriber(key, null, { expired: true });
+      }
     } else if ("Error" in data) {
       this.next_value?.({ error: data.Error });
     } else {
@@ -31,40 +137,440 @@ class IceloadClient {
   async #wait_next_value() {
     const message = await new Promise((resolve) => (this.next_value = resolve));
     if (message.error) {
-      throw new Error(message.error);
+      // The code and path are meant for programs, and the message for people
+      const { code, path, message: text } = message.error;
+      throw Object.assign(new Error(text ?? code), { code, path });
     } else {
       return message;
     }
   }
 
+  async #send_write(message, { durable = false, validate = false } = {}) {
+    if (durable) {
+      message = { Durable: message };
+    }
+    // Validated writes aren't made, so there's nothing to replay
+    if (validate) {
+      message = { Validate: message };
+    } else if (this.envelope_writes) {
+      const nonce = crypto.randomUUID();
+      const timestamp = Date.now();
+      const signature = await this.#sign(`${nonce}\n${timestamp}\n${canonical_json(message)}`);
+      message = { Envelope: { nonce, timestamp, signature, message } };
+    }
+    this.socket.send(JSON.stringify(message));
+  }
+
+  // The hex HMAC-SHA256 of `text`, keyed with the session token from the Welcome
+  async #sign(text) {
+    const encoder = new TextEncoder();
+    const key = await crypto.subtle.importKey(
+      "raw",
+      encoder.encode(this.session),
+      { name: "HMAC", hash: "SHA-256" },
+      false,
+      ["sign"],
+    );
+    const signature = await crypto.subtle.sign("HMAC", key, encoder.encode(text));
+    const bytes = Array.from(new Uint8Array(signature));
+    return bytes.map((byte) => byte.toString(16).padStart(2, "0")).join("");
+  }
+
   async get(key) {
     this.socket.send(JSON.stringify({ Get: key }));
     return await this.#wait_next_value();
   }
 
-  async insert(key, value) {
-    this.socket.send(JSON.stringify({ Insert: [key, value] }));
+  // Several items as they all were at one moment, in the same order as `keys`
+  async getMany(keys) {
+    this.socket.send(JSON.stringify({ GetMany: keys }));
+    return await this.#wait_next_value();
+  }
+
+  // Reference fields are replaced by the items they point at, up to `depth` levels deep
+  async getExpanded(key, depth) {
+    this.socket.send(JSON.stringify({ GetExpanded: [key, depth] }));
+    return await this.#wait_next_value();
+  }
+
+  // Documents and collections nested more than `depth` levels down are replaced by
+  // `{ $ref: key }` placeholders, which can be passed to `get` to load them later
+  async getShallow(key, depth = 0) {
+    this.socket.send(JSON.stringify({ GetShallow: [key, depth] }));
+    return await this.#wait_next_value();
+  }
+
+  // Only the fields at the given paths relative to `key`, such as `[["title"], ["author", "name"]]`
+  async getFields(key, fields) {
+    this.socket.send(JSON.stringify({ GetFields: [key, fields] }));
+    return await this.#wait_next_value();
+  }
+
+  // When the document was created and last written, as `{ created_at, updated_at }` in
+  // milliseconds since the epoch
+  async getMetadata(key) {
+    this.socket.send(JSON.stringify({ GetMetadata: key }));
     return await this.#wait_next_value();
   }
 
-  async update(key, value) {
-    this.socket.send(JSON.stringify({ Update: [key, value] }));
+  // The kept revisions of a document, as `{ revision, at, value }`, oldest first
+  async getHistory(key) {
+    this.socket.send(JSON.stringify({ GetHistory: key }));
     return await this.#wait_next_value();
   }
 
+  async getAt(key, revision) {
+    this.socket.send(JSON.stringify({ GetAt: [key, revision] }));
+    return await this.#wait_next_value();
+  }
+
+  // The refs of the documents under `key` with every word of `query` in their searchable fields
+  async search(key, query) {
+    this.socket.send(JSON.stringify({ Search: [key, query] }));
+    return await this.#wait_next_value();
+  }
+
+  // The members of the collection at `key` matching `filter`, such as
+  // `{ Compare: { field: ["votes"], op: "Ge", value: 10 } }`, combined with `And`, `Or` and `Not`
+  async query(key, filter) {
+    this.socket.send(JSON.stringify({ Query: [key, filter] }));
+    return await this.#wait_next_value();
+  }
+
+  // The keys of the members of the collection at `key`, without their values
+  async listKeys(key) {
+    this.socket.send(JSON.stringify({ ListKeys: key }));
+    return await this.#wait_next_value();
+  }
+
+  // Computed by the server over the members of the collection at `key`: `"Count"`, or one of
+  // `{ Sum: field }`, `{ Min: field }` and `{ Max: field }`
+  async aggregate(key, aggregation) {
+    this.socket.send(JSON.stringify({ Aggregate: [key, aggregation] }));
+    return await this.#wait_next_value();
+  }
+
+  // With `{ durable: true }`, writes only resolve once they've been flushed to disk. With
+  // `{ validate: true }`, they're checked against the schema and permissions but not made.
+  async insert(key, value, options) {
+    await this.#send_write({ Insert: [key, value] }, options);
+    return await this.#wait_next_value();
+  }
+
+  async update(key, value, options) {
+    await this.#send_write({ Update: [key, value] }, options);
+    return await this.#wait_next_value();
+  }
+
+  // The item's part of the schema, in the same form as the schema file, without the document
+  // fields this client may not read
+  async describeSchema(key) {
+    this.socket.send(JSON.stringify({ DescribeSchema: key }));
+    return await this.#wait_next_value();
+  }
+
+  // Ask whether the permission rules allow `op`, e.g. "update", on `key`, without doing it.
+  // Resolves to `{ op, path, user, allowed }`, with the rules' `error` if they failed.
+  async canI(op, key) {
+    this.socket.send(JSON.stringify({ CanI: [op, key] }));
+    return await this.#wait_next_value();
+  }
+
+  // Run a function from the server's functions script, resolving to whatever it returns
+  async call(name, args) {
+    // Functions may write, so calls are enveloped like writes
+    await this.#send_write({ Call: { name, args } });
+    return await this.#wait_next_value();
+  }
+
+  // Set a member of a presence collection until this client leaves it or disconnects
+  async join(key, value) {
+    this.socket.send(JSON.stringify({ Join: [key, value] }));
+    return await this.#wait_next_value();
+  }
+
+  async leave(key) {
+    this.socket.send(JSON.stringify({ Leave: key }));
+    return await this.#wait_next_value();
+  }
+
+  // Create an account on the server and sign in as it, resolving to `{ user, token }`
+  async signUp(username, password) {
+    this.socket.send(JSON.stringify({ SignUp: { username, password } }));
+    return this.#signed_in(await this.#wait_next_value());
+  }
+
+  // Sign in with `{ Password: { username, password } }`, or `{ Token: token }` from an earlier
+  // sign in. Subscriptions made as another user end.
+  async signIn(credentials) {
+    this.socket.send(JSON.stringify({ SignIn: credentials }));
+    return this.#signed_in(await this.#wait_next_value());
+  }
+
+  // Sign in without an account, as the same user as last time if `token_storage` still has their
+  // token, or as a new one otherwise. Resolves to `{ user, token }`, like signIn.
+  async signInAnonymously() {
+    const stored = this.token_storage?.getItem(ANONYMOUS_TOKEN_KEY);
+    if (stored) {
+      try {
+        return await this.signIn({ Token: stored });
+      } catch (e) {
+        // Signed out of, or the server's data was reset
+        if (e.code !== "InvalidCredentials") {
+          throw e;
+        }
+      }
+    }
+    const response = await this.signIn("Anonymous");
+    this.token_storage?.setItem(ANONYMOUS_TOKEN_KEY, response.value.token);
+    return response;
+  }
+
+  // Authenticate with an ID token from an OpenID Connect provider the server trusts, resolving to
+  // `{ user }`. Send a fresh token before the last one expires to carry on as the same user.
+  async auth(idToken) {
+    this.socket.send(JSON.stringify({ Auth: idToken }));
+    const response = this.#signed_in(await this.#wait_next_value());
+    this.id_token = idToken;
+    return response;
+  }
+
+  // Sign out, revoking the token, which ends the subscriptions made as the user
+  async signOut() {
+    this.socket.send(JSON.stringify("SignOut"));
+    const response = await this.#wait_next_value();
+    if (this.user !== null) {
+      this.#forget_subscriptions();
+    }
+    this.user = null;
+    this.token = null;
+    this.id_token = null;
+    return response;
+  }
+
+  #signed_in(response) {
+    const { user, token } = response.value;
+    if (this.user !== null && this.user !== user) {
+      this.#forget_subscriptions();
+    }
+    this.user = user;
+    this.token = token ?? null;
+    this.id_token = null;
+    return response;
+  }
+
+  #forget_subscriptions() {
+    this.subscribers = {};
+    this.pattern_subscribers = {};
+    this.requests = {};
+  }
+
+  // The callback receives the new value, or null with `{ expired: true }` after it when the server
+  // removed the item itself rather than a client
   async subscribe(key, callback) {
+    const bound = this.#bound(key);
+    if (!(bound in this.subscribers)) {
+      this.requests[bound] = { Subscribe: key };
+      this.socket.send(JSON.stringify(this.requests[bound]));
+      this.subscribers[bound] = new Set();
+    }
+    this.subscribers[bound].add(callback);
+  }
+
+  // Like subscribe, but `key` must be a reference field: the callback receives the whole item it
+  // points at, and follows the field when it's repointed
+  async follow(key, callback) {
+    const bound = this.#bound(key);
+    if (!(bound in this.subscribers)) {
+      this.requests[bound] = { Follow: key };
+      this.socket.send(JSON.stringify(this.requests[bound]));
+      this.subscribers[bound] = new Set();
+    }
+    this.subscribers[bound].add(callback);
+  }
+
+  // Watch every item matching `pattern`, where `"*"` matches any member of a collection, as in
+  // `["chats", "*", "lastMessage"]`. The callback receives the key written along with the value,
+  // and `{ expired: true }` as for subscribe.
+  async subscribePattern(pattern, callback) {
+    const bound = this.#bound(pattern);
+    if (!(bound in this.pattern_subscribers)) {
+      this.requests[bound] = { SubscribePattern: pattern };
+      this.socket.send(JSON.stringify(this.requests[bound]));
+      this.pattern_subscribers[bound] = new Set();
+    }
+    this.pattern_subscribers[bound].add(callback);
+  }
+
+  async unsubscribePattern(pattern, callback) {
+    const bound = this.#bound(pattern);
+    this.pattern_subscribers[bound].delete(callback);
+    if (this.pattern_subscribers[bound].size === 0) {
+      delete this.pattern_subscribers[bound];
+      delete this.requests[bound];
+      this.socket.send(JSON.stringify({ Unsubscribe: pattern }));
+    }
+  }
+
+  scoped(prefix) {
+    return new ScopedIceloadClient(this, prefix);
+  }
+
+  async unsubscribe(key, callback) {
+    const bound = this.#bound(key);
+    this.subscribers[bound].remove(callback);
+    if (this.subscribers[bound].size === 0) {
+      delete this.subscribers[bound];
+      delete this.requests[bound];
+      this.socket.send(JSON.stringify({ Unsubscribe: key }));
+    }
+  }
+
+  // The key with `"$uid"` filled in with the signed-in user, as the server does, since updates
+  // arrive for the key it stands for
+  #bound(key) {
+    return key.map((component) => (component === USER_PLACEHOLDER ? this.user : component));
+  }
+}
+
+// A view of a client where every key is relative to a fixed prefix
+// The text of a message the server compressed with raw deflate
+async function inflate(data) {
+  const inflated = new Blob([data]).stream().pipeThrough(new DecompressionStream("deflate-raw"));
+  return await new Response(inflated).text();
+}
+
+// JSON with the keys of every object in sorted order, as the server writes messages to check the
+// signatures of envelopes
+function canonical_json(value) {
+  if (Array.isArray(value)) {
+    return `[${value.map(canonical_json).join(",")}]`;
+  } else if (value !== null && typeof value === "object") {
+    const members = Object.keys(value)
+      .sort()
+      .map((key) => `${JSON.stringify(key)}:${canonical_json(value[key])}`);
+    return `{${members.join(",")}}`;
+  }
+  return JSON.stringify(value);
+}
+
+class ScopedIceloadClient {
+  constructor(client, prefix) {
+    this.client = client;
+    this.prefix = prefix;
+  }
+
+  #absolute(key) {
+    return [...this.prefix, ...key];
+  }
+
+  // Like subscribe, but `key` must be a reference field: the callback receives the whole item it
+  // points at, and follows the field when it's repointed
+  async follow(key, callback) {
     if (!(key in this.subscribers)) {
-      this.socket.send(JSON.stringify({ Subscribe: key }));
+      this.socket.send(JSON.stringify({ Follow: key }));
       this.subscribers[key] = new Set();
     }
     this.subscribers[key].add(callback);
   }
 
+  scoped(prefix) {
+    return new ScopedIceloadClient(this.client, this.#absolute(prefix));
+  }
+
+  async get(key) {
+    return await this.client.get(this.#absolute(key));
+  }
+
+  async getMany(keys) {
+    return await this.client.getMany(keys.map((key) => this.#absolute(key)));
+  }
+
+  async getExpanded(key, depth) {
+    return await this.client.getExpanded(this.#absolute(key), depth);
+  }
+
+  // The refs in placeholders are absolute
+  async getShallow(key, depth = 0) {
+    return await this.client.getShallow(this.#absolute(key), depth);
+  }
+
+  async getFields(key, fields) {
+    return await this.client.getFields(this.#absolute(key), fields);
+  }
+
+  async getMetadata(key) {
+    return await this.client.getMetadata(this.#absolute(key));
+  }
+
+  async getHistory(key) {
+    return await this.client.getHistory(this.#absolute(key));
+  }
+
+  async getAt(key, revision) {
+    return await this.client.getAt(this.#absolute(key), revision);
+  }
+
+  // The refs found are absolute
+  async search(key, query) {
+    return await this.client.search(this.#absolute(key), query);
+  }
+
+  async query(key, filter) {
+    return await this.client.query(this.#absolute(key), filter);
+  }
+
+  async listKeys(key) {
+    return await this.client.listKeys(this.#absolute(key));
+  }
+
+  async aggregate(key, aggregation) {
+    return await this.client.aggregate(this.#absolute(key), aggregation);
+  }
+
+  async insert(key, value, options) {
+    return await this.client.insert(this.#absolute(key), value, options);
+  }
+
+  async update(key, value, options) {
+    return await this.client.update(this.#absolute(key), value, options);
+  }
+
+  async join(key, value) {
+    return await this.client.join(this.#absolute(key), value);
+  }
+
+  async describeSchema(key) {
+    return await this.client.describeSchema(this.#absolute(key));
+  }
+
+  async canI(op, key) {
+    return await this.client.canI(op, this.#absolute(key));
+  }
+
+  async leave(key) {
+    return await this.client.leave(this.#absolute(key));
+  }
+
+  async subscribe(key, callback) {
+    return await this.client.subscribe(this.#absolute(key), callback);
+  }
+
+  // The reference itself is absolute, so updates are the same as for an unscoped client
+  async follow(key, callback) {
+    return await this.client.follow(this.#absolute(key), callback);
+  }
+
   async unsubscribe(key, callback) {
-    this.subscribers[key].remove(callback);
-    if (this.subscribers[key].size === 0) {
-      delete this.subscribers[key];
-      this.socket.send(JSON.stringify({ Unsubscribe: key }));
-    }
+    return await this.client.unsubscribe(this.#absolute(key), callback);
+  }
+
+  // The keys passed to the callback are absolute
+  async subscribePattern(pattern, callback) {
+    return await this.client.subscribePattern(this.#absolute(pattern), callback);
+  }
+
+  async unsubscribePattern(pattern, callback) {
+    return await this.client.unsubscribePattern(this.#absolute(pattern), callback);
   }
 }
@@ -0,0 +1,8 @@
+// The encoding used by client.js: messages are plain JSON
+export function decode(_type, encoded) {
+  return JSON.parse(encoded);
+}
+
+export function encode(_type, message) {
+  return JSON.stringify(message);
+}
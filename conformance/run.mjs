@@ -0,0 +1,41 @@
+// Checks that a client implementation encodes and decodes every protocol message exactly as the
+// server does.
+//
+// Usage: node conformance/run.mjs [codec module]
+//
+// The codec module exports `decode(type, encoded)` and `encode(type, message)`, where `type` is
+// "ClientMessage" or "ServerMessage". Each fixture is decoded and re-encoded, and must come back
+// byte for byte. Implementations in other languages can run the same check against
+// messages.json directly.
+//
+// Each fixture carries its message in every wire encoding the server supports, one field per
+// encoding; today that's just `json`.
+
+import { readFile } from "node:fs/promises";
+import { pathToFileURL } from "node:url";
+
+const codecPath = process.argv[2] ?? new URL("./json-codec.mjs", import.meta.url).href;
+const codec = await import(
+  codecPath.startsWith("file:") ? codecPath : pathToFileURL(codecPath).href
+);
+const fixtures = JSON.parse(await readFile(new URL("./messages.json", import.meta.url)));
+
+let failures = 0;
+for (const fixture of fixtures) {
+  const description = `${fixture.type} ${fixture.name}`;
+  try {
+    const encoded = codec.encode(fixture.type, codec.decode(fixture.type, fixture.json));
+    if (encoded === fixture.json) {
+      console.log(`pass: ${description}`);
+    } else {
+      failures += 1;
+      console.log(`FAIL: ${description}: expected ${fixture.json}, got ${encoded}`);
+    }
+  } catch (err) {
+    failures += 1;
+    console.log(`FAIL: ${description}: ${err}`);
+  }
+}
+
+console.log(`${fixtures.length - failures} passed, ${failures} failed`);
+process.exit(failures === 0 ? 0 : 1);
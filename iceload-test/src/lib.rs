@@ -0,0 +1,258 @@
+//! Run an iceload server in-process, so applications can be tested end to end against the real
+//! thing without starting the binary.
+//!
+//! A [`TestServer`] is checked against the schema and permission rules it's given, listens on
+//! ports the OS picks so tests can run in parallel, and keeps its data in a temporary database.
+//! Dropping it shuts the server down and deletes everything. [`TestClient`]s connect to it over
+//! WebSocket and speak the wire protocol in [`iceload::message`].
+//!
+//! ```
+//! use iceload::message::{ClientMessage, ServerMessage};
+//! use iceload_test::TestServer;
+//! use serde_json::json;
+//!
+//! let schema = r#"{ "Document": { "greeting": "Scalar" } }"#;
+//! let rules = r#"
+//!     function check(op, path, user)
+//!         return true
+//!     end
+//!     return check
+//! "#;
+//! let server = TestServer::builder(schema, rules)
+//!     .with_seed(json!({ "greeting": "hello" }))
+//!     .start()?;
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let mut client = server.connect().await?;
+//! let greeting = client
+//!     .request(ClientMessage::Get(["greeting"].into()))
+//!     .await?;
+//! assert!(matches!(greeting, ServerMessage::Value(value) if value == "hello"));
+//! # Ok::<(), anyhow::Error>(())
+//! # })?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::{collections::BTreeSet, sync::mpsc, thread, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use iceload::{
+    app::{self, Addresses, ServeOptions},
+    config::Config,
+    logging,
+    message::{ClientMessage, Encoding, ServerMessage, PROTOCOL_VERSION},
+};
+use serde_json::Value;
+use tempfile::TempDir;
+use tokio::{net::TcpStream, sync::oneshot};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// How long a client waits for the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a `TestServer` is started with
+pub struct Builder {
+    schema: String,
+    rules: String,
+    seed: Option<Value>,
+    config: Config,
+}
+
+impl Builder {
+    /// Start with this data in the database, which has to fit the schema
+    pub fn with_seed(mut self, seed: Value) -> Builder {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Serve with these settings. Where it listens, where its data is kept and its schema and
+    /// rules are always chosen by the test server.
+    pub fn with_config(mut self, config: Config) -> Builder {
+        self.config = config;
+        self
+    }
+
+    /// Start the server on a thread of its own, returning once it's accepting connections
+    pub fn start(self) -> anyhow::Result<TestServer> {
+        let dir = tempfile::tempdir()?;
+        let mut config = self.config;
+        config.schema = dir.path().join("schema.json");
+        config.rules = dir.path().join("permission.luau");
+        std::fs::write(&config.schema, self.schema)?;
+        std::fs::write(&config.rules, self.rules)?;
+        config.ephemeral = true;
+        config.listen = "127.0.0.1:0".into();
+        config.listeners.clear();
+        config.http_listen = "127.0.0.1:0".into();
+        config.grpc_listen = ([127, 0, 0, 1], 0).into();
+        if config.admin_listen.is_some() {
+            config.admin_listen = Some("127.0.0.1:0".into());
+        }
+        let seed = match self.seed {
+            Some(seed) => {
+                let path = dir.path().join("seed.json");
+                std::fs::write(&path, seed.to_string())?;
+                Some(path)
+            }
+            None => None,
+        };
+        let options = ServeOptions {
+            wait_for_lock: false,
+            migrate_legacy: false,
+            legacy_separator: '/',
+            seed,
+        };
+
+        let (ready_send, ready) = mpsc::channel();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let thread = thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(app::serve_until(
+                config,
+                options,
+                logging::detached(),
+                None,
+                |addresses| {
+                    let _ = ready_send.send(addresses.clone());
+                },
+                async {
+                    let _ = stopped.await;
+                },
+            ))
+        });
+        let Ok(addresses) = ready.recv() else {
+            // The server only stops before it's ready if it failed to start
+            return match thread.join() {
+                Ok(Err(e)) => Err(e),
+                Ok(Ok(())) => Err(anyhow::anyhow!("the server stopped before it was ready")),
+                Err(_) => Err(anyhow::anyhow!("the server panicked while starting")),
+            };
+        };
+
+        Ok(TestServer {
+            addresses,
+            stop: Some(stop),
+            thread: Some(thread),
+            _dir: dir,
+        })
+    }
+}
+
+/// A server running in-process for the length of a test
+pub struct TestServer {
+    addresses: Addresses,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<anyhow::Result<()>>>,
+    /// Holds the schema, rules and seed files, and is deleted once the server stops
+    _dir: TempDir,
+}
+
+impl TestServer {
+    /// A server checked against `schema`, the contents of a schema file, with `rules` as its
+    /// permission script
+    pub fn builder(schema: impl Into<String>, rules: impl Into<String>) -> Builder {
+        Builder {
+            schema: schema.into(),
+            rules: rules.into(),
+            seed: None,
+            config: Config::default(),
+        }
+    }
+
+    /// The URL to connect to over WebSocket
+    pub fn url(&self) -> &str {
+        &self.addresses.listeners[0]
+    }
+
+    /// The URL of the HTTP gateway
+    pub fn http_url(&self) -> &str {
+        &self.addresses.http
+    }
+
+    /// The URL of the admin API, if the config turns it on
+    pub fn admin_url(&self) -> Option<&str> {
+        self.addresses.admin.as_deref()
+    }
+
+    /// Connect a client and complete the handshake
+    pub async fn connect(&self) -> anyhow::Result<TestClient> {
+        self.connect_to("/").await
+    }
+
+    /// Connect a client to the tenant served at `path`, and complete the handshake
+    pub async fn connect_to(&self, path: &str) -> anyhow::Result<TestClient> {
+        let url = format!("{}{path}", self.url().trim_end_matches('/'));
+        let (socket, _) =
+            tokio::time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url)).await??;
+        let mut client = TestClient { socket };
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            features: BTreeSet::new(),
+            encoding: Encoding::Json,
+            session: None,
+        };
+        match client.request(hello).await? {
+            ServerMessage::Welcome { .. } => Ok(client),
+            ServerMessage::Error(e) => anyhow::bail!("the server refused the connection: {e}"),
+            msg => anyhow::bail!("unexpected response to the handshake: {msg:?}"),
+        }
+    }
+
+    /// Shut the server down gracefully, returning any error it stopped with
+    pub fn stop(mut self) -> anyhow::Result<()> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> anyhow::Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        match self.thread.take().map(thread::JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => anyhow::bail!("the server panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.shut_down();
+    }
+}
+
+/// A connection to a `TestServer`
+pub struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, message: ClientMessage) -> anyhow::Result<()> {
+        let text = serde_json::to_string(&message)?;
+        self.socket.send(Message::Text(text)).await?;
+        Ok(())
+    }
+
+    /// Wait for the next message from the server, such as a subscription update
+    pub async fn receive(&mut self) -> anyhow::Result<ServerMessage> {
+        loop {
+            let Some(message) = tokio::time::timeout(TIMEOUT, self.socket.next()).await? else {
+                anyhow::bail!("the server closed the connection");
+            };
+            if let Message::Text(text) = message? {
+                return Ok(serde_json::from_str(&text)?);
+            }
+        }
+    }
+
+    /// Send a request and wait for its response
+    pub async fn request(&mut self, message: ClientMessage) -> anyhow::Result<ServerMessage> {
+        self.send(message).await?;
+        self.receive().await
+    }
+
+    pub async fn close(mut self) -> anyhow::Result<()> {
+        self.socket.close(None).await?;
+        Ok(())
+    }
+}
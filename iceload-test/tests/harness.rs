@@ -0,0 +1,70 @@
+use iceload::message::{ClientMessage, ServerMessage};
+use iceload_test::TestServer;
+use serde_json::{json, Value};
+
+const SCHEMA: &str = r#"{ "Document": { "hello": { "Document": { "world": "Scalar" } } } }"#;
+const ALLOW_ALL: &str = r#"
+    function check(op, path, user)
+        return true
+    end
+    return check
+"#;
+
+#[tokio::test]
+async fn serving_clients() {
+    let server = TestServer::builder(SCHEMA, ALLOW_ALL).start().unwrap();
+    let mut writer = server.connect().await.unwrap();
+    let mut watcher = server.connect().await.unwrap();
+
+    watcher
+        .send(ClientMessage::Subscribe(["hello", "world"].into()))
+        .await
+        .unwrap();
+    // Responses on a connection are ordered, so this confirms the subscription is in place
+    watcher
+        .request(ClientMessage::Get(["hello"].into()))
+        .await
+        .unwrap();
+    let response = writer
+        .request(ClientMessage::Insert(
+            ["hello"].into(),
+            json!({ "world": "earth" }),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(response, ServerMessage::Value(Value::Null)));
+    let ServerMessage::SubscriptionUpdate(key, Some(_)) = watcher.receive().await.unwrap() else {
+        panic!("expected an update");
+    };
+    assert_eq!(key, ["hello", "world"].into());
+
+    server.stop().unwrap();
+}
+
+#[tokio::test]
+async fn servers_are_independent() {
+    let seeded = TestServer::builder(SCHEMA, ALLOW_ALL)
+        .with_seed(json!({ "hello": { "world": "mars" } }))
+        .start()
+        .unwrap();
+    let empty = TestServer::builder(SCHEMA, ALLOW_ALL).start().unwrap();
+    assert_ne!(seeded.url(), empty.url());
+
+    let get = || ClientMessage::Get(["hello", "world"].into());
+    let mut client = seeded.connect().await.unwrap();
+    let response = client.request(get()).await.unwrap();
+    assert!(matches!(response, ServerMessage::Value(value) if value == "mars"));
+    let mut client = empty.connect().await.unwrap();
+    let response = client.request(get()).await.unwrap();
+    assert!(matches!(response, ServerMessage::Error(_)));
+}
+
+#[test]
+fn failing_to_start() {
+    assert!(TestServer::builder("{", ALLOW_ALL).start().is_err());
+    let seed = json!({ "hello": { "mars": "red" } });
+    let result = TestServer::builder(SCHEMA, ALLOW_ALL)
+        .with_seed(seed)
+        .start();
+    assert!(result.is_err());
+}
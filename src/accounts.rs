@@ -0,0 +1,254 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+use thiserror::Error;
+
+use crate::{
+    config::AccountsConfig,
+    message::Credentials,
+    server::{Server, ServerError},
+};
+
+/// The user accounts of a server's clients, which they sign up and sign in to instead of going
+/// through an identity provider. They're kept in a tree of their own, apart from the data, so no
+/// read of the store can reach them.
+///
+/// Passwords are kept as argon2 hashes, and tokens as hashes too, so a copy of the database
+/// doesn't let anyone sign in. Both are slow to check on purpose, so they're best called off the
+/// async runtime.
+#[derive(Clone)]
+pub struct Accounts {
+    tree: Tree,
+    config: AccountsConfig,
+}
+
+/// What's kept of an account, under its username
+#[derive(Deserialize, Serialize)]
+struct Account {
+    id: String,
+    /// In the PHC string format, with its salt and parameters
+    password_hash: String,
+}
+
+/// An account a client has signed in to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedIn {
+    /// The ID the permission rules are given as the user
+    pub user: String,
+    /// Signs in to the account again, until it's signed out of
+    pub token: String,
+}
+
+#[derive(Debug, Error)]
+pub enum AccountError {
+    #[error("usernames and passwords may not be empty")]
+    Empty,
+    #[error("the username {0:?} is taken")]
+    Taken(String),
+    #[error("the username, password or token is wrong")]
+    InvalidCredentials,
+    #[error("{0} isn't enabled on this server")]
+    Disabled(&'static str),
+    #[error(transparent)]
+    Store(#[from] ServerError),
+}
+
+impl From<sled::Error> for AccountError {
+    fn from(e: sled::Error) -> AccountError {
+        AccountError::Store(e.into())
+    }
+}
+
+impl Accounts {
+    /// The accounts of `server`, which can be signed in to in the ways `config` enables
+    pub fn open(server: &Server, config: AccountsConfig) -> Result<Accounts, ServerError> {
+        Ok(Accounts {
+            tree: server.users_tree()?,
+            config,
+        })
+    }
+
+    /// Create an account, signed in to with a new token
+    pub fn sign_up(&self, username: &str, password: &str) -> Result<SignedIn, AccountError> {
+        if !self.config.enabled {
+            return Err(AccountError::Disabled("signing up"));
+        }
+        if username.is_empty() || password.is_empty() {
+            return Err(AccountError::Empty);
+        }
+        let account = Account {
+            id: random_hex(),
+            password_hash: hash_password(password),
+        };
+        let account_json = serde_json::to_vec(&account).unwrap();
+        let created = self.tree.compare_and_swap(
+            name_key(username),
+            None as Option<&[u8]>,
+            Some(account_json),
+        )?;
+        if created.is_err() {
+            return Err(AccountError::Taken(username.to_string()));
+        }
+        self.issue(account.id)
+    }
+
+    /// Sign in with a password or anonymously, getting a new token, or with a token from an
+    /// earlier sign in
+    pub fn sign_in(&self, credentials: &Credentials) -> Result<SignedIn, AccountError> {
+        match credentials {
+            Credentials::Password { .. } if !self.config.enabled => {
+                Err(AccountError::Disabled("signing in with a password"))
+            }
+            Credentials::Password { username, password } => {
+                let Some(account) = self.tree.get(name_key(username))? else {
+                    return Err(AccountError::InvalidCredentials);
+                };
+                let account: Account =
+                    serde_json::from_slice(&account).expect("accounts are stored as JSON");
+                let hash = PasswordHash::new(&account.password_hash)
+                    .expect("password hashes are stored in the PHC format");
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .map_err(|_| AccountError::InvalidCredentials)?;
+                self.issue(account.id)
+            }
+            // Anonymous users only exist as the tokens that sign in as them
+            Credentials::Anonymous if self.config.anonymous => self.issue(random_hex()),
+            Credentials::Anonymous => Err(AccountError::Disabled("signing in anonymously")),
+            Credentials::Token(token) => match self.tree.get(token_key(token))? {
+                Some(user) => Ok(SignedIn {
+                    user: String::from_utf8(user.to_vec()).expect("user IDs are hex"),
+                    token: token.clone(),
+                }),
+                None => Err(AccountError::InvalidCredentials),
+            },
+        }
+    }
+
+    /// Revoke a token, so it can't be signed in with again
+    pub fn sign_out(&self, token: &str) -> Result<(), AccountError> {
+        self.tree.remove(token_key(token))?;
+        Ok(())
+    }
+
+    fn issue(&self, user: String) -> Result<SignedIn, AccountError> {
+        let token = random_hex();
+        self.tree.insert(token_key(&token), user.as_bytes())?;
+        Ok(SignedIn { user, token })
+    }
+}
+
+fn name_key(username: &str) -> Vec<u8> {
+    [b"name/", username.as_bytes()].concat()
+}
+
+/// Tokens are random, so a fast hash keeps them as safe as a slow one would
+fn token_key(token: &str) -> Vec<u8> {
+    [&b"token/"[..], &Blake2s256::digest(token.as_bytes())].concat()
+}
+
+fn hash_password(password: &str) -> String {
+    let mut salt = [0; 16];
+    getrandom::getrandom(&mut salt).expect("the OS has no source of randomness");
+    let salt = SaltString::encode_b64(&salt).expect("16 bytes is a valid salt");
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("the default parameters take any password")
+        .to_string()
+}
+
+/// An ID or token, which is hard to guess
+fn random_hex() -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("the OS has no source of randomness");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::{Schema, SchemaItem};
+
+    use super::*;
+
+    fn password(username: &str, password: &str) -> Credentials {
+        Credentials::Password {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    fn server() -> Server {
+        Server::open_temporary(Schema::new(SchemaItem::Scalar), sled::Config::new()).unwrap()
+    }
+
+    #[test]
+    fn signing_in() {
+        let server = server();
+        let config = AccountsConfig {
+            enabled: true,
+            anonymous: false,
+        };
+        let accounts = Accounts::open(&server, config).unwrap();
+
+        let ada = accounts.sign_up("ada", "engine").unwrap();
+        assert!(matches!(
+            accounts.sign_up("ada", "other"),
+            Err(AccountError::Taken(_))
+        ));
+        assert!(matches!(
+            accounts.sign_up("grace", ""),
+            Err(AccountError::Empty)
+        ));
+
+        let again = accounts.sign_in(&password("ada", "engine")).unwrap();
+        assert_eq!(again.user, ada.user);
+        assert_ne!(again.token, ada.token);
+        for wrong in [password("ada", "wrong"), password("grace", "engine")] {
+            assert!(matches!(
+                accounts.sign_in(&wrong),
+                Err(AccountError::InvalidCredentials)
+            ));
+        }
+
+        let token = Credentials::Token(ada.token.clone());
+        assert_eq!(accounts.sign_in(&token).unwrap(), ada);
+        accounts.sign_out(&ada.token).unwrap();
+        assert!(matches!(
+            accounts.sign_in(&token),
+            Err(AccountError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            accounts.sign_in(&Credentials::Anonymous),
+            Err(AccountError::Disabled(_))
+        ));
+    }
+
+    #[test]
+    fn signing_in_anonymously() {
+        let server = server();
+        let config = AccountsConfig {
+            enabled: false,
+            anonymous: true,
+        };
+        let accounts = Accounts::open(&server, config).unwrap();
+
+        let first = accounts.sign_in(&Credentials::Anonymous).unwrap();
+        let second = accounts.sign_in(&Credentials::Anonymous).unwrap();
+        assert_ne!(first.user, second.user);
+        let token = Credentials::Token(first.token.clone());
+        assert_eq!(accounts.sign_in(&token).unwrap(), first);
+
+        assert!(matches!(
+            accounts.sign_up("ada", "engine"),
+            Err(AccountError::Disabled(_))
+        ));
+        assert!(matches!(
+            accounts.sign_in(&password("ada", "engine")),
+            Err(AccountError::Disabled(_))
+        ));
+    }
+}
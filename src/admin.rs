@@ -0,0 +1,780 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    changes::Change,
+    delivery::{Delivery, DeliveryError, DeliveryQueue},
+    error::ErrorKind,
+    logging::FilterHandle,
+    message::{ClientMessage, Ref, ServerMessage},
+    profile::{Busy, Profiler},
+    registry::{ClientInfo, ConnectionRegistry},
+    schema::Schema,
+    server::{Maintenance, Server, ServerError, StorageUsage},
+    tenant::{Tenant, Tenants},
+};
+
+/// How many messages a debug capture keeps per connection; older ones are discarded
+const CAPTURE_LIMIT: usize = 1000;
+/// The longest profile that can be captured at once
+const MAX_PROFILE_SECS: u64 = 300;
+/// The most changes returned by one read of the change log
+const MAX_CHANGES: usize = 1000;
+/// The longest a follower's read of the change log waits for a change to be committed
+const MAX_REPLICATION_WAIT_SECS: u64 = 60;
+/// How long a tenant's storage usage is reused for, as finding it scans the whole store
+const USAGE_TTL: Duration = Duration::from_secs(60);
+
+/// Knobs for diagnosing a running server, shared between the admin API and the connections it
+/// inspects
+pub struct Diagnostics {
+    log_filter: FilterHandle,
+    /// Only present when profiling is enabled
+    profiler: Option<Arc<Profiler>>,
+    /// Connections with debug capture turned on, and what has been captured from them so far
+    captures: Mutex<HashMap<u64, VecDeque<Captured>>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Captured {
+    pub direction: Direction,
+    /// The message as JSON, with sensitive values redacted
+    pub message: Value,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+impl Diagnostics {
+    pub fn new(log_filter: FilterHandle, profiler: Option<Arc<Profiler>>) -> Diagnostics {
+        Diagnostics {
+            log_filter,
+            profiler,
+            captures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The tracing filter currently in effect, as `RUST_LOG`-style directives
+    pub fn log_filter(&self) -> String {
+        self.log_filter
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Replace the tracing filter with new `RUST_LOG`-style directives
+    pub fn set_log_filter(&self, directives: &str) -> Result<(), AdminError> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.log_filter
+            .reload(filter)
+            .map_err(|e| AdminError::Internal(e.to_string()))
+    }
+
+    /// Start recording the traffic of a connection. Connection ids are handed out in order, so
+    /// this may be called for a connection that hasn't been accepted yet.
+    pub fn start_capture(&self, connection: u64) {
+        self.captures.lock().unwrap().entry(connection).or_default();
+    }
+
+    /// Stop recording a connection, returning what was captured
+    pub fn stop_capture(&self, connection: u64) -> Option<Vec<Captured>> {
+        let captured = self.captures.lock().unwrap().remove(&connection)?;
+        Some(captured.into())
+    }
+
+    /// What has been captured from a connection so far, if capture is on for it
+    pub fn captured(&self, connection: u64) -> Option<Vec<Captured>> {
+        let captures = self.captures.lock().unwrap();
+        Some(captures.get(&connection)?.iter().cloned().collect())
+    }
+
+    /// Record a request from a connection, if capture is on for it
+    pub fn capture_request(&self, connection: u64, server: &Server, request: &ClientMessage) {
+        self.capture(connection, Direction::Received, || {
+            let redacted = match request {
+                ClientMessage::Insert(key, value) => {
+                    ClientMessage::Insert(key.clone(), server.redact(key, value))
+                }
+                ClientMessage::Update(key, value) => {
+                    ClientMessage::Update(key.clone(), server.redact(key, value))
+                }
+                ClientMessage::Join(key, value) => {
+                    ClientMessage::Join(key.clone(), server.redact(key, value))
+                }
+                _ => return serde_json::to_value(request).unwrap(),
+            };
+            serde_json::to_value(redacted).unwrap()
+        });
+    }
+
+    /// Record the response to a request for `key`, if capture is on for the connection
+    pub fn capture_response(
+        &self,
+        connection: u64,
+        server: &Server,
+        key: Option<&Ref>,
+        response: &ServerMessage,
+    ) {
+        self.capture(connection, Direction::Sent, || match (key, response) {
+            (Some(key), ServerMessage::Value(value)) => {
+                serde_json::to_value(ServerMessage::Value(server.redact(key, value))).unwrap()
+            }
+            (Some(key), ServerMessage::ValueChunk { members, last }) => {
+                serde_json::to_value(ServerMessage::ValueChunk {
+                    members: server.redact(key, members),
+                    last: *last,
+                })
+                .unwrap()
+            }
+            _ => serde_json::to_value(response).unwrap(),
+        });
+    }
+
+    fn capture(&self, connection: u64, direction: Direction, message: impl FnOnce() -> Value) {
+        let mut captures = self.captures.lock().unwrap();
+        let Some(captured) = captures.get_mut(&connection) else {
+            return;
+        };
+        if captured.len() >= CAPTURE_LIMIT {
+            captured.pop_front();
+        }
+        captured.push_back(Captured {
+            direction,
+            message: message(),
+        });
+    }
+}
+
+#[derive(Clone)]
+struct Admin {
+    server: Server,
+    tenants: Arc<Tenants>,
+    registry: Arc<ConnectionRegistry>,
+    diagnostics: Arc<Diagnostics>,
+    deliveries: Arc<DeliveryQueue>,
+    audit: Option<Arc<AuditLog>>,
+    usage: Arc<Mutex<UsageCache>>,
+    /// The secret requests have to carry, if there is one
+    token: Option<Arc<str>>,
+}
+
+/// Each tenant's storage usage, and when it was found
+type UsageCache = HashMap<Option<String>, (Instant, StorageUsage)>;
+
+/// Serve the admin API, which must only be reachable by operators. With a `token`, every request
+/// has to carry it as `Authorization: Bearer <token>`.
+///
+/// - `GET /connections` lists the connected WebSocket clients and what they're subscribed to,
+///   `GET /connections/<id>` describes one, and `DELETE /connections/<id>` disconnects it
+/// - `GET /log-filter` and `PUT /log-filter` read and replace the tracing filter, given as
+///   `RUST_LOG`-style directives in the request body
+/// - `PUT /connections/<id>/capture` starts recording a connection's requests and responses,
+///   `GET` fetches what has been recorded, and `DELETE` stops recording and returns it
+/// - `GET /state` dumps the subscription table and store statistics as JSON
+/// - `GET /storage?tenant=<name>&refresh=<bool>` reports a tenant's size on disk, how many keys
+///   each top-level item of its schema holds, and how many subscriptions there are to each. Key
+///   counts are found by scanning the store, so they're reused for a minute unless `refresh` is
+///   set; `age_secs` says how old they are.
+/// - `PUT /schema?tenant=<name>` replaces a tenant's schema with the one in the request body,
+///   without restarting, and tells its connected clients. It's rejected if anything stored
+///   doesn't fit the new schema, and refused altogether unless the API has a token, as it
+///   rewrites the store.
+/// - `POST /flush` writes everything buffered to disk, for every tenant, answering once it's
+///   there
+/// - `POST /fork?to=<path>` copies the whole database into a new one at `path`, on the server's
+///   machine, without stopping the server. Writes wait until the copy is made.
+/// - `PUT /maintenance` pauses writes to every tenant, giving clients the request body (if any) as
+///   the reason, and `DELETE /maintenance` resumes them. `GET /maintenance` says why writes are
+///   paused, or null if they aren't. Reads and subscriptions carry on throughout.
+/// - `GET /profile?seconds=<n>` times spans for n seconds (10 by default) and returns them as
+///   folded stacks for a flamegraph tool, if profiling is enabled
+/// - `GET /runtime` reports the async runtime's worker and task metrics
+/// - `GET /changes?from=<seq>&limit=<n>` reads the change log from sequence number `from` (the
+///   oldest change that hasn't been trimmed by default), with sensitive values redacted
+/// - `GET /deliveries` lists the integration deliveries waiting to be sent, and
+///   `GET /deliveries/dead` the ones that were given up on. `POST /deliveries/dead/<id>/replay`
+///   queues a dead letter again, and `DELETE /deliveries/dead/<id>` discards it.
+/// - `GET /replication/snapshot?tenant=<name>` dumps a tenant's data (the default tenant's if
+///   no name is given) along with the sequence number of the next change, and
+///   `GET /replication/changes?tenant=<name>&from=<seq>&limit=<n>&wait=<s>` reads its change
+///   log, waiting up to s seconds for a change if there are none yet. Followers use these to
+///   replicate, so nothing is redacted, and both are refused altogether unless the API has a
+///   token. Reading from a change that's been trimmed responds 410 Gone, and the follower has to
+///   start again from a snapshot.
+/// - `GET /audit?from=<id>&limit=<n>&path=<path>&user=<user>` reads the audit log from entry
+///   `from`, if it's enabled. With a slash-separated `path`, only writes that could have changed
+///   the item there are included: writes to it, to anything inside it, or to anything it's inside.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    listener: TcpListener,
+    server: Server,
+    tenants: Arc<Tenants>,
+    registry: Arc<ConnectionRegistry>,
+    diagnostics: Arc<Diagnostics>,
+    deliveries: Arc<DeliveryQueue>,
+    audit: Option<Arc<AuditLog>>,
+    token: Option<String>,
+) -> std::io::Result<()> {
+    let admin = Admin {
+        server,
+        tenants,
+        registry,
+        diagnostics,
+        deliveries,
+        audit,
+        usage: Arc::default(),
+        token: token.map(Arc::from),
+    };
+    let app = Router::new()
+        .route("/log-filter", get(get_log_filter).put(set_log_filter))
+        .route("/connections", get(list_clients))
+        .route("/connections/{id}", get(get_client).delete(kick_client))
+        .route(
+            "/connections/{id}/capture",
+            put(start_capture).get(get_capture).delete(stop_capture),
+        )
+        .route("/state", get(state))
+        .route("/storage", get(storage))
+        .route("/schema", put(replace_schema))
+        .route("/flush", post(flush))
+        .route("/fork", post(fork))
+        .route(
+            "/maintenance",
+            get(get_maintenance).put(pause_writes).delete(resume_writes),
+        )
+        .route("/profile", get(profile))
+        .route("/runtime", get(runtime))
+        .route("/changes", get(read_changes))
+        .route("/deliveries", get(pending_deliveries))
+        .route("/deliveries/dead", get(dead_letters))
+        .route("/deliveries/dead/{id}", delete(discard_dead_letter))
+        .route("/deliveries/dead/{id}/replay", post(replay_dead_letter))
+        .route("/replication/snapshot", get(replication_snapshot))
+        .route("/replication/changes", get(replication_changes))
+        .route("/audit", get(read_audit))
+        .layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin);
+
+    axum::serve(listener, app).await
+}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum AdminError {
+    #[error("invalid log filter: {0}")]
+    InvalidFilter(#[from] tracing_subscriber::filter::ParseError),
+    #[error("no client with connection id {0}")]
+    UnknownClient(u64),
+    #[error("connection {0} is not being captured")]
+    NotCapturing(u64),
+    #[error("profiling is disabled; set profiling = true in the config to enable it")]
+    ProfilingDisabled,
+    #[error("the audit log is disabled; set audit.enabled = true in the config to enable it")]
+    AuditDisabled,
+    #[error("{0}")]
+    ProfileBusy(#[from] Busy),
+    #[error("no dead letter with id {0}")]
+    UnknownDeadLetter(u64),
+    #[error("no tenant named {0}")]
+    UnknownTenant(String),
+    #[error("invalid schema: {0}")]
+    InvalidSchema(#[from] serde_json::Error),
+    #[error("the stored data doesn't fit the new schema: {0}")]
+    IncompatibleSchema(ServerError),
+    #[error("{0}")]
+    Delivery(#[from] DeliveryError),
+    #[error("{0}")]
+    Server(#[from] ServerError),
+    #[error("the admin token is missing or wrong")]
+    Unauthorized,
+    #[error("{0} takes admin credentials; set admin_token in the config to allow it")]
+    NeedsToken(&'static str),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AdminError::InvalidFilter(_) | AdminError::InvalidSchema(_) => StatusCode::BAD_REQUEST,
+            AdminError::IncompatibleSchema(_) => StatusCode::CONFLICT,
+            AdminError::UnknownClient(_)
+            | AdminError::NotCapturing(_)
+            | AdminError::UnknownDeadLetter(_)
+            | AdminError::UnknownTenant(_)
+            | AdminError::ProfilingDisabled
+            | AdminError::AuditDisabled => StatusCode::NOT_FOUND,
+            AdminError::ProfileBusy(_) => StatusCode::CONFLICT,
+            AdminError::Server(ref e) if e.kind() == ErrorKind::Trimmed => StatusCode::GONE,
+            AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminError::NeedsToken(_) => StatusCode::FORBIDDEN,
+            AdminError::Server(_) | AdminError::Delivery(_) | AdminError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+/// Turn away requests that don't carry the admin token, if there is one
+async fn authorize(
+    State(admin): State<Admin>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AdminError> {
+    let Some(token) = &admin.token else {
+        return Ok(next.run(request).await);
+    };
+    let given = (request.headers().get(header::AUTHORIZATION))
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+    // Comparing digests takes as long however much of the token is right
+    let matches =
+        given.is_some_and(|given| Blake2s256::digest(given) == Blake2s256::digest(&**token));
+    if matches {
+        Ok(next.run(request).await)
+    } else {
+        Err(AdminError::Unauthorized)
+    }
+}
+
+async fn get_log_filter(State(admin): State<Admin>) -> String {
+    admin.diagnostics.log_filter()
+}
+
+async fn set_log_filter(
+    State(admin): State<Admin>,
+    directives: String,
+) -> Result<StatusCode, AdminError> {
+    admin.diagnostics.set_log_filter(directives.trim())?;
+    tracing::info!(filter = directives.trim(), "log filter changed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_clients(State(admin): State<Admin>) -> Json<Vec<ClientInfo>> {
+    Json(admin.registry.clients())
+}
+
+async fn get_client(
+    State(admin): State<Admin>,
+    Path(id): Path<u64>,
+) -> Result<Json<ClientInfo>, AdminError> {
+    admin
+        .registry
+        .client(id)
+        .map(Json)
+        .ok_or(AdminError::UnknownClient(id))
+}
+
+async fn kick_client(
+    State(admin): State<Admin>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AdminError> {
+    if admin.registry.kick(id) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AdminError::UnknownClient(id))
+    }
+}
+
+async fn start_capture(State(admin): State<Admin>, Path(id): Path<u64>) -> StatusCode {
+    admin.diagnostics.start_capture(id);
+    StatusCode::NO_CONTENT
+}
+
+async fn get_capture(
+    State(admin): State<Admin>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<Captured>>, AdminError> {
+    admin
+        .diagnostics
+        .captured(id)
+        .map(Json)
+        .ok_or(AdminError::NotCapturing(id))
+}
+
+async fn stop_capture(
+    State(admin): State<Admin>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<Captured>>, AdminError> {
+    admin
+        .diagnostics
+        .stop_capture(id)
+        .map(Json)
+        .ok_or(AdminError::NotCapturing(id))
+}
+
+async fn state(State(admin): State<Admin>) -> Result<Json<Value>, AdminError> {
+    Ok(Json(json!({
+        "subscriptions": admin.registry.subscriptions(),
+        "subscription_tasks": admin.registry.counts(),
+        "store": admin.server.stats()?,
+    })))
+}
+
+#[derive(Deserialize)]
+struct StorageParams {
+    tenant: Option<String>,
+    #[serde(default)]
+    refresh: bool,
+}
+
+async fn storage(
+    State(admin): State<Admin>,
+    Query(params): Query<StorageParams>,
+) -> Result<Json<Value>, AdminError> {
+    let tenant = admin.tenant(params.tenant.as_deref())?;
+    let cached = (admin.usage.lock().unwrap().get(&tenant.name))
+        .filter(|(at, _)| !params.refresh && at.elapsed() < USAGE_TTL)
+        .cloned();
+    let (at, usage) = match cached {
+        Some(cached) => cached,
+        None => {
+            let server = tenant.server.clone();
+            let usage = (tokio::task::spawn_blocking(move || server.usage()).await)
+                .map_err(|e| AdminError::Internal(e.to_string()))??;
+            let found = (Instant::now(), usage);
+            (admin.usage.lock().unwrap()).insert(tenant.name.clone(), found.clone());
+            found
+        }
+    };
+    let mut subscriptions = BTreeMap::<String, usize>::new();
+    for client in admin.registry.clients() {
+        if client.tenant != tenant.name {
+            continue;
+        }
+        for key in client.subscriptions {
+            let top = key.0.first().cloned().unwrap_or_default();
+            *subscriptions.entry(top).or_default() += 1;
+        }
+    }
+    Ok(Json(json!({
+        "size_on_disk": usage.size_on_disk,
+        "keys": usage.keys,
+        "blob_keys": usage.blob_keys,
+        "subscriptions": subscriptions,
+        "age_secs": at.elapsed().as_secs(),
+    })))
+}
+
+#[derive(Deserialize)]
+struct SchemaParams {
+    tenant: Option<String>,
+}
+
+async fn replace_schema(
+    State(admin): State<Admin>,
+    Query(params): Query<SchemaParams>,
+    body: String,
+) -> Result<StatusCode, AdminError> {
+    // Requests only get this far with the token if there is one
+    if admin.token.is_none() {
+        return Err(AdminError::NeedsToken("replacing schemas"));
+    }
+    let schema: Schema = serde_json::from_str(&body)?;
+    let server = admin.tenant(params.tenant.as_deref())?.server.clone();
+    // Checking the data against the schema reads all of it
+    (tokio::task::spawn_blocking(move || server.replace_schema(schema)).await)
+        .map_err(|e| AdminError::Internal(e.to_string()))?
+        .map_err(|e| match e.kind() {
+            ErrorKind::Storage => AdminError::Server(e),
+            _ => AdminError::IncompatibleSchema(e),
+        })?;
+    tracing::info!(tenant = params.tenant, "schema replaced");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn flush(State(admin): State<Admin>) -> Result<StatusCode, AdminError> {
+    let server = admin.server.clone();
+    (tokio::task::spawn_blocking(move || server.flush()).await)
+        .map_err(|e| AdminError::Internal(e.to_string()))??;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct ForkParams {
+    to: String,
+}
+
+async fn fork(
+    State(admin): State<Admin>,
+    Query(params): Query<ForkParams>,
+) -> Result<StatusCode, AdminError> {
+    let server = admin.server.clone();
+    let to = params.to.clone();
+    // Copying reads the whole database
+    (tokio::task::spawn_blocking(move || server.fork(&to)).await)
+        .map_err(|e| AdminError::Internal(e.to_string()))??;
+    tracing::info!(to = params.to, "forked the database");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_maintenance(State(admin): State<Admin>) -> Json<Option<Maintenance>> {
+    Json(admin.server.maintenance())
+}
+
+async fn pause_writes(State(admin): State<Admin>, reason: String) -> StatusCode {
+    let reason = Some(reason.trim().to_string()).filter(|reason| !reason.is_empty());
+    tracing::warn!(reason, "writes paused for maintenance");
+    admin.server.pause_writes(Maintenance { reason });
+    StatusCode::NO_CONTENT
+}
+
+async fn resume_writes(State(admin): State<Admin>) -> StatusCode {
+    admin.server.resume_writes();
+    tracing::info!("writes resumed");
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize)]
+struct ProfileParams {
+    #[serde(default = "default_profile_secs")]
+    seconds: u64,
+}
+
+fn default_profile_secs() -> u64 {
+    10
+}
+
+async fn profile(
+    State(admin): State<Admin>,
+    Query(params): Query<ProfileParams>,
+) -> Result<String, AdminError> {
+    let profiler = admin
+        .diagnostics
+        .profiler
+        .as_ref()
+        .ok_or(AdminError::ProfilingDisabled)?;
+    let duration = Duration::from_secs(params.seconds.min(MAX_PROFILE_SECS));
+    tracing::info!(?duration, "capturing a profile");
+    Ok(profiler.capture(duration).await?)
+}
+
+async fn runtime() -> Json<Value> {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let workers: Vec<Value> = (0..metrics.num_workers())
+        .map(|worker| {
+            json!({
+                "busy_secs": metrics.worker_total_busy_duration(worker).as_secs_f64(),
+                "parks": metrics.worker_park_count(worker),
+            })
+        })
+        .collect();
+    Json(json!({
+        "workers": workers,
+        "alive_tasks": metrics.num_alive_tasks(),
+        "global_queue_depth": metrics.global_queue_depth(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct ChangesParams {
+    #[serde(default)]
+    from: u64,
+    #[serde(default = "default_changes_limit")]
+    limit: usize,
+}
+
+fn default_changes_limit() -> usize {
+    100
+}
+
+async fn read_changes(
+    State(admin): State<Admin>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Json<Vec<Change>>, AdminError> {
+    // Changes that have been trimmed are skipped over
+    let from = params.from.max(admin.server.oldest_change()?);
+    let changes = admin.server.changes(from, params.limit.min(MAX_CHANGES))?;
+    Ok(Json(
+        changes
+            .iter()
+            .map(|change| admin.server.redact_change(change))
+            .collect(),
+    ))
+}
+
+async fn pending_deliveries(State(admin): State<Admin>) -> Result<Json<Vec<Delivery>>, AdminError> {
+    Ok(Json(admin.deliveries.pending()?))
+}
+
+async fn dead_letters(State(admin): State<Admin>) -> Result<Json<Vec<Delivery>>, AdminError> {
+    Ok(Json(admin.deliveries.dead_letters()?))
+}
+
+async fn replay_dead_letter(
+    State(admin): State<Admin>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AdminError> {
+    if admin.deliveries.replay(id)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AdminError::UnknownDeadLetter(id))
+    }
+}
+
+async fn discard_dead_letter(
+    State(admin): State<Admin>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AdminError> {
+    if admin.deliveries.discard(id)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AdminError::UnknownDeadLetter(id))
+    }
+}
+
+#[derive(Deserialize)]
+struct ReplicationParams {
+    tenant: Option<String>,
+    #[serde(default)]
+    from: u64,
+    #[serde(default = "default_changes_limit")]
+    limit: usize,
+    #[serde(default)]
+    wait: u64,
+}
+
+impl Admin {
+    fn tenant(&self, name: Option<&str>) -> Result<&Tenant, AdminError> {
+        self.tenants
+            .get(name)
+            .ok_or_else(|| AdminError::UnknownTenant(name.unwrap_or_default().to_string()))
+    }
+}
+
+async fn replication_snapshot(
+    State(admin): State<Admin>,
+    Query(params): Query<ReplicationParams>,
+) -> Result<Json<Value>, AdminError> {
+    // Followers get everything, sensitive fields included, so it's never open to anyone
+    if admin.token.is_none() {
+        return Err(AdminError::NeedsToken("replication"));
+    }
+    let server = admin.tenant(params.tenant.as_deref())?.server.clone();
+    // Dumping reads the whole database and holds up writes, so keep it off the workers
+    let (seq, snapshot) = tokio::task::spawn_blocking(move || server.snapshot())
+        .await
+        .map_err(|e| AdminError::Internal(format!("{e}")))??;
+    Ok(Json(json!({ "seq": seq, "snapshot": snapshot })))
+}
+
+async fn replication_changes(
+    State(admin): State<Admin>,
+    Query(params): Query<ReplicationParams>,
+) -> Result<Json<Vec<Change>>, AdminError> {
+    if admin.token.is_none() {
+        return Err(AdminError::NeedsToken("replication"));
+    }
+    let server = &admin.tenant(params.tenant.as_deref())?.server;
+    let limit = params.limit.min(MAX_CHANGES);
+    // Watch before reading, so a change committed in between isn't missed
+    let mut committed = server.watch_changes();
+    let changes = server.changes(params.from, limit)?;
+    if !changes.is_empty() || params.wait == 0 {
+        return Ok(Json(changes));
+    }
+    let wait = Duration::from_secs(params.wait.min(MAX_REPLICATION_WAIT_SECS));
+    let _ = tokio::time::timeout(wait, committed.changed()).await;
+    Ok(Json(server.changes(params.from, limit)?))
+}
+
+#[derive(Deserialize)]
+struct AuditParams {
+    #[serde(default)]
+    from: u64,
+    #[serde(default = "default_changes_limit")]
+    limit: usize,
+    path: Option<String>,
+    user: Option<String>,
+}
+
+async fn read_audit(
+    State(admin): State<Admin>,
+    Query(params): Query<AuditParams>,
+) -> Result<Json<Vec<AuditEntry>>, AdminError> {
+    let audit = admin.audit.as_ref().ok_or(AdminError::AuditDisabled)?;
+    let path: Option<Vec<&str>> = params
+        .path
+        .as_deref()
+        .map(|path| path.split('/').filter(|c| !c.is_empty()).collect());
+    let entries = audit.read(params.from, params.limit.min(MAX_CHANGES), |entry| {
+        let written = &entry.attempt.path.0;
+        let related = path.as_ref().is_none_or(|path| {
+            let shared = written.len().min(path.len());
+            written[..shared].iter().eq(path[..shared].iter())
+        });
+        related && (params.user.is_none() || entry.attempt.user == params.user)
+    })?;
+    Ok(Json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::reload;
+
+    use crate::schema::{Schema, SchemaItem};
+
+    use super::*;
+
+    #[test]
+    fn capture_redacts_sensitive_values() {
+        let (_, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let diagnostics = Diagnostics::new(handle, None);
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::open(
+            dir.path().to_str().unwrap(),
+            Schema::new(SchemaItem::Document(
+                [(
+                    "pin".to_string(),
+                    SchemaItem::Sensitive(Box::new(SchemaItem::Scalar)),
+                )]
+                .into_iter()
+                .collect(),
+            )),
+        )
+        .unwrap();
+        let key = Ref(vec!["pin".into()]);
+
+        // Nothing is recorded until capture is turned on
+        diagnostics.capture_request(0, &server, &ClientMessage::Get(key.clone()));
+        assert!(diagnostics.captured(0).is_none());
+
+        diagnostics.start_capture(0);
+        diagnostics.capture_request(
+            0,
+            &server,
+            &ClientMessage::Insert(key.clone(), "1234".into()),
+        );
+        diagnostics.capture_response(0, &server, Some(&key), &ServerMessage::Value("1234".into()));
+        let captured = diagnostics.stop_capture(0).unwrap();
+        assert_eq!(captured[0].direction, Direction::Received);
+        assert_eq!(
+            captured[0].message,
+            json!({ "Insert": [["pin"], "[redacted]"] })
+        );
+        assert_eq!(captured[1].message, json!({ "Value": "[redacted]" }));
+        assert!(diagnostics.captured(0).is_none());
+    }
+}
@@ -0,0 +1,2299 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{self, ready, Poll},
+    time::Duration,
+};
+
+use flate2::{write::DeflateEncoder, Compression};
+use futures_util::{
+    future,
+    stream::{self, BoxStream},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{mpsc, watch, Semaphore},
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        self,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header, StatusCode},
+        protocol::{
+            frame::{
+                coding::{Data, OpCode},
+                Frame,
+            },
+            WebSocketConfig,
+        },
+        Error,
+    },
+};
+use tracing::Instrument;
+
+#[cfg(feature = "grpc")]
+use crate::grpc;
+#[cfg(feature = "nats")]
+use crate::nats;
+use crate::{
+    accounts::{AccountError, Accounts, SignedIn},
+    admin::{self, Diagnostics},
+    audit::{Attempt, AuditLog, Source},
+    backup::Backups,
+    config::{AccountsConfig, Config, LimitsConfig},
+    delivery::DeliveryQueue,
+    features::FeatureFlags,
+    functions::{self, FunctionPool, Functions},
+    gc, handshake, http,
+    integration::Integrations,
+    jobs::Jobs,
+    limits::{self, LimitError},
+    listener::Listener,
+    logging,
+    message::{
+        ClientMessage, Encoding, ErrorCode, ErrorMessage, Explanation, Ref, RuleCheck,
+        ServerMessage, PROTOCOL_VERSION, USER_PLACEHOLDER,
+    },
+    oidc::IdentityProviders,
+    outbox::{Outbox, SlowConsumerPolicy},
+    permission::{Operation, PermissionPool, Permissions},
+    profile::Profiler,
+    registry::{self, ConnectionRegistry, TaskId},
+    replay::ReplayGuard,
+    replication::Follower,
+    retention,
+    schema::{KeyFormat, Schema},
+    server::{Chunked, Event, Server, ServerError},
+    session::{Identity, Session, Sessions},
+    tenant::{Tenant, Tenants},
+    tls,
+    webhook::Webhook,
+};
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// The longest a client may ask for subscription updates to be held back
+const MAX_DEBOUNCE: Duration = Duration::from_secs(60);
+/// How many changes a resumable subscription reads from the log at a time
+const RESUME_BATCH: usize = 100;
+/// Roughly how much JSON each `ValueChunk` holds
+const VALUE_CHUNK_SIZE: usize = 64 * 1024;
+/// How long a new connection has to send its `Hello`
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// The feature that has the server compress what it sends, offered when `connections.compression`
+/// is set
+const DEFLATE: &str = "deflate";
+
+/// Compile the functions script at `path`, or one with no functions if there isn't one
+fn load_functions(path: Option<&Path>) -> anyhow::Result<&'static [u8]> {
+    let source = match path {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => functions::NO_FUNCTIONS.to_string(),
+    };
+    Ok(Functions::load_bytecode(&source)?)
+}
+
+/// Insert the tree in the JSON file at `path` as the whole database, unless something has
+/// already been written to it
+fn load_seed(server: &Server, path: &Path) -> anyhow::Result<()> {
+    if !server.is_empty() {
+        tracing::info!("the database already holds data, so the seed file isn't loaded");
+        return Ok(());
+    }
+    let seed: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if let Err(e) = server.insert(&Ref(Vec::new()), seed) {
+        anyhow::bail!(
+            "the seed file {} doesn't fit the schema: {e}",
+            path.display()
+        );
+    }
+    tracing::info!(seed = %path.display(), "loaded seed data");
+    Ok(())
+}
+
+/// Open the database, optionally waiting for another process to release its lock
+pub async fn open_server(
+    data: &str,
+    config: &Config,
+    wait_for_lock: bool,
+) -> anyhow::Result<Server> {
+    let mut warned = false;
+    loop {
+        let schema = Schema::load(&config.schema)?;
+        match Server::open_with(data, schema, config.sled.to_sled()) {
+            Err(err @ ServerError::DatabaseLocked { .. }) if wait_for_lock => {
+                if !warned {
+                    tracing::warn!("{err}; waiting for it to be released");
+                    warned = true;
+                }
+                tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// How `serve` treats the database it opens, beyond what the config says
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    /// If the database is locked by another process, wait for it to be released instead of
+    /// failing
+    pub wait_for_lock: bool,
+    /// Convert data written by the legacy string-keyed server into the current layout, rather
+    /// than refusing to start
+    pub migrate_legacy: bool,
+    /// The separator between path components in legacy keys
+    pub legacy_separator: char,
+    /// A JSON file holding the whole tree to start with, inserted if the database is empty
+    pub seed: Option<PathBuf>,
+}
+
+/// Where a running server accepts connections
+#[derive(Clone, Debug)]
+pub struct Addresses {
+    /// The URL of each WebSocket listener, starting with the one `listen` configures
+    pub listeners: Vec<String>,
+    /// The URL of the HTTP gateway
+    pub http: String,
+    /// The URL of the admin API, if it's enabled
+    pub admin: Option<String>,
+}
+
+/// Run the server `config` describes until the process is asked to stop: the WebSocket
+/// listener, along with the HTTP gateway and whatever else the config turns on
+pub async fn serve(
+    config: Config,
+    options: ServeOptions,
+    log_filter: logging::FilterHandle,
+    profiler: Option<Arc<Profiler>>,
+) -> anyhow::Result<()> {
+    let shutdown = shutdown_signal();
+    serve_until(config, options, log_filter, profiler, announce, shutdown).await
+}
+
+/// Like `serve`, but passing the addresses bound to `ready` once connections are being accepted,
+/// and shutting down once `shutdown` resolves rather than on a signal, so another program can
+/// run a server of its own
+pub async fn serve_until(
+    config: Config,
+    options: ServeOptions,
+    log_filter: logging::FilterHandle,
+    profiler: Option<Arc<Profiler>>,
+    ready: impl FnOnce(&Addresses),
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    if config.dev_mode {
+        tracing::warn!("dev mode is on, so clients are shown the schema and permission rules");
+    }
+    let mut listeners = Vec::new();
+    let addresses = (config.listeners.iter()).map(|listener| (&listener.address, &listener.tls));
+    for (address, tls) in std::iter::once((&config.listen, &config.tls)).chain(addresses) {
+        let tls_acceptor = match tls {
+            Some(tls) => Some(tls::acceptor(&tls.cert, &tls.key)?),
+            None => None,
+        };
+        listeners.push((Listener::bind(address).await?, tls_acceptor));
+    }
+
+    let source = std::fs::read_to_string(&config.rules)?;
+    let permission_bytecode = Permissions::load_bytecode(&source)?;
+
+    let mut server = if config.ephemeral {
+        tracing::info!("using a temporary database; nothing will be kept after exiting");
+        Server::open_temporary(Schema::load(&config.schema)?, config.sled.to_sled())?
+    } else {
+        open_server(&config.data, &config, options.wait_for_lock).await?
+    };
+    if server.has_legacy_keys()? {
+        if !options.migrate_legacy {
+            anyhow::bail!(
+                "the database contains data from the legacy string-keyed server; \
+                 run with --migrate-legacy to convert it"
+            );
+        }
+        let report = server.migrate_legacy(options.legacy_separator)?;
+        tracing::info!(migrated = report.migrated, "migrated legacy keys");
+        for (key, reason) in report.rejected.iter() {
+            tracing::error!(key, "could not migrate legacy key: {reason}");
+        }
+    }
+
+    if let Some(seed) = &options.seed {
+        if config.replication.leader.is_some() {
+            tracing::warn!("seed data isn't loaded on followers; they replicate the leader's");
+        } else {
+            load_seed(&server, seed)?;
+        }
+    }
+
+    if config.replication.leader.is_some() {
+        server = server.read_only();
+    }
+
+    if config.history.keep > 0 {
+        server = server.with_history(config.history.keep)?;
+    }
+
+    if let Some(chunk_size) = config.sled.chunk_size {
+        server = server.with_chunk_size(chunk_size);
+    }
+
+    if config.sled.sync_writes {
+        server = server.with_sync_writes();
+    }
+
+    if let Some(slow_ms) = config.log.slow_ms {
+        server = server.with_slow_threshold(Duration::from_millis(slow_ms));
+    }
+
+    let mut tenants = Tenants::new(
+        server.clone(),
+        permission_bytecode,
+        load_functions(config.functions.as_deref())?,
+    );
+    for (name, tenant) in &config.tenants {
+        anyhow::ensure!(
+            KeyFormat::Slug.matches(name),
+            "tenant names must be lowercase words separated by dashes, not {name:?}"
+        );
+        let source = std::fs::read_to_string(&tenant.rules)?;
+        tenants.add(
+            name,
+            Schema::load(&tenant.schema)?,
+            Permissions::load_bytecode(&source)?,
+            load_functions(tenant.functions.as_deref())?,
+        )?;
+    }
+
+    let tenants = Arc::new(tenants);
+    let backups = config
+        .backups
+        .dir
+        .as_ref()
+        .map(|dir| Arc::new(Backups::new(tenants.clone(), dir.clone(), &config.backups)));
+    if let Some(backups) = &backups {
+        tokio::spawn(backups.clone().run());
+    }
+
+    // Removing orphans writes, so like jobs it only happens on the leader
+    match (config.gc.interval_secs, &config.replication.leader) {
+        (Some(_), Some(_)) => {
+            tracing::warn!(
+                "orphans aren't cleaned up on followers, which only take replicated writes"
+            )
+        }
+        (Some(secs), None) => {
+            tokio::spawn(gc::run(tenants.clone(), Duration::from_secs(secs.max(1))));
+        }
+        (None, _) => {}
+    }
+
+    tokio::spawn(retention::run(
+        tenants.clone(),
+        config.changes.keep,
+        Duration::from_secs(config.changes.trim_interval_secs.max(1)),
+    ));
+
+    // Jobs write, so they only run on the leader
+    let jobs = Arc::new(Jobs::new(tenants.clone(), &config.jobs)?);
+    let jobs = if config.replication.leader.is_some() {
+        if !jobs.is_empty() {
+            tracing::warn!("jobs don't run on followers; they run on the leader");
+        }
+        None
+    } else if jobs.is_empty() {
+        None
+    } else {
+        jobs.clone().start();
+        Some(jobs)
+    };
+
+    if let Some(leader) = &config.replication.leader {
+        Follower::new(tenants.clone(), leader, config.replication.token.clone())?.start();
+        tracing::info!(leader, "replicating");
+    }
+
+    let audit = if config.audit.enabled {
+        Some(Arc::new(AuditLog::open(&server, config.audit.denied)?))
+    } else {
+        None
+    };
+
+    let deliveries = Arc::new(DeliveryQueue::open(&server, config.deliveries.clone())?);
+    let mut integrations = Integrations::new(&server, deliveries.clone());
+    // Followers apply the leader's changes, which the leader's integrations have already sent
+    let following = config.replication.leader.is_some();
+    if following && (!config.webhooks.is_empty() || config.nats.is_some()) {
+        tracing::warn!("integrations don't run on followers; configure them on the leader");
+    }
+    if !config.webhooks.is_empty() && !following {
+        let tls = tls::connector()?;
+        for webhook in &config.webhooks {
+            // Deliveries are queued by sink name, so it has to stay the same across restarts
+            integrations.add(
+                Ref(webhook.path.clone()),
+                format!("webhook {}", webhook.url),
+                Arc::new(Webhook::new(&webhook.url, tls.clone())?),
+            );
+        }
+    }
+    #[cfg(feature = "nats")]
+    if let Some(nats_config) = config.nats.as_ref().filter(|_| !following) {
+        integrations.add(
+            Ref(nats_config.path.clone()),
+            format!("nats {} {}", nats_config.url, nats_config.subject),
+            Arc::new(nats::Nats::new(&server, nats_config)?),
+        );
+    }
+    #[cfg(not(feature = "nats"))]
+    if config.nats.is_some() {
+        tracing::warn!("[nats] is configured, but this build doesn't have the nats feature");
+    }
+    integrations.start()?;
+    tokio::spawn(deliveries.clone().run());
+
+    let registry = Arc::new(ConnectionRegistry::new());
+    tokio::spawn(registry::watchdog(registry.clone(), WATCHDOG_INTERVAL));
+    let replay_guard = Arc::new(ReplayGuard::new(
+        config.replay.window(),
+        config.replay.require_envelopes,
+    ));
+
+    let http_listener = TcpListener::bind(&config.http_listen).await?;
+    let http = format!("http://{}", http_listener.local_addr()?);
+    let accounts = (config.accounts.enabled || config.accounts.anonymous)
+        .then(|| Accounts::open(&server, config.accounts))
+        .transpose()?;
+    tokio::spawn(http::serve(
+        http_listener,
+        server.clone(),
+        permission_bytecode,
+        accounts.clone(),
+        config.limits,
+        config.connections.clone(),
+        registry.clone(),
+        replay_guard.clone(),
+        audit.clone(),
+        backups,
+        jobs,
+    ));
+
+    let diagnostics = Arc::new(Diagnostics::new(log_filter, profiler));
+    let mut admin = None;
+    if let Some(admin_listen) = &config.admin_listen {
+        let admin_listener = TcpListener::bind(admin_listen).await?;
+        admin = Some(format!("http://{}", admin_listener.local_addr()?));
+        tokio::spawn(admin::serve(
+            admin_listener,
+            server.clone(),
+            tenants.clone(),
+            registry.clone(),
+            diagnostics.clone(),
+            deliveries.clone(),
+            audit.clone(),
+            config.admin_token.clone(),
+        ));
+    }
+
+    #[cfg(feature = "grpc")]
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(grpc::IceloadService::new(
+                server.clone(),
+                permission_bytecode,
+                accounts,
+                config.limits,
+                config.connections.clone(),
+                registry.clone(),
+                replay_guard.clone(),
+                audit.clone(),
+            ))
+            .serve(config.grpc_listen),
+    );
+
+    let feature_flags = FeatureFlags::new(config.features);
+
+    // Each listener accepts on its own task, and they all feed the one loop below
+    let (incoming_send, mut incoming) = mpsc::channel(1);
+    let mut accepting = JoinSet::new();
+    let mut urls = Vec::new();
+    for (listener, tls_acceptor) in listeners {
+        urls.push(listener.url(tls_acceptor.is_some())?);
+        let incoming_send = incoming_send.clone();
+        accepting.spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::error!("stopped accepting connections: {e}");
+                        break;
+                    }
+                };
+                let accepted = (stream, peer, tls_acceptor.clone());
+                if incoming_send.send(accepted).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(incoming_send);
+    ready(&Addresses {
+        listeners: urls,
+        http,
+        admin,
+    });
+
+    let identity_providers = if config.oidc.is_empty() {
+        None
+    } else {
+        let providers = IdentityProviders::new(config.oidc.clone(), tls::connector()?);
+        Some(Arc::new(providers))
+    };
+
+    let (shutdown_send, shutdown_receiver) = watch::channel(false);
+    let context = Context {
+        tenants,
+        replay_guard,
+        registry,
+        diagnostics,
+        audit,
+        accounts: config.accounts,
+        identity_providers,
+        shutdown: shutdown_receiver,
+        dev_mode: config.dev_mode,
+        send_buffer: config.connections.send_buffer,
+        slow_consumer: config.connections.slow_consumer,
+        ping_interval: config.connections.ping_interval(),
+        idle_timeout: config.connections.idle_timeout(),
+        max_frame_bytes: config.connections.max_frame_bytes,
+        compression: config.connections.compression,
+        max_in_flight: config.connections.max_in_flight.max(1),
+        limits: config.limits,
+        sessions: Arc::new(Sessions::new(config.connections.session_grace())),
+        allowed_origins: config.connections.allowed_origins.into(),
+    };
+    let connection_limit = Arc::new(Semaphore::new(
+        config
+            .connections
+            .max
+            .unwrap_or(Semaphore::MAX_PERMITS)
+            .min(Semaphore::MAX_PERMITS),
+    ));
+
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer, tls_acceptor) = tokio::select! {
+            accepted = incoming.recv() => match accepted {
+                Some(accepted) => accepted,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        let Ok(permit) = connection_limit.clone().try_acquire_owned() else {
+            tracing::warn!(%peer, "rejected a connection: too many connections");
+            continue;
+        };
+        let context = context.clone();
+        let connection_id = context.registry.next_connection_id();
+        let features = feature_flags.assign(connection_id);
+        let span = tracing::info_span!(
+            "connection",
+            client = connection_id,
+            %peer,
+            tenant = tracing::field::Empty,
+        );
+        tokio::spawn(
+            async move {
+                let result = match tls_acceptor {
+                    Some(acceptor) => {
+                        let stream = match acceptor.accept(stream).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                tracing::warn!("TLS handshake failed: {e}");
+                                return;
+                            }
+                        };
+                        client_task(context, stream, peer, features, connection_id).await
+                    }
+                    None => client_task(context, stream, peer, features, connection_id).await,
+                };
+                if let Err(e) = result {
+                    tracing::warn!("connection ended: {e}");
+                }
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+
+    tracing::info!("shutting down");
+    drop(accepting);
+    drop(context);
+    // Every connection holds a receiver, and drops it once it has finished its current request
+    // and told its client we're going away
+    shutdown_send.send_replace(true);
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, shutdown_send.closed())
+        .await
+        .is_err()
+    {
+        tracing::warn!("some connections did not close in time");
+    }
+    server.flush()?;
+
+    Ok(())
+}
+
+/// Print where the server is listening. Bound addresses are announced on stdout rather than
+/// logged, so scripts can rely on finding them there whatever the log settings.
+fn announce(addresses: &Addresses) {
+    println!("HTTP gateway listening on {}", addresses.http);
+    if let Some(admin) = &addresses.admin {
+        println!("admin API listening on {admin}");
+    }
+    for url in addresses.listeners.iter() {
+        println!("listening on {url}");
+    }
+}
+
+/// Resolve once the process is asked to stop, by Ctrl-C or SIGTERM
+// The handlers are installed straight away rather than on first poll, as the accept loop may not
+// get around to polling this before a signal arrives
+fn shutdown_signal() -> impl Future<Output = ()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut interrupt = signal(SignalKind::interrupt()).expect("failed to listen for SIGINT");
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        async move {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    async {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// State shared by every connection
+#[derive(Clone)]
+struct Context {
+    tenants: Arc<Tenants>,
+    replay_guard: Arc<ReplayGuard>,
+    registry: Arc<ConnectionRegistry>,
+    diagnostics: Arc<Diagnostics>,
+    audit: Option<Arc<AuditLog>>,
+    /// How clients may sign in to accounts the server keeps itself
+    accounts: AccountsConfig,
+    /// The OpenID Connect providers whose ID tokens clients may authenticate with
+    identity_providers: Option<Arc<IdentityProviders>>,
+    /// Becomes true when the server starts shutting down
+    shutdown: watch::Receiver<bool>,
+    dev_mode: bool,
+    send_buffer: usize,
+    slow_consumer: SlowConsumerPolicy,
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    max_frame_bytes: usize,
+    compression: bool,
+    max_in_flight: usize,
+    limits: LimitsConfig,
+    sessions: Arc<Sessions>,
+    allowed_origins: Arc<[String]>,
+}
+
+// The handshake callback's error type is chosen by tungstenite
+#[allow(clippy::result_large_err)]
+async fn client_task(
+    context: Context,
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    peer: String,
+    features: BTreeSet<String>,
+    connection_id: u64,
+) -> anyhow::Result<()> {
+    let Context {
+        tenants,
+        replay_guard,
+        registry,
+        diagnostics,
+        audit,
+        accounts,
+        identity_providers,
+        mut shutdown,
+        dev_mode,
+        send_buffer,
+        slow_consumer,
+        ping_interval,
+        idle_timeout,
+        max_frame_bytes,
+        compression,
+        max_in_flight,
+        limits,
+        sessions,
+        allowed_origins,
+    } = context;
+
+    // Clients pick a tenant with the path they connect to
+    let mut tenant = None;
+    // Oversized messages are refused as soon as their frame header arrives, before they're read
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(limits.max_message_bytes),
+        max_frame_size: Some(limits.max_message_bytes),
+        ..WebSocketConfig::default()
+    };
+    let ws_stream = accept_hdr_async_with_config(
+        stream,
+        |request: &Request, mut response: Response| {
+            let reject = |status, reason| {
+                let mut response = ErrorResponse::new(Some(reason));
+                *response.status_mut() = status;
+                Err(response)
+            };
+            // Stops pages on other sites from connecting with their visitors' cookies
+            if !handshake::origin_allowed(request, &allowed_origins) {
+                return reject(StatusCode::FORBIDDEN, "origin not allowed".to_string());
+            }
+            match handshake::negotiate_subprotocol(request) {
+                Ok(Some(protocol)) => {
+                    (response.headers_mut()).insert(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+                }
+                Ok(None) => {}
+                Err(reason) => return reject(StatusCode::BAD_REQUEST, reason),
+            }
+            // Extensions like permessage-deflate are never accepted, as tungstenite can't speak
+            // them; messages are compressed with the `deflate` feature instead
+            let path = request.uri().path();
+            match tenants.for_path(path) {
+                Some(selected) => {
+                    tenant = Some(selected.clone());
+                    Ok(response)
+                }
+                None => reject(StatusCode::NOT_FOUND, format!("no tenant at {path}")),
+            }
+        },
+        Some(ws_config),
+    )
+    .await?;
+    let Tenant {
+        name: tenant,
+        server,
+        permission_bytecode,
+        function_bytecode,
+    } = tenant.expect("the tenant is chosen during the handshake");
+    if let Some(tenant) = &tenant {
+        tracing::Span::current().record("tenant", tenant.as_str());
+    }
+    let accounts = (accounts.enabled || accounts.anonymous)
+        .then(|| Accounts::open(&server, accounts))
+        .transpose()?;
+    let (mut ws_send, mut ws_recv) = ws_stream.split();
+    tracing::debug!("connection opened");
+
+    let mut features = features;
+    if compression {
+        features.insert(DEFLATE.to_string());
+    }
+    let handshake = handshake(
+        &mut ws_recv,
+        &mut ws_send,
+        features,
+        &sessions,
+        tenant.as_deref(),
+    );
+    let Handshake {
+        encoding,
+        compressed,
+        session: session_token,
+        resumed,
+    } = match handshake.await {
+        Ok(Some(handshake)) => handshake,
+        done => {
+            let _ = ws_send.close().await;
+            return done.map(|_| ());
+        }
+    };
+
+    let outbox = Outbox::new(send_buffer, slow_consumer);
+
+    let recv_resp = outbox.clone();
+    let change_log = server.clone();
+    let send_task = tokio::spawn(async move {
+        let mut pings = tokio::time::interval(ping_interval);
+        // The first tick would be immediate, but the client was only just heard from
+        pings.reset();
+        loop {
+            let message = tokio::select! {
+                msg = recv_resp.recv() => match msg {
+                    Some(msg) if compressed => deflate(encode(encoding, &msg)),
+                    Some(msg) => encode(encoding, &msg),
+                    None => break,
+                },
+                _ = pings.tick() => tungstenite::Message::Ping(ping_payload(&change_log)),
+            };
+            for frame in fragment(message, max_frame_bytes) {
+                if ws_send.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = ws_send.close().await;
+    });
+
+    // Clients stay who they were in the session they resume
+    let identity = resumed
+        .as_ref()
+        .and_then(|resumed| resumed.identity.clone());
+    let permissions = Permissions::new(permission_bytecode)
+        .with_slow_threshold(server.slow_threshold())
+        .with_claims(identity.as_ref().and_then(Identity::claims).cloned());
+    let pooled_permissions = PermissionPool::new(permission_bytecode, server.slow_threshold());
+    let mut connection = Connection {
+        id: connection_id,
+        tenant: tenant.clone(),
+        server,
+        permission_bytecode,
+        permissions,
+        pooled_permissions: Arc::new(pooled_permissions),
+        functions: Arc::new(FunctionPool::new(function_bytecode)),
+        registry,
+        diagnostics,
+        audit,
+        accounts,
+        identity_providers,
+        identity,
+        outbox,
+        subscriptions: HashMap::new(),
+        requests: HashMap::new(),
+        limits,
+        dev_mode,
+        replies: Replies::default(),
+        in_flight: Vec::new(),
+    };
+    let kicked = connection.registry.register(connection_id, peer, tenant);
+    let mut schema_changes = connection.server.schema_changes();
+    // The first change the client may not have been sent updates for, which its session is
+    // caught up from if it's resumed
+    let mut acked = match &resumed {
+        Some(resumed) => resumed.cursor,
+        None => connection.server.next_change()?,
+    };
+    if let Some(resumed) = resumed {
+        tracing::debug!("resumed a session");
+        connection
+            .restore(resumed.subscriptions, resumed.cursor)
+            .await?;
+    }
+
+    // Whether to deliver what's queued before closing, so the client learns why it's going away
+    let mut drain = false;
+    // Whether the client may want to resume the session, as the connection wasn't closed on
+    // purpose
+    let mut park = true;
+    let mut idle_deadline = Instant::now() + idle_timeout;
+    let result = async {
+        loop {
+            // Reads and writes of the store run alongside each other, and their responses are sent
+            // as they finish, in order. Shutdown waits for them to be answered, so it never
+            // interrupts one midway.
+            let msg = tokio::select! {
+                msg = ws_recv.next(), if connection.replies.len() < max_in_flight => msg,
+                Some(reply) = connection.replies.next() => {
+                    connection.deliver(reply?).await?;
+                    continue;
+                }
+                _ = shutdown.changed() => {
+                    drain = true;
+                    park = false;
+                    connection.flush_replies().await?;
+                    connection.outbox.send(ServerMessage::ServerShutdown).await?;
+                    break;
+                }
+                _ = kicked.notified() => {
+                    tracing::info!("disconnected by an administrator");
+                    drain = true;
+                    park = false;
+                    connection.flush_replies().await?;
+                    connection
+                        .outbox
+                        .send(ServerMessage::error(
+                            ErrorCode::Disconnected,
+                            "disconnected by an administrator",
+                        ))
+                        .await?;
+                    break;
+                }
+                Ok(()) = schema_changes.changed() => {
+                    connection.outbox.send(ServerMessage::SchemaChanged).await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(idle_deadline) => {
+                    tracing::info!("closing a connection that went silent");
+                    break;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(Error::ConnectionClosed) => break,
+                Err(Error::Capacity(e)) => {
+                    tracing::info!("closing a connection that sent too much: {e}");
+                    drain = true;
+                    park = false;
+                    let e = LimitError::MessageTooLarge {
+                        limit: limits.max_message_bytes,
+                    };
+                    connection.flush_replies().await?;
+                    connection.outbox.send(ServerMessage::from(&e)).await?;
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            idle_deadline = Instant::now() + idle_timeout;
+            let msg = match msg {
+                // Pongs echo the number of the next change as of their ping, so the client has
+                // been sent the updates for every change before it
+                tungstenite::Message::Pong(payload) => {
+                    if let Ok(next) = <[u8; 8]>::try_from(payload.as_slice()) {
+                        acked = u64::from_be_bytes(next);
+                    }
+                    continue;
+                }
+                // tungstenite answers pings itself; they only matter for keeping the connection
+                tungstenite::Message::Ping(_) | tungstenite::Message::Frame(_) => continue,
+                tungstenite::Message::Close(_) => {
+                    park = false;
+                    break;
+                }
+                msg => decode(&msg)?,
+            };
+            if let Err(e) = limits::check(&limits, &msg) {
+                tracing::debug!("rejected by limits: {e}");
+                connection.respond(None, ServerMessage::from(&e), None);
+                continue;
+            }
+            let msg = match replay_guard.open(msg, &session_token) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    tracing::debug!("rejected by replay protection: {e}");
+                    connection.respond(None, ServerMessage::error(e.kind().into(), e), None);
+                    continue;
+                }
+            };
+            let span = match msg.key() {
+                Some(key) => tracing::info_span!("request", op = msg.op(), r#ref = %key),
+                None => tracing::info_span!("request", op = msg.op()),
+            };
+            connection.handle(msg).instrument(span).await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    tracing::debug!("connection closed");
+    if park {
+        let subscriptions = (connection.requests.drain())
+            .filter(|(key, _)| connection.subscriptions.contains_key(key))
+            .map(|(_, msg)| msg)
+            .collect();
+        let session = Session {
+            tenant: connection.tenant.clone(),
+            subscriptions,
+            cursor: acked,
+            identity: connection.identity.clone(),
+        };
+        sessions.park(session_token, session);
+    }
+    connection.registry.unregister(connection_id);
+    connection.server.leave_all(connection_id);
+    connection.outbox.close();
+    if drain {
+        let _ = send_task.await;
+    } else {
+        send_task.abort();
+    }
+
+    result
+}
+
+/// What a client settled on in its `Hello`
+struct Handshake {
+    encoding: Encoding,
+    /// Whether everything after the `Welcome` is compressed, as the `deflate` feature is enabled
+    compressed: bool,
+    /// The token the client can resume this connection's session with
+    session: String,
+    /// The session the client asked to resume, if it could be
+    resumed: Option<Session>,
+}
+
+/// Wait for the client's `Hello` and answer it, if the client speaks a protocol version this
+/// server does. Clients that don't are told why, in JSON.
+async fn handshake(
+    ws_recv: &mut (impl Stream<Item = Result<tungstenite::Message, Error>> + Unpin),
+    ws_send: &mut (impl Sink<tungstenite::Message, Error = Error> + Unpin),
+    features: BTreeSet<String>,
+    sessions: &Sessions,
+    tenant: Option<&str>,
+) -> anyhow::Result<Option<Handshake>> {
+    let hello = match tokio::time::timeout(HELLO_TIMEOUT, ws_recv.next()).await {
+        Ok(Some(msg)) => decode(&msg?).ok(),
+        Ok(None) => return Ok(None),
+        Err(_) => None,
+    };
+    let rejection = match hello {
+        Some(ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            features: supported,
+            encoding,
+            session: resume,
+        }) => {
+            // Experimental features are only enabled for clients that know how to handle them
+            let features: BTreeSet<String> = features.intersection(&supported).cloned().collect();
+            let compressed = features.contains(DEFLATE);
+            let resumed = resume.and_then(|token| sessions.resume(&token, tenant));
+            let session = Sessions::issue();
+            let welcome = ServerMessage::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                features,
+                session: session.clone(),
+                resumed: resumed.is_some(),
+            };
+            ws_send.send(encode(encoding, &welcome)).await?;
+            return Ok(Some(Handshake {
+                encoding,
+                compressed,
+                session,
+                resumed,
+            }));
+        }
+        Some(ClientMessage::Hello {
+            protocol_version, ..
+        }) => {
+            format!("protocol version {protocol_version} isn't supported, only {PROTOCOL_VERSION}")
+        }
+        _ => "connections must open with a Hello".to_string(),
+    };
+    tracing::debug!("rejected handshake: {rejection}");
+    let rejection = ServerMessage::error(ErrorCode::IncompatibleProtocol, rejection);
+    ws_send.send(encode(Encoding::Json, &rejection)).await?;
+    Ok(None)
+}
+
+/// Encode a message for a client in the encoding it picked during the handshake
+fn encode(encoding: Encoding, msg: &ServerMessage) -> tungstenite::Message {
+    match encoding {
+        Encoding::Json => tungstenite::Message::Text(serde_json::to_string(msg).unwrap()),
+        Encoding::MessagePack => {
+            tungstenite::Message::Binary(rmp_serde::to_vec_named(msg).unwrap())
+        }
+    }
+}
+
+/// Compress an encoded message with raw deflate, for a client with the `deflate` feature. It's
+/// sent in a binary frame whichever encoding it's in, and the client inflates it before decoding.
+fn deflate(message: tungstenite::Message) -> tungstenite::Message {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&message.into_data())
+        .and_then(|_| encoder.finish())
+        .map(tungstenite::Message::Binary)
+        .expect("compressing into memory can't fail")
+}
+
+/// Split a long message into frames of at most `max_bytes`, which the client's WebSocket puts back
+/// together before it sees the message
+fn fragment(message: tungstenite::Message, max_bytes: usize) -> Vec<tungstenite::Message> {
+    let (opcode, data) = match message {
+        tungstenite::Message::Text(text) if text.len() > max_bytes => {
+            (Data::Text, text.into_bytes())
+        }
+        tungstenite::Message::Binary(bytes) if bytes.len() > max_bytes => (Data::Binary, bytes),
+        message => return vec![message],
+    };
+    let chunks: Vec<&[u8]> = data.chunks(max_bytes.max(1)).collect();
+    let last = chunks.len() - 1;
+    (chunks.into_iter().enumerate())
+        .map(|(index, chunk)| {
+            let opcode = if index == 0 { opcode } else { Data::Continue };
+            let frame = Frame::message(chunk.to_vec(), OpCode::Data(opcode), index == last);
+            tungstenite::Message::Frame(frame)
+        })
+        .collect()
+}
+
+/// Decode a message from a client, which may use either encoding whichever it picked
+fn decode(msg: &tungstenite::Message) -> anyhow::Result<ClientMessage> {
+    match msg {
+        tungstenite::Message::Binary(bytes) => Ok(rmp_serde::from_slice(bytes)?),
+        msg => Ok(serde_json::from_str(msg.to_text()?)?),
+    }
+}
+
+/// A client connected over WebSocket
+struct Connection {
+    id: u64,
+    /// The tenant the client connected to, or None for the default tenant
+    tenant: Option<String>,
+    server: Server,
+    permission_bytecode: &'static [u8],
+    permissions: Permissions<'static>,
+    /// Rules for requests run on the blocking pool to borrow, as any number may be running
+    pooled_permissions: Arc<PermissionPool>,
+    functions: Arc<FunctionPool>,
+    registry: Arc<ConnectionRegistry>,
+    diagnostics: Arc<Diagnostics>,
+    audit: Option<Arc<AuditLog>>,
+    /// The server's own user accounts, if clients may sign in to them
+    accounts: Option<Accounts>,
+    identity_providers: Option<Arc<IdentityProviders>>,
+    /// Who the client signed in or authenticated as, whose ID the permission rules are checked for
+    identity: Option<Identity>,
+    outbox: Outbox,
+    /// The subscription task for each key the client is subscribed to
+    subscriptions: HashMap<Ref, TaskId>,
+    /// The message that started each subscription, to restore it with if the client resumes its
+    /// session
+    requests: HashMap<Ref, ClientMessage>,
+    limits: LimitsConfig,
+    /// Whether to explain each response
+    dev_mode: bool,
+    replies: Replies,
+    /// The requests reading or writing the store that haven't finished yet
+    in_flight: Vec<InFlight>,
+}
+
+/// The responses to requests still being handled, in the order the requests arrived. Each
+/// request's responses, usually just the one, are sent once every earlier request's have been.
+#[derive(Default)]
+struct Replies {
+    queue: VecDeque<BoxStream<'static, anyhow::Result<Reply>>>,
+}
+
+impl Replies {
+    /// How many requests are still to be answered in full
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn push_back(&mut self, replies: BoxStream<'static, anyhow::Result<Reply>>) {
+        self.queue.push_back(replies);
+    }
+}
+
+impl Stream for Replies {
+    type Item = anyhow::Result<Reply>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Some(replies) = self.queue.front_mut() {
+            match ready!(replies.poll_next_unpin(cx)) {
+                Some(reply) => return Poll::Ready(Some(reply)),
+                None => {
+                    self.queue.pop_front();
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+/// A response waiting to be sent, preceded by its explanation in dev mode
+struct Reply {
+    key: Option<Ref>,
+    response: ServerMessage,
+    explanation: Option<Explanation>,
+}
+
+/// How a write is committed, which wrapping it in `Durable` or `Validate` changes
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Commit {
+    Normal,
+    /// Flushed to disk before it's answered
+    Durable,
+    /// Checked as though it were made, then rolled back
+    DryRun,
+}
+
+/// A request running alongside others, which later requests for overlapping refs may have to
+/// wait for
+struct InFlight {
+    /// The refs the request reads or writes, as picked out by `touches`
+    keys: Vec<Ref>,
+    write: bool,
+    /// Closed once the request has finished
+    done: watch::Receiver<()>,
+}
+
+impl InFlight {
+    fn is_done(&self) -> bool {
+        self.done.has_changed().is_err()
+    }
+
+    fn overlaps(&self, key: &Ref) -> bool {
+        self.keys.iter().any(|touched| touched.overlaps(key))
+    }
+}
+
+impl Connection {
+    async fn handle(&mut self, mut msg: ClientMessage) -> anyhow::Result<()> {
+        self.diagnostics
+            .capture_request(self.id, &self.server, &msg);
+        // Clients name their own user with a placeholder, so they can't get someone else's ID in
+        if let Err(key) = msg.bind_user(self.user()) {
+            let unbound = ServerMessage::Error(ErrorMessage {
+                code: ErrorCode::PermissionDenied,
+                path: Some(key.clone()),
+                message: Some(format!("{USER_PLACEHOLDER} stands for the signed-in user")),
+            });
+            self.respond(Some(&key), unbound, None);
+            return Ok(());
+        }
+        // Durable writes are handled like any other, and flushed before they're answered. Writes
+        // being validated are too, up to the point they'd be committed.
+        let (msg, commit) = match msg {
+            ClientMessage::Durable(write) if write.is_write() => (*write, Commit::Durable),
+            ClientMessage::Validate(write) if write.is_write() => (*write, Commit::DryRun),
+            msg => (msg, Commit::Normal),
+        };
+        let key = msg.key().cloned();
+        let is_write = msg.is_write();
+        let required = match &msg {
+            ClientMessage::Get(_)
+            | ClientMessage::GetExpanded(..)
+            | ClientMessage::GetChunked(_)
+            | ClientMessage::GetShallow(..)
+            | ClientMessage::GetFields(..)
+            | ClientMessage::GetMetadata(_)
+            | ClientMessage::GetHistory(_)
+            | ClientMessage::GetAt(..)
+            | ClientMessage::Search(..)
+            | ClientMessage::Query(..)
+            | ClientMessage::ListKeys(_)
+            | ClientMessage::Aggregate(..)
+            | ClientMessage::Subscribe(_)
+            | ClientMessage::SubscribeDebounced(..)
+            | ClientMessage::SubscribeFrom { .. }
+            | ClientMessage::SubscribePattern(_)
+            | ClientMessage::Follow(_)
+            | ClientMessage::DescribeSchema(_) => Some(Operation::Read),
+            ClientMessage::Insert(..) | ClientMessage::Join(..) => Some(Operation::Insert),
+            ClientMessage::Update(..) => Some(Operation::Update),
+            ClientMessage::Remove(_) | ClientMessage::Leave(_) => Some(Operation::Remove),
+            // Calls are checked against the function's name, as they have no ref, and the refs in
+            // a GetMany are checked together
+            ClientMessage::Hello { .. }
+            | ClientMessage::GetMany(_)
+            | ClientMessage::Unsubscribe(_)
+            | ClientMessage::Call { .. }
+            | ClientMessage::SignUp { .. }
+            | ClientMessage::SignIn(_)
+            | ClientMessage::SignOut
+            | ClientMessage::Auth(_)
+            | ClientMessage::CanI(..)
+            | ClientMessage::Envelope(_)
+            | ClientMessage::Durable(_)
+            | ClientMessage::Validate(_) => None,
+        };
+        let mut explanation = key
+            .as_ref()
+            .filter(|_| self.dev_mode)
+            .map(|key| Explanation {
+                schema: describe_schema(&self.server, key),
+                rule: None,
+            });
+        if let (Some(op), Some(key)) = (required, &key) {
+            let allowed = self.permissions.check(op, key, self.user())?;
+            if let Some(explanation) = &mut explanation {
+                explanation.rule = Some(RuleCheck {
+                    op: op.as_str().to_string(),
+                    path: key.clone(),
+                    user: self.user().map(String::from),
+                    allowed,
+                    error: None,
+                });
+            }
+            if !allowed {
+                tracing::debug!("denied by permission rules");
+                if is_write && commit != Commit::DryRun {
+                    self.audit(op, key, false);
+                }
+                let denied = ServerMessage::Error(ErrorMessage {
+                    code: ErrorCode::PermissionDenied,
+                    path: Some(key.clone()),
+                    message: None,
+                });
+                self.respond(Some(key), denied, explanation);
+                return Ok(());
+            }
+        }
+
+        if let (true, Some(key)) = (msg.is_subscription(), &key) {
+            // Subscribing to the same key again replaces the subscription, so it isn't counted
+            if !self.subscriptions.contains_key(key) {
+                let held = self.subscriptions.len();
+                let total = self.registry.task_count();
+                if let Err(e) = limits::check_subscription(&self.limits, key, held, total) {
+                    tracing::debug!("rejected by limits: {e}");
+                    self.respond(Some(key), ServerMessage::from(&e), explanation);
+                    return Ok(());
+                }
+            }
+            self.requests.insert(key.clone(), msg.clone());
+        }
+
+        if reads_or_writes(&msg) {
+            self.start(msg, required, commit, explanation);
+            return Ok(());
+        }
+        // Anything else that touches the store waits for earlier writes to what it touches, as
+        // though requests were handled one at a time
+        if !matches!(
+            msg,
+            ClientMessage::Unsubscribe(_)
+                | ClientMessage::Hello { .. }
+                | ClientMessage::SignUp { .. }
+                | ClientMessage::SignIn(_)
+                | ClientMessage::SignOut
+                | ClientMessage::Auth(_)
+                | ClientMessage::CanI(..)
+                | ClientMessage::Envelope(_)
+                | ClientMessage::Durable(_)
+                | ClientMessage::Validate(_)
+        ) {
+            self.settle(key.as_ref()).await;
+        }
+
+        let response = match msg {
+            ClientMessage::Subscribe(key) => {
+                self.subscribe(key, None);
+                return Ok(());
+            }
+            ClientMessage::SubscribeDebounced(key, millis) => {
+                let window = Duration::from_millis(millis.into()).min(MAX_DEBOUNCE);
+                self.subscribe(key, Some(window));
+                return Ok(());
+            }
+            ClientMessage::SubscribeFrom { key, token } => {
+                let task = resume(self.server.clone(), key.clone(), token, self.outbox.clone());
+                self.track_subscription(key, task);
+                return Ok(());
+            }
+            ClientMessage::SubscribePattern(pattern) => {
+                match self.server.subscribe_pattern(&pattern) {
+                    Ok(events) => {
+                        let task = watch_pattern(
+                            self.server.clone(),
+                            events,
+                            self.permission_bytecode,
+                            self.user().map(String::from),
+                            self.claims(),
+                            pattern.clone(),
+                            self.outbox.clone(),
+                        );
+                        self.track_subscription(pattern, task);
+                        return Ok(());
+                    }
+                    Err(e) => ServerMessage::from(&e),
+                }
+            }
+            ClientMessage::Follow(key) => match self.server.reference(&key) {
+                Ok(_) => {
+                    let task = follow(
+                        self.server.clone(),
+                        self.permission_bytecode,
+                        self.user().map(String::from),
+                        self.claims(),
+                        key.clone(),
+                        self.outbox.clone(),
+                    );
+                    self.track_subscription(key, task);
+                    return Ok(());
+                }
+                Err(e) => ServerMessage::from(&e),
+            },
+            ClientMessage::Unsubscribe(key) => {
+                self.requests.remove(&key);
+                if let Some(task) = self.subscriptions.remove(&key) {
+                    self.registry.abort(task);
+                }
+                return Ok(());
+            }
+            ClientMessage::SignUp { username, password } => {
+                self.sign_in(move |accounts| accounts.sign_up(&username, &password))
+                    .await
+            }
+            ClientMessage::SignIn(credentials) => {
+                self.sign_in(move |accounts| accounts.sign_in(&credentials))
+                    .await
+            }
+            ClientMessage::SignOut => {
+                let signed_out = match (&self.accounts, &self.identity) {
+                    (Some(accounts), Some(Identity::Account(signed_in))) => {
+                        accounts.sign_out(&signed_in.token)
+                    }
+                    _ => Ok(()),
+                };
+                match signed_out {
+                    Ok(()) => {
+                        self.become_user(None);
+                        ServerMessage::Value(Value::Null)
+                    }
+                    Err(e) => ServerMessage::from(&e),
+                }
+            }
+            ClientMessage::Auth(token) => self.authenticate(&token).await,
+            ClientMessage::CanI(op, key) => {
+                let (allowed, error) = match self.permissions.check(op, &key, self.user()) {
+                    Ok(allowed) => (allowed, None),
+                    Err(e) => (false, Some(e.to_string())),
+                };
+                let rule = RuleCheck {
+                    op: op.as_str().to_string(),
+                    path: key,
+                    user: self.user().map(String::from),
+                    allowed,
+                    error,
+                };
+                ServerMessage::Value(serde_json::to_value(rule).unwrap())
+            }
+            ClientMessage::Hello { .. } => {
+                ServerMessage::error(ErrorCode::InvalidRequest, "the handshake is already done")
+            }
+            ClientMessage::Envelope(_) => {
+                ServerMessage::error(ErrorCode::InvalidRequest, "envelopes may not be nested")
+            }
+            ClientMessage::Durable(_) => ServerMessage::error(
+                ErrorCode::InvalidRequest,
+                "only Insert, Update and Remove can be made durable",
+            ),
+            ClientMessage::Validate(_) => ServerMessage::error(
+                ErrorCode::InvalidRequest,
+                "only Insert, Update and Remove can be validated",
+            ),
+            ClientMessage::Get(_)
+            | ClientMessage::GetMany(_)
+            | ClientMessage::GetExpanded(..)
+            | ClientMessage::GetChunked(_)
+            | ClientMessage::GetShallow(..)
+            | ClientMessage::GetFields(..)
+            | ClientMessage::GetMetadata(_)
+            | ClientMessage::GetHistory(_)
+            | ClientMessage::GetAt(..)
+            | ClientMessage::Search(..)
+            | ClientMessage::Query(..)
+            | ClientMessage::ListKeys(_)
+            | ClientMessage::Aggregate(..)
+            | ClientMessage::DescribeSchema(_)
+            | ClientMessage::Insert(..)
+            | ClientMessage::Update(..)
+            | ClientMessage::Remove(_)
+            | ClientMessage::Join(..)
+            | ClientMessage::Leave(_)
+            | ClientMessage::Call { .. } => {
+                unreachable!("requests run on the blocking pool are started")
+            }
+        };
+        self.respond(key.as_ref(), response, explanation);
+        Ok(())
+    }
+
+    /// Run a request on the blocking pool, alongside any others, as picked out by
+    /// `reads_or_writes`. It waits for earlier requests for overlapping refs to finish first if
+    /// either of them writes, so writes to a ref apply in the order they were sent and reads see
+    /// them. Its responses are sent as it makes them, once earlier requests are answered.
+    fn start(
+        &mut self,
+        msg: ClientMessage,
+        required: Option<Operation>,
+        commit: Commit,
+        explanation: Option<Explanation>,
+    ) {
+        let key = msg.key().cloned();
+        let keys = touches(&msg);
+        let write =
+            msg.has_effects() || matches!(msg, ClientMessage::Join(..) | ClientMessage::Leave(_));
+        self.in_flight.retain(|request| !request.is_done());
+        let earlier: Vec<_> = (self.in_flight.iter())
+            .filter(|request| {
+                (write || request.write) && keys.iter().any(|key| request.overlaps(key))
+            })
+            .map(|request| request.done.clone())
+            .collect();
+        let (done, finished) = watch::channel(());
+        self.in_flight.push(InFlight {
+            keys,
+            write,
+            done: finished,
+        });
+
+        let runner = Runner {
+            server: match commit {
+                Commit::DryRun => self.server.clone().dry_run(),
+                Commit::Normal | Commit::Durable => self.server.clone(),
+            },
+            durable: commit == Commit::Durable,
+            connection: self.id,
+            user: self.user().map(String::from),
+            claims: self.claims(),
+            permissions: self.pooled_permissions.clone(),
+            functions: self.functions.clone(),
+        };
+        // Writes that are only validated aren't made, so there's nothing to audit
+        let audited = msg.is_write() && commit != Commit::DryRun;
+        let (audit, tenant) = (self.audit.clone(), self.tenant.clone());
+        let span = tracing::Span::current();
+        // Only one response is read ahead of the client, so a huge chunked read isn't held in
+        // memory waiting for it
+        let (replies, received) = mpsc::channel(1);
+        let task = tokio::spawn(async move {
+            for mut earlier in earlier {
+                // Only ever errors, once the request it's waiting for has finished
+                let _ = earlier.changed().await;
+            }
+            let ran = tokio::task::spawn_blocking(move || {
+                let _span = span.entered();
+                let mut explanation = explanation;
+                let mut reply = |response: ServerMessage| {
+                    if let (true, Some(op), Some(key)) = (audited, required, &key) {
+                        if !matches!(response, ServerMessage::Error(_)) {
+                            let user = runner.user.clone();
+                            record(
+                                audit.as_deref(),
+                                tenant.clone(),
+                                runner.connection,
+                                user,
+                                op,
+                                key,
+                                true,
+                            );
+                        }
+                    }
+                    let reply = Reply {
+                        key: key.clone(),
+                        response,
+                        explanation: explanation.take(),
+                    };
+                    // The connection is only gone if it's closing
+                    replies.blocking_send(Ok(reply)).is_ok()
+                };
+                if let Err(e) = runner.run(msg, &mut reply) {
+                    let _ = replies.blocking_send(Err(e));
+                }
+            });
+            ran.await.expect("requests don't panic");
+            drop(done);
+        });
+        let replies = stream::unfold((received, task), |(mut received, task)| async move {
+            match received.recv().await {
+                Some(reply) => Some((reply, (received, task))),
+                None => {
+                    task.await.expect("requests don't panic");
+                    None
+                }
+            }
+        });
+        self.replies.push_back(replies.boxed());
+    }
+
+    /// Wait for requests still in flight that write to anything overlapping `key`, or to
+    /// anything at all if there's no key
+    async fn settle(&mut self, key: Option<&Ref>) {
+        let earlier: Vec<_> = (self.in_flight.iter())
+            .filter(|request| request.write && key.is_none_or(|key| request.overlaps(key)))
+            .map(|request| request.done.clone())
+            .collect();
+        for mut earlier in earlier {
+            let _ = earlier.changed().await;
+        }
+    }
+
+    /// Log a write to the audit log, if it's kept
+    fn audit(&self, op: Operation, key: &Ref, allowed: bool) {
+        record(
+            self.audit.as_deref(),
+            self.tenant.clone(),
+            self.id,
+            self.user().map(String::from),
+            op,
+            key,
+            allowed,
+        );
+    }
+
+    /// The ID of the user the client signed in or authenticated as, if it has
+    fn user(&self) -> Option<&str> {
+        self.identity.as_ref().and_then(Identity::user)
+    }
+
+    /// What the client's ID token claimed about its user, if it authenticated with one
+    fn claims(&self) -> Option<Value> {
+        self.identity.as_ref().and_then(Identity::claims).cloned()
+    }
+
+    /// Sign in to one of the server's accounts, on the blocking pool as checking passwords is
+    /// slow on purpose
+    async fn sign_in(
+        &mut self,
+        sign_in: impl FnOnce(&Accounts) -> Result<SignedIn, AccountError> + Send + 'static,
+    ) -> ServerMessage {
+        let Some(accounts) = self.accounts.clone() else {
+            return ServerMessage::error(
+                ErrorCode::InvalidRequest,
+                "this server doesn't keep accounts",
+            );
+        };
+        let result = tokio::task::spawn_blocking(move || sign_in(&accounts))
+            .await
+            .expect("signing in doesn't panic");
+        match result {
+            Ok(signed_in) => {
+                tracing::debug!(user = signed_in.user, "signed in");
+                let response = json!({ "user": signed_in.user, "token": signed_in.token });
+                self.become_user(Some(Identity::Account(signed_in)));
+                ServerMessage::Value(response)
+            }
+            Err(e) => {
+                tracing::debug!("failed to sign in: {e}");
+                ServerMessage::from(&e)
+            }
+        }
+    }
+
+    /// Authenticate with an ID token, which is checked against its provider's keys
+    async fn authenticate(&mut self, token: &str) -> ServerMessage {
+        let Some(providers) = self.identity_providers.clone() else {
+            return ServerMessage::error(
+                ErrorCode::InvalidRequest,
+                "this server doesn't accept ID tokens",
+            );
+        };
+        match providers.verify(token).await {
+            Ok(verified) => {
+                tracing::debug!(user = verified.user, "authenticated");
+                let response = json!({ "user": verified.user });
+                self.become_user(Some(Identity::IdToken(verified)));
+                ServerMessage::Value(response)
+            }
+            Err(e) => {
+                tracing::debug!("failed to authenticate: {e}");
+                ServerMessage::from(&e)
+            }
+        }
+    }
+
+    /// Act as another user, or as no one. Subscriptions started as a user who signed in end with
+    /// them, as the permission rules let them read what the next user may not.
+    fn become_user(&mut self, identity: Option<Identity>) {
+        let user = identity.as_ref().and_then(Identity::user);
+        // A user whose ID token expired is still the one its subscriptions were started as
+        let previous = self.identity.as_ref().map(|identity| match identity {
+            Identity::Account(signed_in) => &signed_in.user,
+            Identity::IdToken(verified) => &verified.user,
+        });
+        if previous.is_some() && previous.map(String::as_str) != user {
+            self.requests.clear();
+            for (_, task) in self.subscriptions.drain() {
+                self.registry.abort(task);
+            }
+        }
+        self.identity = identity;
+        self.permissions.set_claims(self.claims());
+    }
+
+    /// Queue the response to a request for `key`, to be sent once earlier requests are answered
+    fn respond(
+        &mut self,
+        key: Option<&Ref>,
+        response: ServerMessage,
+        explanation: Option<Explanation>,
+    ) {
+        let reply = Reply {
+            key: key.cloned(),
+            response,
+            explanation,
+        };
+        self.replies
+            .push_back(stream::once(future::ready(Ok(reply))).boxed());
+    }
+
+    /// Send a response, preceded by its explanation in dev mode
+    // Borrowing mutably keeps this future `Send`, as the Lua state in `permissions` isn't `Sync`
+    async fn deliver(&mut self, reply: Reply) -> anyhow::Result<()> {
+        if let Some(explanation) = reply.explanation {
+            self.outbox
+                .send(ServerMessage::Explain(explanation))
+                .await?;
+        }
+        self.diagnostics.capture_response(
+            self.id,
+            &self.server,
+            reply.key.as_ref(),
+            &reply.response,
+        );
+        Ok(self.outbox.send(reply.response).await?)
+    }
+
+    /// Wait for every request still being handled, sending their responses in order
+    async fn flush_replies(&mut self) -> anyhow::Result<()> {
+        while let Some(reply) = self.replies.next().await {
+            self.deliver(reply?).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the client an update whenever anything at or under `key` is written. With a
+    /// `debounce` window, each update is held back that long, and replaced by any later write to
+    /// the same ref in the meantime.
+    fn subscribe(&mut self, key: Ref, debounce: Option<Duration>) {
+        let mut subscriber = self.server.subscribe(&key);
+        let server = self.server.clone();
+        let sender = self.outbox.clone();
+        let key_ = key.clone();
+        self.track_subscription(key, async move {
+            while let Some(event) = subscriber.next().await {
+                // Updates waiting to be sent, along with the ref each one wrote to
+                let mut pending = vec![subscription_update(&server, &key_, event)];
+                if let Some(window) = debounce {
+                    let deadline = tokio::time::sleep(window);
+                    tokio::pin!(deadline);
+                    loop {
+                        let event = tokio::select! {
+                            event = subscriber.next() => event,
+                            _ = &mut deadline => break,
+                        };
+                        let Some(event) = event else {
+                            break;
+                        };
+                        let (written, update) = subscription_update(&server, &key_, event);
+                        match pending.iter_mut().find(|(other, _)| *other == written) {
+                            Some((_, superseded)) => *superseded = update,
+                            None => pending.push((written, update)),
+                        }
+                    }
+                }
+                let _span = tracing::trace_span!("fan_out").entered();
+                for (_, update) in pending {
+                    if sender.send_update(update).is_err() {
+                        // The client is gone, or was disconnected for falling behind
+                        tracing::debug!("subscription ended");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Carry on with the subscriptions of a resumed session, then send an update for each item
+    /// they cover that's been written since `cursor`. Resumable subscriptions and followed
+    /// references catch up on their own.
+    async fn restore(
+        &mut self,
+        subscriptions: Vec<ClientMessage>,
+        cursor: u64,
+    ) -> anyhow::Result<()> {
+        for msg in subscriptions {
+            let msg = match msg {
+                ClientMessage::SubscribeFrom { key, .. } => ClientMessage::SubscribeFrom {
+                    key,
+                    token: Some(cursor),
+                },
+                msg => msg,
+            };
+            // Subscribing checks the permission rules again, in case they've changed
+            self.handle(msg).await?;
+        }
+
+        let mut written = Vec::new();
+        let mut from = cursor;
+        loop {
+            let changes = match self.server.changes(from, RESUME_BATCH) {
+                Ok(changes) => changes,
+                // What was written while the client was away has been trimmed, so it may have
+                // been anything
+                Err(ServerError::ChangesTrimmed { .. }) => {
+                    written.push(Ref(Vec::new()));
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(last) = changes.last() {
+                from = last.seq + 1;
+            }
+            written.extend(changes.iter().map(|change| change.op.path().clone()));
+            if changes.len() < RESUME_BATCH {
+                break;
+            }
+        }
+        let mut updates = Vec::new();
+        for (key, msg) in &self.requests {
+            if !self.subscriptions.contains_key(key) {
+                continue;
+            }
+            match msg {
+                ClientMessage::Subscribe(key) | ClientMessage::SubscribeDebounced(key, _)
+                    if written.iter().any(|path| path.overlaps(key)) =>
+                {
+                    let value = self.server.get(key).ok().map(|value| value.to_string());
+                    updates.push(ServerMessage::SubscriptionUpdate(key.clone(), value));
+                }
+                ClientMessage::SubscribePattern(pattern) => {
+                    for item in written_items(&self.server, pattern, &written) {
+                        if !self
+                            .permissions
+                            .check(Operation::Read, &item, self.user())?
+                        {
+                            continue;
+                        }
+                        let value = self.server.get(&item).ok().map(|value| value.to_string());
+                        updates.push(ServerMessage::PatternUpdate {
+                            pattern: pattern.clone(),
+                            key: item,
+                            value,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        for update in updates {
+            if self.outbox.send_update(update).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a subscription task on behalf of the client until it unsubscribes from `key`
+    fn track_subscription(&mut self, key: Ref, task: impl Future<Output = ()> + Send + 'static) {
+        let task = self
+            .registry
+            .spawn(self.id, key.clone(), &self.outbox, task.in_current_span());
+        // Subscribing to the same key twice replaces the old subscription
+        if let Some(previous) = self.subscriptions.insert(key, task) {
+            self.registry.abort(previous);
+        }
+    }
+}
+
+/// Send the client the whole item at `key` whenever a committed change touches it, along with the
+/// number of the next change, which the client can resume from after reconnecting. Resuming from
+/// `token` only sends the item if a change since then touched it, while starting without one
+/// sends it right away.
+async fn resume(server: Server, key: Ref, token: Option<u64>, sender: Outbox) {
+    // Watch before reading, so a change committed in between isn't missed
+    let mut committed = server.watch_changes();
+    let next = match server.next_change() {
+        Ok(next) => next,
+        Err(e) => {
+            tracing::warn!("failed to read the change log: {e}");
+            return;
+        }
+    };
+    // A token from the future can't be trusted to say what the client has seen
+    let (mut cursor, mut touched) = match token {
+        Some(token) if token <= next => (token, false),
+        _ => (next, true),
+    };
+    loop {
+        let changes = match server.changes(cursor, RESUME_BATCH) {
+            Ok(changes) => changes,
+            // The changes since the token have been trimmed, so the item is sent again
+            Err(ServerError::ChangesTrimmed { oldest, .. }) => {
+                cursor = oldest;
+                touched = true;
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("failed to read the change log: {e}");
+                return;
+            }
+        };
+        if let Some(last) = changes.last() {
+            cursor = last.seq + 1;
+        }
+        touched |= changes.iter().any(|change| change.op.path().overlaps(&key));
+        if touched {
+            touched = false;
+            let update = ServerMessage::ResumableUpdate {
+                key: key.clone(),
+                value: server.get(&key).unwrap_or(Value::Null),
+                token: cursor,
+            };
+            if sender.send_update(update).is_err() {
+                tracing::debug!("subscription ended");
+                return;
+            }
+        }
+        if changes.len() < RESUME_BATCH && committed.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Send the client the whole item that the reference in `field` points at, and again whenever
+/// the item changes or the field is repointed. A missing target, or one the client may not read,
+/// is sent as None.
+async fn follow(
+    server: Server,
+    permission_bytecode: &'static [u8],
+    user: Option<String>,
+    claims: Option<Value>,
+    field: Ref,
+    sender: Outbox,
+) {
+    let permissions = Permissions::new(permission_bytecode)
+        .with_slow_threshold(server.slow_threshold())
+        .with_claims(claims);
+    let mut field_events = server.subscribe(&field);
+    loop {
+        let target = server.reference(&field).ok().flatten().filter(|target| {
+            permissions
+                .check(Operation::Read, target, user.as_deref())
+                .unwrap_or(false)
+        });
+        let mut target_events = target.as_ref().map(|target| server.subscribe(target));
+        loop {
+            let value = target
+                .as_ref()
+                .and_then(|target| server.get(target).ok())
+                .map(|value| value.to_string());
+            let update = ServerMessage::SubscriptionUpdate(field.clone(), value);
+            if sender.send_update(update).is_err() {
+                tracing::debug!("subscription ended");
+                return;
+            }
+
+            let target_changed = async {
+                match &mut target_events {
+                    Some(events) => events.next().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                event = field_events.next() => match event {
+                    // Repointed, or cleared
+                    Some(_) => break,
+                    None => return,
+                },
+                event = target_changed => if event.is_none() {
+                    return;
+                },
+            }
+        }
+    }
+}
+
+/// Send the client each write to an item matching `pattern`, if the permission rules let it read
+/// that item. Rules are checked per item, as the pattern alone can't say which items they allow.
+async fn watch_pattern(
+    server: Server,
+    mut events: impl Stream<Item = Event> + Unpin,
+    permission_bytecode: &'static [u8],
+    user: Option<String>,
+    claims: Option<Value>,
+    pattern: Ref,
+    sender: Outbox,
+) {
+    let permissions = Permissions::new(permission_bytecode)
+        .with_slow_threshold(server.slow_threshold())
+        .with_claims(claims);
+    while let Some(event) = events.next().await {
+        let (key, value, expired) = match event {
+            Event::Insert { key, value } => match server.event_value(&key, &value) {
+                Ok(value) => (key, Some(value), false),
+                Err(e) => {
+                    if sender.send_update(ServerMessage::from(&e)).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            },
+            Event::Remove { key } => (key, None, false),
+            Event::Expire { key } => (key, None, true),
+        };
+        // Removing something containing matching items is reported as the removal of each one
+        let Some(item) = key.0.get(..pattern.0.len()) else {
+            continue;
+        };
+        let item = Ref(item.to_vec());
+        if !permissions
+            .check(Operation::Read, &item, user.as_deref())
+            .unwrap_or(false)
+        {
+            continue;
+        }
+        let pattern = pattern.clone();
+        let update = if expired {
+            ServerMessage::PatternExpired { pattern, key }
+        } else {
+            ServerMessage::PatternUpdate {
+                pattern,
+                key,
+                value,
+            }
+        };
+        if sender.send_update(update).is_err() {
+            tracing::debug!("subscription ended");
+            return;
+        }
+    }
+}
+
+/// The items matching `pattern` that were written at, within, or as part of something containing
+/// them, by writes to the refs in `written`
+fn written_items(server: &Server, pattern: &Ref, written: &[Ref]) -> Vec<Ref> {
+    fn expand(item: Ref, value: &Value, rest: &[String], items: &mut Vec<Ref>) {
+        let Some((component, rest)) = rest.split_first() else {
+            if !items.contains(&item) {
+                items.push(item);
+            }
+            return;
+        };
+        let Value::Object(members) = value else {
+            return;
+        };
+        for (name, member) in members {
+            if component == "*" || component == name {
+                expand(item.child(name), member, rest, items);
+            }
+        }
+    }
+
+    let mut items = Vec::new();
+    for path in written {
+        let depth = path.0.len().min(pattern.0.len());
+        if !path.matches(&Ref(pattern.0[..depth].to_vec())) {
+            continue;
+        }
+        let item = Ref(path.0[..depth].to_vec());
+        match server.get(&item) {
+            Ok(value) => expand(item, &value, &pattern.0[depth..], &mut items),
+            // Removed items can't be found by looking, so only one named outright is reported
+            Err(_) if depth == pattern.0.len() && !items.contains(&item) => items.push(item),
+            Err(_) => {}
+        }
+    }
+    items
+}
+
+/// What to ping a client with: the number of the next change, which the client's pong echoes
+fn ping_payload(server: &Server) -> Vec<u8> {
+    match server.next_change() {
+        Ok(next) => next.to_be_bytes().to_vec(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The update for a subscription to `subscribed` that `event` causes, along with the ref that was
+/// written to. A value its codec can't decode is reported as an error instead.
+fn subscription_update(server: &Server, subscribed: &Ref, event: Event) -> (Ref, ServerMessage) {
+    match event {
+        Event::Insert { key, value } => {
+            let update = match server.event_value(&key, &value) {
+                Ok(value) => ServerMessage::SubscriptionUpdate(subscribed.clone(), Some(value)),
+                Err(e) => ServerMessage::from(&e),
+            };
+            (key, update)
+        }
+        Event::Remove { key } => (
+            key,
+            ServerMessage::SubscriptionUpdate(subscribed.clone(), None),
+        ),
+        Event::Expire { key } => (key, ServerMessage::SubscriptionExpired(subscribed.clone())),
+    }
+}
+
+/// The schema node at `key`, for explanations
+fn describe_schema(server: &Server, key: &Ref) -> String {
+    match server.schema().resolve(&key.0) {
+        // Resolving unwraps sensitive items, so say so separately
+        Ok(item) if server.schema().is_sensitive(&key.0) => format!("sensitive {item}"),
+        Ok(item) => item.to_string(),
+        Err(e) => format!("nothing matches {key}: {e}"),
+    }
+}
+
+/// What a request started on the blocking pool runs with
+struct Runner {
+    server: Server,
+    /// Whether writes are flushed to disk before they're answered
+    durable: bool,
+    connection: u64,
+    user: Option<String>,
+    claims: Option<Value>,
+    permissions: Arc<PermissionPool>,
+    functions: Arc<FunctionPool>,
+}
+
+impl Runner {
+    /// Handle a request picked out by `reads_or_writes`, passing each response to `reply` as it's
+    /// made. Most requests have just the one, but a chunked read has one for each chunk, and
+    /// stops early if `reply` says the client's gone.
+    fn run(
+        &self,
+        msg: ClientMessage,
+        reply: &mut impl FnMut(ServerMessage) -> bool,
+    ) -> anyhow::Result<()> {
+        let response = match msg {
+            ClientMessage::GetChunked(key) => match self.server.get_chunked(&key, VALUE_CHUNK_SIZE)
+            {
+                Ok(Chunked::Whole(value)) => ServerMessage::Value(value),
+                Ok(Chunked::Pieces(chunks)) => {
+                    let mut chunks = chunks.peekable();
+                    while let Some(chunk) = chunks.next() {
+                        let response = match chunk {
+                            Ok(members) => ServerMessage::ValueChunk {
+                                members: Value::Object(members),
+                                last: chunks.peek().is_none(),
+                            },
+                            Err(e) => ServerMessage::from(&e),
+                        };
+                        let failed = matches!(response, ServerMessage::Error(_));
+                        if !reply(response) || failed {
+                            break;
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(e) => ServerMessage::from(&e),
+            },
+            ClientMessage::GetMany(keys) => {
+                let user = self.user.as_deref();
+                let checks: Vec<_> = (keys.iter())
+                    .map(|key| (Operation::Read, key, user))
+                    .collect();
+                let allowed =
+                    self.with_permissions(|permissions| permissions.check_all(&checks))?;
+                let mut denied = None;
+                for (key, allowed) in keys.iter().zip(allowed) {
+                    if !allowed? {
+                        denied = Some(key.clone());
+                        break;
+                    }
+                }
+                match denied {
+                    Some(key) => {
+                        tracing::debug!(r#ref = %key, "denied by permission rules");
+                        ServerMessage::Error(ErrorMessage {
+                            code: ErrorCode::PermissionDenied,
+                            path: Some(key),
+                            message: None,
+                        })
+                    }
+                    None => match self.server.get_many(&keys) {
+                        Ok(values) => ServerMessage::Value(Value::Array(values)),
+                        Err(e) => ServerMessage::from(&e),
+                    },
+                }
+            }
+            ClientMessage::DescribeSchema(key) => {
+                let result = self.with_permissions(|permissions| {
+                    self.server.describe(&key, &mut |targets| {
+                        permissions.readable(targets, self.user.as_deref())
+                    })
+                });
+                match result {
+                    Ok(described) => ServerMessage::Value(described),
+                    Err(e) => ServerMessage::from(&e),
+                }
+            }
+            ClientMessage::GetExpanded(key, depth) => {
+                let result = self.with_permissions(|permissions| {
+                    self.server.get_expanded(&key, depth, &mut |targets| {
+                        permissions.readable(targets, self.user.as_deref())
+                    })
+                });
+                match result {
+                    Ok(value) => ServerMessage::Value(value),
+                    Err(e) => ServerMessage::from(&e),
+                }
+            }
+            ClientMessage::Join(key, value) => {
+                write_response(self.server.join(self.connection, &key, value))
+            }
+            ClientMessage::Leave(key) => write_response(self.server.leave(self.connection, &key)),
+            ClientMessage::Call { name, args } => {
+                let function = Ref(vec![name.clone()]);
+                let allowed = self.with_permissions(|permissions| {
+                    permissions.check(Operation::Call, &function, self.user.as_deref())
+                })?;
+                if !allowed {
+                    tracing::debug!("denied by permission rules");
+                    ServerMessage::Error(ErrorMessage {
+                        code: ErrorCode::PermissionDenied,
+                        path: Some(function),
+                        message: None,
+                    })
+                } else {
+                    let functions = self.functions.take();
+                    let result = functions.call(&self.server, &name, &args);
+                    self.functions.put(functions);
+                    match result {
+                        Ok(value) => ServerMessage::Value(value),
+                        Err(e) => {
+                            tracing::debug!("function failed: {e}");
+                            ServerMessage::from(&e)
+                        }
+                    }
+                }
+            }
+            msg => run(&self.server, msg, self.durable),
+        };
+        reply(response);
+        Ok(())
+    }
+
+    /// Check against rules borrowed for the caller's user
+    fn with_permissions<T>(&self, check: impl FnOnce(&Permissions<'static>) -> T) -> T {
+        let mut permissions = self.permissions.take();
+        permissions.set_claims(self.claims.clone());
+        let result = check(&permissions);
+        self.permissions.put(permissions);
+        result
+    }
+}
+
+/// Handle a request that only reads or writes the store
+fn run(server: &Server, msg: ClientMessage, durable: bool) -> ServerMessage {
+    match msg {
+        ClientMessage::Get(key) => match server.get(&key) {
+            Ok(value) => {
+                tracing::debug!(value = %server.redact(&key, &value), "read");
+                ServerMessage::Value(value)
+            }
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::GetShallow(key, depth) => match server.get_shallow(&key, depth) {
+            Ok(value) => ServerMessage::Value(value),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::GetFields(key, fields) => match server.get_fields(&key, &fields) {
+            Ok(value) => ServerMessage::Value(value),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::GetMetadata(key) => match server.metadata(&key) {
+            Ok(metadata) => ServerMessage::Value(serde_json::to_value(metadata).unwrap()),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::GetHistory(key) => match server.history(&key) {
+            Ok(revisions) => ServerMessage::Value(serde_json::to_value(revisions).unwrap()),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::GetAt(key, revision) => match server.revision(&key, revision) {
+            Ok(revision) => ServerMessage::Value(serde_json::to_value(revision).unwrap()),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::Search(key, query) => match server.search(&key, &query) {
+            Ok(found) => ServerMessage::Value(serde_json::to_value(found).unwrap()),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::Query(key, filter) => match server.query(&key, &filter) {
+            Ok(members) => ServerMessage::Value(members),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::ListKeys(key) => match server.list_keys(&key) {
+            Ok(keys) => ServerMessage::Value(serde_json::to_value(keys).unwrap()),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::Aggregate(key, aggregation) => match server.aggregate(&key, &aggregation) {
+            Ok(result) => ServerMessage::Value(result),
+            Err(e) => ServerMessage::from(&e),
+        },
+        ClientMessage::Insert(key, value) => committed(server, server.insert(&key, value), durable),
+        ClientMessage::Update(key, value) => committed(server, server.update(&key, value), durable),
+        ClientMessage::Remove(key) => committed(server, server.remove(&key), durable),
+        _ => unreachable!("only reads and writes of the store are run"),
+    }
+}
+
+/// Whether a request reads or writes the store, or runs the rules or functions against it, so it's
+/// run on the blocking pool alongside others rather than holding up the connection
+fn reads_or_writes(msg: &ClientMessage) -> bool {
+    matches!(
+        msg,
+        ClientMessage::Get(_)
+            | ClientMessage::GetMany(_)
+            | ClientMessage::GetExpanded(..)
+            | ClientMessage::GetChunked(_)
+            | ClientMessage::GetShallow(..)
+            | ClientMessage::GetFields(..)
+            | ClientMessage::GetMetadata(_)
+            | ClientMessage::GetHistory(_)
+            | ClientMessage::GetAt(..)
+            | ClientMessage::Search(..)
+            | ClientMessage::Query(..)
+            | ClientMessage::ListKeys(_)
+            | ClientMessage::Aggregate(..)
+            | ClientMessage::DescribeSchema(_)
+            | ClientMessage::Insert(..)
+            | ClientMessage::Update(..)
+            | ClientMessage::Remove(_)
+            | ClientMessage::Join(..)
+            | ClientMessage::Leave(_)
+            | ClientMessage::Call { .. }
+    )
+}
+
+/// The refs a request picked out by `reads_or_writes` reads or writes. A call could touch
+/// anything, and describing a schema touches nothing in the store.
+fn touches(msg: &ClientMessage) -> Vec<Ref> {
+    match msg {
+        ClientMessage::GetMany(keys) => keys.clone(),
+        ClientMessage::Call { .. } => vec![Ref(Vec::new())],
+        ClientMessage::DescribeSchema(_) => Vec::new(),
+        msg => msg.key().cloned().into_iter().collect(),
+    }
+}
+
+/// The response to a write, once it's on disk if the client asked for it to be durable
+fn committed(server: &Server, result: Result<(), ServerError>, durable: bool) -> ServerMessage {
+    write_response(result.and_then(|()| if durable { server.flush() } else { Ok(()) }))
+}
+
+/// Log a write made over WebSocket to the audit log, if it's kept
+fn record(
+    audit: Option<&AuditLog>,
+    tenant: Option<String>,
+    connection: u64,
+    user: Option<String>,
+    op: Operation,
+    key: &Ref,
+    allowed: bool,
+) {
+    if let Some(audit) = audit {
+        audit.record(Attempt {
+            source: Source::WebSocket,
+            tenant,
+            connection: Some(connection),
+            user,
+            op,
+            path: key.clone(),
+            allowed,
+        });
+    }
+}
+
+/// The response to a write: null on success, or the error
+fn write_response(result: Result<(), ServerError>) -> ServerMessage {
+    match result {
+        Ok(()) => ServerMessage::Value(Value::Null),
+        Err(e) => {
+            tracing::debug!("write failed: {e}");
+            ServerMessage::from(&e)
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+
+use crate::{
+    message::Ref,
+    permission::Operation,
+    server::{Server, ServerError},
+};
+
+/// A record of who wrote what, kept so operators can answer who changed a document. Every write
+/// that's carried out is logged, and writes the permission rules deny are too if `denied` is
+/// set. Reads aren't logged.
+pub struct AuditLog {
+    server: Server,
+    tree: Tree,
+    denied: bool,
+}
+
+/// Where a write came in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    WebSocket,
+    Http,
+    Grpc,
+}
+
+/// A write someone tried to make
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Attempt {
+    pub source: Source,
+    /// The tenant written to, or None for the default tenant
+    pub tenant: Option<String>,
+    /// The WebSocket connection the write came over
+    pub connection: Option<u64>,
+    /// The user the permission rules were checked for, if the client is authenticated
+    pub user: Option<String>,
+    pub op: Operation,
+    pub path: Ref,
+    /// Whether the permission rules allowed it
+    pub allowed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// Entries are numbered in the order they're logged, and numbers are never reused
+    pub id: u64,
+    /// When the write was made, in milliseconds since the unix epoch
+    pub at: u64,
+    #[serde(flatten)]
+    pub attempt: Attempt,
+}
+
+impl AuditLog {
+    pub fn open(server: &Server, denied: bool) -> Result<AuditLog, ServerError> {
+        Ok(AuditLog {
+            server: server.clone(),
+            tree: server.system_tree("audit")?,
+            denied,
+        })
+    }
+
+    /// Log a write once it's been carried out, or been denied. A write that fails for any other
+    /// reason changed nothing, so it isn't logged.
+    pub fn record(&self, attempt: Attempt) {
+        if !attempt.allowed && !self.denied {
+            return;
+        }
+        let result = self.server.generate_id().and_then(|id| {
+            let entry = AuditEntry {
+                id,
+                at: now_millis(),
+                attempt,
+            };
+            self.tree
+                .insert(id.to_be_bytes(), serde_json::to_vec(&entry).unwrap())?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            tracing::error!("could not write to the audit log: {e}");
+        }
+    }
+
+    /// Up to `limit` entries that `filter` accepts, starting at the one numbered `from`
+    pub fn read(
+        &self,
+        from: u64,
+        limit: usize,
+        filter: impl Fn(&AuditEntry) -> bool,
+    ) -> Result<Vec<AuditEntry>, ServerError> {
+        let mut entries = Vec::new();
+        for value in self.tree.range(from.to_be_bytes()..).values() {
+            if entries.len() == limit {
+                break;
+            }
+            let entry: AuditEntry =
+                serde_json::from_slice(&value?).expect("audit entries are stored as JSON");
+            if filter(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::{Schema, SchemaItem};
+
+    use super::*;
+
+    fn attempt(op: Operation, path: &[&str], allowed: bool) -> Attempt {
+        Attempt {
+            source: Source::WebSocket,
+            tenant: None,
+            connection: Some(1),
+            user: Some("ada".to_string()),
+            op,
+            path: Ref(path.iter().map(|c| c.to_string()).collect()),
+            allowed,
+        }
+    }
+
+    #[test]
+    fn recording_writes() {
+        let server =
+            Server::open_temporary(Schema::new(SchemaItem::Scalar), sled::Config::new()).unwrap();
+        let quiet = AuditLog::open(&server, false).unwrap();
+        quiet.record(attempt(Operation::Insert, &["posts", "first"], true));
+        quiet.record(attempt(Operation::Remove, &["posts", "first"], false));
+        let entries = quiet.read(0, 10, |_| true).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].attempt.op, Operation::Insert);
+
+        let log = AuditLog::open(&server, true).unwrap();
+        log.record(attempt(Operation::Remove, &["posts", "first"], false));
+        log.record(attempt(Operation::Update, &["posts", "second"], true));
+        let entries = log.read(0, 10, |_| true).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(entries.windows(2).all(|pair| pair[0].id < pair[1].id));
+        assert!(!entries[1].attempt.allowed);
+
+        let first = log
+            .read(0, 10, |entry| entry.attempt.path.0[1] == "first")
+            .unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(log.read(entries[2].id, 10, |_| true).unwrap().len(), 1);
+        assert_eq!(log.read(0, 1, |_| true).unwrap(), entries[..1]);
+    }
+}
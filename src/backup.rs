@@ -0,0 +1,219 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+
+use crate::{config::BackupConfig, server::ServerError, tenant::Tenants};
+
+const PREFIX: &str = "iceload-";
+/// Backups are written under this suffix and renamed once complete, so a crash part way through
+/// never leaves something that looks like a finished backup
+const PARTIAL_SUFFIX: &str = ".partial";
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BackupError {
+    #[error("{0}")]
+    Server(#[from] ServerError),
+    #[error("could not write the backup: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// How the scheduled backups have been going, for metrics
+#[derive(Clone, Debug, Default)]
+pub struct BackupStatus {
+    /// When the last successful backup finished, in seconds since the unix epoch
+    pub last_success: Option<u64>,
+    /// Total size of the files written by the last successful backup
+    pub last_size_bytes: u64,
+    pub last_duration: Duration,
+    /// Backups that have failed since the server started
+    pub failures: u64,
+}
+
+/// Periodically snapshots every tenant's data into a directory, keeping only the most recent
+/// backups.
+///
+/// Each backup is a directory named `iceload-<unix millis>`, holding `data.json` for the
+/// default tenant and `tenant-<name>.json` for each named one. The files are in the format
+/// written by `iceload dump`, so `iceload restore` can read them back.
+pub struct Backups {
+    tenants: Arc<Tenants>,
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+    status: Mutex<BackupStatus>,
+}
+
+impl Backups {
+    pub fn new(tenants: Arc<Tenants>, dir: PathBuf, config: &BackupConfig) -> Backups {
+        Backups {
+            tenants,
+            dir,
+            interval: Duration::from_secs(config.interval_secs.max(1)),
+            keep: config.keep.max(1),
+            status: Mutex::new(BackupStatus::default()),
+        }
+    }
+
+    pub fn status(&self) -> BackupStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Back up on schedule, forever. The first backup is taken one interval after starting.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval =
+            tokio::time::interval_at(tokio::time::Instant::now() + self.interval, self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let backups = self.clone();
+            let started = Instant::now();
+            // Dumping reads the whole database and holds up writes, so keep it off the workers
+            let result = tokio::task::spawn_blocking(move || backups.backup())
+                .await
+                .expect("backups don't panic");
+            let mut status = self.status.lock().unwrap();
+            match result {
+                Ok((path, size)) => {
+                    status.last_success = Some(now().as_secs());
+                    status.last_size_bytes = size;
+                    status.last_duration = started.elapsed();
+                    tracing::info!(path = %path.display(), size, "backed up the database");
+                }
+                Err(e) => {
+                    status.failures += 1;
+                    tracing::error!("backup failed: {e}");
+                }
+            }
+        }
+    }
+
+    /// Take a backup now and prune old ones, returning where it was written and its size
+    pub fn backup(&self) -> Result<(PathBuf, u64), BackupError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let name = format!("{PREFIX}{}", now().as_millis());
+        let partial = self.dir.join(format!("{name}{PARTIAL_SUFFIX}"));
+        std::fs::create_dir(&partial)?;
+
+        let mut size = 0;
+        for tenant in self.tenants.iter() {
+            let file = match &tenant.name {
+                None => "data.json".to_string(),
+                Some(name) => format!("tenant-{name}.json"),
+            };
+            let snapshot = serde_json::to_string_pretty(&tenant.server.dump()?).unwrap() + "\n";
+            std::fs::write(partial.join(file), &snapshot)?;
+            size += snapshot.len() as u64;
+        }
+        let path = self.dir.join(name);
+        std::fs::rename(&partial, &path)?;
+
+        self.prune()?;
+        Ok((path, size))
+    }
+
+    /// Delete all but the newest `keep` backups, and anything left over from interrupted ones
+    fn prune(&self) -> Result<(), BackupError> {
+        let mut backups = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.starts_with(PREFIX) && name.ends_with(PARTIAL_SUFFIX) {
+                std::fs::remove_dir_all(&path)?;
+            } else if let Some(millis) = backup_time(name) {
+                backups.push((millis, path));
+            }
+        }
+        backups.sort_unstable_by(|a, b| b.cmp(a));
+        for (_, path) in backups.iter().skip(self.keep) {
+            tracing::debug!(path = %path.display(), "removing an old backup");
+            std::fs::remove_dir_all(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// When the backup called `name` was taken, if it's one of ours
+fn backup_time(name: &str) -> Option<u128> {
+    name.strip_prefix(PREFIX)?.parse().ok()
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::{json, Value};
+
+    use crate::{
+        message::Ref,
+        schema::{Schema, SchemaItem},
+        server::Server,
+    };
+
+    use super::*;
+
+    /// The backups in `dir`, oldest first
+    fn list(dir: &Path) -> Vec<PathBuf> {
+        let mut backups: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        backups.sort();
+        backups
+    }
+
+    fn schema() -> Schema {
+        Schema::new(SchemaItem::Document(
+            [("title".to_string(), SchemaItem::Scalar)]
+                .into_iter()
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn backups_and_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::open(dir.path().join("data").to_str().unwrap(), schema()).unwrap();
+        let mut tenants = Tenants::new(server.clone(), &[], &[]);
+        tenants.add("blog", schema(), &[], &[]).unwrap();
+        server
+            .insert(&Ref(Vec::new()), json!({ "title": "Home" }))
+            .unwrap();
+
+        let backup_dir = dir.path().join("backups");
+        let config = BackupConfig {
+            dir: None,
+            interval_secs: 60,
+            keep: 2,
+        };
+        let backups = Backups::new(Arc::new(tenants), backup_dir.clone(), &config);
+        // A leftover from a backup that was interrupted
+        std::fs::create_dir_all(backup_dir.join("iceload-1.partial")).unwrap();
+        let mut taken = Vec::new();
+        for _ in 0..3 {
+            taken.push(backups.backup().unwrap().0);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+
+        // Only the newest backups are kept
+        assert_eq!(list(&backup_dir), taken[1..]);
+        let read = |file: &str| -> Value {
+            serde_json::from_str(&std::fs::read_to_string(taken[2].join(file)).unwrap()).unwrap()
+        };
+        assert_eq!(read("data.json"), json!({ "title": "Home" }));
+        assert_eq!(read("tenant-blog.json"), Value::Null);
+    }
+}
@@ -0,0 +1,106 @@
+use sled::{
+    transaction::{TransactionalTree, UnabortableTransactionError},
+    IVec, Tree,
+};
+
+/// The first byte of a manifest, which is stored in place of a value split into chunks. Values
+/// that start with it are always split, however short, so they aren't mistaken for a manifest;
+/// the built-in codecs write text, which never does, as it isn't valid UTF-8.
+const MANIFEST: u8 = 0xff;
+
+/// Values longer than this many bytes are split into chunks unless the server is told otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Whether a value read from the store is a manifest, to be read with `join` or `tx_join`
+pub fn is_manifest(stored: &[u8]) -> bool {
+    stored.first() == Some(&MANIFEST)
+}
+
+/// What to store in place of `value`, writing it to `blobs` in chunks of `chunk_size` bytes if
+/// it's longer than that. Each split value's chunks are kept under an id of its own, so readers
+/// of an older manifest never see a mix of two values.
+pub fn tx_split(
+    blobs: &TransactionalTree,
+    value: &[u8],
+    chunk_size: usize,
+) -> Result<Vec<u8>, UnabortableTransactionError> {
+    if value.len() <= chunk_size && !is_manifest(value) {
+        return Ok(value.to_vec());
+    }
+    let id = blobs.generate_id()?;
+    let mut count = 0;
+    for (index, chunk) in value.chunks(chunk_size.max(1)).enumerate() {
+        blobs.insert(&chunk_key(id, index as u32)[..], chunk)?;
+        count += 1;
+    }
+    Ok(manifest(id, count))
+}
+
+/// Remove the chunks of a value being overwritten or removed, if it was split
+pub fn tx_drop(
+    blobs: &TransactionalTree,
+    stored: &[u8],
+) -> Result<(), UnabortableTransactionError> {
+    if let Some((id, count)) = parse(stored) {
+        for index in 0..count {
+            blobs.remove(&chunk_key(id, index)[..])?;
+        }
+    }
+    Ok(())
+}
+
+/// The value a stored entry holds, as part of a transaction
+pub fn tx_join(
+    blobs: &TransactionalTree,
+    stored: IVec,
+) -> Result<IVec, UnabortableTransactionError> {
+    let Some((id, count)) = parse(&stored) else {
+        return Ok(stored);
+    };
+    let mut value = Vec::new();
+    for index in 0..count {
+        let chunk = blobs.get(chunk_key(id, index))?;
+        value.extend_from_slice(&chunk.expect("a manifest's chunks are written with it"));
+    }
+    Ok(value.into())
+}
+
+/// The value a stored entry holds, or None if it was overwritten since it was read and its chunks
+/// are gone
+pub fn join(blobs: &Tree, stored: IVec) -> sled::Result<Option<IVec>> {
+    let Some((id, count)) = parse(&stored) else {
+        return Ok(Some(stored));
+    };
+    let mut value = Vec::new();
+    for index in 0..count {
+        match blobs.get(chunk_key(id, index))? {
+            Some(chunk) => value.extend_from_slice(&chunk),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(value.into()))
+}
+
+fn manifest(id: u64, count: u32) -> Vec<u8> {
+    let mut manifest = vec![MANIFEST];
+    manifest.extend_from_slice(&id.to_be_bytes());
+    manifest.extend_from_slice(&count.to_be_bytes());
+    manifest
+}
+
+fn parse(stored: &[u8]) -> Option<(u64, u32)> {
+    let (&MANIFEST, rest) = stored.split_first()? else {
+        return None;
+    };
+    let id = u64::from_be_bytes(rest.get(..8)?.try_into().unwrap());
+    let count = u32::from_be_bytes(rest.get(8..12)?.try_into().unwrap());
+    Some((id, count))
+}
+
+/// Chunks are kept in order under their value's id
+fn chunk_key(id: u64, index: u32) -> [u8; 12] {
+    let mut key = [0; 12];
+    key[..8].copy_from_slice(&id.to_be_bytes());
+    key[8..].copy_from_slice(&index.to_be_bytes());
+    key
+}
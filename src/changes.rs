@@ -0,0 +1,268 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::{
+    transaction::{TransactionalTree, UnabortableTransactionError},
+    Db, Tree,
+};
+use tokio::sync::watch;
+
+use crate::message::Ref;
+
+/// What's added to the ids sled generates to make sequence numbers is kept under the empty key,
+/// which sorts before every change. Logs written before sequence numbers were generated kept the
+/// next one here, which makes as good an offset.
+const OFFSET: &[u8] = b"";
+/// The number of the oldest change that hasn't been trimmed, under a key that also sorts before
+/// every change
+const TRIMMED: &[u8] = b"\0";
+/// On a follower, the number of the next change to apply from the leader's log, under another key
+/// that sorts before every change
+const REPLICATED: &[u8] = b"\0\0";
+
+/// A committed write, as recorded in the change log
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Change {
+    /// The change's position in the log. Sequence numbers only go up and are never reused, but
+    /// they skip, and a change may commit before one numbered lower; `Server::changes` only reads
+    /// as far as the first that hasn't.
+    pub seq: u64,
+    #[serde(flatten)]
+    pub op: ChangeOp,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ChangeOp {
+    Insert {
+        path: Ref,
+        value: Value,
+    },
+    Update {
+        path: Ref,
+        value: Value,
+    },
+    Remove {
+        path: Ref,
+    },
+    /// Everything was replaced with a snapshot written by `Server::dump`
+    Restore {
+        snapshot: Value,
+    },
+}
+
+impl ChangeOp {
+    /// The path the change wrote to; a restore replaces everything
+    pub fn path(&self) -> &Ref {
+        const ROOT: &Ref = &Ref(Vec::new());
+        match self {
+            ChangeOp::Insert { path, .. }
+            | ChangeOp::Update { path, .. }
+            | ChangeOp::Remove { path } => path,
+            ChangeOp::Restore { .. } => ROOT,
+        }
+    }
+}
+
+/// Hands out the sequence numbers of changes. They're ids generated by sled, so writers don't
+/// all conflict over a counter, but they're handed out before the transactions making the changes
+/// commit, which may not be in the same order. The log is only read as far as the first change
+/// still being made, so nothing reading it in order skips one that commits late.
+pub struct Sequencer {
+    db: Db,
+    offset: u64,
+    numbering: Mutex<Numbering>,
+    /// Signalled whenever the log can be read further
+    readable: watch::Sender<()>,
+}
+
+struct Numbering {
+    /// The path each change that's been numbered but not yet committed or abandoned writes to
+    pending: BTreeMap<u64, Ref>,
+    /// One more than the highest number handed out so far
+    next: u64,
+}
+
+impl Numbering {
+    fn horizon(&self) -> u64 {
+        self.pending.keys().next().copied().unwrap_or(self.next)
+    }
+}
+
+/// Sequence numbers from `Sequencer::number`, which are pending until this is dropped, once the
+/// changes they number have committed or been abandoned
+pub struct Numbered<'a> {
+    sequencer: &'a Sequencer,
+    seqs: Vec<u64>,
+}
+
+impl Sequencer {
+    /// Number the changes to `log` after every one already in it, and after every number handed
+    /// out for it before, which may never have been committed
+    pub fn open(db: &Db, log: &Tree) -> sled::Result<Sequencer> {
+        let stored = log.get(OFFSET)?.map_or(0, |offset| decode_seq(&offset));
+        let logged = match log.range(key(0)..).keys().next_back() {
+            Some(last) => decode_seq(&last?) + 1,
+            None => trimmed(log)?,
+        };
+        // Generated ids only go up, so once an offset covers the log it always will
+        let generated = db.generate_id()?;
+        let offset = stored.max(logged.saturating_sub(generated));
+        if offset != stored {
+            log.insert(OFFSET, &key(offset))?;
+        }
+        Ok(Sequencer {
+            db: db.clone(),
+            offset,
+            numbering: Mutex::new(Numbering {
+                pending: BTreeMap::new(),
+                next: generated + offset,
+            }),
+            readable: watch::Sender::new(()),
+        })
+    }
+
+    /// Number each of `ops`, which are about to be made in one transaction
+    pub fn number(&self, ops: &[ChangeOp]) -> sled::Result<Numbered<'_>> {
+        // Numbers are handed out under the lock, so none can be in use without being pending
+        let mut numbering = self.numbering.lock().unwrap();
+        let seqs = (ops.iter())
+            .map(|_| Ok(self.db.generate_id()? + self.offset))
+            .collect::<sled::Result<Vec<u64>>>()?;
+        for (&seq, op) in seqs.iter().zip(ops) {
+            numbering.pending.insert(seq, op.path().clone());
+            numbering.next = seq + 1;
+        }
+        Ok(Numbered {
+            sequencer: self,
+            seqs,
+        })
+    }
+
+    /// The lowest number a change that hasn't committed yet may have. Every change numbered
+    /// below it has committed or never will.
+    pub fn horizon(&self) -> u64 {
+        self.numbering.lock().unwrap().horizon()
+    }
+
+    /// The number the next change will be given, or a lower one
+    pub fn next(&self) -> u64 {
+        self.numbering.lock().unwrap().next
+    }
+
+    /// Changes whenever changes that had been numbered have committed, and the log can be read
+    /// further. Marked as seen to begin with, so watch before reading.
+    pub fn watch(&self) -> watch::Receiver<()> {
+        self.readable.subscribe()
+    }
+
+    /// Whether a change that's been numbered but not yet committed or abandoned writes to
+    /// anything overlapping `keys`
+    pub fn pending_overlaps(&self, keys: &[Ref]) -> bool {
+        let numbering = self.numbering.lock().unwrap();
+        (numbering.pending.values()).any(|path| keys.iter().any(|key| path.overlaps(key)))
+    }
+}
+
+impl Numbered<'_> {
+    pub fn seqs(&self) -> &[u64] {
+        &self.seqs
+    }
+}
+
+impl Drop for Numbered<'_> {
+    fn drop(&mut self) {
+        let mut numbering = self.sequencer.numbering.lock().unwrap();
+        let horizon = numbering.horizon();
+        for seq in &self.seqs {
+            numbering.pending.remove(seq);
+        }
+        // Unless a change numbered before these is still being made, these and any numbered
+        // after them that have committed can be read now
+        if numbering.horizon() > horizon {
+            self.sequencer.readable.send_replace(());
+        }
+    }
+}
+
+/// Record `op` in the log as change number `seq`, as part of the transaction that makes it
+pub fn write(
+    log: &TransactionalTree,
+    seq: u64,
+    op: &ChangeOp,
+) -> Result<(), UnabortableTransactionError> {
+    let change = Change {
+        seq,
+        op: op.clone(),
+    };
+    log.insert(&key(seq), serde_json::to_vec(&change).unwrap())?;
+    Ok(())
+}
+
+/// Record, as part of the transaction applying a change from the leader, that the leader's next
+/// change is numbered `next`
+pub fn write_replicated(
+    log: &TransactionalTree,
+    next: u64,
+) -> Result<(), UnabortableTransactionError> {
+    log.insert(REPLICATED, &key(next))?;
+    Ok(())
+}
+
+/// `write_replicated`, for a change from the leader that had nothing to apply
+pub fn record_replicated(log: &Tree, next: u64) -> Result<(), sled::Error> {
+    log.insert(REPLICATED, &key(next))?;
+    Ok(())
+}
+
+/// The number of the next change to apply from the leader's log, if any have been applied
+pub fn replicated(log: &Tree) -> Result<Option<u64>, sled::Error> {
+    Ok(log.get(REPLICATED)?.map(|next| decode_seq(&next)))
+}
+
+/// Up to `limit` changes, starting at the one numbered `from` and stopping before `until`
+pub fn read(log: &Tree, from: u64, until: u64, limit: usize) -> Result<Vec<Change>, sled::Error> {
+    if until <= from {
+        return Ok(Vec::new());
+    }
+    log.range(key(from)..key(until))
+        .values()
+        .take(limit)
+        .map(|value| Ok(serde_json::from_slice(&value?).expect("changes are stored as JSON")))
+        .collect()
+}
+
+/// The number of the oldest change `trim` has left, or a lower one; every change before it is gone
+pub fn trimmed(log: &Tree) -> Result<u64, sled::Error> {
+    Ok(log.get(TRIMMED)?.map_or(0, |oldest| decode_seq(&oldest)))
+}
+
+/// Remove every change but the `keep` most recent, leaving any numbered `pinned` or later,
+/// returning how many were removed
+pub fn trim(log: &Tree, keep: usize, pinned: u64) -> Result<usize, sled::Error> {
+    let Some(newest_removed) = log.range(key(0)..).keys().rev().nth(keep) else {
+        return Ok(0);
+    };
+    let oldest = (decode_seq(&newest_removed?) + 1).min(pinned);
+    if oldest <= trimmed(log)? {
+        return Ok(0);
+    }
+    // Readers check this after reading, so it's moved before anything they read is removed
+    log.insert(TRIMMED, &key(oldest))?;
+    let mut removed = 0;
+    for seq in log.range(key(0)..key(oldest)).keys() {
+        log.remove(seq?)?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Changes are keyed by big-endian sequence number, so they're read back in order
+fn key(seq: u64) -> [u8; 8] {
+    seq.to_be_bytes()
+}
+
+fn decode_seq(value: &[u8]) -> u64 {
+    u64::from_be_bytes(value.try_into().expect("sequence numbers are 8 bytes"))
+}
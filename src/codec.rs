@@ -0,0 +1,83 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::message::Ref;
+
+#[derive(Debug, Error)]
+#[error("{}", .0)]
+pub struct CodecError(pub String);
+
+/// Converts scalar values between their JSON form on the wire and their bytes in the store.
+///
+/// `encode` doubles as validation: returning an error rejects the write.
+pub trait ScalarCodec: Send + Sync {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError>;
+}
+
+/// Named codecs available to `SchemaItem::Custom` fields
+pub type Codecs = HashMap<String, Arc<dyn ScalarCodec>>;
+
+/// The codec for plain `SchemaItem::Scalar` fields, which must be JSON strings
+pub struct StringCodec;
+
+impl ScalarCodec for StringCodec {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        match value {
+            Value::String(string) => Ok(string.as_bytes().to_vec()),
+            _ => Err(CodecError("expected a string".to_string())),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+        String::from_utf8(bytes.to_vec())
+            .map(Value::String)
+            .map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+/// The codec for `SchemaItem::Reference` fields, which must be refs: arrays of strings
+pub struct RefCodec;
+
+impl ScalarCodec for RefCodec {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        let value = Ref::deserialize(value).map_err(|e| CodecError(e.to_string()))?;
+        serde_json::to_vec(&value).map_err(|e| CodecError(e.to_string()))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+        let value: Ref = serde_json::from_slice(bytes).map_err(|e| CodecError(e.to_string()))?;
+        serde_json::to_value(value).map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+/// A codec for any serde type: values are validated by deserializing them into `T` and stored in
+/// `T`'s canonical JSON form
+pub struct SerdeCodec<T>(PhantomData<fn() -> T>);
+
+impl<T> SerdeCodec<T> {
+    pub fn new() -> SerdeCodec<T> {
+        SerdeCodec(PhantomData)
+    }
+}
+
+impl<T> Default for SerdeCodec<T> {
+    fn default() -> SerdeCodec<T> {
+        SerdeCodec::new()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> ScalarCodec for SerdeCodec<T> {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+        let value = T::deserialize(value).map_err(|e| CodecError(e.to_string()))?;
+        serde_json::to_vec(&value).map_err(|e| CodecError(e.to_string()))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+        let value: T = serde_json::from_slice(bytes).map_err(|e| CodecError(e.to_string()))?;
+        serde_json::to_value(value).map_err(|e| CodecError(e.to_string()))
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use iceload::schema::{Schema, SchemaItem};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Language {
+    Rust,
+    Typescript,
+}
+
+/// Emit typed path accessors for every node of the schema, rooted at a type called `Root`
+pub fn generate(schema: &Schema, language: Language) -> String {
+    let mut nodes = Vec::new();
+    collect_nodes(schema.root(), "Root".to_string(), &mut nodes);
+
+    let mut out = String::new();
+    match language {
+        Language::Rust => {
+            writeln!(out, "// Generated by `iceload codegen`. Do not edit.").unwrap();
+            writeln!(out).unwrap();
+            writeln!(out, "#![allow(dead_code)]").unwrap();
+            for node in nodes.iter() {
+                write_rust_node(&mut out, node);
+            }
+        }
+        Language::Typescript => {
+            writeln!(out, "// Generated by `iceload codegen`. Do not edit.").unwrap();
+            writeln!(out).unwrap();
+            writeln!(out, "export interface Client {{").unwrap();
+            writeln!(out, "  get(path: string[]): Promise<unknown>;").unwrap();
+            writeln!(
+                out,
+                "  update(path: string[], value: unknown): Promise<unknown>;"
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "  subscribe(path: string[], callback: (value: string | null) => void): Promise<void>;"
+            )
+            .unwrap();
+            writeln!(out, "}}").unwrap();
+            for node in nodes.iter() {
+                write_ts_node(&mut out, node);
+            }
+        }
+    }
+
+    out
+}
+
+struct Node<'a> {
+    type_name: String,
+    item: &'a SchemaItem,
+    // (field name, child type name), sorted by field name
+    children: Vec<(&'a str, String)>,
+}
+
+fn collect_nodes<'a>(item: &'a SchemaItem, type_name: String, nodes: &mut Vec<Node<'a>>) {
+    let mut children = Vec::new();
+    match item {
+        SchemaItem::Collection(collection) => {
+            let child_type = format!("{type_name}Item");
+            children.push(("", child_type.clone()));
+            nodes.push(Node {
+                type_name,
+                item,
+                children,
+            });
+            collect_nodes(&collection.items, child_type, nodes);
+        }
+        // Members of a presence collection are scalars, so it's addressed like a collection
+        SchemaItem::Presence => {
+            static MEMBER: SchemaItem = SchemaItem::Scalar;
+            let child_type = format!("{type_name}Item");
+            children.push(("", child_type.clone()));
+            nodes.push(Node {
+                type_name,
+                item,
+                children,
+            });
+            collect_nodes(&MEMBER, child_type, nodes);
+        }
+        SchemaItem::Document(fields) => {
+            let mut fields: Vec<_> = fields.iter().collect();
+            fields.sort_by_key(|(name, _)| name.as_str());
+            for (name, _) in fields.iter() {
+                children.push((name.as_str(), format!("{type_name}{}", pascal_case(name))));
+            }
+            let child_types = children.clone();
+            nodes.push(Node {
+                type_name,
+                item,
+                children,
+            });
+            for ((_, child), (_, child_type)) in fields.into_iter().zip(child_types) {
+                collect_nodes(child, child_type, nodes);
+            }
+        }
+        SchemaItem::Scalar
+        | SchemaItem::Custom(_)
+        | SchemaItem::Reference
+        | SchemaItem::ReferenceTo(_)
+        | SchemaItem::Enum(_) => nodes.push(Node {
+            type_name,
+            item,
+            children,
+        }),
+        SchemaItem::Sensitive(inner)
+        | SchemaItem::Ephemeral(inner)
+        | SchemaItem::Optional(inner)
+        | SchemaItem::Searchable(inner) => collect_nodes(inner, type_name, nodes),
+    }
+}
+
+fn write_rust_node(out: &mut String, node: &Node) {
+    let name = &node.type_name;
+    writeln!(out).unwrap();
+    writeln!(out, "#[derive(Clone, Debug, PartialEq, Eq)]").unwrap();
+    writeln!(out, "pub struct {name} {{").unwrap();
+    writeln!(out, "    path: Vec<String>,").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "impl {name} {{").unwrap();
+    if name == "Root" {
+        writeln!(out, "    pub fn new() -> Self {{").unwrap();
+        writeln!(out, "        {name} {{ path: Vec::new() }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out).unwrap();
+    }
+    writeln!(out, "    pub fn path(&self) -> &[String] {{").unwrap();
+    writeln!(out, "        &self.path").unwrap();
+    writeln!(out, "    }}").unwrap();
+    for (field, child_type) in node.children.iter() {
+        writeln!(out).unwrap();
+        if matches!(node.item, SchemaItem::Collection(_) | SchemaItem::Presence) {
+            writeln!(
+                out,
+                "    pub fn doc(&self, key: impl Into<String>) -> {child_type} {{"
+            )
+            .unwrap();
+            writeln!(out, "        let mut path = self.path.clone();").unwrap();
+            writeln!(out, "        path.push(key.into());").unwrap();
+        } else {
+            writeln!(
+                out,
+                "    pub fn {}(&self) -> {child_type} {{",
+                rust_ident(field)
+            )
+            .unwrap();
+            writeln!(out, "        let mut path = self.path.clone();").unwrap();
+            writeln!(out, "        path.push({field:?}.to_string());").unwrap();
+        }
+        writeln!(out, "        {child_type} {{ path }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_ts_node(out: &mut String, node: &Node) {
+    let name = &node.type_name;
+    writeln!(out).unwrap();
+    writeln!(out, "export class {name} {{").unwrap();
+    writeln!(
+        out,
+        "  constructor(readonly client: Client, readonly path: string[] = []) {{}}"
+    )
+    .unwrap();
+    for (field, child_type) in node.children.iter() {
+        writeln!(out).unwrap();
+        if matches!(node.item, SchemaItem::Collection(_) | SchemaItem::Presence) {
+            writeln!(out, "  doc(key: string): {child_type} {{").unwrap();
+            writeln!(
+                out,
+                "    return new {child_type}(this.client, [...this.path, key]);"
+            )
+            .unwrap();
+        } else {
+            writeln!(out, "  {}(): {child_type} {{", ts_ident(field)).unwrap();
+            writeln!(
+                out,
+                "    return new {child_type}(this.client, [...this.path, {field:?}]);"
+            )
+            .unwrap();
+        }
+        writeln!(out, "  }}").unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "  get(): Promise<unknown> {{").unwrap();
+    writeln!(out, "    return this.client.get(this.path);").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "  update(value: unknown): Promise<unknown> {{").unwrap();
+    writeln!(out, "    return this.client.update(this.path, value);").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "  subscribe(callback: (value: string | null) => void): Promise<void> {{"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    return this.client.subscribe(this.path, callback);"
+    )
+    .unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+fn rust_ident(name: &str) -> String {
+    let ident: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{ident}")
+    } else {
+        ident
+    }
+}
+
+fn ts_ident(name: &str) -> String {
+    let pascal = pascal_case(name);
+    let mut chars = pascal.chars();
+    match chars.next() {
+        Some(first) if !first.is_ascii_digit() => std::iter::once(first.to_ascii_lowercase())
+            .chain(chars)
+            .collect(),
+        _ => format!("_{pascal}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iceload::schema::{CollectionSchema, Schema, SchemaItem};
+
+    use super::{generate, Language};
+
+    #[test]
+    fn collection_accessors() {
+        let schema = Schema::new(SchemaItem::Document(
+            [
+                (
+                    "fruits".to_string(),
+                    SchemaItem::Collection(CollectionSchema::new(SchemaItem::Document(
+                        [("color".to_string(), SchemaItem::Scalar)]
+                            .into_iter()
+                            .collect(),
+                    ))),
+                ),
+                ("online".to_string(), SchemaItem::Presence),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+
+        let rust = generate(&schema, Language::Rust);
+        assert!(rust.contains("pub fn fruits(&self) -> RootFruits {"));
+        assert!(rust.contains("pub fn doc(&self, key: impl Into<String>) -> RootFruitsItem {"));
+        assert!(rust.contains("pub fn color(&self) -> RootFruitsItemColor {"));
+        assert!(rust.contains("pub fn doc(&self, key: impl Into<String>) -> RootOnlineItem {"));
+        assert!(!rust.contains("pub fn (&self)"));
+
+        let ts = generate(&schema, Language::Typescript);
+        assert!(ts.contains("doc(key: string): RootFruitsItem {"));
+        assert!(ts.contains("color(): RootFruitsItemColor {"));
+        assert!(ts.contains("doc(key: string): RootOnlineItem {"));
+        assert!(!ts.contains("  _(): "));
+    }
+}
@@ -0,0 +1,631 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{cron::Schedule, outbox::SlowConsumerPolicy};
+
+/// Server settings, read from a TOML file. Every field is optional; command-line flags take
+/// precedence over the file.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to accept WebSocket connections on, or `unix:<path>` for a Unix socket
+    pub listen: String,
+    /// Further addresses to accept WebSocket connections on alongside `listen`, each with its own
+    /// TLS settings
+    pub listeners: Vec<ListenerConfig>,
+    /// Address to serve the HTTP gateway on
+    pub http_listen: String,
+    /// Address to serve gRPC on, when built with the `grpc` feature
+    pub grpc_listen: std::net::SocketAddr,
+    /// Address to serve the admin API on; it's disabled unless set
+    pub admin_listen: Option<String>,
+    /// A secret every admin API request has to carry, as `Authorization: Bearer <token>`. Without
+    /// one, anyone who can reach the admin API may use it, except to replace schemas.
+    pub admin_token: Option<String>,
+    /// Let the admin API capture profiles. This costs a little on every span even when no
+    /// capture is running.
+    pub profiling: bool,
+    /// Explain how each WebSocket request was handled, for developing an app against the server.
+    /// Explanations reveal the schema and permission rules, so this must not be used in
+    /// production.
+    pub dev_mode: bool,
+    /// The database directory
+    pub data: String,
+    /// Keep the database in a temporary directory that's deleted on exit instead of in `data`,
+    /// for tests and demos
+    pub ephemeral: bool,
+    pub schema: PathBuf,
+    pub rules: PathBuf,
+    /// A script of functions clients can run with `Call`; there are none unless it's set
+    pub functions: Option<PathBuf>,
+    /// Serve wss:// instead of ws:// when present
+    pub tls: Option<TlsConfig>,
+    pub replay: ReplayConfig,
+    pub connections: ConnectionConfig,
+    pub limits: LimitsConfig,
+    /// The fraction of connections, from 0 to 1, each experimental feature is enabled for
+    pub features: HashMap<String, f64>,
+    pub sled: SledConfig,
+    pub log: LogConfig,
+    pub deliveries: DeliveryConfig,
+    pub webhooks: Vec<WebhookConfig>,
+    /// Where to publish change events, when built with the `nats` feature
+    pub nats: Option<NatsConfig>,
+    pub backups: BackupConfig,
+    pub gc: GcConfig,
+    pub changes: ChangesConfig,
+    pub history: HistoryConfig,
+    pub audit: AuditConfig,
+    pub accounts: AccountsConfig,
+    /// OpenID Connect providers whose ID tokens clients may authenticate with
+    pub oidc: Vec<OidcConfig>,
+    pub jobs: Vec<JobConfig>,
+    pub replication: ReplicationConfig,
+    /// Further apps hosted alongside the default one, by name
+    pub tenants: BTreeMap<String, TenantConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "127.0.0.1:9002".into(),
+            listeners: Vec::new(),
+            http_listen: "127.0.0.1:9003".into(),
+            grpc_listen: ([127, 0, 0, 1], 9004).into(),
+            admin_listen: None,
+            admin_token: None,
+            profiling: false,
+            dev_mode: false,
+            data: "data".into(),
+            ephemeral: false,
+            schema: "schema.json".into(),
+            rules: "permission.luau".into(),
+            functions: None,
+            tls: None,
+            replay: ReplayConfig::default(),
+            connections: ConnectionConfig::default(),
+            limits: LimitsConfig::default(),
+            features: HashMap::new(),
+            sled: SledConfig::default(),
+            log: LogConfig::default(),
+            deliveries: DeliveryConfig::default(),
+            webhooks: Vec::new(),
+            nats: None,
+            backups: BackupConfig::default(),
+            gc: GcConfig::default(),
+            changes: ChangesConfig::default(),
+            history: HistoryConfig::default(),
+            audit: AuditConfig::default(),
+            accounts: AccountsConfig::default(),
+            oidc: Vec::new(),
+            jobs: Vec::new(),
+            replication: ReplicationConfig::default(),
+            tenants: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// A host and port, or `unix:<path>` for a Unix socket
+    pub address: String,
+    /// Serve wss:// on this listener when present
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+/// An app with its own data, schema, and permission rules. WebSocket clients select it by
+/// connecting to `/<name>`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub schema: PathBuf,
+    pub rules: PathBuf,
+    #[serde(default)]
+    pub functions: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    /// How far an envelope's timestamp may be from the server's clock, in seconds
+    pub window_secs: u64,
+    /// Reject writes and function calls that aren't wrapped in an envelope
+    pub require_envelopes: bool,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> ReplayConfig {
+        ReplayConfig {
+            window_secs: 30,
+            require_envelopes: false,
+        }
+    }
+}
+
+impl ReplayConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// The most WebSocket clients connected at once; further connections are refused
+    pub max: Option<usize>,
+    /// How many outgoing messages may be queued for a client that isn't keeping up
+    pub send_buffer: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// How often to ping each client, in seconds, so quiet connections stay open through proxies
+    pub ping_interval_secs: u64,
+    /// Close a connection after this many seconds without hearing from the client, pongs
+    /// included, ending its subscriptions
+    pub idle_timeout_secs: u64,
+    /// Origins browsers may connect from besides the server's own host, such as
+    /// `https://app.example.com`, or `*` for any. Clients that aren't browsers send no origin, so
+    /// they aren't affected.
+    pub allowed_origins: Vec<String>,
+    /// How long to keep the subscriptions of a connection that drops, in seconds, so its client
+    /// can reconnect and resume them. Zero turns resuming off.
+    pub session_grace_secs: u64,
+    /// Outgoing messages longer than this many bytes are sent in several WebSocket frames, so a
+    /// large value doesn't hold up the connection in one giant frame
+    pub max_frame_bytes: usize,
+    /// The most requests from one client that read or write the store at once. Responses are
+    /// still sent in the order the requests arrived.
+    pub max_in_flight: usize,
+    /// Offer clients the `deflate` feature, which compresses everything sent to them after the
+    /// `Welcome`. Repetitive JSON like subscription updates shrinks a lot, for some CPU.
+    pub compression: bool,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            max: None,
+            send_buffer: 256,
+            slow_consumer: SlowConsumerPolicy::DropOldest,
+            ping_interval_secs: 20,
+            idle_timeout_secs: 60,
+            allowed_origins: Vec::new(),
+            session_grace_secs: 30,
+            max_frame_bytes: 64 * 1024,
+            max_in_flight: 16,
+            compression: false,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    pub fn ping_interval(&self) -> Duration {
+        // Pinging continuously would only waste bandwidth
+        Duration::from_secs(self.ping_interval_secs.max(1))
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn session_grace(&self) -> Duration {
+        Duration::from_secs(self.session_grace_secs)
+    }
+}
+
+/// Caps on what WebSocket clients may send and hold, so one can't stall the server with a huge
+/// request or swamp it with subscriptions
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// The largest message accepted, in bytes; larger ones close the connection
+    pub max_message_bytes: usize,
+    /// The most components a ref may have
+    pub max_ref_depth: usize,
+    /// The longest a single ref component may be, in bytes
+    pub max_component_bytes: usize,
+    /// The largest value an insert or update may write, in bytes of JSON
+    pub max_value_bytes: usize,
+    /// The most subscriptions a single connection may hold at once
+    pub max_subscriptions_per_connection: usize,
+    /// The most subscriptions held across every connection at once
+    pub max_subscriptions: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_message_bytes: 1024 * 1024,
+            max_ref_depth: 32,
+            max_component_bytes: 256,
+            max_value_bytes: 512 * 1024,
+            max_subscriptions_per_connection: 1000,
+            max_subscriptions: 100_000,
+        }
+    }
+}
+
+/// How integrations retry sending to receivers that are down
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveryConfig {
+    /// How long to wait before retrying a failed delivery the first time, in milliseconds; the
+    /// wait doubles with each further failure
+    pub initial_backoff_ms: u64,
+    /// The longest wait between retries, in milliseconds
+    pub max_backoff_ms: u64,
+    /// How many times to try a delivery before moving it to the dead letters
+    pub max_attempts: u32,
+    /// The most delivery attempts to make per second, across every integration
+    pub per_second: f64,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> DeliveryConfig {
+        DeliveryConfig {
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5 * 60 * 1000,
+            max_attempts: 10,
+            per_second: 10.0,
+        }
+    }
+}
+
+impl DeliveryConfig {
+    /// How long to wait after a delivery has failed `attempts` times
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1 << attempts.saturating_sub(1).min(32));
+        Duration::from_millis(backoff.min(self.max_backoff_ms))
+    }
+
+    /// The shortest gap between two delivery attempts
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.per_second.max(0.001))
+    }
+}
+
+/// An HTTP endpoint that change events are POSTed to
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Writes to this path, to anything under it, or to anything containing it are sent; every
+    /// write is sent if it's empty
+    #[serde(default)]
+    pub path: Vec<String>,
+    /// An http:// or https:// URL
+    pub url: String,
+}
+
+/// A NATS subject that change events are published to, each with an `Iceload-Ref` header
+/// holding the hex-encoded ref it wrote to
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NatsConfig {
+    /// A nats:// URL; TLS and credentials aren't supported
+    pub url: String,
+    pub subject: String,
+    /// Writes to this path, to anything under it, or to anything containing it are published;
+    /// every write is if it's empty
+    #[serde(default)]
+    pub path: Vec<String>,
+}
+
+/// Running as a read-only copy of another server
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// The http:// URL of the leader's admin API. When set, this server replicates the leader's
+    /// data and rejects writes.
+    pub leader: Option<String>,
+    /// The leader's `admin_token`, which its admin API needs to replicate
+    pub token: Option<String>,
+}
+
+/// Periodic snapshots of the database
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// The directory to write backups to; they're disabled unless set
+    pub dir: Option<PathBuf>,
+    /// How often to back up, in seconds
+    pub interval_secs: u64,
+    /// How many of the most recent backups to keep
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> BackupConfig {
+        BackupConfig {
+            dir: None,
+            interval_secs: 60 * 60,
+            keep: 24,
+        }
+    }
+}
+
+/// Periodic cleanup of items left behind in the database without the documents or collections
+/// that contain them
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    /// How often to look for them, in seconds; it's disabled unless set
+    pub interval_secs: Option<u64>,
+}
+
+/// The log of committed changes, which followers, integrations and resuming clients read from
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChangesConfig {
+    /// How many of the most recent changes to keep in each tenant's log
+    pub keep: usize,
+    /// How often to trim older changes from the logs, in seconds
+    pub trim_interval_secs: u64,
+}
+
+impl Default for ChangesConfig {
+    fn default() -> ChangesConfig {
+        ChangesConfig {
+            keep: 100_000,
+            trim_interval_secs: 60,
+        }
+    }
+}
+
+/// Past revisions of documents, for undo and auditing
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// How many revisions of each document to keep; none are kept unless it's set
+    pub keep: usize,
+}
+
+/// A log of who made each write, read with the admin API
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Log writes the permission rules deny, as well as the ones they allow
+    pub denied: bool,
+}
+
+/// User accounts kept by the server itself, which clients sign up and sign in to
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    pub enabled: bool,
+    /// Let clients sign in without an account, as a new user each time unless they sign in
+    /// again with the token they were given. Works whether or not `enabled` is set.
+    pub anonymous: bool,
+}
+
+/// An OpenID Connect provider, whose ID tokens clients send with `Auth` to be checked against the
+/// permission rules as their `sub`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Matched against the `iss` claim, and where the discovery document is found
+    pub issuer: String,
+    /// The client ID the tokens have to be issued to, matched against the `aud` claim
+    pub audience: String,
+    /// Where the provider's signing keys are published; found through its discovery document
+    /// unless it's set
+    pub jwks_url: Option<String>,
+    /// The claims besides `sub` and `iss` passed to the permission rules
+    #[serde(default)]
+    pub claims: Vec<String>,
+    /// How long to keep the signing keys before fetching them again, in seconds; an hour unless
+    /// it's set. They're fetched sooner if a token is signed by a key that isn't among them.
+    pub cache_secs: Option<u64>,
+}
+
+/// A function from a functions script to run on a schedule
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    /// Identifies the job in logs and metrics
+    pub name: String,
+    /// When to run, as a cron expression in UTC
+    pub schedule: Schedule,
+    pub function: String,
+    /// The tenant whose functions and data the job uses; the default tenant unless set
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+/// Tuning for the storage engine; anything left unset keeps its default
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SledConfig {
+    /// Values longer than this many bytes are split across several keys, so they don't make
+    /// giant nodes; 64 KiB unless set
+    pub chunk_size: Option<usize>,
+    /// Maximum size of the page cache, in bytes
+    pub cache_capacity: Option<u64>,
+    /// How often to flush to disk, in milliseconds; 0 disables periodic flushing
+    pub flush_every_ms: Option<u64>,
+    /// Flush each write to disk before acknowledging it, so no acknowledged write is lost in a
+    /// crash. Clients can ask for this one write at a time with `Durable` instead.
+    pub sync_writes: bool,
+    pub mode: Option<SledMode>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SledMode {
+    LowSpace,
+    HighThroughput,
+}
+
+impl SledConfig {
+    pub fn to_sled(&self) -> sled::Config {
+        let mut config = sled::Config::new();
+        if let Some(cache_capacity) = self.cache_capacity {
+            config = config.cache_capacity(cache_capacity);
+        }
+        if let Some(flush_every_ms) = self.flush_every_ms {
+            config = config.flush_every_ms((flush_every_ms > 0).then_some(flush_every_ms));
+        }
+        if let Some(mode) = self.mode {
+            config = config.mode(match mode {
+                SledMode::LowSpace => sled::Mode::LowSpace,
+                SledMode::HighThroughput => sled::Mode::HighThroughput,
+            });
+        }
+        config
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Which events to log, as `RUST_LOG`-style directives, e.g. "info,iceload=debug". The
+    /// `RUST_LOG` environment variable takes precedence.
+    pub filter: String,
+    pub format: LogFormat,
+    /// Log a warning for each read, write or permission check that takes longer than this many
+    /// milliseconds, with its path; nothing is timed unless it's set
+    pub slow_ms: Option<u64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            filter: "info".into(),
+            format: LogFormat::Text,
+            slow_ms: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ConfigError {
+    #[error("could not read config file {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("invalid config file {path}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Load the config file if it exists, falling back to the defaults if it doesn't
+    pub fn load_or_default(path: &Path) -> Result<Config, ConfigError> {
+        match Config::load(path) {
+            Err(ConfigError::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                Ok(Config::default())
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = "0.0.0.0:443"
+            data = "/var/lib/iceload"
+
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+
+            [features]
+            coalesce_updates = 0.25
+
+            [sled]
+            cache_capacity = 1048576
+            mode = "high_throughput"
+
+            [log]
+            format = "json"
+
+            [tenants.blog]
+            schema = "blog/schema.json"
+            rules = "blog/permission.luau"
+
+            [[listeners]]
+            address = "unix:/run/iceload.sock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, "0.0.0.0:443");
+        assert_eq!(config.data, "/var/lib/iceload");
+        // Unset fields keep their defaults
+        assert_eq!(config.rules, Path::new("permission.luau"));
+        assert_eq!(config.replay.window_secs, 30);
+        assert_eq!(config.tls.unwrap().key, Path::new("key.pem"));
+        assert_eq!(config.features["coalesce_updates"], 0.25);
+        assert_eq!(config.sled.cache_capacity, Some(1048576));
+        assert!(matches!(config.log.format, LogFormat::Json));
+        assert_eq!(config.log.filter, "info");
+        assert_eq!(config.tenants["blog"].schema, Path::new("blog/schema.json"));
+        assert_eq!(config.listeners[0].address, "unix:/run/iceload.sock");
+        assert!(config.listeners[0].tls.is_none());
+
+        assert!(toml::from_str::<Config>("listne = \"0.0.0.0:443\"").is_err());
+    }
+
+    #[test]
+    fn example() {
+        toml::from_str::<Config>(include_str!("../iceload.example.toml")).unwrap();
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("iceload.toml");
+        assert!(Config::load_or_default(&path).is_ok());
+        assert!(matches!(Config::load(&path), Err(ConfigError::Io { .. })));
+    }
+}
@@ -0,0 +1,198 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CronError {
+    #[error("a cron expression has 5 fields, not {}", .0)]
+    FieldCount(usize),
+    #[error("invalid {field} in cron expression: {value:?}")]
+    InvalidField { field: &'static str, value: String },
+}
+
+/// When a scheduled job runs, from a cron expression of five fields: minute, hour, day of the
+/// month, month, and day of the week, where 0 and 7 are both Sunday. Each field is `*`, a number,
+/// or a range like `1-5`, optionally with a step like `*/15`, or a comma-separated list of those.
+/// Times are in UTC.
+///
+/// As in cron, when both the day of the month and the day of the week are restricted, a day
+/// matching either one is enough.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    // Each field is a set of the values it matches, as bits
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    either_day: bool,
+}
+
+const SECS_PER_MINUTE: u64 = 60;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+/// How far ahead to look for a matching day; every schedule that can match does within this
+/// many, as the calendar repeats every 400 years and leap days come at least every 8
+const SEARCH_DAYS: u64 = 8 * 366;
+
+impl Schedule {
+    /// The first time the schedule matches strictly after `after`, both in seconds since the
+    /// unix epoch, or None if it never does, e.g. on the 31st of February
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let start = after / SECS_PER_MINUTE + 1;
+        let first_day = start / MINUTES_PER_DAY;
+        for day in first_day..first_day + SEARCH_DAYS {
+            if !self.matches_day(day) {
+                continue;
+            }
+            let from = if day == first_day {
+                start % MINUTES_PER_DAY
+            } else {
+                0
+            };
+            for minute_of_day in from..MINUTES_PER_DAY {
+                let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                if has(self.hours, hour) && has(self.minutes, minute) {
+                    return Some((day * MINUTES_PER_DAY + minute_of_day) * SECS_PER_MINUTE);
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the schedule runs on the day this many days after the unix epoch
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, day_of_month) = civil_from_days(day);
+        // The epoch was a Thursday
+        let weekday = (day + 4) % 7;
+        let day_of_month = has(self.days, day_of_month);
+        let weekday = has(self.weekdays, weekday);
+        let day = if self.either_day {
+            day_of_month || weekday
+        } else {
+            day_of_month && weekday
+        };
+        day && has(self.months, month)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = CronError;
+
+    fn from_str(expression: &str) -> Result<Schedule, CronError> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CronError::FieldCount(fields.len()));
+        };
+        let mut weekdays_set = parse_field("day of the week", weekdays, 0, 7)?;
+        if has(weekdays_set, 7) {
+            weekdays_set = (weekdays_set & !(1 << 7)) | 1;
+        }
+        Ok(Schedule {
+            minutes: parse_field("minute", minutes, 0, 59)?,
+            hours: parse_field("hour", hours, 0, 23)?,
+            days: parse_field("day of the month", days, 1, 31)?,
+            months: parse_field("month", months, 1, 12)?,
+            weekdays: weekdays_set,
+            either_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn has(set: u64, value: u64) -> bool {
+    set & (1 << value) != 0
+}
+
+fn parse_field(field: &'static str, value: &str, min: u64, max: u64) -> Result<u64, CronError> {
+    let invalid = || CronError::InvalidField {
+        field,
+        value: value.to_string(),
+    };
+    let number = |s: &str| s.parse::<u64>().map_err(|_| invalid());
+    let mut set = 0;
+    for part in value.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(number(step)?)),
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // A single value with a step runs from it to the end of the range, as in cron
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        let step = step.unwrap_or(1);
+        if first < min || last > max || first > last || step == 0 {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// The year, month, and day of the month this many days after the unix epoch, by Howard
+/// Hinnant's algorithm
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_after() {
+        // Wednesday 2025-10-15 12:34:56 UTC
+        let now = 1_760_531_696;
+        let next = |expression: &str| expression.parse::<Schedule>().unwrap().next_after(now);
+
+        assert_eq!(next("* * * * *"), Some(1_760_531_700));
+        assert_eq!(next("*/15 * * * *"), Some(1_760_532_300));
+        // 03:00 the next morning
+        assert_eq!(next("0 3 * * *"), Some(1_760_583_600));
+        // Midnight on Monday the 20th
+        assert_eq!(next("0 0 * * 1"), Some(1_760_918_400));
+        // The 1st of November, or any Sunday, whichever comes first: Sunday the 19th
+        assert_eq!(next("0 0 1 * 7"), Some(1_760_832_000));
+        assert_eq!(next("0 0 31 2 *"), None);
+        assert_eq!(civil_from_days(1_760_531_696 / 86_400), (2025, 10, 15));
+
+        assert!(matches!(
+            "* * *".parse::<Schedule>(),
+            Err(CronError::FieldCount(3))
+        ));
+        assert!(matches!(
+            "60 * * * *".parse::<Schedule>(),
+            Err(CronError::InvalidField {
+                field: "minute",
+                ..
+            })
+        ));
+    }
+}
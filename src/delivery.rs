@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::{transaction::TransactionError, Transactional, Tree};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::{
+    config::DeliveryConfig,
+    server::{Server, ServerError},
+};
+
+/// How long the worker waits before looking at the queue again after the store fails
+const STORAGE_RETRY: Duration = Duration::from_secs(5);
+
+/// A receiver an integration sends payloads to, such as a webhook endpoint
+pub trait Sink: Send + Sync {
+    /// Send one payload, returning why it failed if it did
+    fn deliver<'a>(&'a self, payload: &'a Value) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// A payload waiting to be sent, or one that was given up on
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    /// The name the sink was registered under
+    pub sink: String,
+    pub payload: Value,
+    /// How many times sending has failed so far
+    pub attempts: u32,
+    /// When the next attempt is due, in milliseconds since the unix epoch
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum DeliveryError {
+    #[error("{0}")]
+    Server(#[from] ServerError),
+    #[error("{0}")]
+    Storage(#[from] sled::Error),
+}
+
+impl From<TransactionError<()>> for DeliveryError {
+    fn from(err: TransactionError<()>) -> DeliveryError {
+        match err {
+            TransactionError::Abort(()) => unreachable!("delivery transactions never abort"),
+            TransactionError::Storage(e) => DeliveryError::Storage(e),
+        }
+    }
+}
+
+/// Payloads waiting to be sent to integrations, kept in the database so they survive restarts.
+///
+/// A failed delivery is retried with exponential backoff until it succeeds or runs out of
+/// attempts, at which point it's moved to the dead letters. Dead letters stay until an operator
+/// replays or discards them.
+pub struct DeliveryQueue {
+    server: Server,
+    pending: Tree,
+    dead: Tree,
+    config: DeliveryConfig,
+    sinks: RwLock<HashMap<String, Arc<dyn Sink>>>,
+    /// Signalled when a delivery is queued, so the worker doesn't sleep through it
+    queued: Notify,
+}
+
+impl DeliveryQueue {
+    pub fn open(server: &Server, config: DeliveryConfig) -> Result<DeliveryQueue, DeliveryError> {
+        Ok(DeliveryQueue {
+            server: server.clone(),
+            pending: server.system_tree("deliveries/pending")?,
+            dead: server.system_tree("deliveries/dead")?,
+            config,
+            sinks: RwLock::new(HashMap::new()),
+            queued: Notify::new(),
+        })
+    }
+
+    /// Send deliveries for `name` to `sink`. Deliveries queued for a name with no sink fail, so
+    /// sinks should be registered before the worker starts.
+    pub fn register(&self, name: &str, sink: Arc<dyn Sink>) {
+        self.sinks.write().unwrap().insert(name.to_string(), sink);
+    }
+
+    /// Queue `payload` to be sent to the sink registered as `sink`. It's on disk by the time
+    /// this returns.
+    pub fn enqueue(&self, sink: &str, payload: Value) -> Result<u64, DeliveryError> {
+        let delivery = Delivery {
+            id: self.server.generate_id()?,
+            sink: sink.to_string(),
+            payload,
+            attempts: 0,
+            next_attempt: now_millis(),
+            last_error: None,
+        };
+        self.pending.insert(key(delivery.id), encode(&delivery))?;
+        self.queued.notify_one();
+        Ok(delivery.id)
+    }
+
+    /// Deliveries waiting to be sent, oldest first
+    pub fn pending(&self) -> Result<Vec<Delivery>, DeliveryError> {
+        list(&self.pending)
+    }
+
+    /// Deliveries that were given up on, oldest first
+    pub fn dead_letters(&self) -> Result<Vec<Delivery>, DeliveryError> {
+        list(&self.dead)
+    }
+
+    /// Queue a dead letter to be sent again with a fresh set of attempts, returning false if
+    /// there's no dead letter with that id
+    pub fn replay(&self, id: u64) -> Result<bool, DeliveryError> {
+        let replayed = (&self.pending, &self.dead).transaction(|(pending, dead)| {
+            let Some(value) = dead.remove(&key(id))? else {
+                return Ok(false);
+            };
+            let mut delivery = decode(&value);
+            delivery.attempts = 0;
+            delivery.next_attempt = now_millis();
+            pending.insert(&key(id), encode(&delivery))?;
+            Ok(true)
+        })?;
+        if replayed {
+            self.queued.notify_one();
+        }
+        Ok(replayed)
+    }
+
+    /// Delete a dead letter for good, returning false if there's no dead letter with that id
+    pub fn discard(&self, id: u64) -> Result<bool, DeliveryError> {
+        Ok(self.dead.remove(key(id))?.is_some())
+    }
+
+    /// Send deliveries as they come due, forever
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let due = match self.next_due() {
+                Ok(due) => due,
+                Err(e) => {
+                    tracing::warn!("failed to read the delivery queue: {e}");
+                    tokio::time::sleep(STORAGE_RETRY).await;
+                    continue;
+                }
+            };
+            let Some(delivery) = due else {
+                self.queued.notified().await;
+                continue;
+            };
+            let wait = Duration::from_millis(delivery.next_attempt.saturating_sub(now_millis()));
+            if !wait.is_zero() {
+                // Something queued in the meantime may be due sooner
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = self.queued.notified() => {}
+                }
+                continue;
+            }
+
+            if let Err(e) = self.attempt(delivery).await {
+                tracing::warn!("failed to update the delivery queue: {e}");
+                tokio::time::sleep(STORAGE_RETRY).await;
+            }
+            tokio::time::sleep(self.config.interval()).await;
+        }
+    }
+
+    /// The pending delivery that is due soonest
+    // The queue is expected to be short, as deliveries only wait while a receiver is down, so
+    // scanning it is simpler than keeping an index by due time
+    fn next_due(&self) -> Result<Option<Delivery>, DeliveryError> {
+        Ok(self
+            .pending()?
+            .into_iter()
+            .min_by_key(|delivery| delivery.next_attempt))
+    }
+
+    async fn attempt(&self, mut delivery: Delivery) -> Result<(), DeliveryError> {
+        let sink = self.sinks.read().unwrap().get(&delivery.sink).cloned();
+        let result = match sink {
+            Some(sink) => sink.deliver(&delivery.payload).await,
+            None => Err(format!("no sink named {}", delivery.sink)),
+        };
+        let id = key(delivery.id);
+        let Err(error) = result else {
+            tracing::debug!(id = delivery.id, sink = delivery.sink, "delivered");
+            self.pending.remove(id)?;
+            return Ok(());
+        };
+
+        delivery.attempts += 1;
+        delivery.last_error = Some(error);
+        if delivery.attempts >= self.config.max_attempts {
+            tracing::warn!(
+                id = delivery.id,
+                sink = delivery.sink,
+                attempts = delivery.attempts,
+                "giving up on a delivery: {}",
+                delivery.last_error.as_deref().unwrap_or_default(),
+            );
+            (&self.pending, &self.dead).transaction(|(pending, dead)| {
+                pending.remove(&id)?;
+                dead.insert(&id, encode(&delivery))?;
+                Ok(())
+            })?;
+        } else {
+            let backoff = self.config.backoff(delivery.attempts);
+            tracing::debug!(
+                id = delivery.id,
+                sink = delivery.sink,
+                retry_in = ?backoff,
+                "delivery failed: {}",
+                delivery.last_error.as_deref().unwrap_or_default(),
+            );
+            delivery.next_attempt = now_millis() + backoff.as_millis() as u64;
+            self.pending.insert(id, encode(&delivery))?;
+        }
+        Ok(())
+    }
+}
+
+fn list(tree: &Tree) -> Result<Vec<Delivery>, DeliveryError> {
+    tree.iter()
+        .values()
+        .map(|value| Ok(decode(&value?)))
+        .collect()
+}
+
+/// Deliveries are keyed by big-endian id, so they're listed in the order they were queued
+fn key(id: u64) -> [u8; 8] {
+    id.to_be_bytes()
+}
+
+fn encode(delivery: &Delivery) -> Vec<u8> {
+    serde_json::to_vec(delivery).unwrap()
+}
+
+fn decode(value: &[u8]) -> Delivery {
+    serde_json::from_slice(value).expect("deliveries are stored as JSON")
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use crate::schema::{Schema, SchemaItem};
+
+    use super::*;
+
+    /// Fails the first `failures` deliveries, then records the rest
+    struct Flaky {
+        failures: Mutex<u32>,
+        delivered: Mutex<Vec<Value>>,
+    }
+
+    impl Sink for Flaky {
+        fn deliver<'a>(&'a self, payload: &'a Value) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async move {
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    return Err("receiver is down".to_string());
+                }
+                self.delivered.lock().unwrap().push(payload.clone());
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_and_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::open(
+            dir.path().to_str().unwrap(),
+            Schema::new(SchemaItem::Scalar),
+        )
+        .unwrap();
+        let config = DeliveryConfig {
+            initial_backoff_ms: 1,
+            max_backoff_ms: 10,
+            max_attempts: 3,
+            per_second: 1000.0,
+        };
+        let queue = Arc::new(DeliveryQueue::open(&server, config).unwrap());
+        let sink = Arc::new(Flaky {
+            failures: Mutex::new(2),
+            delivered: Mutex::new(Vec::new()),
+        });
+        queue.register("hook", sink.clone());
+
+        // Delivered on the third attempt
+        queue.enqueue("hook", json!(1)).unwrap();
+        // Fails every attempt, as nothing is registered under its name
+        let lost = queue.enqueue("nowhere", json!(2)).unwrap();
+        let worker = tokio::spawn(queue.clone().run());
+        while !queue.pending().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(*sink.delivered.lock().unwrap(), vec![json!(1)]);
+        let dead = queue.dead_letters().unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].id, lost);
+        assert_eq!(dead[0].attempts, 3);
+        assert_eq!(dead[0].last_error.as_deref(), Some("no sink named nowhere"));
+
+        // Replayed dead letters go around again
+        queue.register("nowhere", sink.clone());
+        assert!(queue.replay(lost).unwrap());
+        assert!(!queue.replay(lost).unwrap());
+        while !queue.pending().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(*sink.delivered.lock().unwrap(), vec![json!(1), json!(2)]);
+        assert!(queue.dead_letters().unwrap().is_empty());
+        worker.abort();
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use sled::{Event, IVec, Subscriber, Tree};
+use tokio::sync::{mpsc, Notify};
+
+/// Watches each key prefix of a tree with a single sled subscriber, fanning its events out to
+/// every subscription on that prefix, so a thousand clients watching one document cost one
+/// subscriber rather than a thousand
+pub struct Dispatcher {
+    tree: Tree,
+    watches: Mutex<HashMap<Vec<u8>, Arc<Watch>>>,
+}
+
+/// What a subscription receives
+#[derive(Clone, Debug)]
+pub enum Delivery {
+    Event(Event),
+    /// The server is about to remove the key itself, rather than a client removing it, so the
+    /// next removal of the key is an expiry. Delivered before the removal is committed.
+    Expiring(IVec),
+}
+
+impl Delivery {
+    fn key(&self) -> &[u8] {
+        match self {
+            Delivery::Event(event) => event.key(),
+            Delivery::Expiring(key) => key,
+        }
+    }
+}
+
+/// The subscriptions to one prefix, fed by a task reading the prefix's sled subscriber
+struct Watch {
+    subscriptions: Mutex<Vec<mpsc::UnboundedSender<Delivery>>>,
+    /// Signalled once the last subscription is dropped, to stop the task
+    stop: Notify,
+}
+
+impl Dispatcher {
+    pub fn new(tree: Tree) -> Dispatcher {
+        Dispatcher {
+            tree,
+            watches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Receive the events for every key starting with `prefix`, from when this is called. The
+    /// first subscription to a prefix spawns the task that watches it, so this must be called
+    /// within a tokio runtime.
+    pub fn subscribe(self: &Arc<Self>, prefix: Vec<u8>) -> Subscription {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watches = self.watches.lock().unwrap();
+        let watch = watches.entry(prefix.clone()).or_insert_with(|| {
+            let watch = Arc::new(Watch {
+                subscriptions: Mutex::new(Vec::new()),
+                stop: Notify::new(),
+            });
+            tokio::spawn(watch.clone().run(self.tree.watch_prefix(prefix.clone())));
+            watch
+        });
+        watch.subscriptions.lock().unwrap().push(sender);
+        Subscription {
+            events,
+            dispatcher: self.clone(),
+            prefix,
+        }
+    }
+
+    /// Deliver an event for a key that isn't in the tree, like a member of a presence collection,
+    /// to every subscription whose prefix the key starts with
+    pub fn publish(&self, event: Event) {
+        self.deliver(Delivery::Event(event));
+    }
+
+    /// Tell every subscription whose prefix `key` starts with that the server is about to remove
+    /// it, before removing it
+    pub fn expiring(&self, key: IVec) {
+        self.deliver(Delivery::Expiring(key));
+    }
+
+    fn deliver(&self, delivery: Delivery) {
+        let watches = self.watches.lock().unwrap();
+        for (prefix, watch) in watches.iter() {
+            if delivery.key().starts_with(prefix) {
+                let mut subscriptions = watch.subscriptions.lock().unwrap();
+                subscriptions.retain(|subscription| subscription.send(delivery.clone()).is_ok());
+            }
+        }
+    }
+
+    /// Stop watching `prefix` if every subscription to it has been dropped
+    fn prune(&self, prefix: &[u8]) {
+        let mut watches = self.watches.lock().unwrap();
+        let Some(watch) = watches.get(prefix) else {
+            return;
+        };
+        let mut subscriptions = watch.subscriptions.lock().unwrap();
+        subscriptions.retain(|subscription| !subscription.is_closed());
+        if subscriptions.is_empty() {
+            drop(subscriptions);
+            watch.stop.notify_one();
+            watches.remove(prefix);
+        }
+    }
+}
+
+impl Watch {
+    async fn run(self: Arc<Self>, mut subscriber: Subscriber) {
+        loop {
+            let event = tokio::select! {
+                event = &mut subscriber => event,
+                _ = self.stop.notified() => return,
+            };
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            match event {
+                Some(event) => subscriptions.retain(|subscription| {
+                    subscription.send(Delivery::Event(event.clone())).is_ok()
+                }),
+                // The tree is gone, so end every subscription
+                None => {
+                    subscriptions.clear();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The events for one subscriber's prefix, from `Dispatcher::subscribe`
+pub struct Subscription {
+    events: mpsc::UnboundedReceiver<Delivery>,
+    dispatcher: Arc<Dispatcher>,
+    prefix: Vec<u8>,
+}
+
+impl Stream for Subscription {
+    type Item = Delivery;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Delivery>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Closing first lets `prune` see that this subscription is gone
+        self.events.close();
+        self.dispatcher.prune(&self.prefix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::StreamExt;
+    use sled::{Config, Event};
+
+    use super::{Delivery, Dispatcher};
+
+    #[tokio::test]
+    async fn sharing_subscribers() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let dispatcher = Arc::new(Dispatcher::new((*db).clone()));
+        let mut first = dispatcher.subscribe(b"fruits/".to_vec());
+        let mut second = dispatcher.subscribe(b"fruits/".to_vec());
+        let mut other = dispatcher.subscribe(b"vegetables/".to_vec());
+        assert_eq!(dispatcher.watches.lock().unwrap().len(), 2);
+
+        db.insert("fruits/apple", "red").unwrap();
+        for subscription in [&mut first, &mut second] {
+            let Some(Delivery::Event(Event::Insert { key, .. })) = subscription.next().await else {
+                panic!("expected an insert");
+            };
+            assert_eq!(key, "fruits/apple");
+        }
+
+        drop(first);
+        assert_eq!(dispatcher.watches.lock().unwrap().len(), 2);
+        drop(second);
+        assert_eq!(dispatcher.watches.lock().unwrap().len(), 1);
+
+        db.insert("vegetables/kale", "green").unwrap();
+        let Some(Delivery::Event(Event::Insert { key, .. })) = other.next().await else {
+            panic!("expected an insert");
+        };
+        assert_eq!(key, "vegetables/kale");
+
+        dispatcher.expiring("vegetables/kale".into());
+        let Some(Delivery::Expiring(key)) = other.next().await else {
+            panic!("expected an expiry");
+        };
+        assert_eq!(key, "vegetables/kale");
+    }
+}
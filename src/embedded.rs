@@ -0,0 +1,233 @@
+//! Using iceload in-process, without the network layer, for desktop apps and tests that don't
+//! want a WebSocket hop.
+//!
+//! A [`Store`] is opened with a schema and read and written with any types serde can convert,
+//! checked against the schema like writes from clients. Watching an item gives a [`Stream`] of
+//! its value after each change.
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! use futures_util::StreamExt;
+//! use iceload::{
+//!     embedded::Store,
+//!     schema::{CollectionSchema, SchemaItem},
+//!     Schema,
+//! };
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Debug, PartialEq, Deserialize, Serialize)]
+//! struct Post {
+//!     title: String,
+//! }
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let post = SchemaItem::Document(HashMap::from([("title".to_string(), SchemaItem::Scalar)]));
+//! let schema = Schema::new(SchemaItem::Document(HashMap::from([(
+//!     "posts".to_string(),
+//!     SchemaItem::Collection(CollectionSchema::new(post)),
+//! )])));
+//! let store = Store::temporary(schema)?;
+//!
+//! let mut titles = store.watch::<Option<String>>(["posts", "first", "title"]);
+//! store.insert(["posts", "first"], &Post { title: "Hello".to_string() })?;
+//! assert_eq!(titles.next().await.unwrap()?, Some("Hello".to_string()));
+//!
+//! let post: Post = store.get(["posts", "first"])?;
+//! assert_eq!(post.title, "Hello");
+//! # Ok::<(), iceload::embedded::StoreError>(())
+//! # }).unwrap();
+//! ```
+
+use futures_util::{Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    message::Ref,
+    schema::Schema,
+    server::{Server, ServerError},
+};
+
+/// A store opened in-process, read and written with typed values
+#[derive(Clone)]
+pub struct Store {
+    server: Server,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum StoreError {
+    #[error(transparent)]
+    Server(#[from] ServerError),
+    #[error("value at {path} can't be stored: {source}")]
+    Serialize {
+        path: Ref,
+        source: serde_json::Error,
+    },
+    #[error("value at {path} doesn't have the type it was read as: {source}")]
+    Deserialize {
+        path: Ref,
+        source: serde_json::Error,
+    },
+}
+
+impl StoreError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            StoreError::Server(err) => err.kind(),
+            StoreError::Serialize { .. } | StoreError::Deserialize { .. } => {
+                ErrorKind::SchemaMismatch
+            }
+        }
+    }
+
+    /// The path the error occurred at, if it's associated with one
+    pub fn path(&self) -> Option<&Ref> {
+        match self {
+            StoreError::Server(err) => err.path(),
+            StoreError::Serialize { path, .. } | StoreError::Deserialize { path, .. } => Some(path),
+        }
+    }
+}
+
+impl Store {
+    /// Open the database at `path`, which only one process may have open at a time
+    pub fn open(path: &str, schema: Schema) -> Result<Store, StoreError> {
+        Ok(Store::from(Server::open(path, schema)?))
+    }
+
+    /// Open a database that's deleted once the store and every clone of it are dropped
+    pub fn temporary(schema: Schema) -> Result<Store, StoreError> {
+        Ok(Store::from(Server::open_temporary(
+            schema,
+            sled::Config::new(),
+        )?))
+    }
+
+    /// The server underneath, for everything the typed methods don't cover
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Read the item at `key` as a `T`. Optional items that aren't set are read as null, so
+    /// `Option<T>` can read them.
+    pub fn get<T: DeserializeOwned>(&self, key: impl Into<Ref>) -> Result<T, StoreError> {
+        let key = key.into();
+        let value = self.server.get(&key)?;
+        deserialize(key, value)
+    }
+
+    /// Write a whole document or collection at `key`, replacing what was there
+    pub fn insert<T: Serialize>(&self, key: impl Into<Ref>, value: &T) -> Result<(), StoreError> {
+        let key = key.into();
+        let value = serialize(&key, value)?;
+        Ok(self.server.insert(&key, value)?)
+    }
+
+    /// Write the item at `key`, leaving any fields of a document that `value` doesn't have alone
+    pub fn update<T: Serialize>(&self, key: impl Into<Ref>, value: &T) -> Result<(), StoreError> {
+        let key = key.into();
+        let value = serialize(&key, value)?;
+        Ok(self.server.update(&key, value)?)
+    }
+
+    pub fn remove(&self, key: impl Into<Ref>) -> Result<(), StoreError> {
+        Ok(self.server.remove(&key.into())?)
+    }
+
+    /// The value of the item at `key` after each write to it or anything inside it. Writing a
+    /// document changes each of its fields, so the same value can be seen more than once.
+    pub fn watch<T: DeserializeOwned>(
+        &self,
+        key: impl Into<Ref>,
+    ) -> impl Stream<Item = Result<T, StoreError>> + Send + Unpin {
+        let key = key.into();
+        let server = self.server.clone();
+        self.server.subscribe(&key).map(move |_| {
+            let value = server.get(&key)?;
+            deserialize(key.clone(), value)
+        })
+    }
+}
+
+impl From<Server> for Store {
+    fn from(server: Server) -> Store {
+        Store { server }
+    }
+}
+
+fn serialize<T: Serialize>(key: &Ref, value: &T) -> Result<serde_json::Value, StoreError> {
+    serde_json::to_value(value).map_err(|source| StoreError::Serialize {
+        path: key.clone(),
+        source,
+    })
+}
+
+fn deserialize<T: DeserializeOwned>(key: Ref, value: serde_json::Value) -> Result<T, StoreError> {
+    serde_json::from_value(value).map_err(|source| StoreError::Deserialize { path: key, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use crate::schema::{CollectionSchema, SchemaItem};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Planet {
+        name: String,
+        moons: Option<String>,
+    }
+
+    fn store() -> Store {
+        let planet = SchemaItem::Document(HashMap::from([
+            ("name".to_string(), SchemaItem::Scalar),
+            (
+                "moons".to_string(),
+                SchemaItem::Optional(Box::new(SchemaItem::Scalar)),
+            ),
+        ]));
+        Store::temporary(Schema::new(SchemaItem::Document(HashMap::from([(
+            "planets".to_string(),
+            SchemaItem::Collection(CollectionSchema::new(planet)),
+        )]))))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn typed_reads_and_writes() {
+        let store = store();
+        let mars = Planet {
+            name: "Mars".to_string(),
+            moons: None,
+        };
+        store.insert(["planets", "mars"], &mars).unwrap();
+        assert_eq!(store.get::<Planet>(["planets", "mars"]).unwrap(), mars);
+
+        let mut moons = store.watch::<Option<String>>(["planets", "mars", "moons"]);
+        store
+            .update(["planets", "mars", "moons"], &"Phobos, Deimos")
+            .unwrap();
+        assert_eq!(
+            moons.next().await.unwrap().unwrap().as_deref(),
+            Some("Phobos, Deimos")
+        );
+
+        let err = store.get::<u32>(["planets", "mars", "name"]).unwrap_err();
+        assert!(matches!(err, StoreError::Deserialize { .. }));
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        let err = store
+            .get::<Planet>(["planets", "mars", "rings"])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidPath);
+
+        store.remove(["planets", "mars"]).unwrap();
+        assert!(store.get::<Planet>(["planets", "mars"]).is_err());
+    }
+}
@@ -0,0 +1,30 @@
+/// A coarse classification of every error iceload can produce, so callers can react to errors
+/// without matching on each error type's variants or parsing messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The underlying storage engine failed
+    Storage,
+    /// The database is already open in another process
+    Locked,
+    /// A path doesn't exist in the schema
+    InvalidPath,
+    /// The schema allows a path, but there's no value stored there
+    NotFound,
+    /// A value doesn't match the shape the schema expects
+    SchemaMismatch,
+    /// The schema file couldn't be read or parsed
+    InvalidSchema,
+    /// The permission script failed to compile or run
+    Script,
+    /// A write was rejected by replay protection
+    Replay,
+    /// A write was sent to a read-only follower
+    ReadOnly,
+    /// An operator has paused writes to maintain the server
+    Maintenance,
+    /// A request was larger than the server accepts
+    LimitExceeded,
+    /// Part of the change log that was asked for has been trimmed
+    Trimmed,
+}
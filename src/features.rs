@@ -0,0 +1,65 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+};
+
+const BUCKETS: u64 = 10_000;
+
+/// Server-side rollout configuration for experimental protocol behaviors.
+///
+/// Each flag is enabled for roughly `fraction` of connections, chosen deterministically from the
+/// connection ID so the same connection always lands in the same bucket.
+#[derive(Clone, Debug)]
+pub struct FeatureFlags {
+    rollouts: HashMap<String, f64>,
+}
+
+impl FeatureFlags {
+    pub fn new(rollouts: HashMap<String, f64>) -> FeatureFlags {
+        FeatureFlags { rollouts }
+    }
+
+    /// The set of flags enabled for a given connection
+    pub fn assign(&self, connection_id: u64) -> BTreeSet<String> {
+        self.rollouts
+            .iter()
+            .filter(|(name, fraction)| {
+                let mut hasher = DefaultHasher::new();
+                (name.as_str(), connection_id).hash(&mut hasher);
+                let bucket = hasher.finish() % BUCKETS;
+                (bucket as f64) < fraction.clamp(0.0, 1.0) * BUCKETS as f64
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FeatureFlags;
+
+    #[test]
+    fn rollout_fractions() {
+        let flags = FeatureFlags::new(
+            [
+                ("never".to_string(), 0.0),
+                ("always".to_string(), 1.0),
+                ("half".to_string(), 0.5),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let mut half_count = 0;
+        for connection_id in 0..1000 {
+            let enabled = flags.assign(connection_id);
+            assert!(enabled.contains("always"));
+            assert!(!enabled.contains("never"));
+            assert_eq!(enabled, flags.assign(connection_id));
+            if enabled.contains("half") {
+                half_count += 1;
+            }
+        }
+        assert!((400..600).contains(&half_count));
+    }
+}
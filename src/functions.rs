@@ -0,0 +1,328 @@
+use std::sync::{Arc, Mutex};
+
+use mlua::{Compiler, Function, Lua, Table};
+use serde_json::{Map, Number, Value};
+use thiserror::Error;
+
+use crate::{
+    changes::ChangeOp,
+    error::ErrorKind,
+    message::Ref,
+    server::{Server, ServerError},
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FunctionError {
+    #[error("failed to load functions script: {}", .0)]
+    LoadError(#[from] mlua::Error),
+    #[error("no function called {}", .0)]
+    NotFound(String),
+    #[error("function {name} failed: {source}")]
+    CallError { name: String, source: mlua::Error },
+    #[error("{}", .0)]
+    Server(#[from] ServerError),
+}
+
+impl FunctionError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FunctionError::LoadError(_) | FunctionError::CallError { .. } => ErrorKind::Script,
+            FunctionError::NotFound(_) => ErrorKind::NotFound,
+            FunctionError::Server(e) => e.kind(),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Ref> {
+        match self {
+            FunctionError::Server(e) => e.path(),
+            _ => None,
+        }
+    }
+}
+
+/// The script used when none is configured, which has no functions
+pub const NO_FUNCTIONS: &str = "return {}";
+
+/// Runs the functions clients call by name with `Call`. The script returns a table of them, each
+/// taking a database handle and the arguments sent with the call:
+///
+/// ```lua
+/// return {
+///     rename = function(db, args)
+///         local name = db.get({ "users", args.id, "name" })
+///         db.update({ "users", args.id, "name" }, args.name)
+///         return name
+///     end,
+/// }
+/// ```
+///
+/// The handle has `get`, `insert`, `update`, and `remove`, taking paths as tables. Writes are made
+/// together in one transaction once the function returns, and not at all if it fails. If anything
+/// else is written in the meantime the function is run again, so it must not have effects outside
+/// the database. Reads see the database as it was before the call, not the call's own writes.
+pub struct Functions<'a> {
+    lua: Lua,
+    bytecode: &'a [u8],
+}
+
+impl Functions<'_> {
+    pub fn load_bytecode(source: &str) -> Result<&'static [u8], FunctionError> {
+        let bytecode = Compiler::new().compile(source).leak() as &'static [u8];
+
+        // Double check the script compiles and returns a table of functions
+        Lua::new().load(bytecode).eval::<Table>()?;
+
+        Ok(bytecode)
+    }
+
+    pub fn new(bytecode: &[u8]) -> Functions<'_> {
+        Functions {
+            lua: Lua::new(),
+            bytecode,
+        }
+    }
+
+    /// Whether the script has a function called `name`
+    pub fn contains(&self, name: &str) -> Result<bool, FunctionError> {
+        let functions: Table = self.lua.load(self.bytecode).eval()?;
+        Ok(functions.contains_key(name)?)
+    }
+
+    /// Run the function called `name` against `server`, returning what it returns
+    pub fn call(&self, server: &Server, name: &str, args: &Value) -> Result<Value, FunctionError> {
+        let functions: Table = self.lua.load(self.bytecode).eval()?;
+        let Some(function) = functions.get::<_, Option<Function>>(name)? else {
+            return Err(FunctionError::NotFound(name.to_string()));
+        };
+        let call_error = |source| FunctionError::CallError {
+            name: name.to_string(),
+            source,
+        };
+        loop {
+            let from = server.next_change()?;
+            let writes = Arc::new(Mutex::new(Vec::new()));
+            let db = self.handle(server, writes.clone())?;
+            let args = to_lua(&self.lua, args)?;
+            let result = function
+                .call((db, args))
+                .and_then(from_lua)
+                .map_err(call_error)?;
+            let writes = std::mem::take(&mut *writes.lock().unwrap());
+            if writes.is_empty() || server.write_all(from, &writes)? {
+                return Ok(result);
+            }
+            tracing::debug!(function = name, "retrying a call after a conflicting write");
+        }
+    }
+
+    /// The database handle passed to a function, which reads from `server` and adds writes to
+    /// `writes`
+    fn handle(
+        &self,
+        server: &Server,
+        writes: Arc<Mutex<Vec<ChangeOp>>>,
+    ) -> mlua::Result<Table<'_>> {
+        let db = self.lua.create_table()?;
+
+        let reader = server.clone();
+        db.set(
+            "get",
+            self.lua.create_function(move |lua, path: Vec<String>| {
+                match reader.get(&Ref(path)) {
+                    Ok(value) => to_lua(lua, &value),
+                    Err(ServerError::KeyNotFound(_)) => Ok(mlua::Value::Nil),
+                    Err(e) => Err(mlua::Error::external(e)),
+                }
+            })?,
+        )?;
+
+        let write = move |change: ChangeOp| -> mlua::Result<()> {
+            writes.lock().unwrap().push(change);
+            Ok(())
+        };
+        let insert = write.clone();
+        db.set(
+            "insert",
+            self.lua
+                .create_function(move |_, (path, value): (Vec<String>, mlua::Value)| {
+                    insert(ChangeOp::Insert {
+                        path: Ref(path),
+                        value: from_lua(value)?,
+                    })
+                })?,
+        )?;
+        let update = write.clone();
+        db.set(
+            "update",
+            self.lua
+                .create_function(move |_, (path, value): (Vec<String>, mlua::Value)| {
+                    update(ChangeOp::Update {
+                        path: Ref(path),
+                        value: from_lua(value)?,
+                    })
+                })?,
+        )?;
+        db.set(
+            "remove",
+            self.lua.create_function(move |_, path: Vec<String>| {
+                write(ChangeOp::Remove { path: Ref(path) })
+            })?,
+        )?;
+
+        Ok(db)
+    }
+}
+
+/// Function runners for calls to borrow, so that calls made at the same time don't wait on each
+/// other
+pub struct FunctionPool {
+    bytecode: &'static [u8],
+    idle: Mutex<Vec<Functions<'static>>>,
+}
+
+impl FunctionPool {
+    pub fn new(bytecode: &'static [u8]) -> FunctionPool {
+        FunctionPool {
+            bytecode,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn take(&self) -> Functions<'static> {
+        let idle = self.idle.lock().unwrap().pop();
+        idle.unwrap_or_else(|| Functions::new(self.bytecode))
+    }
+
+    pub fn put(&self, functions: Functions<'static>) {
+        self.idle.lock().unwrap().push(functions);
+    }
+}
+
+fn to_lua<'lua>(lua: &'lua Lua, value: &Value) -> mlua::Result<mlua::Value<'lua>> {
+    Ok(match value {
+        Value::Null => mlua::Value::Nil,
+        Value::Bool(b) => mlua::Value::Boolean(*b),
+        Value::Number(n) => match n.as_i64().and_then(|i| mlua::Integer::try_from(i).ok()) {
+            Some(i) => mlua::Value::Integer(i),
+            None => mlua::Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => mlua::Value::String(lua.create_string(s)?),
+        Value::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                table.raw_push(to_lua(lua, item)?)?;
+            }
+            mlua::Value::Table(table)
+        }
+        Value::Object(entries) => {
+            let table = lua.create_table_with_capacity(0, entries.len())?;
+            for (key, entry) in entries {
+                table.raw_set(key.as_str(), to_lua(lua, entry)?)?;
+            }
+            mlua::Value::Table(table)
+        }
+    })
+}
+
+/// Convert a value returned from Lua to JSON. Tables are arrays if they're sequences, and
+/// otherwise objects, so an empty table is an empty object.
+fn from_lua(value: mlua::Value) -> mlua::Result<Value> {
+    Ok(match value {
+        mlua::Value::Nil => Value::Null,
+        mlua::Value::Boolean(b) => Value::Bool(b),
+        mlua::Value::Integer(i) => Value::from(i),
+        mlua::Value::Number(n) => match Number::from_f64(n) {
+            Some(n) => Value::Number(n),
+            None => return Err(mlua::Error::runtime(format!("{n} can't be sent as JSON"))),
+        },
+        mlua::Value::String(s) => Value::String(s.to_str()?.to_string()),
+        mlua::Value::Table(table) if table.raw_len() > 0 => Value::Array(
+            table
+                .sequence_values()
+                .map(|item| from_lua(item?))
+                .collect::<mlua::Result<_>>()?,
+        ),
+        mlua::Value::Table(table) => {
+            let mut entries = Map::new();
+            for pair in table.pairs::<mlua::Value, mlua::Value>() {
+                let (key, entry) = pair?;
+                let key = match key {
+                    mlua::Value::String(s) => s.to_str()?.to_string(),
+                    mlua::Value::Integer(i) => i.to_string(),
+                    key => {
+                        return Err(mlua::Error::runtime(format!(
+                            "a {} can't be an object key",
+                            key.type_name()
+                        )))
+                    }
+                };
+                entries.insert(key, from_lua(entry)?);
+            }
+            Value::Object(entries)
+        }
+        value => {
+            return Err(mlua::Error::runtime(format!(
+                "a {} can't be sent as JSON",
+                value.type_name()
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::schema::{Schema, SchemaItem};
+
+    use super::*;
+
+    #[test]
+    fn call() {
+        let schema = Schema::new(SchemaItem::Document(
+            [
+                ("count".to_string(), SchemaItem::Scalar),
+                ("log".to_string(), SchemaItem::Scalar),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        let server = Server::open_temporary(schema, sled::Config::new()).unwrap();
+        server
+            .insert(&Ref(Vec::new()), json!({ "count": "1", "log": "" }))
+            .unwrap();
+        let bytecode = Functions::load_bytecode(
+            r#"
+            return {
+                add = function(db, args)
+                    local count = tonumber(db.get({ "count" })) + args.by
+                    db.update({ "count" }, tostring(count))
+                    db.update({ "log" }, "added " .. args.by)
+                    return { count = count }
+                end,
+                broken = function(db, args)
+                    db.update({ "count" }, "100")
+                    error("halfway")
+                end,
+            }
+            "#,
+        )
+        .unwrap();
+        let functions = Functions::new(bytecode);
+
+        let result = functions.call(&server, "add", &json!({ "by": 2 }));
+        assert_eq!(result.unwrap(), json!({ "count": 3 }));
+        assert_eq!(
+            server.get(&Ref(Vec::new())).unwrap(),
+            json!({ "count": "3", "log": "added 2" })
+        );
+
+        // Nothing a failing function writes is kept
+        let err = functions.call(&server, "broken", &json!({})).unwrap_err();
+        assert!(matches!(err, FunctionError::CallError { .. }));
+        assert_eq!(server.get(&Ref(vec!["count".into()])).unwrap(), "3");
+        let err = functions.call(&server, "missing", &json!({})).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
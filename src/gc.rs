@@ -0,0 +1,27 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{server::ServerError, tenant::Tenants};
+
+/// Remove orphaned items from every tenant's data on schedule, forever. The first pass runs one
+/// interval after starting.
+pub async fn run(tenants: Arc<Tenants>, interval: Duration) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let tenants = tenants.clone();
+        // Each pass scans the whole database, so keep it off the workers
+        tokio::task::spawn_blocking(move || {
+            for tenant in tenants.iter() {
+                match tenant.server.collect_orphans() {
+                    Ok(_) => {}
+                    // Paused writes include these; the next pass will catch up
+                    Err(ServerError::Maintenance(_)) => return,
+                    Err(e) => tracing::error!(tenant = ?tenant.name, "orphan cleanup failed: {e}"),
+                }
+            }
+        })
+        .await
+        .expect("orphan cleanup doesn't panic");
+    }
+}
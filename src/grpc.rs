@@ -0,0 +1,514 @@
+// The message types and service plumbing here mirror proto/iceload.proto. They're written out by
+// hand, in the shape tonic-build would generate, so that building doesn't require protoc.
+
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::{Stream, StreamExt};
+use serde_json::Value;
+use tokio::sync::Notify;
+use tonic::{
+    body::Body as ResponseBody,
+    codegen::{http, Body, BoxFuture, Service, StdError},
+    metadata::MetadataMap,
+    server::{Grpc, NamedService, ServerStreamingService, UnaryService},
+    Status,
+};
+use tonic_prost::ProstCodec;
+
+use crate::{
+    accounts::{AccountError, Accounts},
+    audit::{Attempt, AuditLog, Source},
+    config::{ConnectionConfig, LimitsConfig},
+    error::ErrorKind,
+    limits::{self, LimitError},
+    message::{self, ClientMessage, Credentials, ServerMessage},
+    outbox::Outbox,
+    permission::{Operation, PermissionPool, Permissions},
+    registry::ConnectionRegistry,
+    replay::ReplayGuard,
+    server::{self, Server, ServerError},
+};
+
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Ref {
+        #[prost(string, repeated, tag = "1")]
+        pub components: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyRequest {
+        #[prost(message, optional, tag = "1")]
+        pub key: Option<Ref>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteRequest {
+        #[prost(message, optional, tag = "1")]
+        pub key: Option<Ref>,
+        #[prost(string, tag = "2")]
+        pub value_json: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ValueResponse {
+        #[prost(string, tag = "1")]
+        pub value_json: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscriptionUpdate {
+        #[prost(message, optional, tag = "1")]
+        pub key: Option<Ref>,
+        #[prost(string, optional, tag = "2")]
+        pub value: Option<String>,
+        #[prost(bool, tag = "3")]
+        pub expired: bool,
+    }
+}
+
+type UpdateStream = Pin<Box<dyn Stream<Item = Result<proto::SubscriptionUpdate, Status>> + Send>>;
+
+/// The `iceload.Iceload` gRPC service, sharing the `Server` core, permission checks, limits, and
+/// connection registry with the WebSocket listener and the HTTP gateway.
+///
+/// Requests are checked as the user an `authorization: Bearer <token>` metadata entry signs in
+/// as, with a token from signing in to one of `accounts` over WebSocket, or as no one without one.
+/// Each `Subscribe` stream is a client of its own to the registry, like an HTTP event stream.
+#[derive(Clone)]
+pub struct IceloadService {
+    handler: Arc<Handler>,
+}
+
+impl IceloadService {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        server: Server,
+        permission_bytecode: &'static [u8],
+        accounts: Option<Accounts>,
+        limits: LimitsConfig,
+        connections: ConnectionConfig,
+        registry: Arc<ConnectionRegistry>,
+        replay_guard: Arc<ReplayGuard>,
+        audit: Option<Arc<AuditLog>>,
+    ) -> IceloadService {
+        let permissions = PermissionPool::new(permission_bytecode, server.slow_threshold());
+        IceloadService {
+            handler: Arc::new(Handler {
+                server,
+                permissions,
+                accounts,
+                limits,
+                connections,
+                registry,
+                replay_guard,
+                audit,
+            }),
+        }
+    }
+}
+
+impl NamedService for IceloadService {
+    const NAME: &'static str = "iceload.Iceload";
+}
+
+impl<B> Service<http::Request<B>> for IceloadService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let handler = self.handler.clone();
+        match req.uri().path() {
+            "/iceload.Iceload/Get" => unary(req, Unary(handler, Handler::get)),
+            "/iceload.Iceload/Insert" => unary(req, Unary(handler, Handler::insert)),
+            "/iceload.Iceload/Update" => unary(req, Unary(handler, Handler::update)),
+            "/iceload.Iceload/Remove" => unary(req, Unary(handler, Handler::remove)),
+            "/iceload.Iceload/Subscribe" => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.server_streaming(Subscribe(handler), req).await)
+            }),
+            _ => Box::pin(async move {
+                let mut response = http::Response::new(ResponseBody::default());
+                let headers = response.headers_mut();
+                headers.insert(
+                    Status::GRPC_STATUS,
+                    (tonic::Code::Unimplemented as i32).into(),
+                );
+                headers.insert(
+                    http::header::CONTENT_TYPE,
+                    tonic::metadata::GRPC_CONTENT_TYPE,
+                );
+                Ok(response)
+            }),
+        }
+    }
+}
+
+fn unary<B, Req, Resp>(
+    req: http::Request<B>,
+    method: Unary<Req, Resp>,
+) -> BoxFuture<http::Response<ResponseBody>, Infallible>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::default());
+        Ok(grpc.unary(method, req).await)
+    })
+}
+
+type Method<Req, Resp> = fn(&Handler, &Caller, Req) -> Result<Resp, Status>;
+
+struct Unary<Req, Resp>(Arc<Handler>, Method<Req, Resp>);
+
+impl<Req: Send + 'static, Resp: Send + 'static> UnaryService<Req> for Unary<Req, Resp> {
+    type Response = Resp;
+    type Future = BoxFuture<tonic::Response<Resp>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        let (handler, method) = (self.0.clone(), self.1);
+        Box::pin(async move {
+            let token = bearer_token(request.metadata())?;
+            let request = request.into_inner();
+            handler
+                .handle(token, move |handler, caller| {
+                    method(handler, caller, request)
+                })
+                .await
+                .map(tonic::Response::new)
+        })
+    }
+}
+
+struct Subscribe(Arc<Handler>);
+
+impl ServerStreamingService<proto::KeyRequest> for Subscribe {
+    type Response = proto::SubscriptionUpdate;
+    type ResponseStream = UpdateStream;
+    type Future = BoxFuture<tonic::Response<UpdateStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<proto::KeyRequest>) -> Self::Future {
+        let handler = self.0.clone();
+        Box::pin(async move {
+            let token = bearer_token(request.metadata())?;
+            let peer = request
+                .remote_addr()
+                .map(|peer| peer.to_string())
+                .unwrap_or_default();
+            let key = to_ref(request.into_inner().key);
+            let checked = key.clone();
+            handler
+                .handle(token, move |_, caller| {
+                    caller.check(Operation::Read, &checked)
+                })
+                .await?;
+            handler.subscribe(key, peer).map(tonic::Response::new)
+        })
+    }
+}
+
+struct Handler {
+    server: Server,
+    permissions: PermissionPool,
+    /// The accounts whose tokens requests may be authenticated with, if the server keeps any
+    accounts: Option<Accounts>,
+    /// The limits writes and subscriptions are held to, along with WebSocket clients
+    limits: LimitsConfig,
+    /// How updates are queued for subscriptions, as they are for WebSocket clients
+    connections: ConnectionConfig,
+    registry: Arc<ConnectionRegistry>,
+    /// Writes can't be enveloped over gRPC, so they're refused if envelopes are required
+    replay_guard: Arc<ReplayGuard>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+/// Who made a request, with the permission rules to check it against
+struct Caller {
+    user: Option<String>,
+    permissions: Permissions<'static>,
+}
+
+impl Caller {
+    fn check(&self, op: Operation, key: &message::Ref) -> Result<(), Status> {
+        let allowed = self
+            .permissions
+            .check(op, key, self.user.as_deref())
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if allowed {
+            Ok(())
+        } else {
+            Err(Status::permission_denied("permissions"))
+        }
+    }
+}
+
+impl Handler {
+    /// Handle a request for the user its token signs in as. Requests are handled on the blocking
+    /// pool, as the store, the rules and checking tokens all block.
+    async fn handle<T: Send + 'static>(
+        self: &Arc<Self>,
+        token: Option<String>,
+        handle: impl FnOnce(&Handler, &Caller) -> Result<T, Status> + Send + 'static,
+    ) -> Result<T, Status> {
+        let handler = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let user = handler.authenticate(token.as_deref())?;
+            let permissions = handler.permissions.take();
+            let caller = Caller { user, permissions };
+            let result = handle(&handler, &caller);
+            handler.permissions.put(caller.permissions);
+            result
+        })
+        .await
+        .expect("handling requests doesn't panic")
+    }
+
+    /// The user `token` signs in as, or no one without a token
+    fn authenticate(&self, token: Option<&str>) -> Result<Option<String>, Status> {
+        let Some(token) = token else {
+            return Ok(None);
+        };
+        let Some(accounts) = &self.accounts else {
+            return Err(to_unauthenticated(AccountError::Disabled("signing in")));
+        };
+        let signed_in = accounts
+            .sign_in(&Credentials::Token(token.to_string()))
+            .map_err(to_unauthenticated)?;
+        Ok(Some(signed_in.user))
+    }
+
+    /// Log a write to the audit log, if it's kept
+    fn audit(&self, caller: &Caller, op: Operation, key: &message::Ref, allowed: bool) {
+        if let Some(audit) = &self.audit {
+            audit.record(Attempt {
+                source: Source::Grpc,
+                tenant: None,
+                connection: None,
+                user: caller.user.clone(),
+                op,
+                path: key.clone(),
+                allowed,
+            });
+        }
+    }
+
+    fn get(&self, caller: &Caller, req: proto::KeyRequest) -> Result<proto::ValueResponse, Status> {
+        let key = to_ref(req.key);
+        limits::check(&self.limits, &ClientMessage::Get(key.clone())).map_err(to_limit_status)?;
+        caller.check(Operation::Read, &key)?;
+        let value = self.server.get(&key).map_err(to_status)?;
+        Ok(proto::ValueResponse {
+            value_json: value.to_string(),
+        })
+    }
+
+    fn insert(
+        &self,
+        caller: &Caller,
+        req: proto::WriteRequest,
+    ) -> Result<proto::ValueResponse, Status> {
+        let value = parse_value(&req.value_json)?;
+        self.write(caller, ClientMessage::Insert(to_ref(req.key), value))
+    }
+
+    fn update(
+        &self,
+        caller: &Caller,
+        req: proto::WriteRequest,
+    ) -> Result<proto::ValueResponse, Status> {
+        let value = parse_value(&req.value_json)?;
+        self.write(caller, ClientMessage::Update(to_ref(req.key), value))
+    }
+
+    fn remove(
+        &self,
+        caller: &Caller,
+        req: proto::KeyRequest,
+    ) -> Result<proto::ValueResponse, Status> {
+        self.write(caller, ClientMessage::Remove(to_ref(req.key)))
+    }
+
+    /// Make a write, held to the same limits and envelope requirements as a WebSocket client's
+    fn write(&self, caller: &Caller, msg: ClientMessage) -> Result<proto::ValueResponse, Status> {
+        limits::check(&self.limits, &msg).map_err(to_limit_status)?;
+        self.replay_guard
+            .check_unenveloped()
+            .map_err(|e| Status::permission_denied(e.to_string()))?;
+        let (op, key, value) = match msg {
+            ClientMessage::Insert(key, value) => (Operation::Insert, key, Some(value)),
+            ClientMessage::Update(key, value) => (Operation::Update, key, Some(value)),
+            ClientMessage::Remove(key) => (Operation::Remove, key, None),
+            _ => unreachable!("only writes are routed here"),
+        };
+        if let Err(status) = caller.check(op, &key) {
+            if status.code() == tonic::Code::PermissionDenied {
+                self.audit(caller, op, &key, false);
+            }
+            return Err(status);
+        }
+        let result = match (op, value) {
+            (Operation::Insert, Some(value)) => self.server.insert(&key, value),
+            (Operation::Update, Some(value)) => self.server.update(&key, value),
+            (Operation::Remove, None) => self.server.remove(&key),
+            _ => unreachable!("writes are only routed with matching values"),
+        };
+        result.map_err(to_status)?;
+        self.audit(caller, op, &key, true);
+        Ok(null_response())
+    }
+
+    /// Stream updates to `key` to a client of its own in the registry, once the caller has been
+    /// checked, so it counts towards the subscription quotas and can be listed and kicked
+    fn subscribe(&self, key: message::Ref, peer: String) -> Result<UpdateStream, Status> {
+        limits::check(&self.limits, &ClientMessage::Subscribe(key.clone()))
+            .map_err(to_limit_status)?;
+        // A stream only ever holds the one subscription
+        let total = self.registry.task_count();
+        limits::check_subscription(&self.limits, &key, 0, total).map_err(to_limit_status)?;
+
+        let registry = self.registry.clone();
+        let connection = registry.next_connection_id();
+        let kicked = registry.register(connection, peer, None);
+        let outbox = Outbox::new(self.connections.send_buffer, self.connections.slow_consumer);
+        let server = self.server.clone();
+        let mut events = self.server.subscribe(&key);
+        let updates = outbox.clone();
+        let subscribed = key.clone();
+        let task = async move {
+            while let Some(event) = events.next().await {
+                let update = match event {
+                    server::Event::Insert {
+                        key: written,
+                        value,
+                    } => match server.event_value(&written, &value) {
+                        Ok(value) => {
+                            ServerMessage::SubscriptionUpdate(subscribed.clone(), Some(value))
+                        }
+                        Err(e) => ServerMessage::from(&e),
+                    },
+                    server::Event::Remove { key: _ } => {
+                        ServerMessage::SubscriptionUpdate(subscribed.clone(), None)
+                    }
+                    server::Event::Expire { key: _ } => {
+                        ServerMessage::SubscriptionExpired(subscribed.clone())
+                    }
+                };
+                if updates.send_update(update).is_err() {
+                    break;
+                }
+            }
+        };
+        registry.spawn(connection, key, &outbox, task);
+
+        let registered = Registered {
+            registry,
+            connection,
+            outbox,
+            kicked,
+        };
+        let updates = futures_util::stream::unfold(registered, |registered| async move {
+            let update = tokio::select! {
+                update = registered.outbox.recv() => update?,
+                _ = registered.kicked.notified() => return None,
+            };
+            let update = match update {
+                ServerMessage::SubscriptionUpdate(key, value) => Ok(proto::SubscriptionUpdate {
+                    key: Some(proto::Ref { components: key.0 }),
+                    value,
+                    expired: false,
+                }),
+                ServerMessage::SubscriptionExpired(key) => Ok(proto::SubscriptionUpdate {
+                    key: Some(proto::Ref { components: key.0 }),
+                    value: None,
+                    expired: true,
+                }),
+                ServerMessage::Error(e) => Err(Status::internal(e.message.unwrap_or_default())),
+                _ => unreachable!("subscriptions are only sent updates"),
+            };
+            Some((update, registered))
+        });
+        Ok(Box::pin(updates))
+    }
+}
+
+/// A subscription's place in the registry, which it gives up once the client goes away
+struct Registered {
+    registry: Arc<ConnectionRegistry>,
+    connection: u64,
+    outbox: Outbox,
+    kicked: Arc<Notify>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.registry.unregister(self.connection);
+        self.outbox.close();
+    }
+}
+
+/// The token in a request's `authorization: Bearer <token>` metadata, if it has one
+fn bearer_token(metadata: &MetadataMap) -> Result<Option<String>, Status> {
+    let Some(authorization) = metadata.get("authorization") else {
+        return Ok(None);
+    };
+    let token = authorization
+        .to_str()
+        .ok()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .ok_or_else(|| to_unauthenticated(AccountError::InvalidCredentials))?;
+    Ok(Some(token.to_string()))
+}
+
+fn to_ref(key: Option<proto::Ref>) -> message::Ref {
+    message::Ref(key.map(|key| key.components).unwrap_or_default())
+}
+
+fn parse_value(value_json: &str) -> Result<Value, Status> {
+    serde_json::from_str(value_json)
+        .map_err(|e| Status::invalid_argument(format!("invalid JSON value: {e}")))
+}
+
+fn null_response() -> proto::ValueResponse {
+    proto::ValueResponse {
+        value_json: Value::Null.to_string(),
+    }
+}
+
+fn to_unauthenticated(err: AccountError) -> Status {
+    match err {
+        AccountError::Store(e) => Status::internal(e.to_string()),
+        e => Status::unauthenticated(e.to_string()),
+    }
+}
+
+fn to_limit_status(err: LimitError) -> Status {
+    Status::resource_exhausted(err.to_string())
+}
+
+fn to_status(err: ServerError) -> Status {
+    let message = err.to_string();
+    match err.kind() {
+        ErrorKind::InvalidPath | ErrorKind::NotFound => Status::not_found(message),
+        ErrorKind::SchemaMismatch => Status::invalid_argument(message),
+        ErrorKind::ReadOnly => Status::failed_precondition(message),
+        ErrorKind::Maintenance => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
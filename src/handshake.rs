@@ -0,0 +1,85 @@
+use tokio_tungstenite::tungstenite::{
+    handshake::server::Request,
+    http::{header, HeaderValue},
+};
+
+use crate::message::SUBPROTOCOL;
+
+/// Whether a connection may be made from the page it says it came from. Browsers always send an
+/// `Origin`, so requests without one come from other programs and are allowed. A browser's
+/// connection is allowed from the same host it's connecting to, from any origin in `allowed`,
+/// or from anywhere if `allowed` holds `*`.
+pub fn origin_allowed(request: &Request, allowed: &[String]) -> bool {
+    let Some(origin) = request.headers().get(header::ORIGIN) else {
+        return true;
+    };
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    if (allowed.iter()).any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin)) {
+        return true;
+    }
+    let host = (request.headers().get(header::HOST)).and_then(|host| host.to_str().ok());
+    match (origin.split_once("://"), host) {
+        (Some((_, authority)), Some(host)) => authority.eq_ignore_ascii_case(host),
+        _ => false,
+    }
+}
+
+/// The subprotocol to accept the connection with. Clients that don't ask for one are accepted
+/// without, but a client asking only for others can't be served.
+pub fn negotiate_subprotocol(request: &Request) -> Result<Option<HeaderValue>, String> {
+    let headers = request.headers().get_all(header::SEC_WEBSOCKET_PROTOCOL);
+    let mut offered = (headers.iter())
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .peekable();
+    if offered.peek().is_none() {
+        return Ok(None);
+    }
+    if offered.any(|protocol| protocol == SUBPROTOCOL) {
+        Ok(Some(HeaderValue::from_static(SUBPROTOCOL)))
+    } else {
+        Err(format!("only the {SUBPROTOCOL} subprotocol is supported"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> Request {
+        let mut request = Request::builder()
+            .uri("/")
+            .header(header::HOST, "example.com");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn checking_origins() {
+        let allowed = ["https://app.example.org".to_string()];
+        let from = |origin| request(&[(header::ORIGIN, origin)]);
+        assert!(origin_allowed(&request(&[]), &[]));
+        assert!(origin_allowed(&from("https://example.com"), &[]));
+        assert!(!origin_allowed(&from("https://evil.example"), &[]));
+        assert!(!origin_allowed(&from("null"), &[]));
+        assert!(origin_allowed(&from("https://app.example.org"), &allowed));
+        assert!(!origin_allowed(&from("http://app.example.org"), &allowed));
+        assert!(origin_allowed(&from("null"), &["*".to_string()]));
+    }
+
+    #[test]
+    fn negotiating_subprotocols() {
+        let offering = |protocols| request(&[(header::SEC_WEBSOCKET_PROTOCOL, protocols)]);
+        assert_eq!(negotiate_subprotocol(&request(&[])), Ok(None));
+        assert_eq!(
+            negotiate_subprotocol(&offering("chat, iceload.v1")),
+            Ok(Some(HeaderValue::from_static("iceload.v1")))
+        );
+        assert!(negotiate_subprotocol(&offering("chat")).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::{IVec, Tree};
+
+/// The most recent revisions of each document, kept after every write so apps can undo changes
+/// or audit them. Revisions of a document are numbered from 1, and outlive the document, which
+/// is recorded as a null revision when it's removed.
+pub struct History {
+    tree: Tree,
+    /// How many revisions of each document to keep
+    keep: u64,
+    /// Held while recording, so revision numbers are handed out one at a time
+    lock: Mutex<()>,
+}
+
+/// A document as it was just after a write
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Revision {
+    pub revision: u64,
+    /// When the write was made, in milliseconds since the unix epoch
+    pub at: u64,
+    pub value: Value,
+}
+
+impl History {
+    pub fn new(tree: Tree, keep: usize) -> History {
+        History {
+            tree,
+            keep: keep.max(1) as u64,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn keep(&self) -> usize {
+        self.keep as usize
+    }
+
+    /// The name of the tree the revisions are kept in
+    pub fn name(&self) -> IVec {
+        self.tree.name()
+    }
+
+    /// Add a revision of the document stored under `encoded_ref`, dropping any that are now
+    /// too old to keep
+    pub fn record(&self, encoded_ref: &[u8], at: u64, value: Value) -> sled::Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        let prefix = prefix(encoded_ref);
+        let revision = match self.tree.scan_prefix(&prefix).next_back() {
+            Some(entry) => revision_number(&entry?.0) + 1,
+            None => 1,
+        };
+        let revision = Revision {
+            revision,
+            at,
+            value,
+        };
+        self.tree.insert(
+            key(&prefix, revision.revision),
+            serde_json::to_vec(&revision).unwrap(),
+        )?;
+        if let Some(oldest) = revision.revision.checked_sub(self.keep) {
+            for entry in self.tree.range(key(&prefix, 0)..=key(&prefix, oldest)) {
+                self.tree.remove(entry?.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every revision kept of the document stored under `encoded_ref`, oldest first
+    pub fn revisions(&self, encoded_ref: &[u8]) -> sled::Result<Vec<Revision>> {
+        self.tree
+            .scan_prefix(prefix(encoded_ref))
+            .values()
+            .map(|value| Ok(decode(&value?)))
+            .collect()
+    }
+
+    pub fn get(&self, encoded_ref: &[u8], revision: u64) -> sled::Result<Option<Revision>> {
+        Ok(self
+            .tree
+            .get(key(&prefix(encoded_ref), revision))?
+            .map(|value| decode(&value)))
+    }
+}
+
+/// What the keys of a document's revisions start with. The length comes first so that the
+/// revisions of the documents inside it, whose refs it's a prefix of, don't share it.
+fn prefix(encoded_ref: &[u8]) -> Vec<u8> {
+    let mut prefix = (encoded_ref.len() as u32).to_be_bytes().to_vec();
+    prefix.extend_from_slice(encoded_ref);
+    prefix
+}
+
+fn key(prefix: &[u8], revision: u64) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(&revision.to_be_bytes());
+    key
+}
+
+fn revision_number(key: &[u8]) -> u64 {
+    let bytes = key[key.len() - 8..]
+        .try_into()
+        .expect("revisions are 8 bytes");
+    u64::from_be_bytes(bytes)
+}
+
+fn decode(value: &[u8]) -> Revision {
+    serde_json::from_slice(value).expect("revisions are stored as JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn keeping_revisions() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let history = History::new(db.open_tree("history").unwrap(), 2);
+
+        for (at, title) in ["a", "b", "c"].into_iter().enumerate() {
+            history
+                .record(b"post", at as u64, json!({ "title": title }))
+                .unwrap();
+        }
+        // A document inside the first, whose ref starts with the same bytes
+        history.record(b"post/comment", 9, json!({})).unwrap();
+
+        let revisions = history.revisions(b"post").unwrap();
+        assert_eq!(
+            revisions,
+            [
+                Revision {
+                    revision: 2,
+                    at: 1,
+                    value: json!({ "title": "b" }),
+                },
+                Revision {
+                    revision: 3,
+                    at: 2,
+                    value: json!({ "title": "c" }),
+                },
+            ]
+        );
+        assert_eq!(history.get(b"post", 1).unwrap(), None);
+        assert_eq!(history.get(b"post", 3).unwrap(), Some(revisions[1].clone()));
+        assert_eq!(history.revisions(b"post/comment").unwrap().len(), 1);
+    }
+}
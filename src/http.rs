@@ -0,0 +1,508 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Json, Router,
+};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, sync::Notify};
+
+use crate::{
+    accounts::{AccountError, Accounts},
+    audit::{Attempt, AuditLog, Source},
+    backup::Backups,
+    config::{ConnectionConfig, LimitsConfig},
+    error::ErrorKind,
+    jobs::{JobStatus, Jobs},
+    limits::{self, LimitError},
+    message::{Credentials, Ref, ServerMessage},
+    outbox::Outbox,
+    permission::{Operation, PermissionError, PermissionPool, Permissions},
+    registry::ConnectionRegistry,
+    replay::{ReplayError, ReplayGuard},
+    server::{self, Server, ServerError},
+};
+
+#[derive(Clone)]
+struct Gateway {
+    server: Server,
+    permissions: Arc<PermissionPool>,
+    /// The accounts whose tokens requests may be authenticated with, if the server keeps any
+    accounts: Option<Accounts>,
+    /// The subscription quotas event streams are held to, along with WebSocket clients
+    limits: LimitsConfig,
+    /// How updates are queued for event streams, as they are for WebSocket clients
+    connections: ConnectionConfig,
+    registry: Arc<ConnectionRegistry>,
+    /// Writes can't be enveloped over HTTP, so they're refused if envelopes are required
+    replay_guard: Arc<ReplayGuard>,
+    audit: Option<Arc<AuditLog>>,
+    backups: Option<Arc<Backups>>,
+    jobs: Option<Arc<Jobs>>,
+}
+
+/// Serve a REST API over the store: `GET`, `PUT` (insert), `PATCH` (update), and `DELETE` on
+/// `/v1/<path>`, subject to the same permission checks as WebSocket clients. `GET` takes an
+/// `expand=<depth>` parameter to inline the items that references point at.
+///
+/// Requests are checked as the user an `Authorization: Bearer <token>` header signs in as, with
+/// a token from signing in to one of `accounts` over WebSocket, or as no one without one.
+/// Writes can't be enveloped, so they're refused when `replay_guard` requires envelopes.
+///
+/// `GET /events/<path>` streams subscription updates as Server-Sent Events, each carrying the
+/// same `SubscriptionUpdate` message a WebSocket client would receive. Each stream is a client of
+/// its own to the registry, holding one subscription, so it counts towards the subscription
+/// quotas and can be listed and kicked like any other.
+///
+/// `GET /metrics` reports server health in the Prometheus text format, including how scheduled
+/// backups and jobs are going if there are any.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    listener: TcpListener,
+    server: Server,
+    permission_bytecode: &'static [u8],
+    accounts: Option<Accounts>,
+    limits: LimitsConfig,
+    connections: ConnectionConfig,
+    registry: Arc<ConnectionRegistry>,
+    replay_guard: Arc<ReplayGuard>,
+    audit: Option<Arc<AuditLog>>,
+    backups: Option<Arc<Backups>>,
+    jobs: Option<Arc<Jobs>>,
+) -> std::io::Result<()> {
+    let permissions = PermissionPool::new(permission_bytecode, server.slow_threshold());
+    let gateway = Gateway {
+        server,
+        permissions: Arc::new(permissions),
+        accounts,
+        limits,
+        connections,
+        registry,
+        replay_guard,
+        audit,
+        backups,
+        jobs,
+    };
+    let routes = get(get_value)
+        .put(insert_value)
+        .patch(update_value)
+        .delete(remove_value);
+    let app = Router::new()
+        .route("/events", get(subscribe))
+        .route("/events/", get(subscribe))
+        .route("/events/{*path}", get(subscribe))
+        .route("/v1", routes.clone())
+        .route("/v1/", routes.clone())
+        .route("/v1/{*path}", routes)
+        .route("/metrics", get(metrics))
+        .with_state(gateway);
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+}
+
+enum GatewayError {
+    Unauthenticated(AccountError),
+    PermissionDenied,
+    Permission(PermissionError),
+    Limit(LimitError),
+    Replay(ReplayError),
+    Server(ServerError),
+}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            GatewayError::Unauthenticated(AccountError::Store(e)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}"))
+            }
+            GatewayError::Unauthenticated(e) => (StatusCode::UNAUTHORIZED, format!("{e}")),
+            GatewayError::PermissionDenied => (StatusCode::FORBIDDEN, "permissions".to_string()),
+            GatewayError::Permission(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}")),
+            GatewayError::Limit(e) => (StatusCode::TOO_MANY_REQUESTS, format!("{e}")),
+            GatewayError::Replay(e) => (StatusCode::FORBIDDEN, format!("{e}")),
+            GatewayError::Server(e) => {
+                let status = match e.kind() {
+                    ErrorKind::InvalidPath | ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                    ErrorKind::SchemaMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+                    ErrorKind::ReadOnly => StatusCode::METHOD_NOT_ALLOWED,
+                    ErrorKind::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, format!("{e}"))
+            }
+        };
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+/// Who made a request, with the permission rules to check it against
+struct Caller {
+    user: Option<String>,
+    permissions: Permissions<'static>,
+}
+
+impl Caller {
+    fn check(&self, op: Operation, key: &Ref) -> Result<(), GatewayError> {
+        let allowed = self
+            .permissions
+            .check(op, key, self.user.as_deref())
+            .map_err(GatewayError::Permission)?;
+        if allowed {
+            Ok(())
+        } else {
+            Err(GatewayError::PermissionDenied)
+        }
+    }
+}
+
+impl Gateway {
+    /// Handle a request for the user its token signs in as. Requests are handled on the blocking
+    /// pool, as the store, the rules and checking tokens all block.
+    async fn handle<T: Send + 'static>(
+        &self,
+        headers: &HeaderMap,
+        handle: impl FnOnce(&Gateway, &Caller) -> Result<T, GatewayError> + Send + 'static,
+    ) -> Result<T, GatewayError> {
+        let token = bearer_token(headers)?;
+        let gateway = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let user = gateway.authenticate(token.as_deref())?;
+            let permissions = gateway.permissions.take();
+            let caller = Caller { user, permissions };
+            let result = handle(&gateway, &caller);
+            gateway.permissions.put(caller.permissions);
+            result
+        })
+        .await
+        .expect("handling requests doesn't panic")
+    }
+
+    /// The user `token` signs in as, or no one without a token
+    fn authenticate(&self, token: Option<&str>) -> Result<Option<String>, GatewayError> {
+        let Some(token) = token else {
+            return Ok(None);
+        };
+        let Some(accounts) = &self.accounts else {
+            return Err(GatewayError::Unauthenticated(AccountError::Disabled(
+                "signing in",
+            )));
+        };
+        let signed_in = accounts
+            .sign_in(&Credentials::Token(token.to_string()))
+            .map_err(GatewayError::Unauthenticated)?;
+        Ok(Some(signed_in.user))
+    }
+
+    /// Log a write to the audit log, if it's kept
+    fn audit(&self, caller: &Caller, op: Operation, key: &Ref, allowed: bool) {
+        if let Some(audit) = &self.audit {
+            audit.record(Attempt {
+                source: Source::Http,
+                tenant: None,
+                connection: None,
+                user: caller.user.clone(),
+                op,
+                path: key.clone(),
+                allowed,
+            });
+        }
+    }
+}
+
+/// The token in a request's `Authorization: Bearer <token>` header, if it has one
+fn bearer_token(headers: &HeaderMap) -> Result<Option<String>, GatewayError> {
+    let Some(authorization) = headers.get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let token = authorization
+        .to_str()
+        .ok()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .ok_or(GatewayError::Unauthenticated(
+            AccountError::InvalidCredentials,
+        ))?;
+    Ok(Some(token.to_string()))
+}
+
+fn to_ref(path: Option<Path<String>>) -> Ref {
+    let Some(Path(path)) = path else {
+        return Ref(Vec::new());
+    };
+    Ref(path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct GetParams {
+    /// How many levels of references to expand
+    #[serde(default)]
+    expand: u32,
+}
+
+async fn get_value(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    path: Option<Path<String>>,
+    Query(params): Query<GetParams>,
+) -> Result<Json<Value>, GatewayError> {
+    let key = to_ref(path);
+    gateway
+        .handle(&headers, move |gateway, caller| {
+            get_ref(gateway, caller, key, params.expand)
+        })
+        .await
+}
+
+async fn insert_value(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    path: Option<Path<String>>,
+    Json(value): Json<Value>,
+) -> Result<Json<Value>, GatewayError> {
+    let key = to_ref(path);
+    gateway
+        .handle(&headers, move |gateway, caller| {
+            write_ref(gateway, caller, Operation::Insert, key, Some(value))
+        })
+        .await
+}
+
+async fn update_value(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    path: Option<Path<String>>,
+    Json(value): Json<Value>,
+) -> Result<Json<Value>, GatewayError> {
+    let key = to_ref(path);
+    gateway
+        .handle(&headers, move |gateway, caller| {
+            write_ref(gateway, caller, Operation::Update, key, Some(value))
+        })
+        .await
+}
+
+async fn remove_value(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    path: Option<Path<String>>,
+) -> Result<Json<Value>, GatewayError> {
+    let key = to_ref(path);
+    gateway
+        .handle(&headers, move |gateway, caller| {
+            write_ref(gateway, caller, Operation::Remove, key, None)
+        })
+        .await
+}
+
+/// An event stream's place in the registry, which it gives up once the client goes away
+struct Registered {
+    registry: Arc<ConnectionRegistry>,
+    connection: u64,
+    outbox: Outbox,
+    kicked: Arc<Notify>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.registry.unregister(self.connection);
+        self.outbox.close();
+    }
+}
+
+async fn subscribe(
+    State(gateway): State<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    path: Option<Path<String>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, GatewayError> {
+    let key = to_ref(path);
+    let checked = key.clone();
+    gateway
+        .handle(&headers, move |_, caller| {
+            caller.check(Operation::Read, &checked)
+        })
+        .await?;
+    // A stream only ever holds the one subscription
+    let total = gateway.registry.task_count();
+    limits::check_subscription(&gateway.limits, &key, 0, total).map_err(GatewayError::Limit)?;
+
+    let registry = gateway.registry.clone();
+    let connection = registry.next_connection_id();
+    let kicked = registry.register(connection, peer.to_string(), None);
+    let outbox = Outbox::new(
+        gateway.connections.send_buffer,
+        gateway.connections.slow_consumer,
+    );
+    let server = gateway.server.clone();
+    let mut events = gateway.server.subscribe(&key);
+    let updates = outbox.clone();
+    let subscribed = key.clone();
+    let task = async move {
+        while let Some(event) = events.next().await {
+            let update = match event {
+                server::Event::Insert {
+                    key: written,
+                    value,
+                } => match server.event_value(&written, &value) {
+                    Ok(value) => ServerMessage::SubscriptionUpdate(subscribed.clone(), Some(value)),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                server::Event::Remove { key: _ } => {
+                    ServerMessage::SubscriptionUpdate(subscribed.clone(), None)
+                }
+                server::Event::Expire { key: _ } => {
+                    ServerMessage::SubscriptionExpired(subscribed.clone())
+                }
+            };
+            if updates.send_update(update).is_err() {
+                break;
+            }
+        }
+    };
+    registry.spawn(connection, key, &outbox, task);
+
+    let registered = Registered {
+        registry,
+        connection,
+        outbox,
+        kicked,
+    };
+    let events = futures_util::stream::unfold(registered, |registered| async move {
+        let update = tokio::select! {
+            update = registered.outbox.recv() => update?,
+            _ = registered.kicked.notified() => return None,
+        };
+        let event = Event::default().data(serde_json::to_string(&update).unwrap());
+        Some((Ok(event), registered))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+async fn metrics(State(gateway): State<Gateway>) -> String {
+    let tasks = gateway.registry.counts();
+    let mut metrics = format!(
+        "# HELP iceload_subscription_tasks Subscription tasks by health.\n\
+         # TYPE iceload_subscription_tasks gauge\n\
+         iceload_subscription_tasks{{state=\"live\"}} {}\n\
+         iceload_subscription_tasks{{state=\"orphaned\"}} {}\n\
+         # HELP iceload_subscription_tasks_reaped_total Orphaned subscription tasks cleaned up by the watchdog.\n\
+         # TYPE iceload_subscription_tasks_reaped_total counter\n\
+         iceload_subscription_tasks_reaped_total {}\n",
+        tasks.live, tasks.orphaned, tasks.reaped
+    );
+    if let Some(backups) = &gateway.backups {
+        let status = backups.status();
+        metrics += &format!(
+            "# HELP iceload_backup_last_success_timestamp_seconds When the last successful backup finished, or 0 if none has.\n\
+             # TYPE iceload_backup_last_success_timestamp_seconds gauge\n\
+             iceload_backup_last_success_timestamp_seconds {}\n\
+             # HELP iceload_backup_last_size_bytes Size of the last successful backup.\n\
+             # TYPE iceload_backup_last_size_bytes gauge\n\
+             iceload_backup_last_size_bytes {}\n\
+             # HELP iceload_backup_last_duration_seconds How long the last successful backup took.\n\
+             # TYPE iceload_backup_last_duration_seconds gauge\n\
+             iceload_backup_last_duration_seconds {}\n\
+             # HELP iceload_backup_failures_total Backups that have failed since the server started.\n\
+             # TYPE iceload_backup_failures_total counter\n\
+             iceload_backup_failures_total {}\n",
+            status.last_success.unwrap_or(0),
+            status.last_size_bytes,
+            status.last_duration.as_secs_f64(),
+            status.failures,
+        );
+    }
+    if let Some(jobs) = &gateway.jobs {
+        let statuses = jobs.statuses();
+        let series = |name: &str, help: &str, kind: &str, value: &dyn Fn(&JobStatus) -> String| {
+            let mut lines = format!("# HELP {name} {help}\n# TYPE {name} {kind}\n");
+            for (job, status) in &statuses {
+                lines += &format!("{name}{{job={job:?}}} {}\n", value(status));
+            }
+            lines
+        };
+        metrics += &series(
+            "iceload_job_runs_total",
+            "Scheduled job runs that have finished since the server started.",
+            "counter",
+            &|status| status.runs.to_string(),
+        );
+        metrics += &series(
+            "iceload_job_failures_total",
+            "Scheduled job runs that have failed since the server started.",
+            "counter",
+            &|status| status.failures.to_string(),
+        );
+        metrics += &series(
+            "iceload_job_last_success_timestamp_seconds",
+            "When a job last ran successfully, or 0 if it hasn't.",
+            "gauge",
+            &|status| status.last_success.unwrap_or(0).to_string(),
+        );
+        metrics += &series(
+            "iceload_job_last_duration_seconds",
+            "How long a job's last successful run took.",
+            "gauge",
+            &|status| status.last_duration.as_secs_f64().to_string(),
+        );
+    }
+    metrics
+}
+
+fn get_ref(
+    gateway: &Gateway,
+    caller: &Caller,
+    key: Ref,
+    expand: u32,
+) -> Result<Json<Value>, GatewayError> {
+    caller.check(Operation::Read, &key)?;
+    gateway
+        .server
+        .get_expanded(&key, expand, &mut |targets| {
+            caller.permissions.readable(targets, caller.user.as_deref())
+        })
+        .map(Json)
+        .map_err(GatewayError::Server)
+}
+
+fn write_ref(
+    gateway: &Gateway,
+    caller: &Caller,
+    op: Operation,
+    key: Ref,
+    value: Option<Value>,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .replay_guard
+        .check_unenveloped()
+        .map_err(GatewayError::Replay)?;
+    if let Err(e) = caller.check(op, &key) {
+        if matches!(e, GatewayError::PermissionDenied) {
+            gateway.audit(caller, op, &key, false);
+        }
+        return Err(e);
+    }
+    let result = match (op, value) {
+        (Operation::Insert, Some(value)) => gateway.server.insert(&key, value),
+        (Operation::Update, Some(value)) => gateway.server.update(&key, value),
+        (Operation::Remove, None) => gateway.server.remove(&key),
+        _ => unreachable!("writes are only routed with matching values"),
+    };
+    result.map_err(GatewayError::Server)?;
+    gateway.audit(caller, op, &key, true);
+    Ok(Json(Value::Null))
+}
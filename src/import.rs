@@ -0,0 +1,369 @@
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use iceload::{message::Ref, schema::SchemaItem, server::Server};
+
+/// Where a Firestore export keeps a document's subcollections, and the collections at its root
+const SUBCOLLECTIONS: &str = "__collections__";
+/// The key a Firestore export uses to mark a value it couldn't write as plain JSON
+const DATATYPE: &str = "__datatype__";
+
+/// A part of an export that doesn't fit the schema, and is left out of the import
+struct Mismatch {
+    path: Ref,
+    reason: String,
+}
+
+/// Import a Firebase Realtime Database export, or a Firestore export with its subcollections
+/// under `__collections__`, from `export_path` into `server`, printing a line for everything that
+/// doesn't fit the schema.
+///
+/// Each top-level item in the export replaces the one already stored. Items that don't fit
+/// aren't imported, and neither is anything containing them that needs them, but the rest of the
+/// export is. Numbers and booleans are written to scalars as strings, and Firestore timestamps as
+/// milliseconds since the Unix epoch. Firebase leaves out empty objects, so collections a document
+/// doesn't have are imported empty.
+///
+/// Returns whether everything in the export was imported.
+pub fn run(export_path: &Path, server: &Server) -> anyhow::Result<bool> {
+    let export: Value = serde_json::from_str(&std::fs::read_to_string(export_path)?)?;
+    let mut mismatches = Vec::new();
+    let schema = server.schema();
+    let items = top_level(schema.root(), export, &mut mismatches);
+    let mut imported = 0;
+    for (key, item, value) in items {
+        let Some(value) = fit(&key, item, value, &mut mismatches) else {
+            continue;
+        };
+        match server.insert(&key, value) {
+            Ok(()) => imported += 1,
+            Err(e) => mismatches.push(Mismatch {
+                path: key,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    for Mismatch { path, reason } in mismatches.iter() {
+        println!("skipped {path}: {reason}");
+    }
+    println!("imported {imported} items from {}", export_path.display());
+    Ok(mismatches.is_empty())
+}
+
+/// The export split into the items at the top of the schema, each of which is written on its
+/// own so one that doesn't fit doesn't keep the rest out
+fn top_level<'a>(
+    root: &'a SchemaItem,
+    export: Value,
+    mismatches: &mut Vec<Mismatch>,
+) -> Vec<(Ref, &'a SchemaItem, Value)> {
+    let root_ref = Ref(Vec::new());
+    let Some(members) = members(&root_ref, export, mismatches) else {
+        return Vec::new();
+    };
+    let mut items = Vec::new();
+    for (key, value) in members {
+        let path = Ref(vec![key.clone()]);
+        let item = match root {
+            SchemaItem::Document(fields) => fields.get(&key),
+            SchemaItem::Collection(collection) => {
+                collection.allows_key(&key).then_some(&*collection.items)
+            }
+            _ => None,
+        };
+        match item {
+            Some(item) => items.push((path, item, value)),
+            None => mismatches.push(Mismatch {
+                path,
+                reason: "not in the schema".to_string(),
+            }),
+        }
+    }
+    items
+}
+
+/// `value` in the form `item` takes, or None if it doesn't fit
+fn fit(
+    path: &Ref,
+    item: &SchemaItem,
+    value: Value,
+    mismatches: &mut Vec<Mismatch>,
+) -> Option<Value> {
+    match item {
+        SchemaItem::Sensitive(inner) | SchemaItem::Searchable(inner) => {
+            fit(path, inner, value, mismatches)
+        }
+        SchemaItem::Optional(inner) => match value {
+            Value::Null => Some(Value::Null),
+            value => fit(path, inner, value, mismatches),
+        },
+        SchemaItem::Ephemeral(_) | SchemaItem::Presence => skip(
+            mismatches,
+            path,
+            "held in memory, so it can't be imported".to_string(),
+        ),
+        SchemaItem::Document(fields) => {
+            let mut fitted = Map::new();
+            for (key, value) in members(path, value, mismatches)? {
+                let field_path = path.child(&key);
+                match fields.get(&key) {
+                    Some(item) if is_stored(item) => {
+                        let value = fit(&field_path, item, value, mismatches)?;
+                        // Optional fields that aren't set are left out
+                        if !value.is_null() {
+                            fitted.insert(key, value);
+                        }
+                    }
+                    Some(_) => {}
+                    None => mismatches.push(Mismatch {
+                        path: field_path,
+                        reason: "not in the schema".to_string(),
+                    }),
+                }
+            }
+            let mut missing: Vec<&String> = Vec::new();
+            for (key, item) in fields {
+                if !is_required(item) || fitted.contains_key(key) {
+                    continue;
+                }
+                // Firebase doesn't keep empty objects, so empty collections are left out
+                if let SchemaItem::Collection(_) = unwrapped(item) {
+                    fitted.insert(key.clone(), Value::Object(Map::new()));
+                } else {
+                    missing.push(key);
+                }
+            }
+            missing.sort_unstable();
+            match missing.first() {
+                Some(missing) => skip(mismatches, path, format!("missing the field {missing:?}")),
+                None => Some(Value::Object(fitted)),
+            }
+        }
+        SchemaItem::Collection(collection) => {
+            let mut fitted = Map::new();
+            for (key, value) in members(path, value, mismatches)? {
+                let member_path = path.child(&key);
+                if !collection.allows_key(&key) {
+                    mismatches.push(Mismatch {
+                        path: member_path,
+                        reason: "the key isn't in the format the schema requires".to_string(),
+                    });
+                } else if let Some(value) = fit(&member_path, &collection.items, value, mismatches)
+                {
+                    fitted.insert(key, value);
+                }
+            }
+            Some(Value::Object(fitted))
+        }
+        SchemaItem::Scalar | SchemaItem::Enum(_) => match scalar(value) {
+            Ok(Value::String(value)) => match item {
+                SchemaItem::Enum(allowed) if !allowed.contains(&value) => skip(
+                    mismatches,
+                    path,
+                    format!("{value:?} isn't one of {allowed:?}"),
+                ),
+                _ => Some(Value::String(value)),
+            },
+            Ok(_) => skip(mismatches, path, "expected a scalar".to_string()),
+            Err(reason) => skip(mismatches, path, reason),
+        },
+        SchemaItem::Reference | SchemaItem::ReferenceTo(_) => match scalar(value) {
+            // Firebase keeps references as slash-separated paths
+            Ok(Value::String(target)) => Some(
+                (target.split('/'))
+                    .filter(|component| !component.is_empty())
+                    .collect(),
+            ),
+            Ok(value @ Value::Array(_)) => Some(value),
+            Ok(_) => skip(mismatches, path, "expected a reference".to_string()),
+            Err(reason) => skip(mismatches, path, reason),
+        },
+        // Custom codecs decide for themselves what they take
+        SchemaItem::Custom(_) => Some(value),
+    }
+}
+
+/// The members of a document or collection in an export: an object, with any Firestore
+/// subcollections merged in, or an array, which the Realtime Database exports for objects whose
+/// keys are all small numbers
+fn members(
+    path: &Ref,
+    value: Value,
+    mismatches: &mut Vec<Mismatch>,
+) -> Option<Vec<(String, Value)>> {
+    match value {
+        Value::Object(mut object) => {
+            let subcollections = object.remove(SUBCOLLECTIONS);
+            let mut members: Vec<_> = object.into_iter().collect();
+            match subcollections {
+                Some(Value::Object(subcollections)) => members.extend(subcollections),
+                Some(_) => mismatches.push(Mismatch {
+                    path: path.child(SUBCOLLECTIONS),
+                    reason: "expected an object of subcollections".to_string(),
+                }),
+                None => {}
+            }
+            Some(members)
+        }
+        // Missing indices are exported as nulls
+        Value::Array(elements) => Some(
+            (elements.into_iter().enumerate())
+                .filter(|(_, element)| !element.is_null())
+                .map(|(index, element)| (index.to_string(), element))
+                .collect(),
+        ),
+        _ => {
+            mismatches.push(Mismatch {
+                path: path.clone(),
+                reason: "expected an object".to_string(),
+            });
+            None
+        }
+    }
+}
+
+/// A scalar from an export, with numbers, booleans and the Firestore datatypes iceload has a
+/// form for turned into strings
+fn scalar(value: Value) -> Result<Value, String> {
+    let Value::Object(mut object) = value else {
+        return Ok(match value {
+            Value::Number(number) => Value::String(number.to_string()),
+            Value::Bool(bool) => Value::String(bool.to_string()),
+            value => value,
+        });
+    };
+    let Some(Value::String(datatype)) = object.remove(DATATYPE) else {
+        return Ok(Value::Object(object));
+    };
+    let value = object.remove("value").unwrap_or_default();
+    match datatype.as_str() {
+        "timestamp" => {
+            let seconds = value.get("_seconds").and_then(Value::as_i64);
+            let nanoseconds = value.get("_nanoseconds").and_then(Value::as_i64);
+            match (seconds, nanoseconds) {
+                (Some(seconds), Some(nanoseconds)) => Ok(Value::String(
+                    (seconds * 1000 + nanoseconds / 1_000_000).to_string(),
+                )),
+                _ => Err("a timestamp without _seconds and _nanoseconds".to_string()),
+            }
+        }
+        "documentReference" => Ok(value),
+        datatype => Err(format!("Firestore {datatype} values can't be imported")),
+    }
+}
+
+/// Record that the item at `path` doesn't fit, so it's left out
+fn skip(mismatches: &mut Vec<Mismatch>, path: &Ref, reason: String) -> Option<Value> {
+    mismatches.push(Mismatch {
+        path: path.clone(),
+        reason,
+    });
+    None
+}
+
+/// Whether the item is stored, rather than held in memory and left out of imports
+fn is_stored(item: &SchemaItem) -> bool {
+    !matches!(item, SchemaItem::Ephemeral(_) | SchemaItem::Presence)
+}
+
+/// Whether a document has to have the field for it to be written
+fn is_required(item: &SchemaItem) -> bool {
+    !matches!(
+        item,
+        SchemaItem::Optional(_) | SchemaItem::Ephemeral(_) | SchemaItem::Presence
+    )
+}
+
+/// The item, without the wrappers that don't change what it holds
+fn unwrapped(item: &SchemaItem) -> &SchemaItem {
+    match item {
+        SchemaItem::Sensitive(inner) | SchemaItem::Searchable(inner) => unwrapped(inner),
+        item => item,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use iceload::schema::{CollectionSchema, KeyFormat};
+
+    use super::*;
+
+    fn reasons(mismatches: &[Mismatch]) -> Vec<(String, &str)> {
+        (mismatches.iter())
+            .map(|Mismatch { path, reason }| (path.to_string(), reason.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn fitting_exports() {
+        let user = SchemaItem::Document(HashMap::from([
+            ("name".to_string(), SchemaItem::Scalar),
+            ("age".to_string(), SchemaItem::Scalar),
+            (
+                "joined".to_string(),
+                SchemaItem::Optional(Box::new(SchemaItem::Scalar)),
+            ),
+            (
+                "friend".to_string(),
+                SchemaItem::Optional(Box::new(SchemaItem::Reference)),
+            ),
+            (
+                "posts".to_string(),
+                SchemaItem::Collection(
+                    CollectionSchema::new(SchemaItem::Scalar).with_keys(KeyFormat::Numeric),
+                ),
+            ),
+        ]));
+        let users = SchemaItem::Collection(CollectionSchema::new(user));
+        let export = json!({
+            "ada": {
+                "name": "Ada",
+                "age": 36,
+                "joined": {
+                    "__datatype__": "timestamp",
+                    "value": { "_seconds": 1700000000, "_nanoseconds": 500000000 }
+                },
+                "friend": {
+                    "__datatype__": "documentReference",
+                    "value": "users/grace"
+                },
+                "__collections__": {
+                    "posts": ["first", null, "third"]
+                }
+            },
+            "grace": { "name": "Grace", "age": 85, "nickname": "Amazing Grace" },
+            "alan": { "name": "Alan" }
+        });
+
+        let mut mismatches = Vec::new();
+        let path = Ref::from(["users"]);
+        let fitted = fit(&path, &users, export, &mut mismatches).unwrap();
+        assert_eq!(
+            fitted,
+            json!({
+                "ada": {
+                    "name": "Ada",
+                    "age": "36",
+                    "joined": "1700000000500",
+                    "friend": ["users", "grace"],
+                    "posts": { "0": "first", "2": "third" }
+                },
+                "grace": { "name": "Grace", "age": "85", "posts": {} }
+            })
+        );
+        let mut found = reasons(&mismatches);
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                ("/users/alan".to_string(), "missing the field \"age\""),
+                ("/users/grace/nickname".to_string(), "not in the schema"),
+            ]
+        );
+    }
+}
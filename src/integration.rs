@@ -0,0 +1,166 @@
+use std::{sync::Arc, time::Duration};
+
+use sled::Tree;
+use thiserror::Error;
+
+use crate::{
+    changes::Change,
+    delivery::{DeliveryError, DeliveryQueue, Sink},
+    message::Ref,
+    server::{Server, ServerError},
+};
+
+/// How many changes to read from the log at a time
+const BATCH: usize = 100;
+/// How long to wait before reading the log again after the store fails
+const STORAGE_RETRY: Duration = Duration::from_secs(5);
+/// Where the sequence number of the next change to send is kept
+const CURSOR: &[u8] = b"cursor";
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum IntegrationError {
+    #[error("{0}")]
+    Server(#[from] ServerError),
+    #[error("{0}")]
+    Delivery(#[from] DeliveryError),
+    #[error("{0}")]
+    Storage(#[from] sled::Error),
+}
+
+/// Sends change events to the integrations, such as webhooks, configured for the paths they
+/// touch.
+///
+/// Events are read from the change log, so they're only sent once their write has committed, and
+/// handed to the delivery queue, which retries receivers that are down. How far through the log
+/// they've got is kept in the database, so nothing is skipped across restarts, though an event
+/// may be sent twice if the server stops at the wrong moment.
+pub struct Integrations {
+    server: Server,
+    queue: Arc<DeliveryQueue>,
+    routes: Vec<Route>,
+}
+
+struct Route {
+    path: Ref,
+    /// The name the receiver is registered under with the delivery queue
+    sink: String,
+}
+
+impl Route {
+    /// Whether a write to `path` should be sent: one to the route's path, to anything under it,
+    /// or to anything containing it
+    fn matches(&self, path: &Ref) -> bool {
+        path.overlaps(&self.path)
+    }
+}
+
+impl Integrations {
+    pub fn new(server: &Server, queue: Arc<DeliveryQueue>) -> Integrations {
+        Integrations {
+            server: server.clone(),
+            queue,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Send events for writes touching `path` to `sink`. Deliveries are queued by `name`, so it
+    /// has to stay the same across restarts.
+    pub fn add(&mut self, path: Ref, name: String, sink: Arc<dyn Sink>) {
+        self.queue.register(&name, sink);
+        self.routes.push(Route { path, sink: name });
+    }
+
+    /// Start sending events in the background, from where they left off. Integrations only hear
+    /// about writes made while some integration was configured.
+    pub fn start(self) -> Result<(), IntegrationError> {
+        let cursor = self.server.system_tree("integrations")?;
+        if self.routes.is_empty() {
+            cursor.remove(CURSOR)?;
+            return Ok(());
+        }
+        if cursor.get(CURSOR)?.is_none() {
+            cursor.insert(CURSOR, &self.server.next_change()?.to_be_bytes())?;
+        }
+        tokio::spawn(self.run(cursor));
+        Ok(())
+    }
+
+    async fn run(self, cursor: Tree) {
+        loop {
+            // Watch before reading, so a change committed in between isn't missed
+            let mut committed = self.server.watch_changes();
+            if let Err(e) = self.dispatch(&cursor) {
+                tracing::warn!("failed to queue change events: {e}");
+                tokio::time::sleep(STORAGE_RETRY).await;
+                continue;
+            }
+            if committed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Queue events for every change since the last call
+    fn dispatch(&self, cursor: &Tree) -> Result<(), IntegrationError> {
+        loop {
+            let from = read_cursor(cursor)?.unwrap_or(0);
+            let changes = self.server.changes(from, BATCH)?;
+            let Some(last) = changes.last() else {
+                return Ok(());
+            };
+            let next = last.seq + 1;
+            for change in changes {
+                self.queue_change(&change)?;
+            }
+            cursor.insert(CURSOR, &next.to_be_bytes())?;
+        }
+    }
+
+    fn queue_change(&self, change: &Change) -> Result<(), IntegrationError> {
+        let routes: Vec<&Route> = self
+            .routes
+            .iter()
+            .filter(|route| route.matches(change.op.path()))
+            .collect();
+        if routes.is_empty() {
+            return Ok(());
+        }
+        let event = serde_json::to_value(self.server.redact_change(change)).unwrap();
+        for route in routes {
+            self.queue.enqueue(&route.sink, event.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// The sequence number of the next change the integrations of `server` will send, if any are
+/// configured
+pub fn cursor(server: &Server) -> Result<Option<u64>, IntegrationError> {
+    read_cursor(&server.system_tree("integrations")?)
+}
+
+fn read_cursor(cursor: &Tree) -> Result<Option<u64>, IntegrationError> {
+    Ok((cursor.get(CURSOR)?).map(|cursor| u64::from_be_bytes(cursor.as_ref().try_into().unwrap())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_paths() {
+        let route = Route {
+            path: Ref(vec!["posts".into(), "first".into()]),
+            sink: String::new(),
+        };
+        let path = |components: &[&str]| Ref(components.iter().map(|c| c.to_string()).collect());
+        assert!(route.matches(&path(&["posts", "first"])));
+        assert!(route.matches(&path(&["posts", "first", "title"])));
+        // Replacing something that contains the path changes it too
+        assert!(route.matches(&path(&["posts"])));
+        assert!(route.matches(&path(&[])));
+        assert!(!route.matches(&path(&["posts", "second"])));
+        assert!(!route.matches(&path(&["users"])));
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    config::JobConfig,
+    functions::{FunctionError, Functions},
+    tenant::Tenants,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum JobError {
+    #[error("there's more than one job called {}", .0)]
+    DuplicateName(String),
+    #[error("job {job} is for tenant {tenant}, which doesn't exist")]
+    UnknownTenant { job: String, tenant: String },
+    #[error("job {job} runs {function}, which isn't in the functions script")]
+    UnknownFunction { job: String, function: String },
+    #[error("{}", .0)]
+    Function(#[from] FunctionError),
+}
+
+/// How a scheduled job has been going, for metrics
+#[derive(Clone, Debug, Default)]
+pub struct JobStatus {
+    /// Runs that have finished since the server started, whether or not they succeeded
+    pub runs: u64,
+    /// Runs that have failed since the server started
+    pub failures: u64,
+    /// When the last successful run finished, in seconds since the unix epoch
+    pub last_success: Option<u64>,
+    pub last_duration: Duration,
+}
+
+struct Job {
+    config: JobConfig,
+    status: Mutex<JobStatus>,
+}
+
+/// Runs functions from the tenants' functions scripts on cron schedules, e.g. to clean up stale
+/// sessions every night. A job is called with null arguments, and its writes are made in one
+/// transaction like any other call. A run that's still going when the next is due delays it
+/// rather than overlapping it.
+pub struct Jobs {
+    tenants: Arc<Tenants>,
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    /// Check that each job names a tenant and function that exist
+    pub fn new(tenants: Arc<Tenants>, configs: &[JobConfig]) -> Result<Jobs, JobError> {
+        let mut names = HashSet::new();
+        for config in configs {
+            if !names.insert(&config.name) {
+                return Err(JobError::DuplicateName(config.name.clone()));
+            }
+            let Some(tenant) = tenants.get(config.tenant.as_deref()) else {
+                return Err(JobError::UnknownTenant {
+                    job: config.name.clone(),
+                    tenant: config.tenant.clone().unwrap_or_default(),
+                });
+            };
+            if !Functions::new(tenant.function_bytecode).contains(&config.function)? {
+                return Err(JobError::UnknownFunction {
+                    job: config.name.clone(),
+                    function: config.function.clone(),
+                });
+            }
+        }
+        let jobs = configs
+            .iter()
+            .map(|config| Job {
+                config: config.clone(),
+                status: Mutex::new(JobStatus::default()),
+            })
+            .collect();
+        Ok(Jobs { tenants, jobs })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// The status of each job, by name
+    pub fn statuses(&self) -> Vec<(String, JobStatus)> {
+        self.jobs
+            .iter()
+            .map(|job| (job.config.name.clone(), job.status.lock().unwrap().clone()))
+            .collect()
+    }
+
+    /// Run every job on its schedule, forever
+    pub fn start(self: Arc<Self>) {
+        for index in 0..self.jobs.len() {
+            tokio::spawn(self.clone().schedule(index));
+        }
+    }
+
+    async fn schedule(self: Arc<Self>, index: usize) {
+        let job = &self.jobs[index];
+        loop {
+            let Some(next) = job.config.schedule.next_after(now().as_secs()) else {
+                tracing::warn!(job = job.config.name, "job's schedule never matches");
+                return;
+            };
+            let wait = Duration::from_secs(next).saturating_sub(now());
+            tokio::time::sleep(wait).await;
+
+            let jobs = self.clone();
+            let started = Instant::now();
+            // Functions block on Lua and the store, so keep them off the workers
+            let result = tokio::task::spawn_blocking(move || jobs.run(index))
+                .await
+                .expect("jobs don't panic");
+            job.record(&result, started.elapsed());
+        }
+    }
+
+    /// Run the job at `index` now, returning what its function returns
+    fn run(&self, index: usize) -> Result<Value, FunctionError> {
+        let config = &self.jobs[index].config;
+        let tenant = self
+            .tenants
+            .get(config.tenant.as_deref())
+            .expect("tenants are checked when the jobs are created");
+        Functions::new(tenant.function_bytecode).call(
+            &tenant.server,
+            &config.function,
+            &Value::Null,
+        )
+    }
+}
+
+impl Job {
+    fn record(&self, result: &Result<Value, FunctionError>, duration: Duration) {
+        let mut status = self.status.lock().unwrap();
+        status.runs += 1;
+        match result {
+            Ok(_) => {
+                status.last_success = Some(now().as_secs());
+                status.last_duration = duration;
+                tracing::info!(job = self.config.name, ?duration, "ran a scheduled job");
+            }
+            Err(e) => {
+                status.failures += 1;
+                tracing::error!(job = self.config.name, "scheduled job failed: {e}");
+            }
+        }
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{
+        message::Ref,
+        schema::{Schema, SchemaItem},
+        server::Server,
+    };
+
+    use super::*;
+
+    fn job(name: &str, function: &str, tenant: Option<&str>) -> JobConfig {
+        JobConfig {
+            name: name.to_string(),
+            schedule: "0 3 * * *".parse().unwrap(),
+            function: function.to_string(),
+            tenant: tenant.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn running_jobs() {
+        let schema = Schema::new(SchemaItem::Document(
+            [("sessions".to_string(), SchemaItem::Scalar)]
+                .into_iter()
+                .collect(),
+        ));
+        let server = Server::open_temporary(schema, sled::Config::new()).unwrap();
+        server
+            .insert(&Ref(Vec::new()), json!({ "sessions": "3" }))
+            .unwrap();
+        let bytecode = Functions::load_bytecode(
+            r#"
+            return {
+                cleanup = function(db)
+                    db.update({ "sessions" }, "0")
+                end,
+                broken = function(db)
+                    error("no")
+                end,
+            }
+            "#,
+        )
+        .unwrap();
+        let tenants = Arc::new(Tenants::new(server.clone(), &[], bytecode));
+
+        for (config, expected) in [
+            (job("a", "missing", None), "isn't in the functions script"),
+            (job("a", "cleanup", Some("blog")), "doesn't exist"),
+        ] {
+            let Err(err) = Jobs::new(tenants.clone(), &[config]) else {
+                panic!("expected the job to be rejected");
+            };
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+        assert!(matches!(
+            Jobs::new(
+                tenants.clone(),
+                &[job("a", "cleanup", None), job("a", "broken", None)]
+            ),
+            Err(JobError::DuplicateName(_))
+        ));
+
+        let jobs = Jobs::new(
+            tenants,
+            &[
+                job("cleanup", "cleanup", None),
+                job("broken", "broken", None),
+            ],
+        )
+        .unwrap();
+        for index in 0..2 {
+            let result = jobs.run(index);
+            jobs.jobs[index].record(&result, Duration::ZERO);
+        }
+        assert_eq!(server.get(&Ref(vec!["sessions".into()])).unwrap(), "0");
+        let statuses = jobs.statuses();
+        assert_eq!(statuses[0].0, "cleanup");
+        assert_eq!((statuses[0].1.runs, statuses[0].1.failures), (1, 0));
+        assert!(statuses[0].1.last_success.is_some());
+        assert_eq!((statuses[1].1.runs, statuses[1].1.failures), (1, 1));
+        assert!(statuses[1].1.last_success.is_none());
+    }
+}
@@ -0,0 +1,63 @@
+//! A schema-aware realtime document store, usable on its own through the `iceload` binary or
+//! embedded in another program.
+//!
+//! A [`Server`] holds the data, checked against a [`Schema`], and is read and written directly or
+//! through the messages in [`message`]. [`Permissions`] decide what clients may do, and
+//! [`app::serve`] runs everything the binary does: the WebSocket listener, HTTP gateway and the
+//! rest of what the config turns on. [`embedded::Store`] wraps a server for programs that use it
+//! in-process, with typed reads and writes.
+
+pub mod app;
+pub mod codec;
+pub mod config;
+pub mod embedded;
+pub mod error;
+pub mod logging;
+pub mod message;
+pub mod permission;
+pub mod profile;
+pub mod schema;
+pub mod server;
+
+mod accounts;
+mod admin;
+mod audit;
+mod backup;
+mod blob;
+mod changes;
+mod cron;
+mod delivery;
+mod dispatch;
+mod features;
+mod functions;
+mod gc;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handshake;
+mod history;
+mod http;
+mod integration;
+mod jobs;
+mod limits;
+mod listener;
+mod memory;
+#[cfg(feature = "nats")]
+mod nats;
+mod oidc;
+mod outbox;
+mod presence;
+mod query;
+mod registry;
+mod replay;
+mod replication;
+mod retention;
+mod search;
+mod session;
+mod slow;
+mod tenant;
+mod tls;
+mod webhook;
+
+pub use permission::Permissions;
+pub use schema::Schema;
+pub use server::{Server, ServerError};
@@ -0,0 +1,212 @@
+use std::io;
+
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    config::LimitsConfig,
+    error::ErrorKind,
+    message::{ClientMessage, Ref},
+};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LimitError {
+    #[error("messages may be at most {limit} bytes")]
+    MessageTooLarge { limit: usize },
+    #[error("refs may be at most {limit} components deep: {path}")]
+    RefTooDeep { path: Ref, limit: usize },
+    #[error("ref components may be at most {limit} bytes: {path}")]
+    ComponentTooLong { path: Ref, limit: usize },
+    #[error("values may be at most {limit} bytes of JSON: {path}")]
+    ValueTooLarge { path: Ref, limit: usize },
+    #[error("connections may hold at most {limit} subscriptions: {path}")]
+    TooManySubscriptions { path: Ref, limit: usize },
+    #[error("the server is holding as many subscriptions as it allows, {limit}: {path}")]
+    ServerSubscriptionsFull { path: Ref, limit: usize },
+}
+
+impl LimitError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::LimitExceeded
+    }
+
+    /// The ref in the rejected request that broke a limit, if it was one
+    pub fn path(&self) -> Option<&Ref> {
+        match self {
+            LimitError::MessageTooLarge { .. } => None,
+            LimitError::RefTooDeep { path, .. }
+            | LimitError::ComponentTooLong { path, .. }
+            | LimitError::ValueTooLarge { path, .. }
+            | LimitError::TooManySubscriptions { path, .. }
+            | LimitError::ServerSubscriptionsFull { path, .. } => Some(path),
+        }
+    }
+}
+
+/// Check a decoded message against the limits before it's handled. The size of the message as a
+/// whole is enforced by the WebSocket layer, before it's decoded.
+pub fn check(limits: &LimitsConfig, msg: &ClientMessage) -> Result<(), LimitError> {
+    if let ClientMessage::Envelope(envelope) = msg {
+        return check(limits, &envelope.message);
+    }
+    if let ClientMessage::Durable(write) | ClientMessage::Validate(write) = msg {
+        return check(limits, write);
+    }
+    if let ClientMessage::GetMany(paths) = msg {
+        return paths.iter().try_for_each(|path| check_ref(limits, path));
+    }
+    let Some(path) = msg.key() else {
+        return Ok(());
+    };
+    check_ref(limits, path)?;
+    if let ClientMessage::Insert(_, value)
+    | ClientMessage::Update(_, value)
+    | ClientMessage::Join(_, value) = msg
+    {
+        if json_len(value) > limits.max_value_bytes {
+            return Err(LimitError::ValueTooLarge {
+                path: path.clone(),
+                limit: limits.max_value_bytes,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn check_ref(limits: &LimitsConfig, path: &Ref) -> Result<(), LimitError> {
+    if path.0.len() > limits.max_ref_depth {
+        return Err(LimitError::RefTooDeep {
+            path: path.clone(),
+            limit: limits.max_ref_depth,
+        });
+    }
+    if path
+        .0
+        .iter()
+        .any(|component| component.len() > limits.max_component_bytes)
+    {
+        return Err(LimitError::ComponentTooLong {
+            path: path.clone(),
+            limit: limits.max_component_bytes,
+        });
+    }
+    Ok(())
+}
+
+/// Check that a connection holding `held` subscriptions may start another at `path`, while the
+/// server holds `total` across every connection
+pub fn check_subscription(
+    limits: &LimitsConfig,
+    path: &Ref,
+    held: usize,
+    total: usize,
+) -> Result<(), LimitError> {
+    if held >= limits.max_subscriptions_per_connection {
+        return Err(LimitError::TooManySubscriptions {
+            path: path.clone(),
+            limit: limits.max_subscriptions_per_connection,
+        });
+    }
+    if total >= limits.max_subscriptions {
+        return Err(LimitError::ServerSubscriptionsFull {
+            path: path.clone(),
+            limit: limits.max_subscriptions,
+        });
+    }
+    Ok(())
+}
+
+/// How long a value is as JSON, without building the string
+fn json_len(value: &Value) -> usize {
+    struct Counter(usize);
+
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, value).expect("writing to a counter can't fail");
+    counter.0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{
+        config::LimitsConfig,
+        message::{ClientMessage, Envelope, Ref},
+    };
+
+    use super::{check, check_subscription, LimitError};
+
+    #[test]
+    fn limits() {
+        let limits = LimitsConfig {
+            max_message_bytes: 1024,
+            max_ref_depth: 2,
+            max_component_bytes: 5,
+            max_value_bytes: 20,
+            max_subscriptions_per_connection: 2,
+            max_subscriptions: 3,
+        };
+        let path = |components: &[&str]| Ref(components.iter().map(|c| c.to_string()).collect());
+
+        assert_eq!(
+            check(&limits, &ClientMessage::Get(path(&["hello", "world"]))),
+            Ok(())
+        );
+        assert_eq!(
+            check(&limits, &ClientMessage::Get(path(&["a", "b", "c"]))),
+            Err(LimitError::RefTooDeep {
+                path: path(&["a", "b", "c"]),
+                limit: 2
+            })
+        );
+        assert_eq!(
+            check(&limits, &ClientMessage::Subscribe(path(&["planets"]))),
+            Err(LimitError::ComponentTooLong {
+                path: path(&["planets"]),
+                limit: 5
+            })
+        );
+
+        let write = ClientMessage::Insert(path(&["hello"]), json!({ "world": "earth" }));
+        assert_eq!(check(&limits, &write), Ok(()));
+        let write = ClientMessage::Update(path(&["hello"]), json!("a much longer value"));
+        assert!(matches!(
+            check(&limits, &write),
+            Err(LimitError::ValueTooLarge { .. })
+        ));
+        // Enveloped writes are checked too
+        let envelope = ClientMessage::Envelope(Envelope {
+            nonce: "nonce".into(),
+            timestamp: 0,
+            signature: String::new(),
+            message: Box::new(write),
+        });
+        assert!(matches!(
+            check(&limits, &envelope),
+            Err(LimitError::ValueTooLarge { .. })
+        ));
+
+        let planets = path(&["planets"]);
+        assert_eq!(check_subscription(&limits, &planets, 1, 2), Ok(()));
+        assert!(matches!(
+            check_subscription(&limits, &planets, 2, 2),
+            Err(LimitError::TooManySubscriptions { limit: 2, .. })
+        ));
+        assert!(matches!(
+            check_subscription(&limits, &planets, 0, 3),
+            Err(LimitError::ServerSubscriptionsFull { limit: 3, .. })
+        ));
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/// A connection accepted by a [`Listener`], whichever kind of socket it came in on
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+/// Where WebSocket connections come in: a TCP address, or a Unix socket for addresses written
+/// `unix:<path>`
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, String),
+}
+
+impl Listener {
+    /// Listen on `address`. A Unix socket left behind by a previous run is replaced.
+    pub async fn bind(address: &str) -> io::Result<Listener> {
+        match address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(
+                    tokio::net::UnixListener::bind(path)?,
+                    path.to_string(),
+                ))
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets aren't supported on this platform",
+            )),
+            None => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+        }
+    }
+
+    /// Wait for the next connection, returning it along with who it's from. Peers on a Unix
+    /// socket are usually unnamed, so they're described by the socket instead.
+    pub async fn accept(&self) -> io::Result<(Box<dyn Socket>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), format!("unix:{path}")))
+            }
+        }
+    }
+
+    /// The URL clients connect to, with the address actually listened on. Unix sockets are
+    /// given as `ws+unix://<path>`.
+    pub fn url(&self, tls: bool) -> io::Result<String> {
+        let scheme = if tls { "wss" } else { "ws" };
+        match self {
+            Listener::Tcp(listener) => Ok(format!("{scheme}://{}", listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(format!("{scheme}+unix://{path}")),
+        }
+    }
+}
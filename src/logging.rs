@@ -0,0 +1,47 @@
+use std::{io::IsTerminal, sync::Arc};
+
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    config::{LogConfig, LogFormat},
+    profile::Profiler,
+};
+
+/// Lets the tracing filter be replaced while the server is running
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Send tracing output to stderr, filtered by `RUST_LOG` if it's set or the configured filter if
+/// not. Falls back to logging everything at info level if the configured filter is invalid.
+///
+/// The profiler, if there is one, sees spans regardless of the filter.
+pub fn init(config: &LogConfig, profiler: Option<&Arc<Profiler>>) -> FilterHandle {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.filter))
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let output = match config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(profiler.map(|profiler| profiler.layer()))
+        .init();
+    handle
+}
+
+/// A filter that isn't attached to anything, for a server run by a program that sets up tracing
+/// itself. The admin API can't change it.
+pub fn detached() -> FilterHandle {
+    reload::Layer::new(EnvFilter::new("info")).1
+}
@@ -1,179 +1,438 @@
-use std::collections::HashMap;
-
-use futures_util::{SinkExt, StreamExt};
-use schema::{Schema, SchemaItem};
-use serde_json::Value;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{
-    accept_async,
-    tungstenite::{self, Error},
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-mod message;
-use message::{ClientMessage, ServerMessage};
-mod permission;
-mod schema;
-mod server;
-use server::Server;
+use clap::{Parser, Subcommand};
+use serde_json::Value;
 
-use crate::{
-    permission::{Operation, Permissions},
-    server::Event,
+use iceload::{
+    app::{self, open_server, ServeOptions},
+    config::{Config, LogFormat, TlsConfig},
+    logging,
+    profile::Profiler,
+    schema::Schema,
 };
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let addr = "127.0.0.1:9002";
-    let listener = TcpListener::bind(&addr).await?;
-
-    let source = std::fs::read_to_string("permission.luau")?;
-    let permission_bytecode = Permissions::load_bytecode(&source)?;
-
-    let test_schema = Schema::new(SchemaItem::Document(
-        [(
-            "hello".to_string(),
-            SchemaItem::Document(
-                [
-                    ("world".to_string(), SchemaItem::Scalar),
-                    ("new york".to_string(), SchemaItem::Scalar),
-                ]
-                .into_iter()
-                .collect(),
-            ),
-        )]
-        .into_iter()
-        .collect(),
-    ));
+mod codegen;
+mod import;
+mod rules_test;
+mod schema_check;
+mod shell;
+mod tabular;
 
-    let server = Server::open("data", test_schema)?;
-
-    while let Ok((stream, _)) = listener.accept().await {
-        let server = server.clone();
-        tokio::spawn(async move {
-            client_task(server, stream, permission_bytecode)
-                .await
-                .unwrap()
-        });
-    }
-
-    Ok(())
+#[derive(Parser)]
+#[command(about = "A schema-aware realtime document store")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Configuration file [default: iceload.toml, if it exists]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Address to accept WebSocket connections on [default: 127.0.0.1:9002]
+    #[arg(long)]
+    listen: Option<String>,
+    /// Address to serve the HTTP gateway on [default: 127.0.0.1:9003]
+    #[arg(long)]
+    http_listen: Option<String>,
+    /// Address to serve gRPC on [default: 127.0.0.1:9004]
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_listen: Option<std::net::SocketAddr>,
+    /// Address to serve the admin API on [default: disabled]
+    #[arg(long)]
+    admin_listen: Option<String>,
+    /// Which events to log, as RUST_LOG-style directives [default: info]
+    #[arg(long, global = true)]
+    log_filter: Option<String>,
+    /// How to format log output [default: text]
+    #[arg(long, global = true, value_enum)]
+    log_format: Option<LogFormat>,
+    /// The database directory [default: data]
+    #[arg(long)]
+    data: Option<String>,
+    /// Keep the database in a temporary directory that's deleted on exit
+    #[arg(long, conflicts_with = "data")]
+    ephemeral: bool,
+    /// The schema file [default: schema.json]
+    #[arg(long)]
+    schema: Option<PathBuf>,
+    /// The permission script [default: permission.luau]
+    #[arg(long)]
+    rules: Option<PathBuf>,
+    /// If the database is locked by another process, wait for it to be released instead of
+    /// exiting
+    #[arg(long, global = true)]
+    wait_for_lock: bool,
+    /// Convert data written by the legacy string-keyed server into the current layout on startup
+    #[arg(long)]
+    migrate_legacy: bool,
+    /// The separator between path components in legacy keys
+    #[arg(long, default_value = "/")]
+    legacy_separator: char,
+    /// A JSON file holding the whole tree to insert on startup, if the database is empty
+    #[arg(long)]
+    seed: Option<PathBuf>,
+    /// Explain how each request was handled to clients; never use in production
+    #[arg(long)]
+    dev: bool,
+    /// Replicate the server whose admin API is at this http:// URL, serving reads only
+    #[arg(long)]
+    follow: Option<String>,
+    /// PEM certificate chain to serve wss:// with; requires --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 }
 
-async fn client_task(
-    server: Server,
-    stream: TcpStream,
-    permission_bytecode: &[u8],
-) -> anyhow::Result<()> {
-    let permissions = Permissions::new(permission_bytecode);
-
-    let ws_stream = accept_async(stream).await.expect("Failed to accept");
-    let (mut ws_send, mut ws_recv) = ws_stream.split();
-
-    let (send_resp, mut recv_resp) = tokio::sync::mpsc::unbounded_channel();
+#[derive(Subcommand)]
+enum Command {
+    /// Generate typed path accessors from a schema file
+    Codegen {
+        /// The schema to generate accessors for
+        #[arg(default_value = "schema.json")]
+        schema: PathBuf,
+        #[arg(long, value_enum, default_value = "rust")]
+        language: codegen::Language,
+    },
+    /// Copy the database into a new directory, e.g. for a preview environment. This needs the
+    /// database to itself; `POST /fork` on the admin API forks a running server instead.
+    Fork {
+        /// Where to create the copy; must not already exist
+        destination: String,
+        /// The database to copy, if not the configured one
+        #[arg(long)]
+        data: Option<String>,
+    },
+    /// Write the whole database to a JSON file, e.g. for a backup
+    Dump {
+        /// The file to write
+        out: PathBuf,
+        /// The database to dump, if not the configured one
+        #[arg(long)]
+        data: Option<String>,
+    },
+    /// Replace the whole database with the contents of a JSON file written by `dump`
+    Restore {
+        /// The file to read
+        input: PathBuf,
+        /// The database to restore into, if not the configured one
+        #[arg(long)]
+        data: Option<String>,
+    },
+    /// Look for keys laid out wrongly in the database, such as items whose document or
+    /// collection isn't stored
+    Fsck {
+        /// The database to check, if not the configured one
+        #[arg(long)]
+        data: Option<String>,
+        /// Remove the keys that are laid out wrongly, or fix them where they can be. Keys the
+        /// legacy server wrote are among them, so migrate those first.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Import a Firebase Realtime Database or Firestore JSON export, reporting whatever doesn't
+    /// fit the schema
+    Import {
+        /// The export to read
+        input: PathBuf,
+        /// The database to import into, if not the configured one
+        #[arg(long)]
+        data: Option<String>,
+        /// Check the export against the schema and report what would be skipped, without
+        /// writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Write the documents of a collection as CSV, one row each with a column for each scalar
+    /// field, e.g. for a spreadsheet
+    ExportCsv {
+        /// The collection to export, e.g. `teams/core/members`
+        path: String,
+        /// The file to write, if not standard output
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// The database to export from, if not the configured one
+        #[arg(long)]
+        data: Option<String>,
+    },
+    /// Write each row of a CSV file, in the form `export-csv` writes, to a collection
+    ImportCsv {
+        /// The collection to import into
+        path: String,
+        /// The file to read
+        input: PathBuf,
+        /// The database to import into, if not the configured one
+        #[arg(long)]
+        data: Option<String>,
+    },
+    /// Interactively read and write data, either over the network or directly on disk
+    Shell {
+        /// The server to connect to
+        #[arg(long, default_value = "ws://127.0.0.1:9002")]
+        url: String,
+        /// Open this database directory directly instead of connecting to a server
+        #[arg(long)]
+        data: Option<String>,
+    },
+    /// Work with the permission rules
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+    /// Work with schema files
+    Schema {
+        #[command(subcommand)]
+        command: SchemaCommand,
+    },
+}
 
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = recv_resp.recv().await {
-            let resp_str = serde_json::to_string(&msg).unwrap();
-            ws_send
-                .send(tungstenite::Message::Text(resp_str))
-                .await
-                .unwrap();
-        }
-    });
+#[derive(Subcommand)]
+enum RulesCommand {
+    /// Run a file of declared test cases against the permission rules
+    Test {
+        /// JSON file containing a list of { op, path, user, expected } cases
+        cases: PathBuf,
+        /// The permission script to test
+        #[arg(long, default_value = "permission.luau")]
+        rules: PathBuf,
+    },
+}
 
-    let mut subscriptions = HashMap::new();
+#[derive(Subcommand)]
+enum SchemaCommand {
+    /// Look for problems in a schema file, such as fields declared twice or empty documents
+    Check {
+        /// The schema file to check
+        #[arg(default_value = "schema.json")]
+        file: PathBuf,
+        /// Also check that the data in this database directory, as read with the configured
+        /// schema, fits the one being checked
+        #[arg(long)]
+        data: Option<String>,
+    },
+}
 
-    while let Some(msg) = ws_recv.next().await {
-        let msg = match msg {
-            Ok(msg) => msg,
-            Err(Error::ConnectionClosed) => break,
-            Err(err) => return Err(err.into()),
-        };
-        let msg = msg.to_text()?;
-        let msg: ClientMessage = serde_json::from_str(msg)?;
-        match msg {
-            ClientMessage::Get(key) => {
-                if !permissions.check(Operation::Read, &key)? {
-                    send_resp.send(ServerMessage::Error("permissions".into()))?;
-                }
-                let value = server.get(&key).unwrap();
-                println!("Get result {value:?}");
-                send_resp.send(ServerMessage::Value(value)).unwrap();
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    // A broken config file is reported once the command needs it; until then log with defaults
+    let (log_config, profiling) = cli
+        .config()
+        .map(|config| (config.log, config.profiling))
+        .unwrap_or_default();
+    let profiler = profiling.then(|| Arc::new(Profiler::new()));
+    let log_filter = logging::init(&log_config, profiler.as_ref());
+    match cli.command {
+        None => {
+            let options = ServeOptions {
+                wait_for_lock: cli.wait_for_lock,
+                migrate_legacy: cli.migrate_legacy,
+                legacy_separator: cli.legacy_separator,
+                seed: cli.seed.clone(),
+            };
+            app::serve(cli.config()?, options, log_filter, profiler).await
+        }
+        Some(Command::Codegen { schema, language }) => {
+            print!("{}", codegen::generate(&Schema::load(&schema)?, language));
+            Ok(())
+        }
+        Some(Command::Fork {
+            ref destination,
+            ref data,
+        }) => {
+            let config = cli.config()?;
+            let data = data.as_ref().unwrap_or(&config.data);
+            open_server(data, &config, cli.wait_for_lock)
+                .await?
+                .fork(destination)?;
+            println!("forked {data} into {destination}");
+            Ok(())
+        }
+        Some(Command::Dump { ref out, ref data }) => {
+            let config = cli.config()?;
+            let data = data.as_ref().unwrap_or(&config.data);
+            let snapshot = open_server(data, &config, cli.wait_for_lock)
+                .await?
+                .dump()?;
+            std::fs::write(out, serde_json::to_string_pretty(&snapshot)? + "\n")?;
+            println!("dumped {data} to {}", out.display());
+            Ok(())
+        }
+        Some(Command::Restore {
+            ref input,
+            ref data,
+        }) => {
+            let config = cli.config()?;
+            let data = data.as_ref().unwrap_or(&config.data);
+            let snapshot: Value = serde_json::from_str(&std::fs::read_to_string(input)?)?;
+            let server = open_server(data, &config, cli.wait_for_lock).await?;
+            server.restore(&snapshot)?;
+            server.flush()?;
+            println!("restored {} into {data}", input.display());
+            Ok(())
+        }
+        Some(Command::Fsck { ref data, repair }) => {
+            let config = cli.config()?;
+            let data = data.as_ref().unwrap_or(&config.data);
+            let server = open_server(data, &config, cli.wait_for_lock).await?;
+            let problems = server.check_integrity(repair)?;
+            server.flush()?;
+            for problem in problems.iter() {
+                println!("{problem}");
             }
-            ClientMessage::Insert(key, value) => {
-                if !permissions.check(Operation::Insert, &key)? {
-                    send_resp.send(ServerMessage::Error("permissions".into()))?;
-                }
-                match server.insert(&key, value) {
-                    Ok(_) => send_resp.send(ServerMessage::Value(Value::Null)).unwrap(),
-                    Err(e) => send_resp
-                        .send(ServerMessage::Error(format!("{e}")))
-                        .unwrap(),
+            match (problems.len(), repair) {
+                (0, _) => println!("no problems found in {data}"),
+                (count, true) => println!("repaired {count} problems in {data}"),
+                (count, false) => {
+                    println!("found {count} problems in {data}; run with --repair to fix them");
+                    std::process::exit(1);
                 }
             }
-            ClientMessage::Update(key, value) => {
-                if !permissions.check(Operation::Update, &key)? {
-                    send_resp.send(ServerMessage::Error("permissions".into()))?;
-                }
-                match server.update(&key, value) {
-                    Ok(_) => send_resp.send(ServerMessage::Value(Value::Null)).unwrap(),
-                    Err(e) => send_resp
-                        .send(ServerMessage::Error(format!("{e}")))
-                        .unwrap(),
-                }
+            Ok(())
+        }
+        Some(Command::Import {
+            ref input,
+            ref data,
+            dry_run,
+        }) => {
+            let config = cli.config()?;
+            let data = data.as_ref().unwrap_or(&config.data);
+            let mut server = open_server(data, &config, cli.wait_for_lock).await?;
+            if dry_run {
+                server = server.dry_run();
+            }
+            let complete = import::run(input, &server)?;
+            server.flush()?;
+            if !complete {
+                std::process::exit(1);
             }
-            ClientMessage::Remove(key) => {
-                if !permissions.check(Operation::Remove, &key)? {
-                    send_resp.send(ServerMessage::Error("permissions".into()))?;
+            Ok(())
+        }
+        Some(Command::ExportCsv {
+            ref path,
+            ref out,
+            ref data,
+        }) => {
+            let config = cli.config()?;
+            let data = data.as_ref().unwrap_or(&config.data);
+            let server = open_server(data, &config, cli.wait_for_lock).await?;
+            let path = tabular::parse_path(path);
+            match out {
+                Some(out) => {
+                    let rows = tabular::export(&server, &path, std::fs::File::create(out)?)?;
+                    println!("exported {rows} rows of {path} to {}", out.display());
                 }
-                match server.remove(&key) {
-                    Ok(_) => send_resp.send(ServerMessage::Value(Value::Null)).unwrap(),
-                    Err(e) => send_resp
-                        .send(ServerMessage::Error(format!("{e}")))
-                        .unwrap(),
+                None => {
+                    tabular::export(&server, &path, std::io::stdout().lock())?;
                 }
             }
-            ClientMessage::Subscribe(key) => {
-                if !permissions.check(Operation::Read, &key)? {
-                    send_resp.send(ServerMessage::Error("permissions".into()))?;
-                }
-                let mut subscriber = server.subscribe(&key);
-                let sender = send_resp.clone();
-                let key_ = key.clone();
-                let handle = tokio::spawn(async move {
-                    while let Some(event) = subscriber.next().await {
-                        match event {
-                            Event::Insert { key: _, value } => {
-                                let value = String::from_utf8(value.to_vec()).unwrap();
-                                sender
-                                    .send(ServerMessage::SubscriptionUpdate(
-                                        key_.clone(),
-                                        Some(value),
-                                    ))
-                                    .unwrap();
-                            }
-                            Event::Remove { key: _ } => {
-                                sender
-                                    .send(ServerMessage::SubscriptionUpdate(key_.clone(), None))
-                                    .unwrap();
-                            }
-                        }
-                    }
-                });
-                subscriptions.insert(key, handle);
+            Ok(())
+        }
+        Some(Command::ImportCsv {
+            ref path,
+            ref input,
+            ref data,
+        }) => {
+            let config = cli.config()?;
+            let data = data.as_ref().unwrap_or(&config.data);
+            let server = open_server(data, &config, cli.wait_for_lock).await?;
+            let path = tabular::parse_path(path);
+            let report = tabular::import(&server, &path, std::fs::File::open(input)?)?;
+            server.flush()?;
+            for (row, reason) in report.rejected.iter() {
+                println!("skipped row {row}: {reason}");
             }
-            ClientMessage::Unsubscribe(key) => {
-                if let Some(handle) = subscriptions.get(&key) {
-                    handle.abort();
-                }
+            println!("imported {} rows into {path}", report.imported);
+            if !report.rejected.is_empty() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Some(Command::Shell { ref url, ref data }) => {
+            let backend = match data {
+                Some(data) => shell::Backend::direct(
+                    open_server(data, &cli.config()?, cli.wait_for_lock).await?,
+                ),
+                None => shell::Backend::connect(url).await?,
+            };
+            shell::run(backend).await
+        }
+        Some(Command::Rules {
+            command: RulesCommand::Test { cases, rules },
+        }) => {
+            if !rules_test::run(&rules, &cases)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Some(Command::Schema {
+            command: SchemaCommand::Check { ref file, ref data },
+        }) => {
+            let server = match data {
+                Some(data) => Some(open_server(data, &cli.config()?, cli.wait_for_lock).await?),
+                None => None,
+            };
+            if !schema_check::run(file, server.as_ref())? {
+                std::process::exit(1);
             }
+            Ok(())
         }
     }
+}
 
-    send_task.abort();
-    for subscriber in subscriptions.values() {
-        subscriber.abort();
+impl Cli {
+    /// The configuration file, overridden by any flags given on the command line
+    fn config(&self) -> anyhow::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::load_or_default(Path::new("iceload.toml"))?,
+        };
+        if let Some(listen) = &self.listen {
+            config.listen = listen.clone();
+        }
+        if let Some(http_listen) = &self.http_listen {
+            config.http_listen = http_listen.clone();
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_listen) = self.grpc_listen {
+            config.grpc_listen = grpc_listen;
+        }
+        if let Some(admin_listen) = &self.admin_listen {
+            config.admin_listen = Some(admin_listen.clone());
+        }
+        if let Some(log_filter) = &self.log_filter {
+            config.log.filter = log_filter.clone();
+        }
+        if let Some(log_format) = self.log_format {
+            config.log.format = log_format;
+        }
+        if let Some(data) = &self.data {
+            config.data = data.clone();
+        }
+        if self.ephemeral {
+            config.ephemeral = true;
+        }
+        if let Some(schema) = &self.schema {
+            config.schema = schema.clone();
+        }
+        if let Some(rules) = &self.rules {
+            config.rules = rules.clone();
+        }
+        if self.dev {
+            config.dev_mode = true;
+        }
+        if let Some(leader) = &self.follow {
+            config.replication.leader = Some(leader.clone());
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            config.tls = Some(TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+            });
+        }
+        Ok(config)
     }
-
-    Ok(())
 }
@@ -0,0 +1,116 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{Mutex, MutexGuard},
+};
+
+use sled::{Event, IVec};
+
+/// The ephemeral items of a tree, keyed by encoded ref like the store, but never written to disk
+#[derive(Default)]
+pub struct MemoryTree {
+    entries: Mutex<BTreeMap<Vec<u8>, IVec>>,
+}
+
+impl MemoryTree {
+    pub fn get(&self, key: &[u8]) -> Option<IVec> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    /// Every entry whose key starts with `prefix`, in the same form as a scan of the store
+    pub fn scan(&self, prefix: &[u8]) -> BTreeMap<IVec, IVec> {
+        scan(&self.entries.lock().unwrap(), prefix)
+    }
+
+    /// Run `tx` against the tree, keeping its writes only if it succeeds, and passing an event
+    /// for each to `publish`. Transactions run one at a time, so unlike sled's they're never
+    /// retried, and their events are published in the order they commit.
+    pub fn transaction<T, E>(
+        &self,
+        tx: impl FnOnce(&MemoryTransaction) -> Result<T, E>,
+        publish: impl Fn(Event),
+    ) -> Result<T, E> {
+        let mut entries = self.entries.lock().unwrap();
+        let transaction = MemoryTransaction {
+            entries: &entries,
+            writes: RefCell::new(BTreeMap::new()),
+        };
+        let result = tx(&transaction)?;
+        let writes = transaction.writes.into_inner();
+        apply(&mut entries, writes, publish);
+        Ok(result)
+    }
+
+    /// Remove everything whose key starts with `prefix`, passing an event for each to `publish`
+    pub fn remove_prefix(&self, prefix: &[u8], publish: impl Fn(Event)) {
+        let mut entries = self.entries.lock().unwrap();
+        let removed = scan(&entries, prefix)
+            .into_keys()
+            .map(|key| (key.to_vec(), None))
+            .collect();
+        apply(&mut entries, removed, publish);
+    }
+}
+
+/// The writes of a `MemoryTree::transaction`, which it reads back before they're applied
+pub struct MemoryTransaction<'a> {
+    entries: &'a MutexGuard<'a, BTreeMap<Vec<u8>, IVec>>,
+    writes: RefCell<BTreeMap<Vec<u8>, Option<IVec>>>,
+}
+
+impl MemoryTransaction<'_> {
+    pub fn get(&self, key: &[u8]) -> Option<IVec> {
+        match self.writes.borrow().get(key) {
+            Some(written) => written.clone(),
+            None => self.entries.get(key).cloned(),
+        }
+    }
+
+    pub fn insert(&self, key: &[u8], value: &[u8]) {
+        self.writes
+            .borrow_mut()
+            .insert(key.to_vec(), Some(value.into()));
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        self.writes.borrow_mut().insert(key.to_vec(), None);
+    }
+
+    /// Every entry whose key starts with `prefix`, as of the start of the transaction
+    pub fn scan(&self, prefix: &[u8]) -> BTreeMap<IVec, IVec> {
+        scan(self.entries, prefix)
+    }
+}
+
+fn scan(entries: &BTreeMap<Vec<u8>, IVec>, prefix: &[u8]) -> BTreeMap<IVec, IVec> {
+    entries
+        .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, value)| (IVec::from(&key[..]), value.clone()))
+        .collect()
+}
+
+/// Make `writes`, publishing an event for each that changed anything
+fn apply(
+    entries: &mut BTreeMap<Vec<u8>, IVec>,
+    writes: BTreeMap<Vec<u8>, Option<IVec>>,
+    publish: impl Fn(Event),
+) {
+    for (key, value) in writes {
+        match value {
+            Some(value) => {
+                entries.insert(key.clone(), value.clone());
+                publish(Event::Insert {
+                    key: key.into(),
+                    value,
+                });
+            }
+            None => {
+                if entries.remove(&key).is_some() {
+                    publish(Event::Remove { key: key.into() });
+                }
+            }
+        }
+    }
+}
@@ -1,26 +1,870 @@
+use std::{collections::BTreeSet, fmt::Display};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use ts_rs::TS;
+
+use crate::{
+    accounts::AccountError, error::ErrorKind, functions::FunctionError, limits::LimitError,
+    oidc::OidcError, permission::Operation, server::ServerError,
+};
+
+// TypeScript definitions for these types are exported into bindings/ when running `cargo test`
 
 // TODO: should reads / writes be over the websocket or in a different band?
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The version of the protocol described by these messages. It changes whenever a change to them
+/// would break existing clients.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The WebSocket subprotocol clients may ask for, naming the protocol version they speak
+pub const SUBPROTOCOL: &str = "iceload.v1";
+
+/// A ref component that stands for the user the connection signed in or authenticated as, which
+/// the server fills in before the ref is used, e.g. `["users", "$uid", "inbox"]`
+pub const USER_PLACEHOLDER: &str = "$uid";
+
+#[derive(Clone, Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
 pub enum ClientMessage {
+    /// The first message on every connection: the protocol version the client speaks, the
+    /// optional features it supports, and how the server should encode what it sends. The server
+    /// answers with `Welcome`, or with an `IncompatibleProtocol` error before closing the
+    /// connection. With the `deflate` feature, everything after the `Welcome` is compressed with
+    /// raw deflate and sent in binary frames, whichever the encoding.
+    Hello {
+        protocol_version: u32,
+        features: BTreeSet<String>,
+        #[serde(default)]
+        encoding: Encoding,
+        /// The session from the `Welcome` of a connection that dropped, to resume its
+        /// subscriptions
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[ts(optional)]
+        session: Option<String>,
+    },
     Get(Ref),
+    /// Read several items as they all were at one moment, answered with a `Value` holding an
+    /// array of them in the same order. Writes made while they're read never leave some of them
+    /// older than others.
+    GetMany(Vec<Ref>),
+    /// Read an item with the `Reference` fields in it replaced by the items they point at,
+    /// following references up to the given depth. Targets the client may not read, or that
+    /// don't exist, are sent as null.
+    GetExpanded(Ref, u32),
+    /// Read an item like `Get`, but with the documents and collections nested more than the
+    /// given depth below it sent as `{ "$ref": <ref> }` placeholders, which can be read on their
+    /// own later. The members of a collection are at the same depth as the collection.
+    GetShallow(Ref, u32),
+    /// Read only the given fields of an item, as paths relative to it, answered with a `Value`
+    /// shaped like the item but holding nothing else
+    GetFields(Ref, Vec<Vec<RefComponent>>),
+    /// Read an item like `Get`, but receive a collection or document as a series of `ValueChunk`s,
+    /// so a large one never has to be held in memory whole on either side
+    GetChunked(Ref),
+    /// Read when the document at a ref was created and last written, answered with a `Value` of
+    /// `{ "created_at": <millis>, "updated_at": <millis> }` by the server's clock, or null if the
+    /// document hasn't been written since the server started keeping them
+    GetMetadata(Ref),
+    /// Read the revisions kept of the document at a ref, answered with a `Value` of an array of
+    /// `{ "revision": <n>, "at": <millis>, "value": <document> }`, oldest first. A removed
+    /// document's last revision is null. Empty unless the server keeps history.
+    GetHistory(Ref),
+    /// Read one revision of a document, as listed by `GetHistory`, or `KeyNotFound` if it's no
+    /// longer kept
+    GetAt(Ref, #[ts(type = "number")] u64),
+    /// Find the documents at or under a ref with every word of the query in their searchable
+    /// items, answered with a `Value` of an array of their refs. Words are matched whole and
+    /// without regard to case.
+    Search(Ref, String),
+    /// Read the members of a collection that match a filter, answered with a `Value` of an
+    /// object of them by key, like `Get` of the collection would
+    Query(Ref, Filter),
+    /// Read the keys of the members of a collection, without their values, answered with a
+    /// `Value` of an array of them in the order `GetChunked` reads the members
+    ListKeys(Ref),
+    /// Compute a count or numeric aggregate over the members of a collection, answered with a
+    /// `Value` of the result
+    Aggregate(Ref, Aggregation),
     Insert(Ref, Value),
     Update(Ref, Value),
     Remove(Ref),
     Subscribe(Ref),
+    /// Subscribe like `Subscribe`, but hold each update for up to the given number of
+    /// milliseconds, sending only the latest of any further writes to the same ref in that time
+    SubscribeDebounced(Ref, u32),
+    /// Subscribe to an item, receiving `ResumableUpdate`s that each carry a token. After
+    /// reconnecting, passing the last token received only sends an update if the item has
+    /// changed since; without one, the item is sent right away. Cancelled with `Unsubscribe`.
+    SubscribeFrom {
+        key: Ref,
+        #[ts(type = "number | null")]
+        token: Option<u64>,
+    },
+    /// Subscribe to every item matching a pattern, where a `*` component stands for any member
+    /// of a collection, receiving a `PatternUpdate` for each write to one. Cancelled with
+    /// `Unsubscribe` on the pattern.
+    SubscribePattern(Ref),
     Unsubscribe(Ref),
+    /// Subscribe to whatever item a `Reference` field points at. Each update carries the whole
+    /// target, and the subscription moves along if the field is repointed. Cancelled with
+    /// `Unsubscribe` on the field.
+    Follow(Ref),
+    /// Set a member of a presence collection until this connection leaves it or closes.
+    /// Subscribers to the collection see members join and leave as inserts and removes.
+    Join(Ref, Value),
+    /// Remove a presence member this connection joined
+    Leave(Ref),
+    /// Describe the item at the ref, answered with a `Value` holding its part of the schema in the
+    /// same form as the schema file, so clients can build forms and check input without a copy of
+    /// it. Document fields the client may not read are left out.
+    DescribeSchema(Ref),
+    /// Ask the permission rules whether the connection's user may do an operation on a ref,
+    /// without doing it, answered with a `Value` holding the `RuleCheck`. Rules that fail count
+    /// as a denial, with why in its `error`, so a UI can disable what it would be refused.
+    CanI(Operation, Ref),
+    /// Run a function from the server's functions script, which answers with a `Value` holding
+    /// whatever it returns
+    Call {
+        name: String,
+        #[ts(type = "unknown")]
+        args: Value,
+    },
+    /// Create an account among the server's own user accounts and sign in as it, answered with a
+    /// `Value` of `{ "user": <id>, "token": <token> }`. Only accepted if the server keeps
+    /// accounts.
+    SignUp {
+        username: String,
+        password: String,
+    },
+    /// Sign in to an account, answered like `SignUp`. Everything the connection sends afterwards
+    /// is checked against the permission rules as that account's user, and subscriptions started
+    /// as another user end.
+    SignIn(Credentials),
+    /// Stop acting as the signed-in user, ending the subscriptions started as them, and revoke
+    /// the token the connection signed in with
+    SignOut,
+    /// Authenticate with an ID token from one of the OpenID Connect providers the server trusts,
+    /// answered with a `Value` of `{ "user": <sub> }`. Like `SignIn`, everything the connection
+    /// sends afterwards is checked as that user, along with the claims the provider is configured
+    /// to pass on, until the token expires; sending a fresh token carries on as the same user.
+    Auth(String),
+    Envelope(Envelope),
+    /// Make an `Insert`, `Update` or `Remove` that isn't answered until it's been flushed to
+    /// disk, so a success means it will survive a crash. Goes inside an `Envelope`, if there is
+    /// one.
+    Durable(Box<ClientMessage>),
+    /// Check an `Insert`, `Update` or `Remove` against the schema and the permission rules
+    /// without making it, answered as the write would be. Nothing is written, so it needs no
+    /// `Envelope`.
+    Validate(Box<ClientMessage>),
+}
+
+impl ClientMessage {
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::Insert(..) | ClientMessage::Update(..) | ClientMessage::Remove(..)
+        ) || matches!(self, ClientMessage::Durable(write) if write.is_write())
+    }
+
+    /// Whether replaying the message could change anything: a write, or a call to a function,
+    /// which may write
+    pub fn has_effects(&self) -> bool {
+        self.is_write() || matches!(self, ClientMessage::Call { .. })
+    }
+
+    /// Whether the message starts a subscription, which lasts until it's cancelled with
+    /// `Unsubscribe`
+    pub fn is_subscription(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::Subscribe(_)
+                | ClientMessage::SubscribeDebounced(..)
+                | ClientMessage::SubscribeFrom { .. }
+                | ClientMessage::SubscribePattern(_)
+                | ClientMessage::Follow(_)
+        )
+    }
+
+    /// The name of the operation, for logs
+    pub fn op(&self) -> &'static str {
+        match self {
+            ClientMessage::Hello { .. } => "hello",
+            ClientMessage::Get(_) => "get",
+            ClientMessage::GetMany(_) => "get_many",
+            ClientMessage::GetExpanded(..) => "get_expanded",
+            ClientMessage::GetShallow(..) => "get_shallow",
+            ClientMessage::GetFields(..) => "get_fields",
+            ClientMessage::GetChunked(_) => "get_chunked",
+            ClientMessage::GetMetadata(_) => "get_metadata",
+            ClientMessage::GetHistory(_) => "get_history",
+            ClientMessage::GetAt(..) => "get_at",
+            ClientMessage::Search(..) => "search",
+            ClientMessage::Query(..) => "query",
+            ClientMessage::ListKeys(_) => "list_keys",
+            ClientMessage::Aggregate(..) => "aggregate",
+            ClientMessage::Insert(..) => "insert",
+            ClientMessage::Update(..) => "update",
+            ClientMessage::Remove(_) => "remove",
+            ClientMessage::Subscribe(_) => "subscribe",
+            ClientMessage::SubscribeDebounced(..) => "subscribe_debounced",
+            ClientMessage::SubscribeFrom { .. } => "subscribe_from",
+            ClientMessage::SubscribePattern(_) => "subscribe_pattern",
+            ClientMessage::Unsubscribe(_) => "unsubscribe",
+            ClientMessage::Follow(_) => "follow",
+            ClientMessage::Join(..) => "join",
+            ClientMessage::Leave(_) => "leave",
+            ClientMessage::DescribeSchema(_) => "describe_schema",
+            ClientMessage::CanI(..) => "can_i",
+            ClientMessage::Call { .. } => "call",
+            ClientMessage::SignUp { .. } => "sign_up",
+            ClientMessage::SignIn(_) => "sign_in",
+            ClientMessage::SignOut => "sign_out",
+            ClientMessage::Auth(_) => "auth",
+            ClientMessage::Envelope(_) => "envelope",
+            ClientMessage::Durable(_) => "durable",
+            ClientMessage::Validate(_) => "validate",
+        }
+    }
+
+    /// The ref the message reads, writes, or subscribes to
+    pub fn key(&self) -> Option<&Ref> {
+        match self {
+            ClientMessage::Get(key)
+            | ClientMessage::GetExpanded(key, _)
+            | ClientMessage::GetShallow(key, _)
+            | ClientMessage::GetFields(key, _)
+            | ClientMessage::GetChunked(key)
+            | ClientMessage::GetMetadata(key)
+            | ClientMessage::GetHistory(key)
+            | ClientMessage::GetAt(key, _)
+            | ClientMessage::Search(key, _)
+            | ClientMessage::Query(key, _)
+            | ClientMessage::ListKeys(key)
+            | ClientMessage::Aggregate(key, _)
+            | ClientMessage::Insert(key, _)
+            | ClientMessage::Update(key, _)
+            | ClientMessage::Remove(key)
+            | ClientMessage::Subscribe(key)
+            | ClientMessage::SubscribeDebounced(key, _)
+            | ClientMessage::SubscribeFrom { key, .. }
+            | ClientMessage::SubscribePattern(key)
+            | ClientMessage::Unsubscribe(key)
+            | ClientMessage::Follow(key)
+            | ClientMessage::Join(key, _)
+            | ClientMessage::Leave(key)
+            | ClientMessage::DescribeSchema(key)
+            | ClientMessage::CanI(_, key) => Some(key),
+            ClientMessage::Durable(write) | ClientMessage::Validate(write) => write.key(),
+            ClientMessage::Hello { .. }
+            | ClientMessage::GetMany(_)
+            | ClientMessage::Call { .. }
+            | ClientMessage::SignUp { .. }
+            | ClientMessage::SignIn(_)
+            | ClientMessage::SignOut
+            | ClientMessage::Auth(_)
+            | ClientMessage::Envelope(_) => None,
+        }
+    }
+
+    /// Fill in the `$uid` components of the message's refs with `user`. A client that isn't
+    /// signed in can't, so the first ref with one is returned and the message is left unbound.
+    pub fn bind_user(&mut self, user: Option<&str>) -> Result<(), Ref> {
+        let mut refs = self.refs_mut();
+        let placeholder = |component: &String| component == USER_PLACEHOLDER;
+        let Some(user) = user else {
+            return match refs.into_iter().find(|key| key.0.iter().any(placeholder)) {
+                Some(key) => Err(key.clone()),
+                None => Ok(()),
+            };
+        };
+        for key in refs.iter_mut() {
+            for component in key.0.iter_mut().filter(|component| placeholder(component)) {
+                *component = user.to_string();
+            }
+        }
+        Ok(())
+    }
+
+    fn refs_mut(&mut self) -> Vec<&mut Ref> {
+        match self {
+            ClientMessage::GetMany(keys) => keys.iter_mut().collect(),
+            ClientMessage::Durable(write) | ClientMessage::Validate(write) => write.refs_mut(),
+            ClientMessage::Envelope(envelope) => envelope.message.refs_mut(),
+            ClientMessage::Get(key)
+            | ClientMessage::GetExpanded(key, _)
+            | ClientMessage::GetShallow(key, _)
+            | ClientMessage::GetFields(key, _)
+            | ClientMessage::GetChunked(key)
+            | ClientMessage::GetMetadata(key)
+            | ClientMessage::GetHistory(key)
+            | ClientMessage::GetAt(key, _)
+            | ClientMessage::Search(key, _)
+            | ClientMessage::Query(key, _)
+            | ClientMessage::ListKeys(key)
+            | ClientMessage::Aggregate(key, _)
+            | ClientMessage::Insert(key, _)
+            | ClientMessage::Update(key, _)
+            | ClientMessage::Remove(key)
+            | ClientMessage::Subscribe(key)
+            | ClientMessage::SubscribeDebounced(key, _)
+            | ClientMessage::SubscribeFrom { key, .. }
+            | ClientMessage::SubscribePattern(key)
+            | ClientMessage::Unsubscribe(key)
+            | ClientMessage::Follow(key)
+            | ClientMessage::Join(key, _)
+            | ClientMessage::Leave(key)
+            | ClientMessage::DescribeSchema(key)
+            | ClientMessage::CanI(_, key) => vec![key],
+            ClientMessage::Hello { .. }
+            | ClientMessage::Call { .. }
+            | ClientMessage::SignUp { .. }
+            | ClientMessage::SignIn(_)
+            | ClientMessage::SignOut
+            | ClientMessage::Auth(_) => Vec::new(),
+        }
+    }
+}
+
+/// What a client signs in to an account with
+#[derive(Clone, Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum Credentials {
+    Password {
+        username: String,
+        password: String,
+    },
+    /// As a new user with no account, if the server allows it. The token the sign in is answered
+    /// with signs in as the same user again.
+    Anonymous,
+    /// A token from an earlier `SignUp` or `SignIn`, which lasts until it's signed out of
+    Token(String),
+}
+
+/// How messages are encoded on a WebSocket connection. Clients may send requests in either
+/// encoding, as JSON in text frames or MessagePack in binary frames, but the server sends
+/// everything from its `Welcome` onwards in the one picked in the `Hello`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Which members of a collection a `Query` reads. Fields are checked against the schema before
+/// anything is read.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum Filter {
+    /// Members matching every filter, or every member if there are none
+    And(Vec<Filter>),
+    /// Members matching any of the filters
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    /// Members whose scalar at `field`, a path relative to the member, compares to `value`. A
+    /// field that isn't set compares as null.
+    Compare {
+        field: Vec<RefComponent>,
+        op: Comparison,
+        #[ts(type = "unknown")]
+        value: Value,
+    },
+}
+
+/// How a `Filter::Compare` compares a field to its value. Ordering compares numbers with numbers
+/// and strings with strings, and fails for anything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// The field is a string starting with the value, which must be a string
+    Prefix,
+}
+
+/// What an `Aggregate` computes over the members of a collection. The fields are paths relative
+/// to each member, like those of a `Filter`, and members whose field isn't a number are skipped.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum Aggregation {
+    /// How many members there are
+    Count,
+    /// The total of the field, or 0 if no member has a number there
+    Sum(Vec<RefComponent>),
+    /// The smallest value of the field, or null if no member has a number there
+    Min(Vec<RefComponent>),
+    /// The largest value of the field, or null if no member has a number there
+    Max(Vec<RefComponent>),
+}
+
+/// A write or function call tagged with a single-use nonce and the time it was sent (unix millis),
+/// so the server can reject replays of captured traffic
+#[derive(Clone, Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct Envelope {
+    pub nonce: String,
+    #[ts(type = "number")]
+    pub timestamp: u64,
+    /// The hex HMAC-SHA256, keyed with the session token from the connection's `Welcome`, of the
+    /// nonce, the timestamp, and the message written as JSON with the keys of every object
+    /// sorted, each on its own line. Binds them together, so a captured message can't be wrapped
+    /// again with a fresh nonce.
+    pub signature: String,
+    pub message: Box<ClientMessage>,
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
 pub enum ServerMessage {
+    /// The answer to `Hello`, with the protocol version the connection will use and the features
+    /// enabled for it, which are among those the client said it supports
+    Welcome {
+        protocol_version: u32,
+        features: BTreeSet<String>,
+        /// Sent in the `Hello` of a later connection to resume this one's subscriptions, if this
+        /// one drops
+        session: String,
+        /// Whether the session the `Hello` asked for was resumed. If it was, its subscriptions
+        /// carry on, and an update for each item changed while disconnected follows.
+        resumed: bool,
+    },
     Value(Value),
-    Error(String),
+    /// Part of an item requested with `GetChunked`: an object holding some of the members of a
+    /// collection, or fields of a document. Merging the objects of every chunk gives the whole
+    /// item, and the final chunk is marked `last`. A scalar, or a document with nothing stored in
+    /// it, is sent as a single `Value` instead, and an `Error` ends the series early.
+    ValueChunk {
+        members: Value,
+        last: bool,
+    },
+    Error(ErrorMessage),
     SubscriptionUpdate(Ref, Option<String>),
+    /// The whole item at a key subscribed to with `SubscribeFrom`, or null if there's nothing
+    /// there, sent whenever a change touches it. `token` resumes the subscription from just after
+    /// the changes this update reflects.
+    ResumableUpdate {
+        key: Ref,
+        value: Value,
+        #[ts(type = "number")]
+        token: u64,
+    },
+    /// A write to an item matching a pattern subscribed to with `SubscribePattern`: `key` is the
+    /// ref written, and `value` is as in a `SubscriptionUpdate`
+    PatternUpdate {
+        pattern: Ref,
+        key: Ref,
+        value: Option<String>,
+    },
+    /// Sent instead of a `SubscriptionUpdate` without a value when the item, or something in it,
+    /// was removed by the server itself rather than by a client, e.g. when cleaning up an item
+    /// left behind without the document containing it
+    SubscriptionExpired(Ref),
+    /// Sent instead of a `PatternUpdate` without a value when the server removed the item itself,
+    /// as for `SubscriptionExpired`
+    PatternExpired {
+        pattern: Ref,
+        key: Ref,
+    },
+    /// Sent before the server closes the connection because it is shutting down
+    ServerShutdown,
+    /// Sent when an operator replaces the schema, after which requests are checked against the
+    /// new one. Clients can read what changed with `DescribeSchema`.
+    SchemaChanged,
+    /// Sent in dev mode just before the response to each request, describing how the server
+    /// handled it
+    Explain(Explanation),
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
+/// Why a request failed, in a form clients can act on without parsing prose
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    /// The ref the error is about, if it's about one
+    pub path: Option<Ref>,
+    /// An explanation for people, which may change between versions
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub enum ErrorCode {
+    /// The permission rules don't allow the request
+    PermissionDenied,
+    /// The schema allows the ref, but nothing is stored there
+    KeyNotFound,
+    /// The ref doesn't exist in the schema
+    InvalidPath,
+    /// The value doesn't match the shape the schema expects
+    SchemaMismatch,
+    /// The client is sending requests faster than it's allowed to
+    RateLimited,
+    /// The request, or a ref or value in it, is larger than the server accepts
+    LimitExceeded,
+    /// The server is a read-only follower and can't accept writes
+    ReadOnly,
+    /// Writes are paused while the server is maintained; reads and subscriptions carry on
+    Maintenance,
+    /// Replay protection rejected the write
+    Replay,
+    /// The request isn't valid here, regardless of what's stored
+    InvalidRequest,
+    /// The connection didn't open with a `Hello` for a protocol version the server speaks
+    IncompatibleProtocol,
+    /// An administrator is closing the connection
+    Disconnected,
+    /// A function run with `Call` raised an error
+    FunctionFailed,
+    /// A `SignIn` named an account that doesn't exist, with the wrong password, or a token that
+    /// was revoked, or an `Auth` sent an ID token that isn't valid
+    InvalidCredentials,
+    /// The server failed to handle the request, through no fault of the client
+    Internal,
+}
+
+impl From<ErrorKind> for ErrorCode {
+    fn from(kind: ErrorKind) -> ErrorCode {
+        match kind {
+            ErrorKind::InvalidPath => ErrorCode::InvalidPath,
+            ErrorKind::NotFound => ErrorCode::KeyNotFound,
+            ErrorKind::SchemaMismatch => ErrorCode::SchemaMismatch,
+            ErrorKind::Replay => ErrorCode::Replay,
+            ErrorKind::ReadOnly => ErrorCode::ReadOnly,
+            ErrorKind::Maintenance => ErrorCode::Maintenance,
+            ErrorKind::LimitExceeded => ErrorCode::LimitExceeded,
+            ErrorKind::Storage
+            | ErrorKind::Locked
+            | ErrorKind::InvalidSchema
+            | ErrorKind::Script
+            | ErrorKind::Trimmed => ErrorCode::Internal,
+        }
+    }
+}
+
+impl ServerMessage {
+    /// An error response that isn't about any particular ref
+    pub fn error(code: ErrorCode, message: impl Display) -> ServerMessage {
+        ServerMessage::Error(ErrorMessage {
+            code,
+            path: None,
+            message: Some(message.to_string()),
+        })
+    }
+}
+
+impl From<&ServerError> for ServerMessage {
+    fn from(e: &ServerError) -> ServerMessage {
+        ServerMessage::Error(ErrorMessage {
+            code: e.kind().into(),
+            path: e.path().cloned(),
+            message: Some(e.to_string()),
+        })
+    }
+}
+
+impl From<&FunctionError> for ServerMessage {
+    fn from(e: &FunctionError) -> ServerMessage {
+        let code = match e.kind() {
+            ErrorKind::Script => ErrorCode::FunctionFailed,
+            kind => kind.into(),
+        };
+        ServerMessage::Error(ErrorMessage {
+            code,
+            path: e.path().cloned(),
+            message: Some(e.to_string()),
+        })
+    }
+}
+
+impl From<&AccountError> for ServerMessage {
+    fn from(e: &AccountError) -> ServerMessage {
+        let code = match e {
+            AccountError::Empty | AccountError::Taken(_) | AccountError::Disabled(_) => {
+                ErrorCode::InvalidRequest
+            }
+            AccountError::InvalidCredentials => ErrorCode::InvalidCredentials,
+            AccountError::Store(e) => e.kind().into(),
+        };
+        ServerMessage::error(code, e)
+    }
+}
+
+impl From<&OidcError> for ServerMessage {
+    fn from(e: &OidcError) -> ServerMessage {
+        let code = match e {
+            // The provider not answering isn't the client's fault
+            OidcError::Fetch { .. } => ErrorCode::Internal,
+            _ => ErrorCode::InvalidCredentials,
+        };
+        ServerMessage::error(code, e)
+    }
+}
+
+impl From<&LimitError> for ServerMessage {
+    fn from(e: &LimitError) -> ServerMessage {
+        ServerMessage::Error(ErrorMessage {
+            code: e.kind().into(),
+            path: e.path().cloned(),
+            message: Some(e.to_string()),
+        })
+    }
+}
+
+impl Display for ErrorMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.message, &self.path) {
+            (Some(message), _) => write!(f, "{message}"),
+            (None, Some(path)) => write!(f, "{:?} at {path}", self.code),
+            (None, None) => write!(f, "{:?}", self.code),
+        }
+    }
+}
+
+/// How the server handled a request, to help while building an app against it
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct Explanation {
+    /// The schema node the request's path matched, or why it matched none
+    pub schema: String,
+    /// What the permission rules were asked and what they answered, if the request needed them
+    pub rule: Option<RuleCheck>,
+}
+
+#[derive(Debug, Deserialize, Serialize, TS)]
+#[ts(export)]
+pub struct RuleCheck {
+    /// The operation the rules were asked about, e.g. "read"
+    pub op: String,
+    pub path: Ref,
+    pub user: Option<String>,
+    pub allowed: bool,
+    /// Why the rules failed to answer, which counts as a denial
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize, TS)]
+#[ts(export)]
 pub struct Ref(pub Vec<RefComponent>);
 
+impl Ref {
+    /// This ref extended by one component
+    pub fn child(&self, component: &str) -> Ref {
+        let mut child = self.clone();
+        child.0.push(component.to_string());
+        child
+    }
+
+    /// Whether either ref is at or beneath the other, so a write to one can change the other
+    pub fn overlaps(&self, other: &Ref) -> bool {
+        self.0.starts_with(&other.0) || other.0.starts_with(&self.0)
+    }
+
+    /// Whether this ref is at or beneath one matching `pattern`, where a `*` component matches
+    /// any component
+    pub fn matches(&self, pattern: &Ref) -> bool {
+        self.0.len() >= pattern.0.len()
+            && (self.0.iter().zip(&pattern.0))
+                .all(|(component, pattern)| pattern == "*" || component == pattern)
+    }
+}
+
+impl<const N: usize> From<[&str; N]> for Ref {
+    fn from(components: [&str; N]) -> Ref {
+        Ref(components.map(String::from).to_vec())
+    }
+}
+
+impl Display for Ref {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/{}", self.0.join("/"))
+    }
+}
+
 pub type RefComponent = String;
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    // conformance/messages.json is published for alternative client implementations to check
+    // themselves against, so it has to match what the server actually does
+    #[derive(Deserialize)]
+    struct Fixture {
+        name: String,
+        #[serde(rename = "type")]
+        ty: String,
+        json: String,
+    }
+
+    fn client_variant(message: &ClientMessage) -> &'static str {
+        match message {
+            ClientMessage::Hello { .. } => "Hello",
+            ClientMessage::Get(_) => "Get",
+            ClientMessage::GetMany(_) => "GetMany",
+            ClientMessage::GetExpanded(..) => "GetExpanded",
+            ClientMessage::GetShallow(..) => "GetShallow",
+            ClientMessage::GetFields(..) => "GetFields",
+            ClientMessage::GetChunked(_) => "GetChunked",
+            ClientMessage::GetMetadata(_) => "GetMetadata",
+            ClientMessage::GetHistory(_) => "GetHistory",
+            ClientMessage::GetAt(..) => "GetAt",
+            ClientMessage::Search(..) => "Search",
+            ClientMessage::Query(..) => "Query",
+            ClientMessage::ListKeys(_) => "ListKeys",
+            ClientMessage::Aggregate(..) => "Aggregate",
+            ClientMessage::Insert(..) => "Insert",
+            ClientMessage::Update(..) => "Update",
+            ClientMessage::Remove(_) => "Remove",
+            ClientMessage::Subscribe(_) => "Subscribe",
+            ClientMessage::SubscribeDebounced(..) => "SubscribeDebounced",
+            ClientMessage::SubscribeFrom { .. } => "SubscribeFrom",
+            ClientMessage::SubscribePattern(_) => "SubscribePattern",
+            ClientMessage::Unsubscribe(_) => "Unsubscribe",
+            ClientMessage::Follow(_) => "Follow",
+            ClientMessage::Join(..) => "Join",
+            ClientMessage::Leave(_) => "Leave",
+            ClientMessage::DescribeSchema(_) => "DescribeSchema",
+            ClientMessage::CanI(..) => "CanI",
+            ClientMessage::Call { .. } => "Call",
+            ClientMessage::SignUp { .. } => "SignUp",
+            ClientMessage::SignIn(_) => "SignIn",
+            ClientMessage::SignOut => "SignOut",
+            ClientMessage::Auth(_) => "Auth",
+            ClientMessage::Envelope(_) => "Envelope",
+            ClientMessage::Durable(_) => "Durable",
+            ClientMessage::Validate(_) => "Validate",
+        }
+    }
+
+    fn server_variant(message: &ServerMessage) -> &'static str {
+        match message {
+            ServerMessage::Welcome { .. } => "Welcome",
+            ServerMessage::Value(_) => "Value",
+            ServerMessage::ValueChunk { .. } => "ValueChunk",
+            ServerMessage::Error(_) => "Error",
+            ServerMessage::SubscriptionUpdate(..) => "SubscriptionUpdate",
+            ServerMessage::ResumableUpdate { .. } => "ResumableUpdate",
+            ServerMessage::PatternUpdate { .. } => "PatternUpdate",
+            ServerMessage::SubscriptionExpired(_) => "SubscriptionExpired",
+            ServerMessage::PatternExpired { .. } => "PatternExpired",
+            ServerMessage::ServerShutdown => "ServerShutdown",
+            ServerMessage::SchemaChanged => "SchemaChanged",
+            ServerMessage::Explain(_) => "Explain",
+        }
+    }
+
+    #[test]
+    fn conformance_fixtures() {
+        let fixtures: Vec<Fixture> =
+            serde_json::from_str(include_str!("../conformance/messages.json")).unwrap();
+
+        let mut covered = BTreeSet::new();
+        for fixture in fixtures.iter() {
+            let reencoded = match fixture.ty.as_str() {
+                "ClientMessage" => {
+                    let message: ClientMessage = serde_json::from_str(&fixture.json).unwrap();
+                    covered.insert(client_variant(&message));
+                    serde_json::to_string(&message).unwrap()
+                }
+                "ServerMessage" => {
+                    let message: ServerMessage = serde_json::from_str(&fixture.json).unwrap();
+                    covered.insert(server_variant(&message));
+                    serde_json::to_string(&message).unwrap()
+                }
+                ty => panic!("unknown message type {ty} in fixture {}", fixture.name),
+            };
+            assert_eq!(reencoded, fixture.json, "fixture {}", fixture.name);
+        }
+
+        // Adding a variant breaks the exhaustive matches above; it also needs a fixture
+        let expected = BTreeSet::from([
+            "Hello",
+            "Get",
+            "GetMany",
+            "GetExpanded",
+            "GetShallow",
+            "GetFields",
+            "GetChunked",
+            "GetMetadata",
+            "GetHistory",
+            "GetAt",
+            "Search",
+            "Query",
+            "ListKeys",
+            "Aggregate",
+            "Insert",
+            "Update",
+            "Remove",
+            "Subscribe",
+            "SubscribeDebounced",
+            "SubscribeFrom",
+            "SubscribePattern",
+            "Unsubscribe",
+            "Follow",
+            "Join",
+            "Leave",
+            "DescribeSchema",
+            "CanI",
+            "Call",
+            "SignUp",
+            "SignIn",
+            "SignOut",
+            "Auth",
+            "Envelope",
+            "Durable",
+            "Validate",
+            "Welcome",
+            "Value",
+            "ValueChunk",
+            "Error",
+            "SubscriptionUpdate",
+            "ResumableUpdate",
+            "PatternUpdate",
+            "SubscriptionExpired",
+            "PatternExpired",
+            "ServerShutdown",
+            "SchemaChanged",
+            "Explain",
+        ]);
+        assert_eq!(covered, expected);
+    }
+
+    #[test]
+    fn binding_users() {
+        let inbox = Ref::from(["users", "$uid", "inbox"]);
+        let mut msg = ClientMessage::Durable(Box::new(ClientMessage::Insert(
+            inbox.clone(),
+            Value::String("$uid".to_string()),
+        )));
+        assert_eq!(msg.bind_user(None), Err(inbox.clone()));
+        msg.bind_user(Some("ada")).unwrap();
+        let ClientMessage::Durable(write) = msg else {
+            unreachable!();
+        };
+        // Only refs are bound, not the values written
+        assert!(matches!(
+            *write,
+            ClientMessage::Insert(key, Value::String(value))
+                if key == Ref::from(["users", "ada", "inbox"]) && value == "$uid"
+        ));
+
+        let mut msg = ClientMessage::GetMany(vec![Ref::from(["posts"]), inbox]);
+        msg.bind_user(Some("grace")).unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::GetMany(keys) if keys[1] == Ref::from(["users", "grace", "inbox"])
+        ));
+        let mut msg = ClientMessage::Get(Ref::from(["posts"]));
+        assert_eq!(msg.bind_user(None), Ok(()));
+    }
+}
@@ -0,0 +1,256 @@
+use std::{fmt::Write, time::Duration};
+
+use futures_util::future::BoxFuture;
+use hyper::Uri;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use crate::{config::NatsConfig, delivery::Sink, message::Ref, server::Server};
+
+/// How long the server has to acknowledge a publish before it counts as failed
+const TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PORT: u16 = 4222;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum NatsError {
+    #[error("invalid NATS URL {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("invalid NATS subject {0:?}: it must be non-empty, without whitespace")]
+    InvalidSubject(String),
+}
+
+/// Publishes payloads to a NATS subject, speaking just enough of the client protocol to do so.
+///
+/// Each message carries an `Iceload-Ref` header with the hex encoding of the ref the change wrote
+/// to, as it's stored, so consumers can partition by it, and a `Nats-Msg-Id` header with the
+/// change's sequence number, which JetStream uses to drop duplicates. A publish only counts as
+/// delivered once the server has answered a PING sent after it, so it's known to have been
+/// processed.
+pub struct Nats {
+    server: Server,
+    host: String,
+    port: u16,
+    subject: String,
+    /// Kept open between publishes, and reopened after any failure
+    connection: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl Nats {
+    pub fn new(server: &Server, config: &NatsConfig) -> Result<Nats, NatsError> {
+        let invalid = |reason: &str| NatsError::InvalidUrl {
+            url: config.url.clone(),
+            reason: reason.to_string(),
+        };
+        let url: Uri = config.url.parse().map_err(|e| invalid(&format!("{e}")))?;
+        if url.scheme_str() != Some("nats") {
+            return Err(invalid("only nats:// is supported"));
+        }
+        let Some(host) = url.host() else {
+            return Err(invalid("there's no host"));
+        };
+        if config.subject.is_empty() || config.subject.contains(char::is_whitespace) {
+            return Err(NatsError::InvalidSubject(config.subject.clone()));
+        }
+        Ok(Nats {
+            server: server.clone(),
+            host: host.to_string(),
+            port: url.port_u16().unwrap_or(DEFAULT_PORT),
+            subject: config.subject.clone(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// The message for `payload`, as an HPUB command
+    fn message(&self, payload: &Value) -> Vec<u8> {
+        // Restores don't have a path, and replace everything
+        let path: Ref = payload
+            .get("path")
+            .and_then(|path| serde_json::from_value(path.clone()).ok())
+            .unwrap_or(Ref(Vec::new()));
+        let mut encoded_ref = String::new();
+        for byte in self.server.schema().encode_ref(&path.0) {
+            write!(encoded_ref, "{byte:02x}").unwrap();
+        }
+        let mut headers = format!("NATS/1.0\r\nIceload-Ref: {encoded_ref}\r\n");
+        if let Some(seq) = payload.get("seq").and_then(Value::as_u64) {
+            write!(headers, "Nats-Msg-Id: {seq}\r\n").unwrap();
+        }
+        headers.push_str("\r\n");
+        let body = serde_json::to_vec(payload).unwrap();
+
+        let mut message = format!(
+            "HPUB {} {} {}\r\n{headers}",
+            self.subject,
+            headers.len(),
+            headers.len() + body.len()
+        )
+        .into_bytes();
+        message.extend(body);
+        message.extend(b"\r\n");
+        message
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>, String> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| format!("could not connect: {e}"))?;
+        let mut stream = BufStream::new(stream);
+        let info = read_line(&mut stream).await?;
+        if !info.starts_with("INFO") {
+            return Err(format!("expected INFO, got {info:?}"));
+        }
+        stream
+            .write_all(
+                b"CONNECT {\"verbose\":false,\"pedantic\":false,\"headers\":true,\"name\":\"iceload\"}\r\n",
+            )
+            .await
+            .map_err(|e| format!("{e}"))?;
+        Ok(stream)
+    }
+
+    async fn publish(&self, payload: &Value) -> Result<(), String> {
+        let mut connection = self.connection.lock().await;
+        let stream = match connection.as_mut() {
+            Some(stream) => stream,
+            None => connection.insert(self.connect().await?),
+        };
+        let result = publish(stream, &self.message(payload)).await;
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+}
+
+impl Sink for Nats {
+    fn deliver<'a>(&'a self, payload: &'a Value) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            tokio::time::timeout(TIMEOUT, self.publish(payload))
+                .await
+                .map_err(|_| "timed out".to_string())?
+        })
+    }
+}
+
+async fn publish(stream: &mut BufStream<TcpStream>, message: &[u8]) -> Result<(), String> {
+    stream
+        .write_all(message)
+        .await
+        .map_err(|e| format!("{e}"))?;
+    stream
+        .write_all(b"PING\r\n")
+        .await
+        .map_err(|e| format!("{e}"))?;
+    stream.flush().await.map_err(|e| format!("{e}"))?;
+    loop {
+        let line = read_line(stream).await?;
+        if line == "PONG" {
+            return Ok(());
+        } else if line == "PING" {
+            stream
+                .write_all(b"PONG\r\n")
+                .await
+                .map_err(|e| format!("{e}"))?;
+            stream.flush().await.map_err(|e| format!("{e}"))?;
+        } else if let Some(err) = line.strip_prefix("-ERR") {
+            return Err(format!("server error:{err}"));
+        }
+        // Anything else, like updated INFO, doesn't matter to a publisher
+    }
+}
+
+async fn read_line(stream: &mut BufStream<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    let read = stream
+        .read_line(&mut line)
+        .await
+        .map_err(|e| format!("{e}"))?;
+    if read == 0 {
+        return Err("the server closed the connection".to_string());
+    }
+    Ok(line.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use crate::schema::{Schema, SchemaItem};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn publishing() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::open(
+            dir.path().to_str().unwrap(),
+            Schema::new(SchemaItem::Scalar),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = NatsConfig {
+            url: format!("nats://{}", listener.local_addr().unwrap()),
+            subject: "iceload.changes".into(),
+            path: Vec::new(),
+        };
+        let nats = Nats::new(&server, &config).unwrap();
+
+        let receiver = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            stream.write_all(b"INFO {}\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+            let connect = read_line(&mut stream).await.unwrap();
+            assert!(connect.starts_with("CONNECT "), "{connect}");
+            // The connection is kept for the second publish
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let command = read_line(&mut stream).await.unwrap();
+                let total: usize = command.rsplit(' ').next().unwrap().parse().unwrap();
+                let mut message = vec![0; total + 2];
+                stream.read_exact(&mut message).await.unwrap();
+                received.push((command, String::from_utf8(message).unwrap()));
+                assert_eq!(read_line(&mut stream).await.unwrap(), "PING");
+                // The client answers the server's PINGs while it waits
+                stream.write_all(b"PING\r\n").await.unwrap();
+                stream.flush().await.unwrap();
+                assert_eq!(read_line(&mut stream).await.unwrap(), "PONG");
+                stream.write_all(b"PONG\r\n").await.unwrap();
+                stream.flush().await.unwrap();
+            }
+            received
+        });
+
+        let path = Ref(vec!["posts".into()]);
+        let event = json!({ "seq": 7, "op": "remove", "path": path });
+        nats.deliver(&event).await.unwrap();
+        nats.deliver(&event).await.unwrap();
+        let received = receiver.await.unwrap();
+
+        let encoded: String = server
+            .schema()
+            .encode_ref(&path.0)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let headers = format!("NATS/1.0\r\nIceload-Ref: {encoded}\r\nNats-Msg-Id: 7\r\n\r\n");
+        let body = serde_json::to_string(&event).unwrap();
+        let (command, message) = &received[0];
+        assert_eq!(
+            *command,
+            format!(
+                "HPUB iceload.changes {} {}",
+                headers.len(),
+                headers.len() + body.len()
+            )
+        );
+        assert_eq!(*message, format!("{headers}{body}\r\n"));
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use http_body_util::{BodyExt, Empty};
+use hyper::{body::Bytes, header, Request, Uri};
+use hyper_util::rt::TokioIo;
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+use crate::config::OidcConfig;
+
+/// How long signing keys are kept unless the config says otherwise
+const DEFAULT_CACHE: Duration = Duration::from_secs(3600);
+/// A token signed by a key that isn't cached has the keys fetched again, but no more often than
+/// this, so tokens with made-up key IDs can't be used to flood the provider with requests
+const MIN_REFETCH: Duration = Duration::from_secs(60);
+/// How long a provider has to answer a request for its keys
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OidcError {
+    #[error("the ID token is malformed: {0}")]
+    Malformed(jsonwebtoken::errors::Error),
+    #[error("ID tokens from {0:?} aren't accepted")]
+    UnknownIssuer(String),
+    #[error("the ID token isn't signed by any key {0} publishes")]
+    UnknownKey(String),
+    #[error("the ID token is invalid: {0}")]
+    Invalid(jsonwebtoken::errors::Error),
+    #[error("could not fetch the signing keys of {issuer}: {reason}")]
+    Fetch { issuer: String, reason: String },
+}
+
+/// Who a valid ID token says the client is
+#[derive(Clone, Debug, PartialEq)]
+pub struct Verified {
+    /// The token's `sub`
+    pub user: String,
+    /// The token's `iss` and the claims its provider is configured to pass on, as an object
+    pub claims: Value,
+    /// When the token expires, in seconds since the unix epoch
+    pub expires_at: u64,
+}
+
+/// The OpenID Connect providers clients may authenticate with. Each one's signing keys are
+/// cached and shared by every connection.
+pub struct IdentityProviders {
+    providers: Vec<Provider>,
+    tls: TlsConnector,
+}
+
+struct Provider {
+    config: OidcConfig,
+    keys: Mutex<Keys>,
+}
+
+#[derive(Default)]
+struct Keys {
+    keys: Vec<Key>,
+    /// When the keys were last fetched
+    fetched: Option<Instant>,
+    /// When they were last asked for, whether or not that worked
+    attempted: Option<Instant>,
+}
+
+#[derive(Clone)]
+struct Key {
+    id: Option<String>,
+    key: DecodingKey,
+    /// Tokens signed with any other algorithm are refused, even if the key would take them
+    algorithm: Algorithm,
+}
+
+/// The claims read from a token before it's known which provider's keys to check it with
+#[derive(Deserialize)]
+struct Unverified {
+    iss: String,
+}
+
+impl IdentityProviders {
+    pub fn new(configs: Vec<OidcConfig>, tls: TlsConnector) -> IdentityProviders {
+        let providers = (configs.into_iter())
+            .map(|config| Provider {
+                config,
+                keys: Mutex::default(),
+            })
+            .collect();
+        IdentityProviders { providers, tls }
+    }
+
+    /// Check an ID token's signature against the keys of the provider that issued it, and that
+    /// it was issued to this server's client ID and hasn't expired
+    pub async fn verify(&self, token: &str) -> Result<Verified, OidcError> {
+        let header = jsonwebtoken::decode_header(token).map_err(OidcError::Malformed)?;
+        let mut unverified = Validation::new(header.alg);
+        unverified.insecure_disable_signature_validation();
+        unverified.validate_aud = false;
+        unverified.validate_exp = false;
+        unverified.required_spec_claims.clear();
+        let issuer =
+            jsonwebtoken::decode::<Unverified>(token, &DecodingKey::from_secret(&[]), &unverified)
+                .map_err(OidcError::Malformed)?
+                .claims
+                .iss;
+        let Some(provider) = (self.providers.iter()).find(|p| p.config.issuer == issuer) else {
+            return Err(OidcError::UnknownIssuer(issuer));
+        };
+
+        let key = provider.key(header.kid.as_deref(), &self.tls).await?;
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&[&provider.config.issuer]);
+        validation.set_audience(&[&provider.config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let mut claims = jsonwebtoken::decode::<Map<String, Value>>(token, &key.key, &validation)
+            .map_err(OidcError::Invalid)?
+            .claims;
+        let (Some(Value::String(user)), Some(expires_at)) = (
+            claims.remove("sub"),
+            claims.get("exp").and_then(Value::as_u64),
+        ) else {
+            return Err(OidcError::Invalid(
+                jsonwebtoken::errors::ErrorKind::InvalidSubject.into(),
+            ));
+        };
+        let passed =
+            std::iter::once("iss").chain(provider.config.claims.iter().map(String::as_str));
+        let claims = passed
+            .filter_map(|name| Some((name.to_string(), claims.remove(name)?)))
+            .collect();
+        Ok(Verified {
+            user,
+            claims: Value::Object(claims),
+            expires_at,
+        })
+    }
+}
+
+impl Provider {
+    /// The signing key named `id`, or the only one if the token doesn't name one, fetching the
+    /// provider's keys if they're stale or don't have it
+    async fn key(&self, id: Option<&str>, tls: &TlsConnector) -> Result<Key, OidcError> {
+        let mut keys = self.keys.lock().await;
+        let cache = (self.config.cache_secs).map_or(DEFAULT_CACHE, Duration::from_secs);
+        let fresh = keys.fetched.is_some_and(|at| at.elapsed() < cache);
+        if let (true, Some(key)) = (fresh, keys.find(id)) {
+            return Ok(key.clone());
+        }
+        if keys.attempted.is_none_or(|at| at.elapsed() >= MIN_REFETCH) {
+            keys.attempted = Some(Instant::now());
+            match self.fetch(tls).await {
+                Ok(fetched) => {
+                    keys.keys = fetched;
+                    keys.fetched = keys.attempted;
+                }
+                // Keys that are out of date are better than none
+                Err(e) if keys.fetched.is_some() => tracing::warn!("{e}"),
+                Err(e) => return Err(e),
+            }
+        }
+        match keys.find(id) {
+            Some(key) => Ok(key.clone()),
+            None => Err(OidcError::UnknownKey(self.config.issuer.clone())),
+        }
+    }
+
+    /// Fetch the provider's signing keys, from the discovery document's `jwks_uri` unless the
+    /// config says where they are
+    async fn fetch(&self, tls: &TlsConnector) -> Result<Vec<Key>, OidcError> {
+        let failed = |reason: String| OidcError::Fetch {
+            issuer: self.config.issuer.clone(),
+            reason,
+        };
+        let jwks_url = match &self.config.jwks_url {
+            Some(url) => url.clone(),
+            None => {
+                let discovery = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let document = get_json(&discovery, tls).await.map_err(failed)?;
+                match document.get("jwks_uri").and_then(Value::as_str) {
+                    Some(url) => url.to_string(),
+                    None => return Err(failed("the discovery document has no jwks_uri".into())),
+                }
+            }
+        };
+        let jwks = get_json(&jwks_url, tls).await.map_err(failed)?;
+        let jwks: JwkSet = serde_json::from_value(jwks).map_err(|e| failed(e.to_string()))?;
+        Ok(jwks.keys.iter().filter_map(signing_key).collect())
+    }
+}
+
+impl Keys {
+    fn find(&self, id: Option<&str>) -> Option<&Key> {
+        match id {
+            Some(id) => (self.keys.iter()).find(|key| key.id.as_deref() == Some(id)),
+            None => match self.keys.as_slice() {
+                [only] => Some(only),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The key a JWK holds, if it's one ID tokens can be signed with. Keys that don't say which
+/// algorithm they're for get the usual one for their type.
+fn signing_key(jwk: &Jwk) -> Option<Key> {
+    if jwk.common.public_key_use == Some(PublicKeyUse::Encryption) {
+        return None;
+    }
+    let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(algorithm), _) => Algorithm::from_str(&algorithm.to_string()).ok()?,
+        (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+        (None, AlgorithmParameters::EllipticCurve(params)) => match params.curve {
+            EllipticCurve::P256 => Algorithm::ES256,
+            EllipticCurve::P384 => Algorithm::ES384,
+            _ => return None,
+        },
+        (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+        // Shared secrets have no business in a public key set
+        (None, AlgorithmParameters::OctetKey(_)) => return None,
+    };
+    Some(Key {
+        id: jwk.common.key_id.clone(),
+        key: DecodingKey::from_jwk(jwk).ok()?,
+        algorithm,
+    })
+}
+
+/// GET a JSON document over http:// or https://
+async fn get_json(url: &str, tls: &TlsConnector) -> Result<Value, String> {
+    let url: Uri = url.parse().map_err(|e| format!("invalid URL {url}: {e}"))?;
+    let (Some(host), Some(authority)) = (url.host(), url.authority()) else {
+        return Err(format!("there's no host in {url}"));
+    };
+    let https = match url.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(format!("only http and https are supported: {url}")),
+    };
+    let port = url.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let request = Request::get(url.path_and_query().map_or("/", |p| p.as_str()))
+        .header(header::HOST, authority.as_str())
+        .header(header::ACCEPT, "application/json")
+        .header(header::USER_AGENT, "iceload")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+
+    let get = async {
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(|e| format!("could not connect: {e}"))?;
+        if https {
+            let name = ServerName::try_from(host.to_string()).map_err(|e| format!("{e}"))?;
+            let stream = (tls.connect(name, stream).await)
+                .map_err(|e| format!("TLS handshake failed: {e}"))?;
+            send(stream, request).await
+        } else {
+            send(stream, request).await
+        }
+    };
+    let body = tokio::time::timeout(TIMEOUT, get)
+        .await
+        .map_err(|_| "timed out".to_string())??;
+    serde_json::from_slice(&body).map_err(|e| format!("{e}"))
+}
+
+async fn send(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    request: Request<Empty<Bytes>>,
+) -> Result<Bytes, String> {
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| format!("{e}"))?;
+    // Drives the connection until the response has been read
+    tokio::spawn(connection);
+    let response = (sender.send_request(request).await).map_err(|e| format!("{e}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("responded {status}"));
+    }
+    let body = (response.into_body().collect().await).map_err(|e| format!("{e}"))?;
+    Ok(body.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use crate::tls;
+
+    use super::*;
+
+    const ISSUER: &str = "https://id.example.com";
+
+    /// A provider whose keys are already cached, holding the public half of `signer`
+    fn providers(signer: &rcgen::KeyPair) -> IdentityProviders {
+        let point = signer.public_key_raw();
+        let (x, y) = point[1..].split_at(32);
+        let jwks = json!({ "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "first",
+            "x": URL_SAFE_NO_PAD.encode(x),
+            "y": URL_SAFE_NO_PAD.encode(y),
+        }] });
+        let jwks: JwkSet = serde_json::from_value(jwks).unwrap();
+        let config = OidcConfig {
+            issuer: ISSUER.to_string(),
+            audience: "iceload-app".to_string(),
+            jwks_url: Some("http://127.0.0.1:1/unreachable".to_string()),
+            claims: vec!["email".to_string()],
+            cache_secs: None,
+        };
+        let providers = IdentityProviders::new(vec![config], tls::connector().unwrap());
+        let now = Some(Instant::now());
+        *providers.providers[0].keys.try_lock().unwrap() = Keys {
+            keys: jwks.keys.iter().filter_map(signing_key).collect(),
+            fetched: now,
+            attempted: now,
+        };
+        providers
+    }
+
+    fn sign(signer: &rcgen::KeyPair, kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(kid.to_string());
+        let key = EncodingKey::from_ec_der(&signer.serialize_der());
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn verifying_tokens() {
+        let signer = rcgen::KeyPair::generate().unwrap();
+        let providers = providers(&signer);
+        let exp = jsonwebtoken::get_current_timestamp() + 600;
+        let claims = |iss: &str, aud: &str, exp: u64| {
+            json!({
+                "iss": iss,
+                "aud": aud,
+                "sub": "ada",
+                "exp": exp,
+                "email": "ada@example.com",
+                "nonce": "left out",
+            })
+        };
+
+        let token = sign(&signer, "first", claims(ISSUER, "iceload-app", exp));
+        let verified = providers.verify(&token).await.unwrap();
+        assert_eq!(
+            verified,
+            Verified {
+                user: "ada".to_string(),
+                claims: json!({ "iss": ISSUER, "email": "ada@example.com" }),
+                expires_at: exp,
+            }
+        );
+
+        let token = sign(&signer, "first", claims(ISSUER, "another-app", exp));
+        let result = providers.verify(&token).await;
+        assert!(matches!(result, Err(OidcError::Invalid(_))));
+        let token = sign(&signer, "first", claims(ISSUER, "iceload-app", exp - 1200));
+        let result = providers.verify(&token).await;
+        assert!(matches!(result, Err(OidcError::Invalid(_))));
+        let token = sign(
+            &signer,
+            "first",
+            claims("https://evil.example", "iceload-app", exp),
+        );
+        let result = providers.verify(&token).await;
+        assert!(matches!(result, Err(OidcError::UnknownIssuer(_))));
+
+        // Signed by someone else, with the provider's key ID
+        let forger = rcgen::KeyPair::generate().unwrap();
+        let token = sign(&forger, "first", claims(ISSUER, "iceload-app", exp));
+        let result = providers.verify(&token).await;
+        assert!(matches!(result, Err(OidcError::Invalid(_))));
+        // An unknown key is looked for, but the keys were only just fetched
+        let token = sign(&signer, "second", claims(ISSUER, "iceload-app", exp));
+        let result = providers.verify(&token).await;
+        assert!(matches!(result, Err(OidcError::UnknownKey(_))));
+    }
+}
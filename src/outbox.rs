@@ -0,0 +1,215 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::message::ServerMessage;
+
+/// What to do with a subscription update when a client has fallen too far behind to queue it
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Discard the oldest queued subscription update to make room
+    #[default]
+    DropOldest,
+    /// Close the connection
+    Disconnect,
+}
+
+#[derive(Debug, Error)]
+#[error("the connection is closed")]
+pub struct Closed;
+
+/// A bounded queue of messages waiting to be written to one client.
+///
+/// Responses to requests wait for space, which in turn stops the connection reading further
+/// requests. Subscription updates are produced regardless of the client, so they never wait;
+/// when the queue is full the `SlowConsumerPolicy` decides what gives.
+#[derive(Clone)]
+pub struct Outbox {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    readable: Notify,
+    writable: Notify,
+}
+
+struct State {
+    queue: VecDeque<ServerMessage>,
+    closed: bool,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Outbox {
+        Outbox {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    closed: false,
+                }),
+                capacity: capacity.max(1),
+                policy,
+                readable: Notify::new(),
+                writable: Notify::new(),
+            }),
+        }
+    }
+
+    /// Queue a message, waiting for space if the client is behind
+    pub async fn send(&self, message: ServerMessage) -> Result<(), Closed> {
+        let mut message = Some(message);
+        loop {
+            {
+                let mut state = self.inner.state.lock().unwrap();
+                if state.closed {
+                    return Err(Closed);
+                }
+                if state.queue.len() < self.inner.capacity {
+                    state.queue.extend(message.take());
+                    self.inner.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            self.inner.writable.notified().await;
+        }
+    }
+
+    /// Queue a subscription update without waiting, applying the slow consumer policy if the
+    /// queue is full
+    pub fn send_update(&self, update: ServerMessage) -> Result<(), Closed> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+        if state.queue.len() >= self.inner.capacity {
+            match self.inner.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    let oldest = state.queue.iter().position(|message| {
+                        matches!(
+                            message,
+                            ServerMessage::SubscriptionUpdate(..)
+                                | ServerMessage::ResumableUpdate { .. }
+                                | ServerMessage::PatternUpdate { .. }
+                        )
+                    });
+                    match oldest {
+                        Some(index) => {
+                            state.queue.remove(index);
+                        }
+                        // Everything queued is a response, which can't be dropped
+                        None => return Ok(()),
+                    }
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    state.closed = true;
+                    state.queue.clear();
+                    drop(state);
+                    self.wake_all();
+                    return Err(Closed);
+                }
+            }
+        }
+        state.queue.push_back(update);
+        self.inner.readable.notify_one();
+        Ok(())
+    }
+
+    /// The next message to write to the client, or None once the outbox is closed and drained
+    pub async fn recv(&self) -> Option<ServerMessage> {
+        loop {
+            {
+                let mut state = self.inner.state.lock().unwrap();
+                if let Some(message) = state.queue.pop_front() {
+                    self.inner.writable.notify_one();
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.inner.readable.notified().await;
+        }
+    }
+
+    /// Stop accepting messages; anything already queued is still delivered by `recv`
+    pub fn close(&self) {
+        self.inner.state.lock().unwrap().closed = true;
+        self.wake_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.lock().unwrap().closed
+    }
+
+    fn wake_all(&self) {
+        self.inner.readable.notify_one();
+        // Wake every waiting sender, and leave a permit for one that's about to wait
+        self.inner.writable.notify_waiters();
+        self.inner.writable.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::{ErrorCode, Ref};
+
+    use super::*;
+
+    fn update(n: u32) -> ServerMessage {
+        ServerMessage::SubscriptionUpdate(Ref(Vec::new()), Some(n.to_string()))
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_updates() {
+        let outbox = Outbox::new(3, SlowConsumerPolicy::DropOldest);
+        outbox.send_update(update(0)).unwrap();
+        outbox
+            .send(ServerMessage::error(ErrorCode::Internal, "response"))
+            .await
+            .unwrap();
+        outbox.send_update(update(1)).unwrap();
+        outbox.send_update(update(2)).unwrap();
+        outbox.close();
+
+        let mut received = Vec::new();
+        while let Some(message) = outbox.recv().await {
+            received.push(message);
+        }
+        assert!(matches!(&received[..], [
+            ServerMessage::Error(_),
+            ServerMessage::SubscriptionUpdate(_, Some(first)),
+            ServerMessage::SubscriptionUpdate(_, Some(second)),
+        ] if first == "1" && second == "2"));
+    }
+
+    #[tokio::test]
+    async fn disconnect_slow_consumers() {
+        let outbox = Outbox::new(1, SlowConsumerPolicy::Disconnect);
+        outbox.send_update(update(0)).unwrap();
+        assert!(outbox.send_update(update(1)).is_err());
+        assert!(outbox.is_closed());
+        assert!(outbox.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn responses_wait_for_space() {
+        let outbox = Outbox::new(1, SlowConsumerPolicy::DropOldest);
+        outbox.send(ServerMessage::Value(0.into())).await.unwrap();
+        let sender = outbox.clone();
+        let send = tokio::spawn(async move { sender.send(ServerMessage::Value(1.into())).await });
+        tokio::task::yield_now().await;
+        assert!(!send.is_finished());
+
+        assert!(matches!(outbox.recv().await, Some(ServerMessage::Value(_))));
+        send.await.unwrap().unwrap();
+        assert!(matches!(outbox.recv().await, Some(ServerMessage::Value(_))));
+    }
+}
@@ -1,17 +1,52 @@
-use mlua::{Compiler, Function, Lua};
+use std::{sync::Mutex, time::Duration};
+
+use mlua::{Compiler, Function, Lua, Table};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use thiserror::Error;
+use ts_rs::TS;
 
-use crate::message::Ref;
+use crate::{error::ErrorKind, message::Ref, slow};
 
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum PermissionError {
-    #[error("lua error: {}", .0)]
-    LuaError(#[from] mlua::Error),
+    #[error("failed to load permission script: {}", .0)]
+    LoadError(#[from] mlua::Error),
+    #[error("permission script failed checking {} at {path}: {source}", .op.as_str())]
+    CheckError {
+        op: Operation,
+        path: Ref,
+        source: mlua::Error,
+    },
+}
+
+impl PermissionError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::Script
+    }
 }
 
+/// Runs the check function over a list of `{ op, path, user }` requests, with the same claims, in
+/// a single call into the VM. Each result is `{ true, allowed }` or `{ false, error }`, so one
+/// failing check doesn't hide the verdicts on the rest.
+const CHECK_ALL: &str = r#"
+local check, requests, claims = ...
+local results = {}
+for i, request in ipairs(requests) do
+    local ok, result = pcall(check, request[1], request[2], request[3], claims)
+    results[i] = { ok, if ok then result else tostring(result) }
+end
+return results
+"#;
+
 pub struct Permissions<'a> {
     lua: Lua,
     bytecode: &'a [u8],
+    /// Checks taking longer than this are logged
+    slow_threshold: Option<Duration>,
+    /// What an ID token claimed about the user, given to the rules after the user
+    claims: Option<Value>,
 }
 
 impl Permissions<'_> {
@@ -21,7 +56,7 @@ impl Permissions<'_> {
 
         let lua = Lua::new();
         // Double check script compiles
-        lua.load(permission_function).eval()?;
+        lua.load(permission_function).eval::<()>()?;
 
         Ok(permission_function)
     }
@@ -30,27 +65,253 @@ impl Permissions<'_> {
         Permissions {
             lua: Lua::new(),
             bytecode,
+            slow_threshold: None,
+            claims: None,
         }
     }
 
-    pub fn check(&self, op: Operation, _path: &Ref) -> Result<bool, PermissionError> {
+    /// Log a warning for each check that takes longer than `threshold`, if there is one
+    pub fn with_slow_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_threshold = threshold;
+        self
+    }
+
+    /// Give every check the claims of the user's ID token, as the rules' fourth argument
+    pub fn with_claims(mut self, claims: Option<Value>) -> Self {
+        self.set_claims(claims);
+        self
+    }
+
+    pub fn set_claims(&mut self, claims: Option<Value>) {
+        self.claims = claims;
+    }
+
+    pub fn check(
+        &self,
+        op: Operation,
+        path: &Ref,
+        user: Option<&str>,
+    ) -> Result<bool, PermissionError> {
+        let check = || -> mlua::Result<bool> {
+            let func: Function = self.lua.load(self.bytecode).eval()?;
+            let claims = lua_value(&self.lua, self.claims.as_ref())?;
+            func.call((op.as_str(), path.0.clone(), user, claims))
+        };
+
+        let checked = slow::timed(
+            self.slow_threshold,
+            format_args!("check {}", op.as_str()),
+            path,
+            check,
+        );
+        checked.map_err(|source| PermissionError::CheckError {
+            op,
+            path: path.clone(),
+            source,
+        })
+    }
+
+    /// Evaluate many checks at once, loading the script and entering the VM only once rather
+    /// than once per check. Returns a verdict for each check, in order.
+    pub fn check_all(
+        &self,
+        checks: &[(Operation, &Ref, Option<&str>)],
+    ) -> Result<Vec<Result<bool, PermissionError>>, PermissionError> {
         let func: Function = self.lua.load(self.bytecode).eval()?;
-        // TODO: pass down path
-        // TODO: pass down user ID
-        let result: bool = func.call(match op {
-            Operation::Read => "read",
-            Operation::Insert => "insert",
-            Operation::Update => "update",
-            Operation::Remove => "remove",
-        })?;
+        let requests = self.lua.create_table_with_capacity(checks.len(), 0)?;
+        for (op, path, user) in checks {
+            let request = self.lua.create_table()?;
+            request.raw_set(1, op.as_str())?;
+            request.raw_set(2, path.0.clone())?;
+            request.raw_set(3, *user)?;
+            requests.raw_push(request)?;
+        }
+        let claims = lua_value(&self.lua, self.claims.as_ref())?;
+        let results: Vec<Table> = self.lua.load(CHECK_ALL).call((func, requests, claims))?;
 
-        Ok(result)
+        checks
+            .iter()
+            .zip(results)
+            .map(|(&(op, path, _), result)| {
+                let outcome = if result.raw_get::<_, bool>(1)? {
+                    Ok(result.raw_get(2)?)
+                } else {
+                    Err(mlua::Error::RuntimeError(result.raw_get(2)?))
+                };
+                Ok(outcome.map_err(|source| PermissionError::CheckError {
+                    op,
+                    path: path.clone(),
+                    source,
+                }))
+            })
+            .collect()
     }
+
+    /// Which of `targets` `user` may read, checked in a single call to the script. A check that
+    /// fails counts as a denial.
+    pub fn readable(&self, targets: &[Ref], user: Option<&str>) -> Vec<bool> {
+        let checks: Vec<_> = targets
+            .iter()
+            .map(|target| (Operation::Read, target, user))
+            .collect();
+        match self.check_all(&checks) {
+            Ok(results) => results
+                .into_iter()
+                .map(|result| result.unwrap_or(false))
+                .collect(),
+            Err(e) => {
+                tracing::warn!("failed to check permissions: {e}");
+                vec![false; targets.len()]
+            }
+        }
+    }
+}
+
+/// JSON as the rules see it: objects and arrays as tables, and nothing as nil
+fn lua_value<'lua>(lua: &'lua Lua, value: Option<&Value>) -> mlua::Result<mlua::Value<'lua>> {
+    Ok(match value {
+        None | Some(Value::Null) => mlua::Value::Nil,
+        Some(Value::Bool(bool)) => mlua::Value::Boolean(*bool),
+        Some(Value::Number(number)) => mlua::Value::Number(number.as_f64().unwrap_or_default()),
+        Some(Value::String(string)) => mlua::Value::String(lua.create_string(string)?),
+        Some(Value::Array(elements)) => {
+            let table = lua.create_table_with_capacity(elements.len(), 0)?;
+            for element in elements {
+                table.raw_push(lua_value(lua, Some(element))?)?;
+            }
+            mlua::Value::Table(table)
+        }
+        Some(Value::Object(members)) => {
+            let table = lua.create_table_with_capacity(0, members.len())?;
+            for (key, member) in members {
+                table.raw_set(key.as_str(), lua_value(lua, Some(member))?)?;
+            }
+            mlua::Value::Table(table)
+        }
+    })
 }
 
+/// Permission checkers for requests to borrow, so that requests being handled at the same time
+/// don't wait on each other to be checked
+pub struct PermissionPool {
+    bytecode: &'static [u8],
+    slow_threshold: Option<Duration>,
+    idle: Mutex<Vec<Permissions<'static>>>,
+}
+
+impl PermissionPool {
+    pub fn new(bytecode: &'static [u8], slow_threshold: Option<Duration>) -> PermissionPool {
+        PermissionPool {
+            bytecode,
+            slow_threshold,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn take(&self) -> Permissions<'static> {
+        let idle = self.idle.lock().unwrap().pop();
+        idle.unwrap_or_else(|| {
+            Permissions::new(self.bytecode).with_slow_threshold(self.slow_threshold)
+        })
+    }
+
+    pub fn put(&self, permissions: Permissions<'static>) {
+        self.idle.lock().unwrap().push(permissions);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, TS)]
+#[ts(export)]
+#[serde(rename_all = "lowercase")]
 pub enum Operation {
     Read,
     Insert,
     Update,
     Remove,
+    /// Running a function, whose name is the path checked
+    Call,
+}
+
+impl Operation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Read => "read",
+            Operation::Insert => "insert",
+            Operation::Update => "update",
+            Operation::Remove => "remove",
+            Operation::Call => "call",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_all() {
+        let bytecode = Permissions::load_bytecode(
+            r#"
+            return function(op, path, user)
+                if path[1] == "broken" then
+                    error("no rules for this path")
+                end
+                return op == "read" or user == "admin"
+            end
+            "#,
+        )
+        .unwrap();
+        let permissions = Permissions::new(bytecode);
+        let public = Ref(vec!["public".into()]);
+        let broken = Ref(vec!["broken".into()]);
+
+        let verdicts = permissions
+            .check_all(&[
+                (Operation::Read, &public, None),
+                (Operation::Remove, &public, None),
+                (Operation::Read, &broken, None),
+                (Operation::Remove, &public, Some("admin")),
+            ])
+            .unwrap();
+        assert!(matches!(
+            &verdicts[..],
+            [
+                Ok(true),
+                Ok(false),
+                Err(PermissionError::CheckError { .. }),
+                Ok(true)
+            ]
+        ));
+        // The same verdicts as checking one at a time
+        assert!(permissions
+            .check(Operation::Remove, &public, Some("admin"))
+            .unwrap());
+    }
+
+    #[test]
+    fn claims() {
+        let bytecode = Permissions::load_bytecode(
+            r#"
+            return function(op, path, user, claims)
+                return claims ~= nil and claims.email_verified and claims.roles[1] == path[1]
+            end
+            "#,
+        )
+        .unwrap();
+        let claims = serde_json::json!({ "email_verified": true, "roles": ["editors"] });
+        let editors = Ref(vec!["editors".into()]);
+        let admins = Ref(vec!["admins".into()]);
+
+        let without = Permissions::new(bytecode);
+        assert!(!without
+            .check(Operation::Read, &editors, Some("ada"))
+            .unwrap());
+        let with = Permissions::new(bytecode).with_claims(Some(claims));
+        assert!(with.check(Operation::Read, &editors, Some("ada")).unwrap());
+        assert!(!with.check(Operation::Read, &admins, Some("ada")).unwrap());
+        assert_eq!(
+            with.readable(&[editors, admins], Some("ada")),
+            [true, false]
+        );
+    }
 }
@@ -0,0 +1,67 @@
+use std::{collections::BTreeMap, ops::Bound, sync::Mutex};
+
+use sled::IVec;
+
+/// The members of every presence collection in a tree, by encoded ref. Nothing here is ever
+/// written to disk.
+#[derive(Default)]
+pub struct Presence {
+    members: Mutex<BTreeMap<Vec<u8>, Member>>,
+}
+
+struct Member {
+    /// The connection that joined, which the member goes away with
+    connection: u64,
+    value: IVec,
+}
+
+impl Presence {
+    /// Set a member on behalf of a connection, taking it over if another connection had joined
+    /// it, e.g. a client that reconnected before its old connection timed out
+    pub fn join(&self, key: Vec<u8>, connection: u64, value: IVec) {
+        self.members
+            .lock()
+            .unwrap()
+            .insert(key, Member { connection, value });
+    }
+
+    /// Remove a member, returning whether the connection had joined it
+    pub fn leave(&self, key: &[u8], connection: u64) -> bool {
+        let mut members = self.members.lock().unwrap();
+        match members.get(key) {
+            Some(member) if member.connection == connection => {
+                members.remove(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove every member a connection joined, returning their keys
+    pub fn leave_all(&self, connection: u64) -> Vec<Vec<u8>> {
+        let mut left = Vec::new();
+        self.members.lock().unwrap().retain(|key, member| {
+            if member.connection == connection {
+                left.push(key.clone());
+            }
+            member.connection != connection
+        });
+        left
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<IVec> {
+        let members = self.members.lock().unwrap();
+        members.get(key).map(|member| member.value.clone())
+    }
+
+    /// Every member whose key starts with `prefix`, in the same form as a scan of the store
+    pub fn scan(&self, prefix: &[u8]) -> BTreeMap<IVec, IVec> {
+        self.members
+            .lock()
+            .unwrap()
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, member)| (IVec::from(&key[..]), member.value.clone()))
+            .collect()
+    }
+}
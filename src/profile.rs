@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tracing::{
+    span::{Attributes, Id},
+    subscriber::Interest,
+    Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Filter},
+    registry::LookupSpan,
+    Layer,
+};
+
+#[derive(Debug, Error)]
+#[error("a profile is already being captured")]
+pub struct Busy;
+
+/// Measures where time goes by timing tracing spans, for investigating live servers.
+///
+/// Spans are only recorded while a capture is running, including the trace-level spans around
+/// storage and subscription fan-out that logging normally filters out. A capture is rendered in
+/// the folded stack format read by flamegraph tools such as inferno and speedscope, with each
+/// stack of span names weighted by the microseconds spent directly in its innermost span.
+#[derive(Default)]
+pub struct Profiler {
+    capturing: AtomicBool,
+    /// Folded stack of span names -> microseconds spent in it
+    samples: Mutex<HashMap<String, u64>>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// A tracing layer that feeds this profiler
+    pub fn layer<S>(self: &Arc<Self>) -> impl Layer<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        SpanTimer(self.clone()).with_filter(Capturing(self.clone()))
+    }
+
+    /// Record spans for `duration`, then return what they measured
+    pub async fn capture(&self, duration: Duration) -> Result<String, Busy> {
+        let capture = self.start()?;
+        tokio::time::sleep(duration).await;
+        Ok(capture.finish())
+    }
+
+    fn start(&self) -> Result<Capture<'_>, Busy> {
+        if self.capturing.swap(true, Ordering::SeqCst) {
+            return Err(Busy);
+        }
+        self.samples.lock().unwrap().clear();
+        Ok(Capture(self))
+    }
+}
+
+/// A running capture, which is stopped when dropped, e.g. if whoever asked for the profile goes
+/// away before it's ready
+struct Capture<'a>(&'a Profiler);
+
+impl Capture<'_> {
+    fn finish(self) -> String {
+        self.0.capturing.store(false, Ordering::SeqCst);
+        let mut samples: Vec<_> = std::mem::take(&mut *self.0.samples.lock().unwrap())
+            .into_iter()
+            .collect();
+        samples.sort();
+        let mut folded = String::new();
+        for (stack, micros) in samples {
+            writeln!(folded, "{stack} {micros}").unwrap();
+        }
+        folded
+    }
+}
+
+impl Drop for Capture<'_> {
+    fn drop(&mut self) {
+        self.0.capturing.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Only lets spans through to the profiler while a capture is running
+struct Capturing(Arc<Profiler>);
+
+impl<S> Filter<S> for Capturing {
+    fn enabled(&self, metadata: &Metadata<'_>, _: &Context<'_, S>) -> bool {
+        metadata.is_span() && self.0.capturing.load(Ordering::Relaxed)
+    }
+
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.is_span() {
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+}
+
+struct SpanTimer(Arc<Profiler>);
+
+/// Kept in each span's extensions while it's being profiled
+#[derive(Default)]
+struct Timing {
+    entered_at: Option<Instant>,
+    /// Time spent in child spans since this one was entered
+    children: Duration,
+}
+
+impl<S> Layer<S> for SpanTimer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timing::default());
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                timing.entered_at = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let (elapsed, own) = {
+            let mut extensions = span.extensions_mut();
+            let Some(timing) = extensions.get_mut::<Timing>() else {
+                return;
+            };
+            let Some(entered_at) = timing.entered_at.take() else {
+                return;
+            };
+            let elapsed = entered_at.elapsed();
+            (
+                elapsed,
+                elapsed.saturating_sub(std::mem::take(&mut timing.children)),
+            )
+        };
+        if let Some(parent) = span.parent() {
+            if let Some(timing) = parent.extensions_mut().get_mut::<Timing>() {
+                if timing.entered_at.is_some() {
+                    timing.children += elapsed;
+                }
+            }
+        }
+        // A span can outlive the capture it started in
+        if !self.0.capturing.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let stack: Vec<&str> = span.scope().from_root().map(|span| span.name()).collect();
+        *self
+            .0
+            .samples
+            .lock()
+            .unwrap()
+            .entry(stack.join(";"))
+            .or_default() += own.as_micros() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn folded_stacks() {
+        let profiler = Arc::new(Profiler::new());
+        let subscriber = tracing_subscriber::registry().with(profiler.layer());
+        tracing::subscriber::with_default(subscriber, || {
+            // Spans outside a capture aren't recorded
+            tracing::trace_span!("ignored").in_scope(|| {});
+
+            let capture = profiler.start().unwrap();
+            assert!(profiler.start().is_err());
+            let started = Instant::now();
+            tracing::info_span!("request").in_scope(|| {
+                std::thread::sleep(Duration::from_millis(2));
+                tracing::trace_span!("transaction").in_scope(|| {
+                    std::thread::sleep(Duration::from_millis(5));
+                });
+            });
+            let total = started.elapsed().as_micros() as u64;
+            let folded = capture.finish();
+
+            let weights: HashMap<&str, u64> = folded
+                .lines()
+                .map(|line| {
+                    let (stack, weight) = line.rsplit_once(' ').unwrap();
+                    (stack, weight.parse().unwrap())
+                })
+                .collect();
+            assert_eq!(weights.len(), 2, "{folded}");
+            assert!(weights["request;transaction"] >= 5000);
+            // Time in the child isn't counted against the parent
+            assert!(weights["request"] >= 2000);
+            assert!(weights["request"] + weights["request;transaction"] <= total);
+        });
+        assert!(profiler.start().is_ok());
+    }
+}
@@ -0,0 +1,186 @@
+use std::cmp::Ordering;
+
+use serde_json::{Number, Value};
+
+use crate::{
+    message::{Aggregation, Comparison, Filter, RefComponent},
+    schema::{Schema, SchemaItem},
+};
+
+/// Check that every field a filter compares is a scalar-like item of the collection's members,
+/// and that its value can be compared that way. `members` is the ref of any member of the
+/// collection; the reason a filter is invalid is returned on failure.
+pub fn validate(schema: &Schema, members: &[RefComponent], filter: &Filter) -> Result<(), String> {
+    match filter {
+        Filter::And(filters) | Filter::Or(filters) => filters
+            .iter()
+            .try_for_each(|filter| validate(schema, members, filter)),
+        Filter::Not(filter) => validate(schema, members, filter),
+        Filter::Compare { field, op, value } => {
+            check_field(schema, members, field)?;
+            let field = format!("/{}", field.join("/"));
+            let ordered = matches!(value, Value::Number(_) | Value::String(_));
+            match op {
+                Comparison::Eq | Comparison::Ne => Ok(()),
+                Comparison::Prefix if !value.is_string() => {
+                    Err(format!("{field} can only be prefixed by a string"))
+                }
+                Comparison::Lt | Comparison::Le | Comparison::Gt | Comparison::Ge if !ordered => {
+                    Err(format!("{field} can only be ordered by a number or string"))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+/// Check that the field an aggregation reads is a scalar-like item of the collection's members
+pub fn validate_aggregation(
+    schema: &Schema,
+    members: &[RefComponent],
+    aggregation: &Aggregation,
+) -> Result<(), String> {
+    match aggregation {
+        Aggregation::Count => Ok(()),
+        Aggregation::Sum(field) | Aggregation::Min(field) | Aggregation::Max(field) => {
+            check_field(schema, members, field)
+        }
+    }
+}
+
+fn check_field(
+    schema: &Schema,
+    members: &[RefComponent],
+    field: &[RefComponent],
+) -> Result<(), String> {
+    match schema.resolve(&[members, field].concat()) {
+        Ok(
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_),
+        ) => Ok(()),
+        Ok(_) => Err(format!("/{} isn't a scalar", field.join("/"))),
+        Err(e) => Err(format!("/{}: {e}", field.join("/"))),
+    }
+}
+
+/// Whether a member of a collection matches a filter that's been validated
+pub fn matches(member: &Value, filter: &Filter) -> bool {
+    match filter {
+        Filter::And(filters) => filters.iter().all(|filter| matches(member, filter)),
+        Filter::Or(filters) => filters.iter().any(|filter| matches(member, filter)),
+        Filter::Not(filter) => !matches(member, filter),
+        Filter::Compare { field, op, value } => {
+            let field = field_of(member, field);
+            let order = order(field, value);
+            match op {
+                Comparison::Eq => order == Some(Ordering::Equal) || field == value,
+                Comparison::Ne => order != Some(Ordering::Equal) && field != value,
+                Comparison::Lt => order == Some(Ordering::Less),
+                Comparison::Le => matches!(order, Some(Ordering::Less | Ordering::Equal)),
+                Comparison::Gt => order == Some(Ordering::Greater),
+                Comparison::Ge => matches!(order, Some(Ordering::Greater | Ordering::Equal)),
+                Comparison::Prefix => match (field, value) {
+                    (Value::String(field), Value::String(prefix)) => field.starts_with(prefix),
+                    _ => false,
+                },
+            }
+        }
+    }
+}
+
+/// Compute a validated aggregation other than `Count` over the members of a collection. Sums
+/// stay integers unless a member's field isn't one, or the total overflows.
+pub fn aggregate<'a>(
+    members: impl Iterator<Item = &'a Value>,
+    aggregation: &'a Aggregation,
+) -> Value {
+    let numbers = |field: &'a [RefComponent]| {
+        members.filter_map(move |member| match field_of(member, field) {
+            Value::Number(number) => Some(number),
+            _ => None,
+        })
+    };
+    let by_value = |a: &&Number, b: &&Number| order_numbers(a, b).unwrap_or(Ordering::Equal);
+    match aggregation {
+        Aggregation::Count => unreachable!("members are counted without being read"),
+        Aggregation::Sum(field) => {
+            let mut total = Some(0i64);
+            let mut float = 0.0;
+            for number in numbers(field) {
+                total = total
+                    .zip(number.as_i64())
+                    .and_then(|(a, b)| a.checked_add(b));
+                float += number.as_f64().unwrap_or_default();
+            }
+            total.map_or(Value::from(float), Value::from)
+        }
+        Aggregation::Min(field) => numbers(field)
+            .min_by(by_value)
+            .map_or(Value::Null, |min| Value::Number(min.clone())),
+        Aggregation::Max(field) => numbers(field)
+            .max_by(by_value)
+            .map_or(Value::Null, |max| Value::Number(max.clone())),
+    }
+}
+
+/// The value at `field` within a member, or null if it isn't set
+fn field_of<'a>(member: &'a Value, field: &[RefComponent]) -> &'a Value {
+    field
+        .iter()
+        .try_fold(member, |value, component| value.get(component))
+        .unwrap_or(&Value::Null)
+}
+
+fn order_numbers(a: &Number, b: &Number) -> Option<Ordering> {
+    a.as_f64()?.partial_cmp(&b.as_f64()?)
+}
+
+/// Numbers are ordered by value, whatever their representation, and strings by their bytes.
+/// Nothing else is ordered.
+fn order(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => order_numbers(a, b),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn compare(field: &str, op: Comparison, value: Value) -> Filter {
+        Filter::Compare {
+            field: vec![field.to_string()],
+            op,
+            value,
+        }
+    }
+
+    #[test]
+    fn matching_members() {
+        let member = json!({ "title": "Hello", "votes": 3 });
+        let check = |field, op, value| matches(&member, &compare(field, op, value));
+        assert!(check("votes", Comparison::Eq, json!(3.0)));
+        assert!(check("votes", Comparison::Ge, json!(3)));
+        assert!(!check("votes", Comparison::Lt, json!(3)));
+        assert!(!check("votes", Comparison::Lt, json!("3")));
+        assert!(check("votes", Comparison::Ne, json!("3")));
+        assert!(check("title", Comparison::Prefix, json!("Hel")));
+        assert!(check("missing", Comparison::Eq, Value::Null));
+        assert!(matches(
+            &member,
+            &Filter::Or(vec![
+                compare("title", Comparison::Gt, json!("Z")),
+                Filter::Not(Box::new(compare("votes", Comparison::Le, json!(1)))),
+            ])
+        ));
+        assert!(matches(&member, &Filter::And(vec![])));
+        assert!(!matches(&member, &Filter::Or(vec![])));
+    }
+}
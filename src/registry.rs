@@ -0,0 +1,317 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::{message::Ref, outbox::Outbox};
+
+pub type TaskId = u64;
+
+/// Tracks the connected clients and the subscription tasks spawned on their behalf, so clients
+/// can be inspected and disconnected, and tasks which have stopped or outlived their client can
+/// be found and reaped
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    connections: Mutex<HashMap<u64, ConnectedClient>>,
+    tasks: Mutex<HashMap<TaskId, SubscriptionTask>>,
+    next_connection_id: AtomicU64,
+    next_task_id: AtomicU64,
+    reaped: AtomicU64,
+}
+
+struct ConnectedClient {
+    peer: String,
+    tenant: Option<String>,
+    connected_at: SystemTime,
+    kick: Arc<Notify>,
+}
+
+/// What the registry knows about a connected client
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    /// The client's address, or the Unix socket it connected over
+    pub peer: String,
+    /// The tenant the client connected to, or None for the default one
+    pub tenant: Option<String>,
+    /// When the client connected, in seconds since the unix epoch
+    pub connected_at: u64,
+    pub subscriptions: Vec<Ref>,
+}
+
+struct SubscriptionTask {
+    connection: u64,
+    key: Ref,
+    handle: JoinHandle<()>,
+    client: Outbox,
+}
+
+impl SubscriptionTask {
+    /// A task is orphaned once it has stopped running or nobody is listening to it any more
+    fn is_orphaned(&self) -> bool {
+        self.handle.is_finished() || self.client.is_closed()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct TaskCounts {
+    pub live: usize,
+    pub orphaned: usize,
+    /// How many orphaned tasks the watchdog has cleaned up since startup
+    pub reaped: u64,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry::default()
+    }
+
+    /// An ID for a new connection, which no other connection has had
+    pub fn next_connection_id(&self) -> u64 {
+        self.next_connection_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Record a newly connected client. The returned `Notify` is signalled if the client is
+    /// kicked, at which point the connection should close.
+    pub fn register(&self, connection: u64, peer: String, tenant: Option<String>) -> Arc<Notify> {
+        let kick = Arc::new(Notify::new());
+        self.connections.lock().unwrap().insert(
+            connection,
+            ConnectedClient {
+                peer,
+                tenant,
+                connected_at: SystemTime::now(),
+                kick: kick.clone(),
+            },
+        );
+        kick
+    }
+
+    /// Forget a client once it has disconnected, stopping its subscriptions
+    pub fn unregister(&self, connection: u64) {
+        self.connections.lock().unwrap().remove(&connection);
+        self.abort_connection(connection);
+    }
+
+    /// Ask a client's connection to close, returning false if there's no such client
+    pub fn kick(&self, connection: u64) -> bool {
+        match self.connections.lock().unwrap().get(&connection) {
+            Some(client) => {
+                // Stores a permit, so the kick isn't lost if the connection is busy
+                client.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Every connected client, in the order they connected
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let mut subscriptions = self.subscriptions();
+        let mut clients: Vec<ClientInfo> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, client)| ClientInfo {
+                id,
+                peer: client.peer.clone(),
+                tenant: client.tenant.clone(),
+                connected_at: client
+                    .connected_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                subscriptions: subscriptions.remove(&id).unwrap_or_default(),
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    pub fn client(&self, connection: u64) -> Option<ClientInfo> {
+        self.clients()
+            .into_iter()
+            .find(|client| client.id == connection)
+    }
+
+    /// Spawn a subscription task that delivers updates to `key` to `client` on behalf of
+    /// `connection`
+    pub fn spawn(
+        &self,
+        connection: u64,
+        key: Ref,
+        client: &Outbox,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) -> TaskId {
+        let id = self.next_task_id.fetch_add(1, Ordering::Relaxed);
+        let task = SubscriptionTask {
+            connection,
+            key,
+            handle: tokio::spawn(task),
+            client: client.clone(),
+        };
+        self.tasks.lock().unwrap().insert(id, task);
+        id
+    }
+
+    /// Stop a task and forget about it
+    pub fn abort(&self, id: TaskId) {
+        if let Some(task) = self.tasks.lock().unwrap().remove(&id) {
+            task.handle.abort();
+        }
+    }
+
+    /// Stop every task belonging to a connection, e.g. once it closes
+    pub fn abort_connection(&self, connection: u64) {
+        self.tasks.lock().unwrap().retain(|_, task| {
+            if task.connection == connection {
+                task.handle.abort();
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// How many subscription tasks there are, including orphaned ones not yet reaped
+    pub fn task_count(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+
+    pub fn counts(&self) -> TaskCounts {
+        let tasks = self.tasks.lock().unwrap();
+        let orphaned = tasks.values().filter(|task| task.is_orphaned()).count();
+        TaskCounts {
+            live: tasks.len() - orphaned,
+            orphaned,
+            reaped: self.reaped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The refs each connection is subscribed to
+    pub fn subscriptions(&self) -> BTreeMap<u64, Vec<Ref>> {
+        let mut subscriptions = BTreeMap::<u64, Vec<Ref>>::new();
+        for task in self.tasks.lock().unwrap().values() {
+            subscriptions
+                .entry(task.connection)
+                .or_default()
+                .push(task.key.clone());
+        }
+        subscriptions
+    }
+
+    /// Abort and forget every orphaned task, returning how many there were
+    pub fn reap(&self) -> usize {
+        let mut reaped = 0;
+        self.tasks.lock().unwrap().retain(|_, task| {
+            if task.is_orphaned() {
+                task.handle.abort();
+                reaped += 1;
+                false
+            } else {
+                true
+            }
+        });
+        self.reaped.fetch_add(reaped as u64, Ordering::Relaxed);
+        reaped
+    }
+}
+
+/// Periodically reap orphaned subscription tasks
+pub async fn watchdog(registry: Arc<ConnectionRegistry>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let reaped = registry.reap();
+        if reaped > 0 {
+            tracing::info!(reaped, "watchdog reaped orphaned subscription tasks");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outbox::SlowConsumerPolicy;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reap_orphaned_tasks() {
+        let registry = ConnectionRegistry::new();
+        let client = Outbox::new(1, SlowConsumerPolicy::DropOldest);
+        let other_client = Outbox::new(1, SlowConsumerPolicy::DropOldest);
+
+        let key = Ref(vec!["hello".into()]);
+        registry.spawn(0, key.clone(), &client, std::future::pending());
+        let finished = registry.spawn(1, key.clone(), &other_client, async {});
+        registry.spawn(1, key.clone(), &other_client, std::future::pending());
+        while !registry.tasks.lock().unwrap()[&finished]
+            .handle
+            .is_finished()
+        {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            registry.counts(),
+            TaskCounts {
+                live: 2,
+                orphaned: 1,
+                reaped: 0
+            }
+        );
+
+        // Connection 0's client goes away without unsubscribing
+        client.close();
+        assert_eq!(registry.counts().orphaned, 2);
+        assert_eq!(registry.subscriptions()[&1], vec![key.clone(), key.clone()]);
+
+        assert_eq!(registry.reap(), 2);
+        assert_eq!(
+            registry.counts(),
+            TaskCounts {
+                live: 1,
+                orphaned: 0,
+                reaped: 2
+            }
+        );
+
+        registry.abort_connection(1);
+        assert_eq!(registry.counts().live, 0);
+    }
+
+    #[tokio::test]
+    async fn kick_clients() {
+        let registry = ConnectionRegistry::new();
+        let client = Outbox::new(1, SlowConsumerPolicy::DropOldest);
+        let kicked = registry.register(0, "127.0.0.1:5000".into(), None);
+        registry.register(1, "127.0.0.1:5001".into(), Some("blog".into()));
+        registry.spawn(
+            0,
+            Ref(vec!["hello".into()]),
+            &client,
+            std::future::pending(),
+        );
+
+        let clients = registry.clients();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].subscriptions, vec![Ref(vec!["hello".into()])]);
+        assert!(clients[1].subscriptions.is_empty());
+        assert_eq!(clients[1].tenant.as_deref(), Some("blog"));
+
+        assert!(registry.kick(0));
+        kicked.notified().await;
+        registry.unregister(0);
+        assert!(!registry.kick(0));
+        assert!(registry.client(0).is_none());
+        assert_eq!(registry.counts().live, 0);
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    message::{ClientMessage, Envelope},
+};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReplayError {
+    #[error("envelope timestamp is outside of the accepted window")]
+    Stale,
+    #[error("envelope nonce has already been used")]
+    Replayed,
+    #[error("envelope signature doesn't match its contents")]
+    BadSignature,
+    #[error("only writes and function calls may be enveloped")]
+    NotAWrite,
+    #[error("writes and function calls must be sent in an envelope")]
+    EnvelopeRequired,
+}
+
+impl ReplayError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::Replay
+    }
+}
+
+/// Rejects write envelopes that are too old, whose nonce has already been seen, or that weren't
+/// signed with the key of the connection they arrived on.
+///
+/// Shared between every connection, so a captured envelope can't be replayed over a fresh socket.
+pub struct ReplayGuard {
+    window: Duration,
+    require_envelopes: bool,
+    seen: Mutex<Seen>,
+}
+
+/// The nonces of envelopes still within the window, along with when each one expires. Nonces are
+/// kept per session token, as that's what envelopes are signed with, so two sessions picking the
+/// same nonce don't collide.
+#[derive(Default)]
+struct Seen {
+    // (session token, nonce)
+    nonces: HashSet<(String, String)>,
+    // (envelope timestamp in unix millis, session token, nonce), soonest to expire first
+    expiry: BinaryHeap<Reverse<(u64, String, String)>>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration, require_envelopes: bool) -> ReplayGuard {
+        ReplayGuard {
+            window,
+            require_envelopes,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// Validate a message as it comes off the wire, unwrapping it if it is enveloped. Envelopes
+    /// must be signed with `key`, the session token the connection was welcomed with.
+    pub fn open(&self, msg: ClientMessage, key: &str) -> Result<ClientMessage, ReplayError> {
+        match msg {
+            ClientMessage::Envelope(envelope) => self.open_envelope(envelope, key, now_millis()),
+            msg if msg.has_effects() && self.require_envelopes => {
+                Err(ReplayError::EnvelopeRequired)
+            }
+            msg => Ok(msg),
+        }
+    }
+
+    /// Check a write that arrived without a WebSocket to envelope it on, such as through the HTTP
+    /// gateway, which is refused if envelopes are required
+    pub fn check_unenveloped(&self) -> Result<(), ReplayError> {
+        if self.require_envelopes {
+            Err(ReplayError::EnvelopeRequired)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn open_envelope(
+        &self,
+        envelope: Envelope,
+        key: &str,
+        now: u64,
+    ) -> Result<ClientMessage, ReplayError> {
+        if !envelope.message.has_effects() {
+            return Err(ReplayError::NotAWrite);
+        }
+        let window = self.window.as_millis() as u64;
+        if now.abs_diff(envelope.timestamp) > window {
+            return Err(ReplayError::Stale);
+        }
+        // Checked before the nonce is recorded, so forgeries can't use up nonces
+        let signature = decode_hex(&envelope.signature).ok_or(ReplayError::BadSignature)?;
+        signer(key, &envelope.nonce, envelope.timestamp, &envelope.message)
+            .verify_slice(&signature)
+            .map_err(|_| ReplayError::BadSignature)?;
+
+        let mut seen = self.seen.lock().unwrap();
+        // Anything older than the window would be rejected as stale, so there's no need to
+        // remember its nonce any longer
+        while let Some(Reverse((timestamp, _, _))) = seen.expiry.peek() {
+            if timestamp.saturating_add(window) >= now {
+                break;
+            }
+            let Some(Reverse((_, session, nonce))) = seen.expiry.pop() else {
+                break;
+            };
+            seen.nonces.remove(&(session, nonce));
+        }
+        if !seen
+            .nonces
+            .insert((key.to_string(), envelope.nonce.clone()))
+        {
+            return Err(ReplayError::Replayed);
+        }
+        seen.expiry.push(Reverse((
+            envelope.timestamp,
+            key.to_string(),
+            envelope.nonce,
+        )));
+
+        Ok(*envelope.message)
+    }
+}
+
+/// What signs an envelope: HMAC-SHA256, keyed with the connection's session token, of its nonce,
+/// timestamp, and message, each on its own line. The message is written as JSON with the keys of
+/// every object in sorted order.
+fn signer(key: &str, nonce: &str, timestamp: u64, message: &ClientMessage) -> Hmac<Sha256> {
+    // Going through a `Value` sorts the keys of objects, whatever order they arrived in
+    let message = serde_json::to_value(message).expect("messages can be written as JSON");
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes any key");
+    mac.update(format!("{nonce}\n{timestamp}\n{message}").as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is after the unix epoch")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hmac::Mac;
+    use serde_json::Value;
+
+    use crate::message::{ClientMessage, Envelope, Ref};
+
+    use super::{now_millis, signer, ReplayError, ReplayGuard};
+
+    const KEY: &str = "session";
+
+    fn envelope(nonce: &str, timestamp: u64, message: ClientMessage) -> Envelope {
+        signed(KEY, nonce, timestamp, message)
+    }
+
+    fn signed(key: &str, nonce: &str, timestamp: u64, message: ClientMessage) -> Envelope {
+        let signature = signer(key, nonce, timestamp, &message)
+            .finalize()
+            .into_bytes();
+        Envelope {
+            nonce: nonce.to_string(),
+            timestamp,
+            signature: signature.iter().map(|byte| format!("{byte:02x}")).collect(),
+            message: Box::new(message),
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_and_stale_envelopes() {
+        let guard = ReplayGuard::new(Duration::from_secs(30), false);
+        let remove = || ClientMessage::Remove(Ref(vec!["hello".to_string()]));
+
+        let now = 100_000;
+        assert!(guard
+            .open_envelope(envelope("a", now, remove()), KEY, now)
+            .is_ok());
+        assert_eq!(
+            guard
+                .open_envelope(envelope("a", now, remove()), KEY, now + 1)
+                .unwrap_err(),
+            ReplayError::Replayed
+        );
+        assert!(guard
+            .open_envelope(envelope("b", now, remove()), KEY, now + 1)
+            .is_ok());
+        assert_eq!(
+            guard
+                .open_envelope(envelope("c", now, remove()), KEY, now + 30_001)
+                .unwrap_err(),
+            ReplayError::Stale
+        );
+
+        // Nonces are forgotten once their envelopes would be stale anyway
+        assert!(guard
+            .open_envelope(envelope("d", now + 30_001, remove()), KEY, now + 30_001)
+            .is_ok());
+        assert_eq!(guard.seen.lock().unwrap().nonces.len(), 1);
+    }
+
+    #[test]
+    fn keeps_nonces_per_session() {
+        let guard = ReplayGuard::new(Duration::from_secs(30), false);
+        let remove = || ClientMessage::Remove(Ref(vec!["hello".to_string()]));
+
+        let now = 100_000;
+        assert!(guard
+            .open_envelope(signed(KEY, "a", now, remove()), KEY, now)
+            .is_ok());
+        assert!(guard
+            .open_envelope(signed("other", "a", now, remove()), "other", now)
+            .is_ok());
+        assert_eq!(
+            guard
+                .open_envelope(signed("other", "a", now, remove()), "other", now)
+                .unwrap_err(),
+            ReplayError::Replayed
+        );
+    }
+
+    #[test]
+    fn rejects_forged_envelopes() {
+        let guard = ReplayGuard::new(Duration::from_secs(30), false);
+        let now = 100_000;
+        let r = Ref(vec!["hello".to_string()]);
+
+        // Signed for another session
+        let signed = envelope("a", now, ClientMessage::Remove(r.clone()));
+        assert_eq!(
+            guard.open_envelope(signed, "other", now).unwrap_err(),
+            ReplayError::BadSignature
+        );
+        // Rewrapped with a fresh nonce, or around a different message
+        let mut rewrapped = envelope("a", now, ClientMessage::Remove(r.clone()));
+        rewrapped.nonce = "b".to_string();
+        assert_eq!(
+            guard.open_envelope(rewrapped, KEY, now).unwrap_err(),
+            ReplayError::BadSignature
+        );
+        let mut swapped = envelope("c", now, ClientMessage::Remove(r.clone()));
+        swapped.message = Box::new(ClientMessage::Insert(r, Value::String("1".to_string())));
+        assert_eq!(
+            guard.open_envelope(swapped, KEY, now).unwrap_err(),
+            ReplayError::BadSignature
+        );
+        // None of which used up the nonces they carried
+        assert!(guard
+            .open_envelope(
+                envelope("b", now, ClientMessage::Remove(Ref(vec![]))),
+                KEY,
+                now
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn requires_envelopes_for_writes() {
+        let guard = ReplayGuard::new(Duration::from_secs(30), true);
+        let r = Ref(vec!["hello".to_string()]);
+
+        assert!(guard.open(ClientMessage::Get(r.clone()), KEY).is_ok());
+        assert_eq!(
+            guard
+                .open(
+                    ClientMessage::Update(r, Value::String("1".to_string())),
+                    KEY
+                )
+                .unwrap_err(),
+            ReplayError::EnvelopeRequired
+        );
+        // Functions may write too
+        let call = ClientMessage::Call {
+            name: "increment".to_string(),
+            args: Value::Null,
+        };
+        assert_eq!(
+            guard.open(call.clone(), KEY).unwrap_err(),
+            ReplayError::EnvelopeRequired
+        );
+        let enveloped = ClientMessage::Envelope(envelope("a", now_millis(), call));
+        assert!(guard.open(enveloped, KEY).is_ok());
+    }
+}
@@ -0,0 +1,229 @@
+use std::{sync::Arc, time::Duration};
+
+use http_body_util::{BodyExt, Empty};
+use hyper::{body::Bytes, header, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::net::TcpStream;
+
+use crate::{
+    changes::{Change, ChangeOp},
+    server::ServerError,
+    tenant::{Tenant, Tenants},
+};
+
+/// How many changes to ask the leader for at a time
+const BATCH: usize = 1000;
+/// How long the leader holds a read of the change log open waiting for a change
+const WAIT_SECS: u64 = 30;
+/// How long the leader has to answer before the request counts as failed
+const TIMEOUT: Duration = Duration::from_secs(WAIT_SECS + 30);
+/// How long to wait before trying again after replication fails
+const RETRY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ReplicationError {
+    #[error("invalid leader URL {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("could not reach the leader: {0}")]
+    Request(String),
+    #[error("the leader responded {status}: {body}")]
+    Leader { status: StatusCode, body: String },
+    #[error("{0}")]
+    Server(#[from] ServerError),
+}
+
+/// Keeps every tenant's store a copy of the leader's, by streaming the leader's change log from
+/// its admin API, with its admin token, and applying each change locally.
+///
+/// A tenant with nothing replicated yet starts from a snapshot of the leader's data. After that,
+/// how far through the leader's log each tenant has got is recorded along with each change it
+/// applies, so a follower picks up where it left off after a restart. If the leader has trimmed
+/// changes it hasn't got to by then, it starts again from a snapshot. The leader and follower must
+/// be configured with the same tenants and schemas.
+pub struct Follower {
+    tenants: Arc<Tenants>,
+    leader: Uri,
+    /// The leader's admin token, which it needs to replicate
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    seq: u64,
+    snapshot: Value,
+}
+
+impl Follower {
+    pub fn new(
+        tenants: Arc<Tenants>,
+        leader: &str,
+        token: Option<String>,
+    ) -> Result<Follower, ReplicationError> {
+        let invalid = |reason: &str| ReplicationError::InvalidUrl {
+            url: leader.to_string(),
+            reason: reason.to_string(),
+        };
+        let parsed: Uri = leader.parse().map_err(|e| invalid(&format!("{e}")))?;
+        if parsed.scheme_str() != Some("http") {
+            return Err(invalid("the leader's admin API is only served over http"));
+        }
+        if parsed.host().is_none() {
+            return Err(invalid("there's no host"));
+        }
+        Ok(Follower {
+            tenants,
+            leader: parsed,
+            token,
+        })
+    }
+
+    /// Replicate every tenant in the background, forever
+    pub fn start(self) {
+        let follower = Arc::new(self);
+        for tenant in follower.tenants.iter() {
+            tokio::spawn(follower.clone().follow(tenant.clone()));
+        }
+    }
+
+    async fn follow(self: Arc<Self>, tenant: Tenant) {
+        let name = tenant.name.as_deref().unwrap_or("default");
+        loop {
+            if let Err(e) = self.replicate(&tenant).await {
+                tracing::warn!(tenant = name, "replication failed: {e}");
+                tokio::time::sleep(RETRY).await;
+            }
+        }
+    }
+
+    /// Apply changes from the leader until something fails
+    async fn replicate(&self, tenant: &Tenant) -> Result<(), ReplicationError> {
+        let mut next = match tenant.server.replicated()? {
+            Some(next) => next,
+            None => self.restore(tenant).await?,
+        };
+
+        loop {
+            let query = [("from", next), ("limit", BATCH as u64), ("wait", WAIT_SECS)];
+            let changes: Vec<Change> = match self.request(tenant, "changes", &query).await {
+                Ok(changes) => changes,
+                Err(ReplicationError::Leader {
+                    status: StatusCode::GONE,
+                    ..
+                }) => {
+                    tracing::warn!(
+                        tenant = tenant.name.as_deref().unwrap_or("default"),
+                        "the leader has trimmed changes that weren't applied yet; starting again"
+                    );
+                    next = self.restore(tenant).await?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let Some(last) = changes.last() else {
+                continue;
+            };
+            next = last.seq + 1;
+            let server = tenant.server.clone();
+            // Applying writes to the store, so keep it off the workers
+            tokio::task::spawn_blocking(move || {
+                (changes.iter()).try_for_each(|change| server.apply(&change.op, change.seq + 1))
+            })
+            .await
+            .expect("applying changes doesn't panic")?;
+        }
+    }
+
+    /// Replace the tenant's data with a snapshot of the leader's, returning the number of the
+    /// first change it doesn't include
+    async fn restore(&self, tenant: &Tenant) -> Result<u64, ReplicationError> {
+        let Snapshot { seq, snapshot } = self.request(tenant, "snapshot", &[]).await?;
+        let server = tenant.server.clone();
+        tokio::task::spawn_blocking(move || server.apply(&ChangeOp::Restore { snapshot }, seq))
+            .await
+            .expect("restoring doesn't panic")?;
+        tracing::info!(
+            tenant = tenant.name.as_deref().unwrap_or("default"),
+            seq,
+            "restored a snapshot from the leader"
+        );
+        Ok(seq)
+    }
+
+    /// GET one of the leader's replication endpoints for `tenant`
+    async fn request<T: DeserializeOwned>(
+        &self,
+        tenant: &Tenant,
+        endpoint: &str,
+        query: &[(&str, u64)],
+    ) -> Result<T, ReplicationError> {
+        let mut params: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        // Tenant names are slugs, so they don't need escaping
+        if let Some(name) = &tenant.name {
+            params.push(format!("tenant={name}"));
+        }
+        let path = format!(
+            "{}/replication/{endpoint}?{}",
+            self.leader.path().trim_end_matches('/'),
+            params.join("&")
+        );
+        let body = tokio::time::timeout(TIMEOUT, self.get(&path))
+            .await
+            .map_err(|_| ReplicationError::Request("timed out".to_string()))??;
+        serde_json::from_slice(&body)
+            .map_err(|e| ReplicationError::Request(format!("unexpected response: {e}")))
+    }
+
+    async fn get(&self, path: &str) -> Result<Bytes, ReplicationError> {
+        let request_error = |e: &dyn std::fmt::Display| ReplicationError::Request(format!("{e}"));
+        let host = self
+            .leader
+            .host()
+            .expect("leader URLs are checked for a host");
+        let port = self.leader.port_u16().unwrap_or(80);
+        let authority = self
+            .leader
+            .authority()
+            .expect("URLs with a host have an authority");
+        let mut request = Request::get(path)
+            .header(header::HOST, authority.as_str())
+            .header(header::USER_AGENT, "iceload");
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = request.body(Empty::<Bytes>::new()).unwrap();
+
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(|e| request_error(&e))?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| request_error(&e))?;
+        // Drives the connection until the response has been read
+        tokio::spawn(connection);
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| request_error(&e))?;
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| request_error(&e))?
+            .to_bytes();
+        if !status.is_success() {
+            return Err(ReplicationError::Leader {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+        Ok(body)
+    }
+}
@@ -0,0 +1,41 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    integration::{self, IntegrationError},
+    tenant::{Tenant, Tenants},
+};
+
+/// Trim every tenant's change log down to its `keep` most recent changes on schedule, forever.
+/// Changes the integrations haven't queued yet are kept however many there are, but followers
+/// and resuming clients that fall further behind have to start again.
+pub async fn run(tenants: Arc<Tenants>, keep: usize, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let tenants = tenants.clone();
+        // Each change is removed on its own, so keep it off the workers
+        tokio::task::spawn_blocking(move || {
+            for tenant in tenants.iter() {
+                if let Err(e) = trim(tenant, keep) {
+                    tracing::error!(tenant = ?tenant.name, "trimming the change log failed: {e}");
+                }
+            }
+        })
+        .await
+        .expect("trimming the change log doesn't panic");
+    }
+}
+
+fn trim(tenant: &Tenant, keep: usize) -> Result<(), IntegrationError> {
+    // Integrations only run for the default tenant
+    let pinned = match tenant.name {
+        Some(_) => None,
+        None => integration::cursor(&tenant.server)?,
+    };
+    let removed = tenant.server.trim_changes(keep, pinned)?;
+    if removed > 0 {
+        tracing::debug!(tenant = ?tenant.name, removed, "trimmed the change log");
+    }
+    Ok(())
+}
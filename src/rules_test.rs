@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use iceload::{
+    message::Ref,
+    permission::{Operation, Permissions},
+};
+
+/// A single expectation about the permission script, as declared in a rules test file
+#[derive(Debug, Deserialize)]
+pub struct RuleTestCase {
+    pub op: Operation,
+    pub path: Ref,
+    #[serde(default)]
+    pub user: Option<String>,
+    pub expected: bool,
+}
+
+/// Run every case in `cases_path` against the rules in `rules_path`, printing a line per case.
+///
+/// Returns whether all of the cases passed.
+pub fn run(rules_path: &Path, cases_path: &Path) -> anyhow::Result<bool> {
+    let source = std::fs::read_to_string(rules_path)?;
+    let bytecode = Permissions::load_bytecode(&source)?;
+    let permissions = Permissions::new(bytecode);
+
+    let cases: Vec<RuleTestCase> = serde_json::from_str(&std::fs::read_to_string(cases_path)?)?;
+
+    let checks: Vec<_> = cases
+        .iter()
+        .map(|case| (case.op, &case.path, case.user.as_deref()))
+        .collect();
+    let verdicts = permissions.check_all(&checks)?;
+
+    let mut failures = 0;
+    for (case, result) in cases.iter().zip(verdicts) {
+        let description = format!(
+            "{} {:?} as {}",
+            case.op.as_str(),
+            case.path.0,
+            case.user.as_deref().unwrap_or("<anonymous>")
+        );
+        match result {
+            Ok(allowed) if allowed == case.expected => println!("pass: {description}"),
+            Ok(allowed) => {
+                failures += 1;
+                println!(
+                    "FAIL: {description}: expected {}, got {}",
+                    verdict(case.expected),
+                    verdict(allowed)
+                );
+            }
+            Err(err) => {
+                failures += 1;
+                println!("FAIL: {description}: {err}");
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", cases.len() - failures, failures);
+
+    Ok(failures == 0)
+}
+
+fn verdict(allowed: bool) -> &'static str {
+    if allowed {
+        "allow"
+    } else {
+        "deny"
+    }
+}
@@ -1,54 +1,309 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::Path,
+};
 
+use regex::Regex;
+use serde::{
+    de::{DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::Value;
 use thiserror::Error;
 
-use crate::message::RefComponent;
+use crate::{
+    error::ErrorKind,
+    message::{Ref, RefComponent},
+};
 
-pub struct Schema(SchemaItem);
+#[derive(Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Schema {
+    root: SchemaItem,
+    /// Document field names -> the numbers refs are encoded with
+    #[serde(skip)]
+    field_ids: HashMap<String, u64>,
+    /// The reverse, which may also hold fields that have since been removed from the schema
+    #[serde(skip)]
+    field_names: HashMap<u64, String>,
+}
 
 impl Schema {
     pub fn new(root: SchemaItem) -> Schema {
-        Schema(root)
+        let mut schema = Schema {
+            root,
+            field_ids: HashMap::new(),
+            field_names: HashMap::new(),
+        };
+        let mut names: Vec<String> = schema.field_names().into_iter().collect();
+        names.sort_unstable();
+        schema.set_field_ids(names.into_iter().zip(0..).collect());
+        schema
     }
 
-    // TODO: more efficient encoding format (e.g. dependent on field ordering?)
-    pub fn encode_ref(&self, refs: &[RefComponent]) -> Vec<u8> {
-        let mut encoded = Vec::new();
+    pub fn load(path: &Path) -> Result<Schema, SchemaLoadError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
 
-        for component in refs.iter() {
-            let bytes = component.as_bytes();
-            let len = bytes.len();
-            encoded.extend(len.to_le_bytes());
-            encoded.extend(bytes);
+    /// Parse the contents of a schema file like `load`, along with whatever is wrong with the
+    /// schema that doesn't stop it parsing: keys given twice, of which all but the last would be
+    /// ignored, documents without fields, and items nested where the server can't support them
+    pub fn check(source: &str) -> Result<(Schema, Vec<SchemaProblem>), SchemaLoadError> {
+        let schema: Schema = serde_json::from_str(source)?;
+        let mut problems = Vec::new();
+        let mut deserializer = serde_json::Deserializer::from_str(source);
+        DuplicateKeys {
+            path: &mut Vec::new(),
+            found: &mut problems,
         }
+        .deserialize(&mut deserializer)?;
+        schema.root.check(&mut Vec::new(), true, &mut problems);
+        Ok((schema, problems))
+    }
+
+    pub fn root(&self) -> &SchemaItem {
+        &self.root
+    }
 
+    /// The name of every field of every document in the schema
+    pub fn field_names(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        self.root.field_names(&mut names);
+        names
+    }
+
+    /// Set the numbers document fields are encoded as in refs. They have to stay the same for as
+    /// long as the data they're used to store, so the server keeps them in the database.
+    pub fn set_field_ids(&mut self, ids: HashMap<String, u64>) {
+        self.field_names = ids.iter().map(|(name, id)| (*id, name.clone())).collect();
+        self.field_ids = ids;
+    }
+
+    /// Encode a ref as a key for the store.
+    ///
+    /// Each component is a varint, tagged in its lowest bit: a document field known to the schema
+    /// is stored as its number, and anything else, like a collection key, as its length followed
+    /// by its UTF-8 bytes. Every component is self-delimiting, so the encoding of a ref is a
+    /// prefix of the encoding of everything under it, and nothing else.
+    pub fn encode_ref(&self, refs: &[RefComponent]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut item = Some(&self.root);
+        for component in refs {
+            // Wrappers don't change how what's inside them is stored, so wrapping or unwrapping an
+            // item leaves the keys under it as they were
+            while let Some(
+                SchemaItem::Sensitive(inner)
+                | SchemaItem::Ephemeral(inner)
+                | SchemaItem::Optional(inner)
+                | SchemaItem::Searchable(inner),
+            ) = item
+            {
+                item = Some(inner);
+            }
+            let field_id = match item {
+                Some(SchemaItem::Document(fields)) => {
+                    item = fields.get(component);
+                    self.field_ids.get(component).filter(|_| item.is_some())
+                }
+                Some(SchemaItem::Collection(collection)) => {
+                    item = Some(&collection.items);
+                    None
+                }
+                _ => {
+                    item = None;
+                    None
+                }
+            };
+            match field_id {
+                Some(id) => write_varint(&mut encoded, id << 1),
+                None => {
+                    write_varint(&mut encoded, ((component.len() as u64) << 1) | 1);
+                    encoded.extend(component.as_bytes());
+                }
+            }
+        }
         encoded
     }
 
     pub fn decode_ref(&self, encoded_ref: &[u8]) -> Vec<RefComponent> {
-        let mut decoded = Vec::new();
+        self.try_decode_ref(encoded_ref)
+            .expect("keys in the store are encoded refs")
+    }
 
-        let mut idx = 0;
-        while idx < encoded_ref.len() {
-            let mut str_len_bytes = [0u8; USIZE_LEN];
-            let len_end = idx + USIZE_LEN;
-            str_len_bytes.copy_from_slice(&encoded_ref[idx..len_end]);
-            let str_len = usize::from_le_bytes(str_len_bytes);
-            let str_bytes = &encoded_ref[len_end..len_end + str_len];
-            let string = String::from_utf8(str_bytes.to_vec()).unwrap();
-            decoded.push(string);
-            idx = len_end + str_len;
+    /// Decode a ref, or return None if the bytes weren't produced by `encode_ref`
+    pub fn try_decode_ref(&self, mut encoded_ref: &[u8]) -> Option<Vec<RefComponent>> {
+        let mut decoded = Vec::new();
+        while !encoded_ref.is_empty() {
+            let tagged = read_varint(&mut encoded_ref)?;
+            if tagged & 1 == 0 {
+                decoded.push(self.field_names.get(&(tagged >> 1))?.clone());
+            } else {
+                let len = usize::try_from(tagged >> 1).ok()?;
+                let bytes = encoded_ref.get(..len)?;
+                decoded.push(String::from_utf8(bytes.to_vec()).ok()?);
+                encoded_ref = &encoded_ref[len..];
+            }
         }
+        Some(decoded)
+    }
+
+    /// The key of the collection member stored at `encoded_ref`, if it's directly inside the
+    /// collection encoded as `collection` rather than nested further within one of its members
+    pub fn decode_member(&self, collection: &[u8], encoded_ref: &[u8]) -> Option<RefComponent> {
+        self.decode_member_prefix(collection, encoded_ref)
+            .filter(|(_, member)| member.len() == encoded_ref.len())
+            .map(|(key, _)| key)
+    }
 
-        decoded
+    /// The key of the collection member that `encoded_ref` is at or beneath, along with the
+    /// member's own encoded ref, which everything stored beneath it starts with
+    pub fn decode_member_prefix<'a>(
+        &self,
+        collection: &[u8],
+        encoded_ref: &'a [u8],
+    ) -> Option<(RefComponent, &'a [u8])> {
+        let mut rest = encoded_ref.strip_prefix(collection)?;
+        let tagged = read_varint(&mut rest)?;
+        if tagged & 1 == 0 {
+            return None;
+        }
+        let key = rest.get(..usize::try_from(tagged >> 1).ok()?)?;
+        let member_len = encoded_ref.len() - rest.len() + key.len();
+        Some((
+            String::from_utf8(key.to_vec()).ok()?,
+            &encoded_ref[..member_len],
+        ))
     }
 
     pub fn resolve(&self, refs: &[RefComponent]) -> Result<&SchemaItem, SchemaResolutionError> {
-        self.0.resolve(refs)
+        self.root.resolve(refs)
+    }
+
+    /// Whether the path is of a member of a presence collection, rather than the collection
+    /// itself, as both resolve to the same item
+    pub fn is_presence_member(&self, refs: &[RefComponent]) -> bool {
+        match refs.split_last() {
+            Some((_, parent)) => matches!(self.resolve(parent), Ok(SchemaItem::Presence)),
+            None => false,
+        }
+    }
+
+    /// Whether the item at this path, or anything containing it, is marked sensitive
+    pub fn is_sensitive(&self, refs: &[RefComponent]) -> bool {
+        self.is_within(refs, |item| matches!(item, SchemaItem::Sensitive(_)))
     }
+
+    /// Whether the item at this path, or anything containing it, is held in memory rather than
+    /// stored
+    pub fn is_ephemeral(&self, refs: &[RefComponent]) -> bool {
+        self.is_within(refs, |item| matches!(item, SchemaItem::Ephemeral(_)))
+    }
+
+    /// Whether the item at this path, or anything containing it, is indexed for searching
+    pub fn is_searchable(&self, refs: &[RefComponent]) -> bool {
+        self.is_within(refs, |item| matches!(item, SchemaItem::Searchable(_)))
+    }
+
+    /// The item at this path as the schema declares it, where `resolve` unwraps any sensitive,
+    /// ephemeral, optional or searchable item to what's inside
+    pub fn item(&self, refs: &[RefComponent]) -> Result<&SchemaItem, SchemaResolutionError> {
+        let Some((last, parent)) = refs.split_last() else {
+            return Ok(&self.root);
+        };
+        match self.resolve(parent)? {
+            SchemaItem::Document(fields) => fields
+                .get(last)
+                .ok_or_else(|| SchemaResolutionError::UnknownField(last.clone())),
+            SchemaItem::Collection(collection) => Ok(&collection.items),
+            _ => self.resolve(refs),
+        }
+    }
+
+    /// Whether the item at this path may be left out of whatever contains it
+    pub fn is_optional(&self, refs: &[RefComponent]) -> bool {
+        let Ok(mut item) = self.item(refs) else {
+            return false;
+        };
+        loop {
+            item = match item {
+                SchemaItem::Optional(_) => return true,
+                SchemaItem::Sensitive(inner)
+                | SchemaItem::Ephemeral(inner)
+                | SchemaItem::Searchable(inner) => inner,
+                _ => return false,
+            };
+        }
+    }
+
+    /// Whether `wrapper` matches the item at this path or anything containing it
+    fn is_within(&self, refs: &[RefComponent], wrapper: fn(&SchemaItem) -> bool) -> bool {
+        let mut item = &self.root;
+        let mut refs = refs;
+        loop {
+            if wrapper(item) {
+                return true;
+            }
+            item = match (item, refs.split_first()) {
+                (
+                    SchemaItem::Sensitive(inner)
+                    | SchemaItem::Ephemeral(inner)
+                    | SchemaItem::Optional(inner)
+                    | SchemaItem::Searchable(inner),
+                    _,
+                ) => inner,
+                (_, None) => return false,
+                (SchemaItem::Collection(collection), Some((_, rest))) => {
+                    refs = rest;
+                    &collection.items
+                }
+                (SchemaItem::Document(fields), Some((field, rest))) => match fields.get(field) {
+                    Some(field) => {
+                        refs = rest;
+                        field
+                    }
+                    None => return false,
+                },
+                (
+                    SchemaItem::Scalar
+                    | SchemaItem::Custom(_)
+                    | SchemaItem::Reference
+                    | SchemaItem::ReferenceTo(_)
+                    | SchemaItem::Enum(_)
+                    | SchemaItem::Presence,
+                    _,
+                ) => return false,
+            };
+        }
+    }
+
+    /// A copy of `value`, which lives at `refs`, with every sensitive part of it replaced, so it
+    /// can be logged
+    pub fn redact(&self, refs: &[RefComponent], value: &Value) -> Value {
+        if self.is_sensitive(refs) {
+            return Value::String(REDACTED.to_string());
+        }
+        match self.resolve(refs) {
+            Ok(item) => item.redact(value),
+            Err(_) => value.clone(),
+        }
+    }
+}
+
+/// Stands in for values of sensitive fields in logs and error messages
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SchemaLoadError {
+    #[error("failed to read schema file: {}", .0)]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse schema file: {}", .0)]
+    Parse(#[from] serde_json::Error),
 }
 
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum SchemaResolutionError {
     #[error("unknown field: {}", .0)]
     UnknownField(String),
@@ -56,39 +311,612 @@ pub enum SchemaResolutionError {
     IllegalRefOnScalar,
 }
 
-#[derive(Debug)]
+impl SchemaResolutionError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidPath
+    }
+}
+
+impl SchemaLoadError {
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::InvalidSchema
+    }
+}
+
+/// Something wrong with a schema that parses, found by `Schema::check`. Paths are of items in
+/// the schema, with `*` standing for any member of a collection, except those of duplicate keys,
+/// which are of keys in the schema file.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SchemaProblem {
+    #[error("{} is given more than once", .0)]
+    DuplicateKey(Ref),
+    #[error("the document at {} has no fields", .0)]
+    EmptyDocument(Ref),
+    #[error("the {item} item at {path} {reason}")]
+    UnsupportedNesting {
+        path: Ref,
+        item: &'static str,
+        reason: &'static str,
+    },
+}
+
+/// Finds the keys given more than once in the same object of a JSON document, which
+/// deserializing a map quietly drops all but the last of
+struct DuplicateKeys<'a> {
+    path: &'a mut Vec<String>,
+    found: &'a mut Vec<SchemaProblem>,
+}
+
+impl<'de> DeserializeSeed<'de> for DuplicateKeys<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for DuplicateKeys<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "any JSON value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut items: A) -> Result<(), A::Error> {
+        for index in 0.. {
+            self.path.push(index.to_string());
+            let item = items.next_element_seed(DuplicateKeys {
+                path: &mut *self.path,
+                found: &mut *self.found,
+            })?;
+            self.path.pop();
+            if item.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut entries: A) -> Result<(), A::Error> {
+        let mut seen = HashSet::new();
+        while let Some(key) = entries.next_key::<String>()? {
+            self.path.push(key.clone());
+            if !seen.insert(key) {
+                self.found
+                    .push(SchemaProblem::DuplicateKey(Ref(self.path.clone())));
+            }
+            entries.next_value_seed(DuplicateKeys {
+                path: &mut *self.path,
+                found: &mut *self.found,
+            })?;
+            self.path.pop();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub enum SchemaItem {
-    #[allow(dead_code)]
-    Collection(Box<SchemaItem>),
+    Collection(CollectionSchema),
     Document(HashMap<String, SchemaItem>),
     Scalar,
+    /// A scalar stored with the named codec registered on the server
+    Custom(String),
+    /// A scalar holding the ref of another item, e.g. `["users", "ada"]`, which subscriptions
+    /// can follow
+    Reference,
+    /// A reference that may only point at items whose path matches the pattern, where `*` matches
+    /// any key, e.g. `["users", "*"]`
+    ReferenceTo(Vec<String>),
+    /// A scalar that must be one of a fixed set of strings, e.g. a post's `status`
+    Enum(Vec<String>),
+    /// Any item whose values must never appear in logs or error messages, e.g. personal data
+    Sensitive(Box<SchemaItem>),
+    /// A collection of scalars held in memory rather than stored, e.g. the users online in a
+    /// room. Each member is set by a connection joining it, and goes away when that connection
+    /// leaves it or closes.
+    Presence,
+    /// Any item held in memory rather than stored, e.g. typing indicators or cursors, which
+    /// change too often to be worth writing to disk and are lost when the server stops. It's
+    /// written on its own rather than along with whatever contains it.
+    Ephemeral(Box<SchemaItem>),
+    /// A field that documents may leave out, which reads as null until it's set and can be
+    /// cleared by updating it to null
+    Optional(Box<SchemaItem>),
+    /// Any item whose scalars are indexed by word, so `Search` can find the documents they're in.
+    /// Values written before the item was made searchable aren't indexed until they're written
+    /// again, unless the schema was replaced while the server was running.
+    Searchable(Box<SchemaItem>),
 }
 
 impl SchemaItem {
+    /// Add the problems `Schema::check` looks for in this item, which is at `path`, to `found`.
+    /// `field` is whether it's a field of a document, whose own item it may wrap.
+    fn check(&self, path: &mut Vec<String>, field: bool, found: &mut Vec<SchemaProblem>) {
+        let unsupported = |item, reason| SchemaProblem::UnsupportedNesting {
+            path: Ref(path.clone()),
+            item,
+            reason,
+        };
+        match self {
+            SchemaItem::Collection(collection) => {
+                path.push("*".to_string());
+                collection.items.check(path, false, found);
+                path.pop();
+            }
+            SchemaItem::Document(fields) => {
+                if fields.is_empty() {
+                    found.push(SchemaProblem::EmptyDocument(Ref(path.clone())));
+                }
+                let mut names: Vec<&String> = fields.keys().collect();
+                names.sort_unstable();
+                for name in names {
+                    path.push(name.clone());
+                    fields[name].check(path, true, found);
+                    path.pop();
+                }
+            }
+            SchemaItem::Sensitive(inner)
+            | SchemaItem::Ephemeral(inner)
+            | SchemaItem::Optional(inner)
+            | SchemaItem::Searchable(inner) => {
+                let item = self.wrapper_name();
+                if std::mem::discriminant(self) == std::mem::discriminant(&**inner) {
+                    found.push(unsupported(item, "wraps another of the same kind"));
+                }
+                if matches!(self, SchemaItem::Optional(_)) && !field {
+                    found.push(unsupported(item, "isn't a field of a document"));
+                }
+                if matches!(self, SchemaItem::Searchable(_)) && inner.is_in_memory() {
+                    found.push(unsupported(item, "is held in memory, which isn't indexed"));
+                }
+                if matches!(self, SchemaItem::Ephemeral(_)) && inner.is_searchable() {
+                    found.push(unsupported(item, "holds something searchable in memory"));
+                }
+                inner.check(path, field, found);
+            }
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_)
+            | SchemaItem::Presence => {}
+        }
+    }
+
+    fn wrapper_name(&self) -> &'static str {
+        match self {
+            SchemaItem::Sensitive(_) => "sensitive",
+            SchemaItem::Ephemeral(_) => "ephemeral",
+            SchemaItem::Optional(_) => "optional",
+            SchemaItem::Searchable(_) => "searchable",
+            _ => unreachable!("only wrapping items are named"),
+        }
+    }
+
+    /// Whether this item, or anything within it, is held in memory rather than stored
+    fn is_in_memory(&self) -> bool {
+        self.any(&|item| matches!(item, SchemaItem::Ephemeral(_) | SchemaItem::Presence))
+    }
+
+    /// Whether this item, or anything within it, is searchable
+    fn is_searchable(&self) -> bool {
+        self.any(&|item| matches!(item, SchemaItem::Searchable(_)))
+    }
+
+    fn any(&self, matches: &impl Fn(&SchemaItem) -> bool) -> bool {
+        matches(self)
+            || match self {
+                SchemaItem::Collection(collection) => collection.items.any(matches),
+                SchemaItem::Document(fields) => fields.values().any(|field| field.any(matches)),
+                SchemaItem::Sensitive(inner)
+                | SchemaItem::Ephemeral(inner)
+                | SchemaItem::Optional(inner)
+                | SchemaItem::Searchable(inner) => inner.any(matches),
+                SchemaItem::Scalar
+                | SchemaItem::Custom(_)
+                | SchemaItem::Reference
+                | SchemaItem::ReferenceTo(_)
+                | SchemaItem::Enum(_)
+                | SchemaItem::Presence => false,
+            }
+    }
+
+    fn field_names(&self, names: &mut HashSet<String>) {
+        match self {
+            SchemaItem::Collection(collection) => collection.items.field_names(names),
+            SchemaItem::Document(fields) => {
+                for (name, field) in fields {
+                    names.insert(name.clone());
+                    field.field_names(names);
+                }
+            }
+            SchemaItem::Sensitive(inner)
+            | SchemaItem::Ephemeral(inner)
+            | SchemaItem::Optional(inner)
+            | SchemaItem::Searchable(inner) => inner.field_names(names),
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_)
+            | SchemaItem::Presence => {}
+        }
+    }
+
     fn resolve(&self, refs: &[RefComponent]) -> Result<&SchemaItem, SchemaResolutionError> {
-        if refs.is_empty() {
+        if let SchemaItem::Sensitive(inner)
+        | SchemaItem::Ephemeral(inner)
+        | SchemaItem::Optional(inner)
+        | SchemaItem::Searchable(inner) = self
+        {
+            inner.resolve(refs)
+        } else if refs.is_empty() {
             Ok(self)
         } else {
             match self {
-                SchemaItem::Collection(inner) => inner.resolve(&refs[1..]),
+                SchemaItem::Collection(collection) => collection.items.resolve(&refs[1..]),
                 SchemaItem::Document(fields) => fields
                     .get(&refs[0])
                     .ok_or_else(|| SchemaResolutionError::UnknownField(refs[0].clone()))?
                     .resolve(&refs[1..]),
-                SchemaItem::Scalar => Err(SchemaResolutionError::IllegalRefOnScalar),
+                // Members resolve to the presence item itself; see `Schema::is_presence_member`
+                SchemaItem::Presence if refs.len() == 1 => Ok(self),
+                SchemaItem::Scalar
+                | SchemaItem::Custom(_)
+                | SchemaItem::Reference
+                | SchemaItem::ReferenceTo(_)
+                | SchemaItem::Enum(_)
+                | SchemaItem::Presence => Err(SchemaResolutionError::IllegalRefOnScalar),
+                SchemaItem::Sensitive(_)
+                | SchemaItem::Ephemeral(_)
+                | SchemaItem::Optional(_)
+                | SchemaItem::Searchable(_) => {
+                    unreachable!("wrapping items are unwrapped above")
+                }
+            }
+        }
+    }
+
+    fn redact(&self, value: &Value) -> Value {
+        match (self, value) {
+            (SchemaItem::Sensitive(_), _) => Value::String(REDACTED.to_string()),
+            (
+                SchemaItem::Ephemeral(inner)
+                | SchemaItem::Optional(inner)
+                | SchemaItem::Searchable(inner),
+                _,
+            ) => inner.redact(value),
+            (SchemaItem::Collection(collection), Value::Object(members)) => Value::Object(
+                members
+                    .iter()
+                    .map(|(key, member)| (key.clone(), collection.items.redact(member)))
+                    .collect(),
+            ),
+            (SchemaItem::Document(fields), Value::Object(entries)) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, entry)| {
+                        let entry = match fields.get(key) {
+                            Some(field) => field.redact(entry),
+                            None => entry.clone(),
+                        };
+                        (key.clone(), entry)
+                    })
+                    .collect(),
+            ),
+            _ => value.clone(),
+        }
+    }
+
+    /// Every `Reference` field in `value`, which has this schema, along with the ref it holds
+    pub fn references<'a>(&self, value: &'a mut Value, found: &mut Vec<(&'a mut Value, Ref)>) {
+        match (self, value) {
+            (
+                SchemaItem::Sensitive(inner)
+                | SchemaItem::Ephemeral(inner)
+                | SchemaItem::Optional(inner)
+                | SchemaItem::Searchable(inner),
+                value,
+            ) => inner.references(value, found),
+            (SchemaItem::Reference | SchemaItem::ReferenceTo(_), value) => {
+                if let Ok(target) = Ref::deserialize(&*value) {
+                    found.push((value, target));
+                }
+            }
+            (SchemaItem::Collection(collection), Value::Object(members)) => {
+                for member in members.values_mut() {
+                    collection.items.references(member, found);
+                }
+            }
+            (SchemaItem::Document(fields), Value::Object(entries)) => {
+                for (key, entry) in entries.iter_mut() {
+                    if let Some(field) = fields.get(key) {
+                        field.references(entry, found);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A short description of the item, e.g. `document { name, owner }`, naming a document's fields
+/// but not describing them
+impl Display for SchemaItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaItem::Collection(collection) => {
+                write!(f, "collection of {}", collection.items)?;
+                if let Some(keys) = &collection.keys {
+                    write!(f, " keyed by {keys}")?;
+                }
+                Ok(())
+            }
+            SchemaItem::Document(fields) => {
+                let mut names: Vec<&str> = fields.keys().map(String::as_str).collect();
+                names.sort_unstable();
+                write!(f, "document {{ {} }}", names.join(", "))
+            }
+            SchemaItem::Scalar => write!(f, "scalar"),
+            SchemaItem::Custom(codec) => write!(f, "{codec} scalar"),
+            SchemaItem::Reference => write!(f, "reference"),
+            SchemaItem::ReferenceTo(pattern) => write!(f, "reference to {}", Ref(pattern.clone())),
+            SchemaItem::Enum(variants) => write!(f, "one of {}", variants.join(", ")),
+            SchemaItem::Presence => write!(f, "presence"),
+            SchemaItem::Sensitive(inner) => write!(f, "sensitive {inner}"),
+            SchemaItem::Ephemeral(inner) => write!(f, "ephemeral {inner}"),
+            SchemaItem::Optional(inner) => write!(f, "optional {inner}"),
+            SchemaItem::Searchable(inner) => write!(f, "searchable {inner}"),
+        }
+    }
+}
+
+/// A collection of items of the same shape, keyed by strings.
+///
+/// In a schema file this is either the item schema on its own, or `{ "keys": <format>, "items":
+/// <item schema> }` to restrict which keys may be inserted.
+#[derive(Debug)]
+pub struct CollectionSchema {
+    /// The format every child key must have; any key is allowed if unset
+    pub keys: Option<KeyFormat>,
+    pub items: Box<SchemaItem>,
+}
+
+impl CollectionSchema {
+    pub fn new(items: SchemaItem) -> CollectionSchema {
+        CollectionSchema {
+            keys: None,
+            items: Box::new(items),
+        }
+    }
+
+    pub fn with_keys(mut self, keys: KeyFormat) -> CollectionSchema {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Whether `key` may be used for a child of this collection
+    pub fn allows_key(&self, key: &str) -> bool {
+        self.keys.as_ref().is_none_or(|keys| keys.matches(key))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CollectionRepr {
+    Keyed {
+        keys: KeyFormat,
+        items: Box<SchemaItem>,
+    },
+    Items(Box<SchemaItem>),
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum CollectionReprRef<'a> {
+    Keyed {
+        keys: &'a KeyFormat,
+        items: &'a SchemaItem,
+    },
+    Items(&'a SchemaItem),
+}
+
+impl<'de> Deserialize<'de> for CollectionSchema {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match CollectionRepr::deserialize(deserializer)? {
+            CollectionRepr::Keyed { keys, items } => CollectionSchema {
+                keys: Some(keys),
+                items,
+            },
+            CollectionRepr::Items(items) => CollectionSchema { keys: None, items },
+        })
+    }
+}
+
+impl Serialize for CollectionSchema {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.keys {
+            Some(keys) => CollectionReprRef::Keyed {
+                keys,
+                items: &self.items,
+            },
+            None => CollectionReprRef::Items(&self.items),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The keys a collection accepts for its children
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyFormat {
+    /// Lowercase letters and digits, in words separated by single hyphens, e.g. `hello-world-2`
+    Slug,
+    /// A hyphenated UUID, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`
+    Uuid,
+    /// A non-negative integer without leading zeros
+    Numeric,
+    /// Any key matching the regular expression in full
+    Pattern(KeyPattern),
+}
+
+impl KeyFormat {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyFormat::Slug => key.split('-').all(|word| {
+                !word.is_empty()
+                    && word
+                        .bytes()
+                        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+            }),
+            KeyFormat::Uuid => {
+                let groups: Vec<&str> = key.split('-').collect();
+                groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+                    && groups
+                        .iter()
+                        .all(|group| group.bytes().all(|b| b.is_ascii_hexdigit()))
             }
+            KeyFormat::Numeric => {
+                !key.is_empty()
+                    && key.bytes().all(|b| b.is_ascii_digit())
+                    && (key == "0" || !key.starts_with('0'))
+            }
+            KeyFormat::Pattern(pattern) => pattern.regex.is_match(key),
         }
     }
 }
 
-const USIZE_LEN: usize = std::mem::size_of::<usize>();
+impl Display for KeyFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyFormat::Slug => write!(f, "a slug"),
+            KeyFormat::Uuid => write!(f, "a UUID"),
+            KeyFormat::Numeric => write!(f, "a number"),
+            KeyFormat::Pattern(pattern) => write!(f, "a key matching /{}/", pattern.source),
+        }
+    }
+}
+
+/// A regular expression that child keys must match in full
+#[derive(Clone, Debug)]
+pub struct KeyPattern {
+    source: String,
+    regex: Regex,
+}
+
+impl<'de> Deserialize<'de> for KeyPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        let regex = Regex::new(&format!("^(?:{source})$")).map_err(serde::de::Error::custom)?;
+        Ok(KeyPattern { source, regex })
+    }
+}
+
+impl Serialize for KeyPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(serializer)
+    }
+}
+
+/// Write `value` as a LEB128 varint: seven bits at a time, lowest first, with the top bit of
+/// each byte set if more follow
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read a varint written by `write_varint` off the front of `bytes`
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
 
 #[cfg(test)]
 mod tests {
 
+    use serde_json::json;
+
     use crate::{message::Ref, schema::SchemaItem};
 
-    use super::Schema;
+    use super::{KeyFormat, Schema, SchemaProblem, REDACTED};
+
+    #[test]
+    fn checking_schemas() {
+        let source = r#"{ "Document": {
+            "posts": { "Collection": { "Optional": { "Document": {
+                "title": "Scalar",
+                "title": { "Searchable": { "Ephemeral": "Scalar" } },
+                "meta": { "Document": {} }
+            } } } },
+            "hello": { "Document": { "world": { "Sensitive": { "Sensitive": "Scalar" } } } }
+        } }"#;
+        let (_, problems) = Schema::check(source).unwrap();
+        let path = |path: &str| Ref(path.split('/').map(String::from).collect());
+        assert_eq!(
+            problems,
+            [
+                SchemaProblem::DuplicateKey(path(
+                    "Document/posts/Collection/Optional/Document/title"
+                )),
+                SchemaProblem::UnsupportedNesting {
+                    path: path("hello/world"),
+                    item: "sensitive",
+                    reason: "wraps another of the same kind",
+                },
+                SchemaProblem::UnsupportedNesting {
+                    path: path("posts/*"),
+                    item: "optional",
+                    reason: "isn't a field of a document",
+                },
+                SchemaProblem::EmptyDocument(path("posts/*/meta")),
+                SchemaProblem::UnsupportedNesting {
+                    path: path("posts/*/title"),
+                    item: "searchable",
+                    reason: "is held in memory, which isn't indexed",
+                },
+            ]
+        );
+
+        let (_, problems) = Schema::check(include_str!("../schema.json")).unwrap();
+        assert!(problems.is_empty());
+        assert!(Schema::check(r#"{ "Document": "#).is_err());
+    }
 
     #[test]
     fn round_trip_ref() {
@@ -104,4 +932,170 @@ mod tests {
         let decoded = schema.decode_ref(&encoded);
         assert_eq!(r, Ref(decoded));
     }
+
+    #[test]
+    fn compact_refs() {
+        let schema: Schema = Schema::new(
+            serde_json::from_value(json!({
+                "Document": {
+                    "posts": { "Collection": { "Document": { "title": "Scalar" } } },
+                    "title": "Scalar"
+                }
+            }))
+            .unwrap(),
+        );
+        let post = |components: &[&str]| -> Vec<String> {
+            components.iter().map(|c| c.to_string()).collect()
+        };
+
+        // Fields take a byte each, and keys a byte more than their length
+        let title = schema.encode_ref(&post(&["posts", "hello-world", "title"]));
+        assert_eq!(title.len(), 1 + 1 + "hello-world".len() + 1);
+        assert_eq!(
+            schema.decode_ref(&title),
+            post(&["posts", "hello-world", "title"])
+        );
+        // A collection key that's also a field name is still a key
+        let key = schema.encode_ref(&post(&["posts", "title"]));
+        assert_eq!(key.len(), 1 + 1 + "title".len());
+        assert_eq!(schema.decode_ref(&key), post(&["posts", "title"]));
+
+        let long = "x".repeat(200);
+        let encoded = schema.encode_ref(&post(&["posts", &long]));
+        assert_eq!(schema.decode_ref(&encoded), post(&["posts", &long]));
+        // Everything under a ref shares its encoding as a prefix
+        assert!(title.starts_with(&schema.encode_ref(&post(&["posts", "hello-world"]))));
+        assert!(!encoded.starts_with(&schema.encode_ref(&post(&["posts", "x"]))));
+
+        // Only a collection's direct members are decoded as members
+        let posts = schema.encode_ref(&post(&["posts"]));
+        assert_eq!(
+            schema.decode_member(&posts, &key),
+            Some("title".to_string())
+        );
+        assert_eq!(schema.decode_member(&posts, &title), None);
+        assert_eq!(schema.decode_member(&posts, &posts), None);
+
+        assert!(schema.try_decode_ref(b"fruits/apple").is_none());
+    }
+
+    #[test]
+    fn refs_under_wrappers() {
+        let wrapped = Schema::new(
+            serde_json::from_value(json!({ "Document": {
+                "profile": { "Optional": { "Document": { "bio": { "Searchable": {
+                    "Document": { "text": "Scalar" }
+                } } } } },
+                "tags": { "Optional": { "Collection": { "Document": { "name": "Scalar" } } } }
+            } }))
+            .unwrap(),
+        );
+        let unwrapped = Schema::new(
+            serde_json::from_value(json!({ "Document": {
+                "profile": { "Document": { "bio": { "Document": { "text": "Scalar" } } } },
+                "tags": { "Collection": { "Document": { "name": "Scalar" } } }
+            } }))
+            .unwrap(),
+        );
+        let path = |components: &[&str]| -> Vec<String> {
+            components.iter().map(|c| c.to_string()).collect()
+        };
+
+        // Fields inside optional and searchable items are stored as their numbers
+        let text = wrapped.encode_ref(&path(&["profile", "bio", "text"]));
+        assert_eq!(text.len(), 3);
+        let name = wrapped.encode_ref(&path(&["tags", "rust", "name"]));
+        assert_eq!(name.len(), 1 + 1 + "rust".len() + 1);
+        assert_eq!(wrapped.decode_ref(&name), path(&["tags", "rust", "name"]));
+        // So wrapping them doesn't change where their data is stored
+        assert_eq!(
+            text,
+            unwrapped.encode_ref(&path(&["profile", "bio", "text"]))
+        );
+        assert_eq!(name, unwrapped.encode_ref(&path(&["tags", "rust", "name"])));
+    }
+
+    #[test]
+    fn redact() {
+        let schema: Schema = serde_json::from_value(json!({
+            "Collection": {
+                "Document": {
+                    "name": "Scalar",
+                    "email": { "Sensitive": "Scalar" },
+                    "billing": { "Sensitive": { "Document": { "card": "Scalar" } } }
+                }
+            }
+        }))
+        .unwrap();
+        let user = json!({
+            "name": "Ada",
+            "email": "ada@example.com",
+            "billing": { "card": "4242" }
+        });
+
+        assert_eq!(
+            schema.redact(&[], &json!({ "ada": user })),
+            json!({ "ada": { "name": "Ada", "email": REDACTED, "billing": REDACTED } })
+        );
+        assert_eq!(
+            schema.redact(
+                &["ada".into(), "billing".into(), "card".into()],
+                &json!("4242")
+            ),
+            json!(REDACTED)
+        );
+        assert_eq!(
+            schema.redact(&["ada".into(), "name".into()], &json!("Ada")),
+            json!("Ada")
+        );
+        // Sensitive items are otherwise transparent
+        assert!(schema
+            .resolve(&["ada".into(), "billing".into(), "card".into()])
+            .is_ok());
+    }
+
+    #[test]
+    fn collection_keys() {
+        let schema: Schema = serde_json::from_value(json!({
+            "Document": {
+                "posts": { "Collection": { "keys": "slug", "items": "Scalar" } },
+                "orders": { "Collection": { "keys": { "pattern": "ord_[0-9]+" }, "items": "Scalar" } },
+                "tags": { "Collection": "Scalar" }
+            }
+        }))
+        .unwrap();
+        let Ok(SchemaItem::Collection(posts)) = schema.resolve(&["posts".into()]) else {
+            panic!("posts should be a collection");
+        };
+        assert!(posts.allows_key("hello-world-2"));
+        assert!(!posts.allows_key("Hello"));
+        assert!(!posts.allows_key("hello--world"));
+        let Ok(SchemaItem::Collection(orders)) = schema.resolve(&["orders".into()]) else {
+            panic!("orders should be a collection");
+        };
+        assert!(orders.allows_key("ord_12"));
+        // Patterns must match the whole key
+        assert!(!orders.allows_key("xord_12"));
+        let Ok(SchemaItem::Collection(tags)) = schema.resolve(&["tags".into()]) else {
+            panic!("tags should be a collection");
+        };
+        assert!(tags.allows_key("Anything at all"));
+
+        assert!(KeyFormat::Uuid.matches("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        assert!(!KeyFormat::Uuid.matches("67e55044-10b1-426f-9247"));
+        assert!(KeyFormat::Numeric.matches("0"));
+        assert!(KeyFormat::Numeric.matches("42"));
+        assert!(!KeyFormat::Numeric.matches("042"));
+
+        // Schemas round trip, keeping the plain form for collections without a key format
+        let round_trip = serde_json::to_value(&schema).unwrap();
+        assert_eq!(
+            round_trip["Document"]["tags"],
+            json!({ "Collection": "Scalar" })
+        );
+        assert_eq!(
+            round_trip["Document"]["orders"]["Collection"]["keys"],
+            json!({ "pattern": "ord_[0-9]+" })
+        );
+    }
 }
@@ -0,0 +1,28 @@
+use std::path::Path;
+
+use iceload::{schema::Schema, server::Server};
+
+/// Check the schema in `schema_path` for problems, printing a line for each, and if `server` is
+/// given, whether the data it holds fits the schema.
+///
+/// Returns whether the schema is free of problems and fits the data.
+pub fn run(schema_path: &Path, server: Option<&Server>) -> anyhow::Result<bool> {
+    let (schema, problems) = Schema::check(&std::fs::read_to_string(schema_path)?)?;
+    for problem in problems.iter() {
+        println!("problem: {problem}");
+    }
+    let mut ok = problems.is_empty();
+    if let Some(server) = server {
+        match server.check_schema(schema) {
+            Ok(()) => println!("the stored data fits the schema"),
+            Err(err) => {
+                ok = false;
+                println!("incompatible with the stored data: {err}");
+            }
+        }
+    }
+    if ok {
+        println!("{} is ok", schema_path.display());
+    }
+    Ok(ok)
+}
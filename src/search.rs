@@ -0,0 +1,70 @@
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+/// The words in a searchable value, lowercased. Words are runs of letters and digits, so
+/// punctuation and whitespace separate them. Strings nested in arrays or objects count too.
+pub fn terms(value: &Value) -> BTreeSet<String> {
+    let mut terms = BTreeSet::new();
+    add_terms(value, &mut terms);
+    terms
+}
+
+fn add_terms(value: &Value, terms: &mut BTreeSet<String>) {
+    match value {
+        Value::String(text) => terms.extend(
+            text.split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .map(str::to_lowercase),
+        ),
+        Value::Number(number) => {
+            terms.insert(number.to_string());
+        }
+        Value::Array(items) => {
+            for item in items {
+                add_terms(item, terms);
+            }
+        }
+        Value::Object(entries) => {
+            for entry in entries.values() {
+                add_terms(entry, terms);
+            }
+        }
+        Value::Null | Value::Bool(_) => {}
+    }
+}
+
+/// What the index keys of every item containing `term` start with. Terms never contain a zero
+/// byte, so it separates the term from the item's encoded ref.
+pub fn term_prefix(term: &str) -> Vec<u8> {
+    let mut prefix = term.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// The index key recording that the item stored under `encoded_ref` contains `term`
+pub fn index_key(term: &str, encoded_ref: &[u8]) -> Vec<u8> {
+    let mut key = term_prefix(term);
+    key.extend_from_slice(encoded_ref);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn splitting_terms() {
+        assert_eq!(
+            terms(&json!("Hello, world! Hello-again_2")),
+            BTreeSet::from(["2", "again", "hello", "world"].map(String::from))
+        );
+        assert_eq!(
+            terms(&json!(["Ünïcode", { "n": 42 }, true, null])),
+            BTreeSet::from(["42", "ünïcode"].map(String::from))
+        );
+        assert!(terms(&json!("  ...  ")).is_empty());
+    }
+}
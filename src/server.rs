@@ -1,556 +1,4900 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::{self, Display},
+    ops::Bound,
+    path::Path,
+    slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use futures_util::{FutureExt, Stream};
-use serde_json::{Map, Value};
+use futures_util::{future, Stream, StreamExt};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
 use sled::{
     transaction::{
-        abort, ConflictableTransactionError, TransactionError, TransactionResult, TransactionalTree,
+        abort, ConflictableTransactionError, TransactionError, TransactionResult,
+        TransactionalTree, UnabortableTransactionError,
     },
-    Db, IVec, Subscriber,
+    Db, IVec, Transactional, Tree,
 };
 use thiserror::Error;
+use tokio::sync::watch;
 
 use crate::{
-    message::Ref,
-    schema::{Schema, SchemaItem, SchemaResolutionError},
+    blob,
+    changes::{self, Change, ChangeOp, Sequencer},
+    codec::{CodecError, Codecs, RefCodec, ScalarCodec, StringCodec},
+    dispatch::{Delivery, Dispatcher, Subscription},
+    error::ErrorKind,
+    history::{History, Revision},
+    memory::{MemoryTransaction, MemoryTree},
+    message::{Aggregation, Filter, Ref},
+    presence::Presence,
+    query,
+    schema::{KeyFormat, Schema, SchemaItem, SchemaResolutionError, REDACTED},
+    search, slow,
 };
 
-// TODO: error context
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum ServerError {
     #[error("{}", .0)]
     SledError(#[from] sled::Error),
-    #[error("{}", .0)]
-    SchemaError(#[from] SchemaResolutionError),
-    #[error("key not found")]
-    KeyNotFound,
-    #[error("extra key found")]
-    ExtraKeyFound,
-    #[error("schema mismatch")]
-    SchemaMismatch,
-    #[error("only documents and collections may be inserted, scalar values")]
-    NonDocumentInsert,
+    #[error("{source} at {path}")]
+    SchemaError {
+        path: Ref,
+        source: SchemaResolutionError,
+    },
+    #[error("key not found: {}", .0)]
+    KeyNotFound(Ref),
+    #[error("extra key found: {}", .0)]
+    ExtraKeyFound(Ref),
+    #[error("schema mismatch at {}", .0)]
+    SchemaMismatch(Ref),
+    #[error("invalid value at {path}: {source}")]
+    InvalidScalar { path: Ref, source: CodecError },
+    #[error("no codec named {name} is registered, needed at {path}")]
+    UnknownCodec { path: Ref, name: String },
+    #[error("schema mismatch at {path}: expected one of {}", allowed.join(", "))]
+    InvalidVariant { path: Ref, allowed: Vec<String> },
+    #[error("invalid key at {path}: expected {expected}")]
+    InvalidKey { path: Ref, expected: KeyFormat },
+    #[error("only documents and collections may be inserted, not scalar values: {}", .0)]
+    NonDocumentInsert(Ref),
+    #[error("database at {path} is locked by {}", holder_pid.map(|pid| format!("process {pid}")).unwrap_or_else(|| "another process".to_string()))]
+    DatabaseLocked {
+        path: String,
+        holder_pid: Option<u32>,
+    },
+    #[error("this server is a read-only follower; send writes to the leader")]
+    ReadOnly,
+    #[error("writes are paused for maintenance{}", .0.as_deref().map(|reason| format!(": {reason}")).unwrap_or_default())]
+    Maintenance(Option<String>),
+    #[error("only members of presence collections can be joined: {}", .0)]
+    NotPresence(Ref),
+    #[error("presence is set by joining, not by writes: {}", .0)]
+    PresenceWrite(Ref),
+    #[error("ephemeral items are written on their own, not along with what contains them: {}", .0)]
+    EphemeralWrite(Ref),
+    #[error("invalid query on {path}: {reason}")]
+    InvalidQuery { path: Ref, reason: String },
+    #[error("the change log has been trimmed to {oldest}, so it can't be read from {from}")]
+    ChangesTrimmed { from: u64, oldest: u64 },
+}
+
+impl ServerError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ServerError::SledError(_) => ErrorKind::Storage,
+            ServerError::DatabaseLocked { .. } => ErrorKind::Locked,
+            ServerError::SchemaError { source, .. } => source.kind(),
+            ServerError::KeyNotFound(_) => ErrorKind::NotFound,
+            ServerError::ExtraKeyFound(_)
+            | ServerError::SchemaMismatch(_)
+            | ServerError::NonDocumentInsert(_)
+            | ServerError::InvalidKey { .. }
+            | ServerError::InvalidScalar { .. }
+            | ServerError::InvalidVariant { .. }
+            | ServerError::NotPresence(_)
+            | ServerError::PresenceWrite(_)
+            | ServerError::EphemeralWrite(_)
+            | ServerError::InvalidQuery { .. } => ErrorKind::SchemaMismatch,
+            ServerError::UnknownCodec { .. } => ErrorKind::InvalidSchema,
+            ServerError::ReadOnly => ErrorKind::ReadOnly,
+            ServerError::Maintenance(_) => ErrorKind::Maintenance,
+            ServerError::ChangesTrimmed { .. } => ErrorKind::Trimmed,
+        }
+    }
+
+    /// The path the error occurred at, if it's associated with one
+    pub fn path(&self) -> Option<&Ref> {
+        match self {
+            ServerError::SledError(_)
+            | ServerError::DatabaseLocked { .. }
+            | ServerError::ReadOnly
+            | ServerError::Maintenance(_)
+            | ServerError::ChangesTrimmed { .. } => None,
+            ServerError::SchemaError { path, .. }
+            | ServerError::KeyNotFound(path)
+            | ServerError::ExtraKeyFound(path)
+            | ServerError::SchemaMismatch(path)
+            | ServerError::NonDocumentInsert(path)
+            | ServerError::InvalidKey { path, .. }
+            | ServerError::InvalidScalar { path, .. }
+            | ServerError::InvalidVariant { path, .. }
+            | ServerError::UnknownCodec { path, .. }
+            | ServerError::NotPresence(path)
+            | ServerError::PresenceWrite(path)
+            | ServerError::EphemeralWrite(path)
+            | ServerError::InvalidQuery { path, .. } => Some(path),
+        }
+    }
+}
+
+fn scalar_codec<'a>(
+    codecs: &'a Codecs,
+    key: &Ref,
+    schema: &SchemaItem,
+) -> Result<&'a dyn ScalarCodec, ServerError> {
+    match schema {
+        SchemaItem::Custom(name) => match codecs.get(name) {
+            Some(codec) => Ok(codec.as_ref()),
+            None => Err(ServerError::UnknownCodec {
+                path: key.clone(),
+                name: name.clone(),
+            }),
+        },
+        SchemaItem::Reference | SchemaItem::ReferenceTo(_) => Ok(&RefCodec),
+        _ => Ok(&StringCodec),
+    }
+}
+
+fn encode_scalar(
+    schema: &Schema,
+    codecs: &Codecs,
+    key: &Ref,
+    item: &SchemaItem,
+    val: &Value,
+) -> Result<Vec<u8>, ServerError> {
+    let encoded = scalar_codec(codecs, key, item)?
+        .encode(val)
+        .map_err(|source| invalid_scalar(schema, key, source))?;
+    if let SchemaItem::ReferenceTo(pattern) = item {
+        let target: Ref = serde_json::from_slice(&encoded).expect("reference fields hold refs");
+        let matches = target.0.len() == pattern.len()
+            && (target.0.iter().zip(pattern))
+                .all(|(key, pattern)| pattern == "*" || key == pattern);
+        if !matches {
+            let source = CodecError(format!(
+                "{target} isn't a reference to {}",
+                Ref(pattern.clone())
+            ));
+            return Err(invalid_scalar(schema, key, source));
+        }
+    }
+    if let SchemaItem::Enum(allowed) = item {
+        if !allowed.iter().any(|variant| variant.as_bytes() == encoded) {
+            return Err(ServerError::InvalidVariant {
+                path: key.clone(),
+                allowed: allowed.clone(),
+            });
+        }
+    }
+    Ok(encoded)
+}
+
+fn decode_scalar(
+    schema: &Schema,
+    codecs: &Codecs,
+    key: &Ref,
+    item: &SchemaItem,
+    val: &[u8],
+) -> Result<Value, ServerError> {
+    scalar_codec(codecs, key, item)?
+        .decode(val)
+        .map_err(|source| invalid_scalar(schema, key, source))
+}
+
+fn invalid_scalar(schema: &Schema, key: &Ref, source: CodecError) -> ServerError {
+    // Codec errors tend to quote the offending value
+    let source = if schema.is_sensitive(&key.0) {
+        CodecError(REDACTED.to_string())
+    } else {
+        source
+    };
+    ServerError::InvalidScalar {
+        path: key.clone(),
+        source,
+    }
+}
+
+/// The value of a scalar read from `store` as `stored`, put back together if it was split into
+/// chunks, or None if it's been removed since it was read
+fn unsplit(
+    store: &Tree,
+    blobs: &Tree,
+    encoded_ref: &[u8],
+    mut stored: IVec,
+) -> Result<Option<IVec>, sled::Error> {
+    loop {
+        if let Some(value) = blob::join(blobs, stored)? {
+            return Ok(Some(value));
+        }
+        // Overwritten since it was read, taking its chunks with it
+        match store.get(encoded_ref)? {
+            Some(newer) => stored = newer,
+            None => return Ok(None),
+        }
+    }
 }
 
+/// Remove the fields of documents described in `described` that `readable` says may not be read
+fn hide_unreadable(
+    key: &Ref,
+    item: &SchemaItem,
+    described: &mut Value,
+    readable: &mut impl FnMut(&[Ref]) -> Vec<bool>,
+) {
+    // Each item is described as an object with its kind as the only key
+    let Some(inner) =
+        (described.as_object_mut()).and_then(|described| described.values_mut().next())
+    else {
+        return;
+    };
+    match item {
+        SchemaItem::Document(fields) => {
+            let fields: Vec<_> = fields.iter().collect();
+            let targets: Vec<_> = (fields.iter()).map(|(name, _)| key.child(name)).collect();
+            let allowed = readable(&targets);
+            for (((name, field), target), allowed) in fields.into_iter().zip(&targets).zip(allowed)
+            {
+                if !allowed {
+                    inner.as_object_mut().unwrap().remove(name);
+                } else if let Some(described) = inner.get_mut(name) {
+                    hide_unreadable(target, field, described, readable);
+                }
+            }
+        }
+        SchemaItem::Sensitive(inner_item)
+        | SchemaItem::Ephemeral(inner_item)
+        | SchemaItem::Optional(inner_item)
+        | SchemaItem::Searchable(inner_item) => hide_unreadable(key, inner_item, inner, readable),
+        SchemaItem::Collection(_)
+        | SchemaItem::Scalar
+        | SchemaItem::Custom(_)
+        | SchemaItem::Reference
+        | SchemaItem::ReferenceTo(_)
+        | SchemaItem::Enum(_)
+        | SchemaItem::Presence => {}
+    }
+}
+
+fn resolve<'a>(schema: &'a Schema, key: &Ref) -> Result<&'a SchemaItem, ServerError> {
+    schema
+        .resolve(&key.0)
+        .map_err(|source| ServerError::SchemaError {
+            path: key.clone(),
+            source,
+        })
+}
+
+const PID_FILE: &str = "iceload.pid";
+
+/// The furthest `Server::get_expanded` follows references, however deep a reader asks for
+pub const MAX_EXPANSION_DEPTH: u32 = 4;
+
+/// How many times a read that writes kept landing in is made again before writes are held off
+/// for it
+const CONSISTENT_READ_ATTEMPTS: usize = 3;
+
 #[derive(Clone)]
 pub struct Server {
-    store: Db,
-    schema: Arc<Schema>,
+    db: Db,
+    /// The tree holding this server's data: the database's default tree, or a tenant's own
+    store: Tree,
+    /// Every write committed to `store`, in order
+    changes: Tree,
+    /// Numbers the changes in `changes`
+    sequencer: Arc<Sequencer>,
+    /// The `Metadata` of each document in `store`, by encoded ref
+    meta: Tree,
+    /// The words in searchable items of `store`, keyed by `search::index_key`
+    search: Tree,
+    /// The chunks of values in `store` too long to keep under a single key
+    blobs: Tree,
+    /// The longest value kept under a single key, in bytes
+    chunk_size: usize,
+    /// Past revisions of documents, if they're kept
+    history: Option<Arc<History>>,
+    schema: SharedSchema,
+    codecs: Arc<Codecs>,
+    /// How many write transactions are running right now
+    pending_transactions: Arc<AtomicUsize>,
+    /// Held shared by every write, and exclusively by `dump` so it never sees half a write, and by
+    /// writes that rely on what was read before them so nothing commits in between
+    write_gate: Arc<RwLock<()>>,
+    /// Set on followers, which only take writes replicated from their leader through `apply`
+    read_only: bool,
+    /// Set on a copy made to rehearse writes, whose transactions are checked but never committed
+    dry_run: bool,
+    /// Set on the copy of a follower applying a change from its leader, to the number of the
+    /// leader's next change, which is recorded in the same transaction
+    replicated: Option<u64>,
+    /// Whether every write is flushed to disk before it returns
+    sync_writes: bool,
+    /// Reads and writes taking longer than this are logged
+    slow_threshold: Option<Duration>,
+    /// Set while an operator has paused writes, with the reason they gave if any. Shared with
+    /// every tenant, so the whole database can be paused at once.
+    maintenance: Arc<Mutex<Option<Maintenance>>>,
+    /// Shares sled subscribers between subscriptions to the same item
+    dispatcher: Arc<Dispatcher>,
+    /// The members of presence collections, which are never stored
+    presence: Arc<Presence>,
+    /// The ephemeral items, which are never stored either
+    memory: Arc<MemoryTree>,
+}
+
+/// The schema of a server and its clones, which `Server::replace_schema` swaps out while they're
+/// running. Reads that started before a swap keep the schema they started with until they finish.
+#[derive(Clone)]
+struct SharedSchema(Arc<watch::Sender<Arc<Schema>>>);
+
+impl SharedSchema {
+    fn new(schema: Schema) -> SharedSchema {
+        SharedSchema::of(Arc::new(schema))
+    }
+
+    fn of(schema: Arc<Schema>) -> SharedSchema {
+        SharedSchema(Arc::new(watch::Sender::new(schema)))
+    }
+
+    /// The same schema, to be replaced separately from this one
+    fn detach(&self) -> SharedSchema {
+        SharedSchema::of(self.current())
+    }
+
+    fn current(&self) -> Arc<Schema> {
+        self.0.borrow().clone()
+    }
+
+    fn replace(&self, schema: Arc<Schema>) {
+        self.0.send_replace(schema);
+    }
+}
+
+/// Writes paused by an operator, e.g. for a backup, a migration or an incident
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Maintenance {
+    /// Why, as given by the operator, for clients whose writes are rejected
+    pub reason: Option<String>,
+}
+
+/// A snapshot of the store's health, for diagnostics
+#[derive(Debug, serde::Serialize)]
+pub struct StoreStats {
+    /// Number of keys in the store
+    pub keys: usize,
+    pub size_on_disk: u64,
+    /// Write transactions that have started but not yet committed or aborted
+    pub pending_transactions: usize,
+}
+
+/// Where the store's space is going, found by scanning it
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct StorageUsage {
+    pub size_on_disk: u64,
+    /// Number of keys stored under each top-level item of the schema
+    pub keys: BTreeMap<String, usize>,
+    /// Number of keys holding the chunks of values too large to store under one key
+    pub blob_keys: usize,
 }
 
 impl Server {
     // TODO: read the schema out of the store
     pub fn open(path: &str, schema: Schema) -> Result<Server, ServerError> {
-        let store = sled::open(path)?;
+        Server::open_with(path, schema, sled::Config::new())
+    }
+
+    /// Open the database at `path`, tuning sled with the given configuration
+    pub fn open_with(
+        path: &str,
+        schema: Schema,
+        config: sled::Config,
+    ) -> Result<Server, ServerError> {
+        let store = match config.path(path).open() {
+            Ok(store) => store,
+            // sled doesn't give lock failures their own error, so we have to recognize the message
+            Err(sled::Error::Io(err)) if err.to_string().starts_with("could not acquire lock") => {
+                return Err(ServerError::DatabaseLocked {
+                    path: path.to_string(),
+                    holder_pid: std::fs::read_to_string(Path::new(path).join(PID_FILE))
+                        .ok()
+                        .and_then(|pid| pid.trim().parse().ok()),
+                });
+            }
+            Err(err) => return Err(err.into()),
+        };
+        // Record who holds the lock, so anyone else trying to open the database can say so
+        if let Err(err) = std::fs::write(
+            Path::new(path).join(PID_FILE),
+            std::process::id().to_string(),
+        ) {
+            tracing::warn!("failed to write {PID_FILE} in {path}: {err}");
+        }
+
+        Server::new(store, schema)
+    }
+
+    /// Open a database in a temporary directory, which is deleted once the server and every clone
+    /// of it are dropped
+    pub fn open_temporary(schema: Schema, config: sled::Config) -> Result<Server, ServerError> {
+        Server::new(config.temporary(true).open()?, schema)
+    }
+
+    fn new(db: Db, schema: Schema) -> Result<Server, ServerError> {
+        let store = (*db).clone();
+        let changes = db.open_tree("system/changes")?;
+        let meta = db.open_tree("system/meta")?;
+        let search = db.open_tree("system/search")?;
+        let blobs = db.open_tree("system/blobs")?;
+        let schema = prepare_schema(&db, &store, schema)?;
         Ok(Server {
+            dispatcher: Arc::new(Dispatcher::new(store.clone())),
+            presence: Arc::default(),
+            memory: Arc::default(),
             store,
-            schema: Arc::new(schema),
+            sequencer: Arc::new(Sequencer::open(&db, &changes)?),
+            changes,
+            meta,
+            search,
+            blobs,
+            chunk_size: blob::DEFAULT_CHUNK_SIZE,
+            history: None,
+            db,
+            schema: SharedSchema::new(schema),
+            codecs: Arc::new(Codecs::new()),
+            pending_transactions: Arc::default(),
+            write_gate: Arc::default(),
+            read_only: false,
+            dry_run: false,
+            replicated: None,
+            sync_writes: false,
+            slow_threshold: None,
+            maintenance: Arc::default(),
         })
     }
 
-    pub fn get(&self, key: &Ref) -> Result<Value, ServerError> {
-        let schema = self.schema.resolve(&key.0)?;
-        match schema {
-            SchemaItem::Collection(_inner) => {
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                let Some(value) = self.store.get(encoded_ref)? else {
-                    return Ok(Value::Object(Map::new()));
-                };
-                let keys: HashSet<String> = bincode::deserialize(value.as_ref())
-                    .expect("collections are encoded via bincode");
-                let mut result = Map::new();
-                for child in keys {
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(child.clone());
-                    let sub_value = self.get(&sub_key)?;
-                    result.insert(sub_key.0.pop().unwrap(), sub_value);
-                }
-                Ok(Value::Object(result))
-            }
-            SchemaItem::Document(fields) => {
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                if !self.store.contains_key(encoded_ref)? {
-                    return Ok(Value::Null);
-                }
+    /// A server for the tenant `name`, which keeps its data in its own tree of the same database
+    /// and is checked against its own schema. Tenants can't see each other's data or events, but
+    /// share the codecs registered on this server.
+    pub fn tenant(&self, name: &str, schema: Schema) -> Result<Server, ServerError> {
+        let store = self.db.open_tree(format!("tenant/{name}"))?;
+        let changes = self.db.open_tree(format!("system/changes/{name}"))?;
+        let meta = self.db.open_tree(format!("system/meta/{name}"))?;
+        let search = self.db.open_tree(format!("system/search/{name}"))?;
+        let blobs = self.db.open_tree(format!("system/blobs/{name}"))?;
+        let schema = prepare_schema(&self.db, &store, schema)?;
+        Ok(Server {
+            db: self.db.clone(),
+            dispatcher: Arc::new(Dispatcher::new(store.clone())),
+            presence: Arc::default(),
+            memory: Arc::default(),
+            history: match &self.history {
+                Some(history) => Some(Arc::new(History::new(
+                    self.db.open_tree(format!("system/history/{name}"))?,
+                    history.keep(),
+                ))),
+                None => None,
+            },
+            store,
+            sequencer: Arc::new(Sequencer::open(&self.db, &changes)?),
+            changes,
+            meta,
+            search,
+            blobs,
+            chunk_size: self.chunk_size,
+            schema: SharedSchema::new(schema),
+            codecs: self.codecs.clone(),
+            pending_transactions: Arc::default(),
+            write_gate: Arc::default(),
+            read_only: self.read_only,
+            dry_run: false,
+            replicated: None,
+            sync_writes: self.sync_writes,
+            slow_threshold: self.slow_threshold,
+            maintenance: self.maintenance.clone(),
+        })
+    }
 
-                let mut values = Map::new();
-                for field in fields.keys() {
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(field.clone());
-                    let sub_value = self.get(&sub_key)?;
-                    values.insert(sub_key.0.pop().unwrap(), sub_value);
-                }
-                Ok(Value::Object(values))
+    /// A tree of the database for the server's own bookkeeping, kept apart from every tenant's
+    /// data and from the schema
+    pub fn system_tree(&self, name: &str) -> Result<Tree, ServerError> {
+        Ok(self.db.open_tree(format!("system/{name}"))?)
+    }
+
+    /// The tree holding the user accounts of this server's clients: `__users` for the default
+    /// tenant, and `__users/<name>` for each other one
+    pub fn users_tree(&self) -> Result<Tree, ServerError> {
+        let name = match self.store.name().strip_prefix(b"tenant/") {
+            Some(tenant) => [&b"__users/"[..], tenant].concat(),
+            None => b"__users".to_vec(),
+        };
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// A number that is unique among every call on this database, even across restarts
+    pub fn generate_id(&self) -> Result<u64, ServerError> {
+        Ok(self.db.generate_id()?)
+    }
+
+    /// Copy the entire dataset into a new database at `path`, which must not already exist.
+    /// Writes wait while it's copied, so the copy is of a single moment, and a running server can
+    /// be forked this way through the admin API.
+    ///
+    /// sled has no snapshots for a copy to share pages with, so this copies every key rather than
+    /// being copy-on-write, and takes about as long as a backup.
+    ///
+    /// Useful for giving preview deployments or test runs a realistic dataset to mutate.
+    pub fn fork(&self, path: &str) -> Result<Server, ServerError> {
+        let store = sled::Config::new().path(path).create_new(true).open()?;
+        {
+            let _writes = self.write_gate.write().unwrap();
+            store.import(self.db.export());
+        }
+        store.flush()?;
+        // The copy has every tenant's data, but serves the same one as this server
+        let tree = store.open_tree(self.store.name())?;
+        let changes = store.open_tree(self.changes.name())?;
+        let meta = store.open_tree(self.meta.name())?;
+        let search = store.open_tree(self.search.name())?;
+        let blobs = store.open_tree(self.blobs.name())?;
+        let history = match &self.history {
+            Some(history) => Some(Arc::new(History::new(
+                store.open_tree(history.name())?,
+                history.keep(),
+            ))),
+            None => None,
+        };
+        Ok(Server {
+            dispatcher: Arc::new(Dispatcher::new(tree.clone())),
+            // Presence belongs to the connections of this server, not the copy
+            presence: Arc::default(),
+            memory: Arc::default(),
+            store: tree,
+            sequencer: Arc::new(Sequencer::open(&store, &changes)?),
+            changes,
+            meta,
+            search,
+            blobs,
+            chunk_size: self.chunk_size,
+            history,
+            db: store,
+            // Replacing the schema of one doesn't replace the other's
+            schema: self.schema.detach(),
+            codecs: self.codecs.clone(),
+            pending_transactions: Arc::default(),
+            write_gate: Arc::default(),
+            // The copy is meant to be written to
+            read_only: false,
+            dry_run: false,
+            replicated: None,
+            sync_writes: self.sync_writes,
+            slow_threshold: self.slow_threshold,
+            maintenance: Arc::default(),
+        })
+    }
+
+    /// The schema as it is now, which stays the same for as long as it's held even if it's replaced
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.current()
+    }
+
+    /// Replace the schema while the server is running, e.g. to add an item. Everything stored is
+    /// checked against the new schema first, and nothing changes if any of it doesn't fit: fields
+    /// that are removed must hold nothing, and fields added to documents that already exist must
+    /// be optional. The search index is rebuilt for what the new schema makes searchable, and
+    /// writes wait until it's done, as it scans the whole store.
+    pub fn replace_schema(&self, mut schema: Schema) -> Result<(), ServerError> {
+        let _writes = self.write_gate.write().unwrap();
+        schema.set_field_ids(field_ids(&self.db, &schema, true)?);
+        let schema = Arc::new(schema);
+        let entries = self.check_fits(&schema)?;
+
+        let mut index = sled::Batch::default();
+        for key in self.search.iter().keys() {
+            index.remove(key?);
+        }
+        for (encoded_ref, stored) in entries {
+            let Some(path) = schema.try_decode_ref(&encoded_ref) else {
+                continue;
+            };
+            if !schema.is_searchable(&path) {
+                continue;
             }
-            SchemaItem::Scalar => {
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                match self.store.get(encoded_ref)? {
-                    Some(val) => {
-                        let val = val.to_vec();
-                        let string = String::from_utf8(val).expect("string value");
-                        Ok(Value::String(string))
-                    }
-                    None => Err(ServerError::KeyNotFound),
-                }
+            let key = Ref(path);
+            let item = resolve(&schema, &key)?;
+            if !matches!(
+                item,
+                SchemaItem::Scalar
+                    | SchemaItem::Custom(_)
+                    | SchemaItem::Reference
+                    | SchemaItem::ReferenceTo(_)
+                    | SchemaItem::Enum(_)
+            ) {
+                continue;
+            }
+            let Some(value) = unsplit(&self.store, &self.blobs, &encoded_ref, stored)? else {
+                continue;
+            };
+            let value = decode_scalar(&schema, &self.codecs, &key, item, &value)?;
+            for term in search::terms(&value) {
+                index.insert(search::index_key(&term, &encoded_ref), &[]);
             }
         }
+        self.search.apply_batch(index)?;
+
+        self.schema.replace(schema);
+        Ok(())
     }
 
-    pub fn insert(&self, key: &Ref, val: Value) -> Result<(), ServerError> {
-        let schema = self.schema.resolve(&key.0)?;
-        match schema {
-            SchemaItem::Document(_) | SchemaItem::Collection(_) => {
-                self.transaction(|tx| tx.tx_insert(key, schema, &val))
+    /// Check that everything stored fits `schema`, as `replace_schema` does, but without replacing
+    /// the schema or writing anything
+    pub fn check_schema(&self, mut schema: Schema) -> Result<(), ServerError> {
+        let _writes = self.write_gate.write().unwrap();
+        schema.set_field_ids(field_ids(&self.db, &schema, false)?);
+        self.check_fits(&Arc::new(schema))?;
+        Ok(())
+    }
+
+    /// Check that the data fits `schema`, a replacement for the server's, returning all of it
+    fn check_fits(&self, schema: &Arc<Schema>) -> Result<BTreeMap<IVec, IVec>, ServerError> {
+        let root = Ref(Vec::new());
+        let mut candidate = self.clone();
+        candidate.schema = SharedSchema::of(schema.clone());
+
+        // Nothing is stored for the root document itself, so each of its fields that holds
+        // anything is checked on its own
+        let entries = self.scan(&root)?;
+        let holds_anything = |key: &Ref| {
+            let prefix = schema.encode_ref(&key.0);
+            (entries
+                .range::<[u8], _>((Bound::Included(&prefix[..]), Bound::Unbounded))
+                .next())
+            .is_some_and(|(stored, _)| stored.starts_with(&prefix))
+        };
+        // Everything the new schema expects has to be there, and decode as it says
+        for (key, item) in top_level(schema.root()) {
+            if holds_anything(&key) {
+                candidate.assemble(&key, item, &entries, Missing::Error)?;
             }
-            SchemaItem::Scalar => Err(ServerError::NonDocumentInsert),
+        }
+        // Nothing may be left over, which writing what the old schema reads with the new one finds
+        let checked = MemoryTree::default().transaction(
+            |scratch| {
+                let tx = TransactionHandler {
+                    store: scratch,
+                    meta: None,
+                    search: None,
+                    blobs: None,
+                    chunk_size: self.chunk_size,
+                    written: &RefCell::default(),
+                    now: now_millis(),
+                    schema,
+                    codecs: &self.codecs,
+                    ephemeral: false,
+                };
+                for (key, item) in top_level(self.schema().root()) {
+                    if !holds_anything(&key) {
+                        continue;
+                    }
+                    let value = match self.assemble(&key, item, &entries, Missing::Null) {
+                        Ok(value) => value,
+                        Err(e) => return abort(e),
+                    };
+                    match schema.item(&key.0) {
+                        Ok(item) => tx.tx_restore(&key, item, &value)?,
+                        Err(_) => return abort(ServerError::ExtraKeyFound(key)),
+                    }
+                }
+                Ok(())
+            },
+            |_| {},
+        );
+        match checked {
+            Ok(()) => Ok(entries),
+            Err(ConflictableTransactionError::Abort(e)) => Err(e),
+            Err(_) => unreachable!("memory transactions only fail by aborting"),
         }
     }
 
-    pub fn update(&self, key: &Ref, val: Value) -> Result<(), ServerError> {
-        let schema = self.schema.resolve(&key.0)?;
-        self.transaction(|tx| tx.tx_update(key, schema, &val))
+    /// Sees each schema that replaces this one
+    pub fn schema_changes(&self) -> watch::Receiver<Arc<Schema>> {
+        self.schema.0.subscribe()
     }
 
-    pub fn remove(&self, key: &Ref) -> Result<(), ServerError> {
-        let schema = self.schema.resolve(&key.0)?;
-        self.transaction(|tx| tx.tx_remove(key, schema))
+    /// A copy of a value read from or written to `key` that is safe to log
+    pub fn redact(&self, key: &Ref, value: &Value) -> Value {
+        self.schema().redact(&key.0, value)
     }
 
-    pub fn subscribe(&self, key: &Ref) -> SubscriptionStream {
-        let encoded_ref = self.schema.encode_ref(&key.0);
-        SubscriptionStream {
-            sub: self.store.watch_prefix(encoded_ref),
-            schema: self.schema.clone(),
+    /// A copy of a change with every sensitive value in it redacted
+    pub fn redact_change(&self, change: &Change) -> Change {
+        let op = match &change.op {
+            ChangeOp::Insert { path, value } => ChangeOp::Insert {
+                path: path.clone(),
+                value: self.redact(path, value),
+            },
+            ChangeOp::Update { path, value } => ChangeOp::Update {
+                path: path.clone(),
+                value: self.redact(path, value),
+            },
+            ChangeOp::Remove { path } => ChangeOp::Remove { path: path.clone() },
+            ChangeOp::Restore { snapshot } => ChangeOp::Restore {
+                snapshot: self.redact(&Ref(Vec::new()), snapshot),
+            },
+        };
+        Change {
+            seq: change.seq,
+            op,
         }
     }
 
-    fn transaction(
-        &self,
-        tx: impl Fn(TransactionHandler) -> Result<(), ConflictableTransactionError<ServerError>>,
-    ) -> Result<(), ServerError> {
-        tx_result(self.store.transaction(|tx_db| {
-            tx(TransactionHandler {
-                store: tx_db,
-                schema: &self.schema,
-            })
-        }))
+    pub fn stats(&self) -> Result<StoreStats, ServerError> {
+        Ok(StoreStats {
+            keys: self.store.len(),
+            size_on_disk: self.db.size_on_disk()?,
+            pending_transactions: self.pending_transactions.load(Ordering::Relaxed),
+        })
     }
-}
 
-struct TransactionHandler<'a> {
-    store: &'a TransactionalTree,
-    schema: &'a Schema,
-}
-
-impl TransactionHandler<'_> {
-    fn tx_insert(
-        &self,
-        key: &Ref,
-        schema: &SchemaItem,
-        val: &Value,
-    ) -> Result<(), ConflictableTransactionError<ServerError>> {
-        // TODO: transactional
-        match schema {
-            SchemaItem::Collection(inner) => {
-                let Value::Object(obj) = val else {
-                    return abort(ServerError::SchemaMismatch);
-                };
-                for (primary_key, value) in obj {
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(primary_key.clone());
-                    self.tx_insert(&sub_key, inner, value)?;
-                }
-            }
+    /// How many keys each top-level item of the schema takes up. This scans the whole store, so
+    /// it's slow on large databases and shouldn't be called often.
+    pub fn usage(&self) -> Result<StorageUsage, ServerError> {
+        let schema = self.schema();
+        let mut root = schema.root();
+        while let SchemaItem::Sensitive(inner) | SchemaItem::Ephemeral(inner) = root {
+            root = inner;
+        }
+        let mut keys = BTreeMap::new();
+        match root {
             SchemaItem::Document(fields) => {
-                // TODO: optimize # of loops
-                let Value::Object(obj) = val else {
-                    return abort(ServerError::SchemaMismatch);
-                };
-                for entry in obj.keys() {
-                    if !fields.contains_key(entry) {
-                        return abort(ServerError::ExtraKeyFound);
-                    }
-                }
-                for field in fields.keys() {
-                    if !obj.contains_key(field) {
-                        return abort(ServerError::SchemaMismatch);
+                for name in fields.keys() {
+                    let prefix = schema.encode_ref(slice::from_ref(name));
+                    let mut count = 0;
+                    for key in self.store.scan_prefix(prefix).keys() {
+                        key?;
+                        count += 1;
                     }
-                }
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                self.store.insert(&encoded_ref[..], &[1])?;
-                for (obj_key, obj_value) in obj {
-                    let field = &fields[obj_key];
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(obj_key.clone());
-                    self.tx_insert(&sub_key, field, obj_value)?;
+                    keys.insert(name.clone(), count);
                 }
             }
-            SchemaItem::Scalar => {
-                let Value::String(val) = val else {
-                    return abort(ServerError::SchemaMismatch);
-                };
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                self.store.insert(&encoded_ref[..], val.as_bytes())?;
+            _ => {
+                keys.insert(String::new(), self.store.len());
             }
         }
+        Ok(StorageUsage {
+            size_on_disk: self.db.size_on_disk()?,
+            keys,
+            blob_keys: self.blobs.len(),
+        })
+    }
 
-        if key.0.len() > 1 {
-            let parent_ref = &key.0[..key.0.len() - 1];
-            let parent_schema = match self.schema.resolve(parent_ref) {
-                Ok(schema) => schema,
-                Err(err) => return abort(err.into()),
+    /// Write any buffered changes to disk, e.g. before exiting
+    pub fn flush(&self) -> Result<(), ServerError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Whether nothing has been written to the store yet
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    /// Scan the store for keys that are laid out wrongly: keys that aren't encoded refs, items
+    /// whose document or collection isn't stored, and collections still listing their members as
+    /// they did before each member had a key of its own. With `repair`, the first two are removed
+    /// and the lists are dropped, in one transaction holding off every other write.
+    ///
+    /// Keys written by the legacy server aren't encoded refs either, so they should be migrated
+    /// first. Repairs aren't recorded in the change log, and so aren't replicated.
+    pub fn check_integrity(&self, repair: bool) -> Result<Vec<IntegrityProblem>, ServerError> {
+        let schema = self.schema();
+        if repair && self.read_only {
+            return Err(ServerError::ReadOnly);
+        }
+        let _writes = self.write_gate.write().unwrap();
+        let mut problems = Vec::new();
+        let mut orphaned = BTreeSet::new();
+        // Keys sort before everything stored beneath them, so parents are seen first
+        for entry in self.store.iter() {
+            let (key, value) = entry?;
+            let Some(refs) = schema.try_decode_ref(&key) else {
+                problems.push(IntegrityProblem::Undecodable(key.to_vec()));
+                continue;
             };
-            if let SchemaItem::Collection(_) = parent_schema {
-                let encoded_collection_key = self.schema.encode_ref(parent_ref);
-                let mut keys: HashSet<String> = self
-                    .store
-                    .get(&encoded_collection_key)?
-                    .map(|collection_value| {
-                        bincode::deserialize(collection_value.as_ref()).expect("keys are bincoded")
-                    })
-                    .unwrap_or(HashSet::new());
-                if !keys.contains(key.0.last().unwrap()) {
-                    keys.insert(key.0.last().unwrap().clone());
-                    let keys_encoded = bincode::serialize(&keys).unwrap();
-                    self.store
-                        .insert(&encoded_collection_key[..], keys_encoded)?;
+            if self.is_orphan(&refs, &orphaned)? {
+                orphaned.insert(key.to_vec());
+                problems.push(IntegrityProblem::Orphaned(Ref(refs)));
+                continue;
+            }
+            let path = Ref(refs);
+            if let Ok(SchemaItem::Collection(_)) = resolve(&schema, &path) {
+                if value.as_ref() != [1] {
+                    let listed: HashSet<String> = bincode::deserialize(&value).unwrap_or_default();
+                    let mut missing = Vec::new();
+                    for member in listed {
+                        if !self
+                            .store
+                            .contains_key(schema.encode_ref(&path.child(&member).0))?
+                        {
+                            missing.push(member);
+                        }
+                    }
+                    missing.sort_unstable();
+                    problems.push(IntegrityProblem::StaleMembers {
+                        collection: path,
+                        missing,
+                    });
                 }
             }
         }
-
-        Ok(())
+        if !repair || self.dry_run || problems.is_empty() {
+            return Ok(problems);
+        }
+        tx_result(
+            (&self.store, &self.meta, &self.blobs).transaction(|(store, meta, blobs)| {
+                for problem in problems.iter() {
+                    let key = match problem {
+                        IntegrityProblem::Undecodable(key) => key.clone(),
+                        IntegrityProblem::Orphaned(path) => schema.encode_ref(&path.0),
+                        IntegrityProblem::StaleMembers { collection, .. } => {
+                            store.insert(schema.encode_ref(&collection.0), &[1])?;
+                            continue;
+                        }
+                    };
+                    if let Some(old) = store.remove(&key[..])? {
+                        blob::tx_drop(blobs, &old)?;
+                    }
+                    meta.remove(&key[..])?;
+                }
+                Ok(())
+            }),
+        )?;
+        Ok(problems)
     }
 
-    fn tx_update(
-        &self,
-        key: &Ref,
-        schema: &SchemaItem,
-        val: &Value,
-    ) -> Result<(), ConflictableTransactionError<ServerError>> {
-        match schema {
-            SchemaItem::Collection(inner) => {
-                let Value::Object(obj) = val else {
-                    return abort(ServerError::SchemaMismatch);
+    /// Remove the items whose document or collection isn't stored, which a crash part way
+    /// through a write or a bug in an older version can leave behind. Each top-level item is
+    /// scanned while writes carry on, then its orphans are checked again and removed while
+    /// holding them off. Subscribers see the removals as expiries. Returns how many keys were
+    /// removed.
+    pub fn collect_orphans(&self) -> Result<usize, ServerError> {
+        let schema = self.schema();
+        if self.read_only {
+            return Err(ServerError::ReadOnly);
+        }
+        if let Some(Maintenance { reason }) = self.maintenance() {
+            return Err(ServerError::Maintenance(reason));
+        }
+        let mut removed = 0;
+        for (path, _) in top_level(schema.root()) {
+            let mut orphaned = BTreeSet::new();
+            for key in self.store.scan_prefix(schema.encode_ref(&path.0)).keys() {
+                let key = key?;
+                let Some(refs) = schema.try_decode_ref(&key) else {
+                    continue;
                 };
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                if self.store.get(encoded_ref)?.is_none() {
-                    return abort(ServerError::KeyNotFound);
-                }
-                for (primary_key, value) in obj {
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(primary_key.clone());
-                    self.tx_update(&sub_key, inner, value)?;
+                if self.is_orphan(&refs, &orphaned)? {
+                    orphaned.insert(key.to_vec());
                 }
             }
-            SchemaItem::Document(fields) => {
-                let Value::Object(obj) = val else {
-                    return abort(ServerError::SchemaMismatch);
-                };
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                self.store.remove(&encoded_ref[..])?;
-                if self.store.get(encoded_ref)?.is_none() {
-                    return abort(ServerError::KeyNotFound);
-                }
-                for (obj_key, obj_value) in obj {
-                    let Some(field) = fields.get(obj_key) else {
-                        return abort(ServerError::ExtraKeyFound);
-                    };
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(obj_key.clone());
-                    self.tx_update(&sub_key, field, obj_value)?;
-                }
+            if orphaned.is_empty() || self.dry_run {
+                continue;
             }
-            SchemaItem::Scalar => {
-                let Value::String(val) = val else {
-                    return abort(ServerError::SchemaMismatch);
-                };
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                if self.store.get(&encoded_ref)?.is_none() {
-                    return abort(ServerError::KeyNotFound);
+            // Subscribers are told before the removal is committed, so nothing can be written
+            // in between that would make them wrong
+            let _writes = self.write_gate.write().unwrap();
+            let mut confirmed = BTreeSet::new();
+            for key in orphaned {
+                let refs = schema.decode_ref(&key);
+                if self.store.contains_key(&key)? && self.is_orphan(&refs, &confirmed)? {
+                    self.dispatcher.expiring(key.clone().into());
+                    confirmed.insert(key);
                 }
-                self.store.insert(&encoded_ref[..], val.as_bytes())?;
             }
+            tx_result((&self.store, &self.meta, &self.blobs).transaction(
+                |(store, meta, blobs)| {
+                    for key in confirmed.iter() {
+                        if let Some(old) = store.remove(&key[..])? {
+                            blob::tx_drop(blobs, &old)?;
+                        }
+                        meta.remove(&key[..])?;
+                    }
+                    Ok(())
+                },
+            ))?;
+            removed += confirmed.len();
         }
-        Ok(())
+        if removed > 0 {
+            tracing::info!(removed, "removed orphaned keys");
+        }
+        Ok(removed)
     }
 
-    fn tx_remove(
+    /// Whether the item at `refs` is an orphan: its document or collection isn't stored, or is
+    /// an orphan itself, as recorded in `orphaned`. The root document isn't always stored, so
+    /// top-level items never are.
+    fn is_orphan(
         &self,
-        key: &Ref,
-        schema: &SchemaItem,
-    ) -> Result<(), ConflictableTransactionError<ServerError>> {
-        match schema {
-            SchemaItem::Collection(inner) => {
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                let Some(value) = self.store.get(&encoded_ref)? else {
-                    return abort(ServerError::KeyNotFound);
-                };
-                let keys: HashSet<String> = bincode::deserialize(value.as_ref())
-                    .expect("collections are encoded via bincode");
-                for child in keys {
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(child.clone());
-                    self.tx_remove(&sub_key, inner)?;
-                }
-                self.store.remove(&encoded_ref[..])?;
-            }
-            SchemaItem::Document(fields) => {
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                self.store.remove(&encoded_ref[..])?;
-                for (field, ty) in fields {
-                    let mut sub_key = key.clone();
-                    sub_key.0.push(field.clone());
-                    self.tx_remove(&sub_key, ty)?;
-                }
-            }
-            SchemaItem::Scalar => {
-                let encoded_ref = self.schema.encode_ref(&key.0);
-                self.store.remove(&encoded_ref[..])?;
+        refs: &[String],
+        orphaned: &BTreeSet<Vec<u8>>,
+    ) -> Result<bool, ServerError> {
+        let Some((_, parent)) = refs.split_last().filter(|_| refs.len() > 1) else {
+            return Ok(false);
+        };
+        let parent = self.schema().encode_ref(parent);
+        Ok(orphaned.contains(&parent) || !self.store.contains_key(&parent)?)
+    }
+
+    /// Whether the store contains keys written by the legacy string-keyed server
+    pub fn has_legacy_keys(&self) -> Result<bool, ServerError> {
+        for key in self.store.iter().keys() {
+            if self.schema().try_decode_ref(&key?).is_none() {
+                return Ok(true);
             }
         }
-        if key.0.len() > 1 {
-            let parent_ref = &key.0[..key.0.len() - 1];
-            let parent_schema = match self.schema.resolve(parent_ref) {
-                Ok(schema) => schema,
-                Err(err) => return abort(err.into()),
-            };
-            if let SchemaItem::Collection(_) = parent_schema {
-                let encoded_collection_key = self.schema.encode_ref(parent_ref);
-                let mut keys: HashSet<String> = self
-                    .store
-                    .get(&encoded_collection_key)?
-                    .map(|collection_value| {
-                        bincode::deserialize(collection_value.as_ref()).expect("keys are bincoded")
-                    })
-                    .unwrap_or(HashSet::new());
-                keys.remove(key.0.last().unwrap());
-                let keys_encoded = bincode::serialize(&keys).unwrap();
-                self.store
-                    .insert(&encoded_collection_key[..], keys_encoded)?;
+        Ok(false)
+    }
+
+    /// Convert flat string keys written by the legacy server into the encoded-ref layout.
+    ///
+    /// Legacy keys are split on `separator` into paths and their values are treated as scalar
+    /// strings. Each top-level field of the schema is converted in its own transaction; subtrees
+    /// that don't match the schema are left untouched and reported.
+    pub fn migrate_legacy(&self, separator: char) -> Result<MigrationReport, ServerError> {
+        let current = self.schema();
+        let mut report = MigrationReport::default();
+
+        // top-level field -> (legacy keys, assembled value)
+        let mut subtrees: HashMap<Option<String>, (Vec<IVec>, Value)> = HashMap::new();
+        let root_is_document = matches!(current.root(), SchemaItem::Document(_));
+        for entry in self.store.iter() {
+            let (key, value) = entry?;
+            if current.try_decode_ref(&key).is_some() {
+                continue;
+            }
+            let legacy_key = String::from_utf8_lossy(&key).into_owned();
+            let mut path: Vec<String> = legacy_key
+                .split(separator)
+                .filter(|component| !component.is_empty())
+                .map(str::to_string)
+                .collect();
+            let group = if root_is_document && !path.is_empty() {
+                Some(path.remove(0))
+            } else {
+                None
+            };
+            let value = Value::String(String::from_utf8_lossy(&value).into_owned());
+
+            let (keys, tree) = subtrees
+                .entry(group)
+                .or_insert_with(|| (Vec::new(), Value::Object(Map::new())));
+            if insert_at_path(tree, &path, value) {
+                keys.push(key);
+            } else {
+                report
+                    .rejected
+                    .push((legacy_key, "conflicts with another legacy key".to_string()));
+            }
+        }
+
+        if root_is_document && !subtrees.is_empty() {
+            self.store.insert(current.encode_ref(&[]), &[1])?;
+        }
+
+        for (group, (keys, value)) in subtrees {
+            let path = Ref(group.into_iter().collect());
+            let result = resolve(&current, &path).and_then(|schema| {
+                // Scalars are stored directly rather than being assembled into an object
+                let value = match (schema, value) {
+                    (SchemaItem::Scalar, Value::Object(mut obj)) if obj.len() == 1 => {
+                        obj.remove("").unwrap_or(Value::Object(obj))
+                    }
+                    (_, value) => value,
+                };
+                let change = ChangeOp::Insert {
+                    path: path.clone(),
+                    value: value.clone(),
+                };
+                self.transaction(&change, |tx| {
+                    for key in keys.iter() {
+                        tx.store.remove(key)?;
+                    }
+                    tx.tx_insert(&path, schema, &value)
+                })
+            });
+            match result {
+                Ok(()) => report.migrated += keys.len(),
+                Err(e) => report.rejected.push((path.to_string(), e.to_string())),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Reject every write, except changes replicated from a leader with `apply`
+    pub fn read_only(mut self) -> Server {
+        self.read_only = true;
+        self
+    }
+
+    /// Check every write as if making it, with the schema and anything else that would reject it,
+    /// but roll it back rather than commit it. Nothing is logged, recorded or published.
+    pub fn dry_run(mut self) -> Server {
+        self.dry_run = true;
+        self
+    }
+
+    /// Flush every write to disk before returning from it, rather than leaving it to sled's
+    /// periodic flush. Slower, but a write that returns survives a crash. Tenants opened afterwards
+    /// flush theirs too.
+    pub fn with_sync_writes(mut self) -> Server {
+        self.sync_writes = true;
+        self
+    }
+
+    /// Log a warning for each read or write that takes longer than `threshold`, with the path it
+    /// was made to. Tenants opened afterwards log theirs too.
+    pub fn with_slow_threshold(mut self, threshold: Duration) -> Server {
+        self.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// How long a read or write may take before it's logged, if they're timed at all
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        self.slow_threshold
+    }
+
+    /// Reject every write to the store, from clients and leaders alike, until `resume_writes` is
+    /// called. Reads and subscriptions carry on, as do ephemeral items and presence, which
+    /// aren't stored. Applies to every tenant.
+    pub fn pause_writes(&self, maintenance: Maintenance) {
+        *self.maintenance.lock().unwrap() = Some(maintenance);
+    }
+
+    pub fn resume_writes(&self) {
+        *self.maintenance.lock().unwrap() = None;
+    }
+
+    /// Why writes are paused, if they are
+    pub fn maintenance(&self) -> Option<Maintenance> {
+        self.maintenance.lock().unwrap().clone()
+    }
+
+    /// Keep the last `keep` revisions of every document written from now on, for `history` and
+    /// `revision`. Tenants opened afterwards keep theirs too.
+    pub fn with_history(mut self, keep: usize) -> Result<Server, ServerError> {
+        let tree = self.db.open_tree("system/history")?;
+        self.history = Some(Arc::new(History::new(tree, keep)));
+        Ok(self)
+    }
+
+    /// Split values longer than `chunk_size` bytes across several keys, so a large value doesn't
+    /// make one giant node in sled. Values already written are read back whatever size they were
+    /// split at. Tenants opened afterwards split theirs the same way.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Server {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Register a codec for `SchemaItem::Custom` fields with the given name.
+    ///
+    /// Must be called before the server is cloned, as clones share their codecs.
+    pub fn with_codec(mut self, name: &str, codec: impl ScalarCodec + 'static) -> Server {
+        Arc::make_mut(&mut self.codecs).insert(name.to_string(), Arc::new(codec));
+        self
+    }
+
+    pub fn get(&self, key: &Ref) -> Result<Value, ServerError> {
+        let _span = tracing::trace_span!("get").entered();
+        slow::timed(self.slow_threshold, "get", key, || {
+            self.consistently(slice::from_ref(key), || self.get_item(key))
+        })
+    }
+
+    /// Read several items as they all were at one moment
+    pub fn get_many(&self, keys: &[Ref]) -> Result<Vec<Value>, ServerError> {
+        let _span = tracing::trace_span!("get_many").entered();
+        self.consistently(keys, || keys.iter().map(|key| self.get_item(key)).collect())
+    }
+
+    /// Make a `read` of the items at `keys` that sees them all as they were at one moment. It
+    /// takes many reads from sled to read a document or collection, and writes can commit between
+    /// them, so the read is made again if anything it covers was written in the meantime. If
+    /// writes keep landing, it's made while holding them off.
+    fn consistently<T>(
+        &self,
+        keys: &[Ref],
+        read: impl Fn() -> Result<T, ServerError>,
+    ) -> Result<T, ServerError> {
+        for _ in 0..CONSISTENT_READ_ATTEMPTS {
+            let from = self.next_change()?;
+            let result = read();
+            if !self.written_since(from, keys)? {
+                return result;
+            }
+        }
+        let _writes = self.write_gate.write().unwrap();
+        read()
+    }
+
+    /// Whether a change numbered `from` or later wrote to anything overlapping `keys`, or may be
+    /// writing to it now
+    fn written_since(&self, from: u64, keys: &[Ref]) -> Result<bool, ServerError> {
+        if self.sequencer.next() == from {
+            return Ok(false);
+        }
+        // Checked first, as a change that's no longer being made by the time the log is read
+        // will be in it
+        if self.sequencer.pending_overlaps(keys) {
+            return Ok(true);
+        }
+        // Including the changes committed after one that's still being made
+        let changes = changes::read(&self.changes, from, u64::MAX, usize::MAX)?;
+        if from < changes::trimmed(&self.changes)? {
+            return Ok(true);
+        }
+        Ok((changes.iter()).any(|change| keys.iter().any(|key| change.op.path().overlaps(key))))
+    }
+
+    fn get_item(&self, key: &Ref) -> Result<Value, ServerError> {
+        let current = self.schema();
+        let schema = resolve(&current, key)?;
+        match schema {
+            SchemaItem::Collection(_) | SchemaItem::Document(_) => {
+                let mut entries = self.scan(key)?;
+                // Ephemeral items are kept apart from the store, but read as if they were in it
+                entries.extend(self.memory.scan(&current.encode_ref(&key.0)));
+                self.assemble(key, schema, &entries, Missing::Error)
+            }
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_) => {
+                let encoded_ref = current.encode_ref(&key.0);
+                let val = if current.is_ephemeral(&key.0) {
+                    self.memory.get(&encoded_ref)
+                } else {
+                    match self.store.get(&encoded_ref)? {
+                        Some(stored) => unsplit(&self.store, &self.blobs, &encoded_ref, stored)?,
+                        None => None,
+                    }
+                };
+                match val {
+                    Some(val) => decode_scalar(&current, &self.codecs, key, schema, &val),
+                    None if current.is_optional(&key.0) => Ok(Value::Null),
+                    None => Err(ServerError::KeyNotFound(key.clone())),
+                }
             }
+            SchemaItem::Presence if current.is_presence_member(&key.0) => {
+                match self.presence.get(&current.encode_ref(&key.0)) {
+                    Some(val) => decode_scalar(&current, &self.codecs, key, schema, &val),
+                    None => Err(ServerError::KeyNotFound(key.clone())),
+                }
+            }
+            SchemaItem::Presence => self.assemble(key, schema, &BTreeMap::new(), Missing::Error),
+            SchemaItem::Sensitive(_)
+            | SchemaItem::Ephemeral(_)
+            | SchemaItem::Optional(_)
+            | SchemaItem::Searchable(_) => {
+                unreachable!("resolve unwraps wrapping items")
+            }
+        }
+    }
+
+    /// Every entry stored at or under `key`, read in a single pass over the store rather than one
+    /// read per member and field
+    fn scan(&self, key: &Ref) -> Result<BTreeMap<IVec, IVec>, ServerError> {
+        Ok(self
+            .store
+            .scan_prefix(self.schema().encode_ref(&key.0))
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Build the value at `key`, which has the shape `schema`, out of entries read by `scan`
+    fn assemble(
+        &self,
+        key: &Ref,
+        schema: &SchemaItem,
+        entries: &BTreeMap<IVec, IVec>,
+        missing: Missing,
+    ) -> Result<Value, ServerError> {
+        let current = self.schema();
+        let entry = |key: &Ref| entries.get(&current.encode_ref(&key.0)[..]);
+        match schema {
+            SchemaItem::Collection(collection) => {
+                let mut members = Map::new();
+                for member in collection_members(&current, key, entries) {
+                    let value =
+                        self.assemble(&key.child(&member), &collection.items, entries, missing)?;
+                    members.insert(member, value);
+                }
+                Ok(Value::Object(members))
+            }
+            SchemaItem::Document(fields) => {
+                if entry(key).is_none() {
+                    return Ok(Value::Null);
+                }
+                let mut values = Map::new();
+                for (field, schema) in fields {
+                    let value = self.assemble(&key.child(field), schema, entries, missing)?;
+                    values.insert(field.clone(), value);
+                }
+                Ok(Value::Object(values))
+            }
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_) => {
+                let encoded_ref = current.encode_ref(&key.0);
+                let val = match entries.get(&encoded_ref[..]) {
+                    // Ephemeral items are never split, whatever they start with
+                    Some(stored) if blob::is_manifest(stored) && !current.is_ephemeral(&key.0) => {
+                        unsplit(&self.store, &self.blobs, &encoded_ref, stored.clone())?
+                    }
+                    val => val.cloned(),
+                };
+                match (val, missing) {
+                    (Some(val), _) => decode_scalar(&current, &self.codecs, key, schema, &val),
+                    (None, Missing::Error) => Err(ServerError::KeyNotFound(key.clone())),
+                    (None, Missing::Null) => Ok(Value::Null),
+                }
+            }
+            // Presence is never stored, so it isn't among the entries
+            SchemaItem::Presence => {
+                let members = self.presence.scan(&current.encode_ref(&key.0));
+                let mut values = Map::new();
+                for member in collection_members(&current, key, &members) {
+                    let member_key = key.child(&member);
+                    let val = &members[&current.encode_ref(&member_key.0)[..]];
+                    let value = decode_scalar(&current, &self.codecs, &member_key, schema, val)?;
+                    values.insert(member, value);
+                }
+                Ok(Value::Object(values))
+            }
+            SchemaItem::Sensitive(inner) | SchemaItem::Searchable(inner) => {
+                self.assemble(key, inner, entries, missing)
+            }
+            // Ephemeral items are null until they're set, and in dumps, which only read the store
+            SchemaItem::Ephemeral(inner) => self.assemble(key, inner, entries, Missing::Null),
+            SchemaItem::Optional(inner) => self.assemble(key, inner, entries, Missing::Null),
+        }
+    }
+
+    /// Like `get`, but a collection or document comes in pieces of its members or fields, each
+    /// roughly `chunk_size` bytes of JSON, which are only read from the store as they're needed.
+    /// Members written while the pieces are being read may or may not be included.
+    pub fn get_chunked(&self, key: &Ref, chunk_size: usize) -> Result<Chunked, ServerError> {
+        let schema = self.schema();
+        if schema.is_ephemeral(&key.0) {
+            return Ok(Chunked::Whole(self.get_item(key)?));
         }
+        let members: Box<dyn Iterator<Item = Result<(String, Value), ServerError>> + Send> =
+            match resolve(&schema, key)? {
+                SchemaItem::Collection(_) => Box::new(CollectionMembers {
+                    server: self.clone(),
+                    key: key.clone(),
+                    prefix: schema.encode_ref(&key.0),
+                    iter: self.store.scan_prefix(schema.encode_ref(&key.0)),
+                    next: None,
+                }),
+                SchemaItem::Document(fields)
+                    if self.store.contains_key(schema.encode_ref(&key.0))? =>
+                {
+                    let server = self.clone();
+                    let key = key.clone();
+                    let fields: Vec<String> = fields.keys().cloned().collect();
+                    Box::new(fields.into_iter().map(move |field| {
+                        let value = server.get_item(&key.child(&field))?;
+                        Ok((field, value))
+                    }))
+                }
+                _ => return Ok(Chunked::Whole(self.get_item(key)?)),
+            };
+        Ok(Chunked::Pieces(ValueChunks {
+            members,
+            chunk_size,
+            started: false,
+        }))
+    }
+
+    /// The whole database as one JSON value shaped like the schema, which `restore` can read
+    /// back. Anything with nothing stored in it is null.
+    ///
+    /// Writes wait until the dump is finished, so it's a consistent snapshot.
+    pub fn dump(&self) -> Result<Value, ServerError> {
+        let _writes = self.write_gate.write().unwrap();
+        self.dump_item(&Ref(Vec::new()), self.schema().root())
+    }
+
+    /// A dump, along with the sequence number of the first change it doesn't include, so a
+    /// follower can restore it and then apply the change log from that point
+    pub fn snapshot(&self) -> Result<(u64, Value), ServerError> {
+        let _writes = self.write_gate.write().unwrap();
+        Ok((
+            self.next_change()?,
+            self.dump_item(&Ref(Vec::new()), self.schema().root())?,
+        ))
+    }
+
+    fn dump_item(&self, key: &Ref, schema: &SchemaItem) -> Result<Value, ServerError> {
+        let entries = self.scan(key)?;
+        self.assemble(key, schema, &entries, Missing::Null)
+    }
 
+    /// Replace everything in the database with a snapshot taken by `dump`. The snapshot is
+    /// checked against the schema as it's written, and nothing changes if it doesn't match.
+    pub fn restore(&self, snapshot: &Value) -> Result<(), ServerError> {
+        let existing = self.store.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let indexed = self.search.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let chunks = self.blobs.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        let change = ChangeOp::Restore {
+            snapshot: snapshot.clone(),
+        };
+        self.transaction(&change, |tx| {
+            for key in existing.iter() {
+                tx.store.remove(key)?;
+                if let Some(meta) = tx.meta {
+                    meta.remove(key)?;
+                }
+            }
+            if let Some(search) = tx.search {
+                for key in indexed.iter() {
+                    search.remove(key)?;
+                }
+            }
+            if let Some(blobs) = tx.blobs {
+                for key in chunks.iter() {
+                    blobs.remove(key)?;
+                }
+            }
+            tx.tx_restore(&Ref(Vec::new()), self.schema().root(), snapshot)
+        })?;
+        self.forget(&Ref(Vec::new()));
         Ok(())
     }
-}
 
-fn tx_result<T>(result: TransactionResult<T, ServerError>) -> Result<T, ServerError> {
-    match result {
-        Ok(val) => Ok(val),
-        Err(TransactionError::Abort(e)) => Err(e),
-        Err(TransactionError::Storage(e)) => Err(e.into()),
+    /// When the document at `key` was created and last written, or None if it hasn't been written
+    /// since metadata was first kept. Ephemeral documents have none.
+    pub fn metadata(&self, key: &Ref) -> Result<Option<Metadata>, ServerError> {
+        let schema = self.schema();
+        if !matches!(resolve(&schema, key)?, SchemaItem::Document(_)) {
+            return Err(ServerError::SchemaMismatch(key.clone()));
+        }
+        let encoded_ref = schema.encode_ref(&key.0);
+        let exists = if schema.is_ephemeral(&key.0) {
+            self.memory.get(&encoded_ref).is_some()
+        } else {
+            self.store.get(&encoded_ref)?.is_some()
+        };
+        if !exists {
+            return Err(ServerError::KeyNotFound(key.clone()));
+        }
+        Ok(self
+            .meta
+            .get(&encoded_ref)?
+            .map(|meta| Metadata::decode(&meta)))
     }
-}
 
-pub struct SubscriptionStream {
-    sub: Subscriber,
-    schema: Arc<Schema>,
-}
+    /// The revisions kept of the document at `key`, oldest first. There are none unless the
+    /// server keeps history.
+    pub fn history(&self, key: &Ref) -> Result<Vec<Revision>, ServerError> {
+        let schema = self.schema();
+        if !matches!(resolve(&schema, key)?, SchemaItem::Document(_)) {
+            return Err(ServerError::SchemaMismatch(key.clone()));
+        }
+        match &self.history {
+            Some(history) => Ok(history.revisions(&schema.encode_ref(&key.0))?),
+            None => Ok(Vec::new()),
+        }
+    }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Event {
-    /// A new complete (key, value) pair
-    Insert {
-        /// The key that has been set
-        key: Ref,
-        /// The value that has been set
-        value: IVec,
-    },
-    /// A deleted key
-    Remove {
-        /// The key that has been removed
-        key: Ref,
-    },
-}
+    /// The document at `key` as it was at `revision`, if that revision is still kept
+    pub fn revision(&self, key: &Ref, revision: u64) -> Result<Revision, ServerError> {
+        let schema = self.schema();
+        if !matches!(resolve(&schema, key)?, SchemaItem::Document(_)) {
+            return Err(ServerError::SchemaMismatch(key.clone()));
+        }
+        let found = match &self.history {
+            Some(history) => history.get(&schema.encode_ref(&key.0), revision)?,
+            None => None,
+        };
+        found.ok_or_else(|| ServerError::KeyNotFound(key.clone()))
+    }
 
-impl Stream for SubscriptionStream {
-    type Item = Event;
+    /// The documents at or under `key` with every word of `query` in their searchable items,
+    /// in order of their refs. A searchable item outside any document is returned itself.
+    pub fn search(&self, key: &Ref, query: &str) -> Result<Vec<Ref>, ServerError> {
+        let schema = self.schema();
+        resolve(&schema, key)?;
+        let prefix = schema.encode_ref(&key.0);
+        let mut found: Option<BTreeSet<Vec<String>>> = None;
+        for term in search::terms(&Value::String(query.to_string())) {
+            let term_prefix = search::term_prefix(&term);
+            let mut matches = BTreeSet::new();
+            for index_key in self.search.scan_prefix(&term_prefix).keys() {
+                let encoded_ref = &index_key?[term_prefix.len()..];
+                if encoded_ref.starts_with(&prefix) {
+                    matches.insert(self.containing_document(schema.decode_ref(encoded_ref)));
+                }
+            }
+            found = Some(match found {
+                Some(found) => found.intersection(&matches).cloned().collect(),
+                None => matches,
+            });
+        }
+        Ok(found.unwrap_or_default().into_iter().map(Ref).collect())
+    }
 
-    fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
-        self.sub.poll_unpin(cx).map(|evt| {
-            evt.map(|evt| match evt {
-                sled::Event::Insert { key, value } => Event::Insert {
-                    key: Ref(self.schema.decode_ref(key.as_ref())),
-                    value,
-                },
-                sled::Event::Remove { key } => Event::Remove {
-                    key: Ref(self.schema.decode_ref(key.as_ref())),
-                },
+    /// The members of the collection at `key` that match `filter`, as an object of them by key.
+    /// There are no indexes on fields, so every member is read.
+    pub fn query(&self, key: &Ref, filter: &Filter) -> Result<Value, ServerError> {
+        let schema = self.schema();
+        if !matches!(resolve(&schema, key)?, SchemaItem::Collection(_)) {
+            return Err(ServerError::SchemaMismatch(key.clone()));
+        }
+        // Collections resolve their members whatever the key, so any will do
+        let members = [key.0.as_slice(), &[String::new()]].concat();
+        query::validate(&schema, &members, filter).map_err(|reason| ServerError::InvalidQuery {
+            path: key.clone(),
+            reason,
+        })?;
+        let mut members = match self.get(key)? {
+            Value::Object(members) => members,
+            _ => unreachable!("collections are read as objects"),
+        };
+        members.retain(|_, member| query::matches(member, filter));
+        Ok(Value::Object(members))
+    }
+
+    /// Compute an aggregation over the members of the collection at `key`. Counting only reads
+    /// which members are stored, not their values; anything else reads every member.
+    pub fn aggregate(&self, key: &Ref, aggregation: &Aggregation) -> Result<Value, ServerError> {
+        let schema = self.schema();
+        if !matches!(resolve(&schema, key)?, SchemaItem::Collection(_)) {
+            return Err(ServerError::SchemaMismatch(key.clone()));
+        }
+        let members = [key.0.as_slice(), &[String::new()]].concat();
+        query::validate_aggregation(&schema, &members, aggregation).map_err(|reason| {
+            ServerError::InvalidQuery {
+                path: key.clone(),
+                reason,
+            }
+        })?;
+        if let Aggregation::Count = aggregation {
+            return Ok(Value::from(self.list_keys(key)?.len()));
+        }
+        let members = match self.get(key)? {
+            Value::Object(members) => members,
+            _ => unreachable!("collections are read as objects"),
+        };
+        Ok(query::aggregate(members.values(), aggregation))
+    }
+
+    /// The keys of the members of the collection or presence collection at `key`, in the order
+    /// they're stored, which is the order `get_chunked` reads them in. Only the keys are read,
+    /// not the members' values.
+    pub fn list_keys(&self, key: &Ref) -> Result<Vec<String>, ServerError> {
+        let schema = self.schema();
+        let collection = schema.encode_ref(&key.0);
+        let members = [key.0.as_slice(), &[String::new()]].concat();
+        match resolve(&schema, key)? {
+            SchemaItem::Collection(_) if schema.is_ephemeral(&members) => Ok(collection_members(
+                &schema,
+                key,
+                &self.memory.scan(&collection),
+            )),
+            SchemaItem::Collection(_) => {
+                let mut keys = Vec::new();
+                for stored in self.store.scan_prefix(&collection).keys() {
+                    keys.extend(schema.decode_member(&collection, &stored?));
+                }
+                Ok(keys)
+            }
+            SchemaItem::Presence if !schema.is_presence_member(&key.0) => Ok(collection_members(
+                &schema,
+                key,
+                &self.presence.scan(&collection),
+            )),
+            _ => Err(ServerError::SchemaMismatch(key.clone())),
+        }
+    }
+
+    /// The ref of the closest document containing the item at `refs`
+    fn containing_document(&self, mut refs: Vec<String>) -> Vec<String> {
+        for len in (0..refs.len()).rev() {
+            if let Ok(SchemaItem::Document(_)) = self.schema().resolve(&refs[..len]) {
+                refs.truncate(len);
+                break;
+            }
+        }
+        refs
+    }
+
+    /// The ref held by a `SchemaItem::Reference` field, or None if it isn't set
+    pub fn reference(&self, field: &Ref) -> Result<Option<Ref>, ServerError> {
+        if !matches!(
+            resolve(&self.schema(), field)?,
+            SchemaItem::Reference | SchemaItem::ReferenceTo(_)
+        ) {
+            return Err(ServerError::SchemaMismatch(field.clone()));
+        }
+        match self.get(field) {
+            Ok(value) => Ok(Some(
+                serde_json::from_value(value).expect("reference fields hold refs"),
+            )),
+            Err(ServerError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The schema of the item at `key`, as JSON in the same form as the schema file. The fields of
+    /// documents in it are left out wherever `readable` says they may not be read, though
+    /// collections are described whatever keys their members have.
+    pub fn describe(
+        &self,
+        key: &Ref,
+        readable: &mut impl FnMut(&[Ref]) -> Vec<bool>,
+    ) -> Result<Value, ServerError> {
+        let schema = self.schema();
+        let item = (schema.item(&key.0)).map_err(|source| ServerError::SchemaError {
+            path: key.clone(),
+            source,
+        })?;
+        let mut described = serde_json::to_value(item).expect("schemas are valid JSON");
+        hide_unreadable(key, item, &mut described, readable);
+        Ok(described)
+    }
+
+    /// Read `key` with the `Reference` fields in it replaced by the items they point at,
+    /// following references from those items in turn up to `depth` levels (at most
+    /// `MAX_EXPANSION_DEPTH`). `readable` is given the targets found in each item together and
+    /// says which of them the reader may see; the rest, along with references to nothing, become
+    /// null.
+    pub fn get_expanded(
+        &self,
+        key: &Ref,
+        depth: u32,
+        readable: &mut impl FnMut(&[Ref]) -> Vec<bool>,
+    ) -> Result<Value, ServerError> {
+        let mut value = self.get(key)?;
+        let current = self.schema();
+        let schema = resolve(&current, key)?;
+        self.expand(schema, &mut value, depth.min(MAX_EXPANSION_DEPTH), readable)?;
+        Ok(value)
+    }
+
+    fn expand(
+        &self,
+        schema: &SchemaItem,
+        value: &mut Value,
+        depth: u32,
+        readable: &mut impl FnMut(&[Ref]) -> Vec<bool>,
+    ) -> Result<(), ServerError> {
+        if depth == 0 {
+            return Ok(());
+        }
+        let mut references = Vec::new();
+        schema.references(value, &mut references);
+        if references.is_empty() {
+            return Ok(());
+        }
+        let (fields, targets): (Vec<_>, Vec<_>) = references.into_iter().unzip();
+        let allowed = readable(&targets);
+        for ((field, target), allowed) in fields.into_iter().zip(&targets).zip(allowed) {
+            *field = if allowed {
+                self.get_expanded(target, depth - 1, readable)
+                    .or_else(|e| match e.kind() {
+                        // A dangling reference, or one to a path the schema doesn't have
+                        ErrorKind::NotFound | ErrorKind::InvalidPath => Ok(Value::Null),
+                        _ => Err(e),
+                    })?
+            } else {
+                Value::Null
+            };
+        }
+        Ok(())
+    }
+
+    /// Read `key` with the documents and collections nested more than `depth` levels below it
+    /// replaced by placeholders of `{ "$ref": <ref> }`. A collection's members are at the same
+    /// level as the collection, so reading one at depth 0 gives the scalars of each member.
+    pub fn get_shallow(&self, key: &Ref, depth: u32) -> Result<Value, ServerError> {
+        let mut value = self.get(key)?;
+        self.truncate(key, &mut value, depth);
+        Ok(value)
+    }
+
+    /// Read only the items at `fields`, paths relative to `key`, into a value shaped like the
+    /// item at `key`. Each field is read on its own, so nothing else in the item is.
+    pub fn get_fields(&self, key: &Ref, fields: &[Vec<String>]) -> Result<Value, ServerError> {
+        resolve(&self.schema(), key)?;
+        let mut value = Value::Object(Map::new());
+        for field in fields {
+            let field_value = self.get(&Ref([key.0.as_slice(), field].concat()))?;
+            let Some((last, parents)) = field.split_last() else {
+                value = field_value;
+                continue;
+            };
+            let mut parent = &mut value;
+            for component in parents {
+                if !parent[component].is_object() {
+                    parent[component] = Value::Object(Map::new());
+                }
+                parent = &mut parent[component];
+            }
+            parent[last] = field_value;
+        }
+        Ok(value)
+    }
+
+    fn truncate(&self, key: &Ref, value: &mut Value, depth: u32) {
+        let schema = self.schema();
+        let Value::Object(entries) = value else {
+            return;
+        };
+        let in_document = match schema.resolve(&key.0) {
+            Ok(SchemaItem::Document(_)) => true,
+            Ok(SchemaItem::Collection(_)) => false,
+            _ => return,
+        };
+        for (name, entry) in entries.iter_mut() {
+            let child = key.child(name);
+            if !in_document {
+                self.truncate(&child, entry, depth);
+                continue;
+            }
+            let nested = matches!(
+                schema.resolve(&child.0),
+                Ok(SchemaItem::Document(_) | SchemaItem::Collection(_) | SchemaItem::Presence)
+            );
+            // Unset documents are null, and have nothing to read later
+            if !nested || entry.is_null() {
+                continue;
+            }
+            match depth.checked_sub(1) {
+                Some(depth) => self.truncate(&child, entry, depth),
+                None => *entry = json!({ "$ref": child }),
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &Ref, val: Value) -> Result<(), ServerError> {
+        slow::timed(self.slow_threshold, "insert", key, || {
+            let current = self.schema();
+            let schema = resolve(&current, key)?;
+            match schema {
+                SchemaItem::Document(_) | SchemaItem::Collection(_) => {
+                    if current.is_ephemeral(&key.0) {
+                        return self.memory_transaction(|tx, _| tx.tx_insert(key, schema, &val));
+                    }
+                    let change = ChangeOp::Insert {
+                        path: key.clone(),
+                        value: val.clone(),
+                    };
+                    self.transaction(&change, |tx| tx.tx_insert(key, schema, &val))
+                }
+                SchemaItem::Scalar
+                | SchemaItem::Custom(_)
+                | SchemaItem::Reference
+                | SchemaItem::ReferenceTo(_)
+                | SchemaItem::Enum(_) => Err(ServerError::NonDocumentInsert(key.clone())),
+                SchemaItem::Presence => Err(ServerError::PresenceWrite(key.clone())),
+                SchemaItem::Sensitive(_)
+                | SchemaItem::Ephemeral(_)
+                | SchemaItem::Optional(_)
+                | SchemaItem::Searchable(_) => {
+                    unreachable!("resolve unwraps wrapping items")
+                }
+            }
+        })
+    }
+
+    pub fn update(&self, key: &Ref, val: Value) -> Result<(), ServerError> {
+        slow::timed(self.slow_threshold, "update", key, || {
+            let current = self.schema();
+            let schema = resolve(&current, key)?;
+            if current.is_ephemeral(&key.0) {
+                let prefix = current.encode_ref(&key.0);
+                return self.memory_transaction(|tx, memory| {
+                    tx.tx_update(key, schema, &val, &memory.scan(&prefix))
+                });
+            }
+            let change = ChangeOp::Update {
+                path: key.clone(),
+                value: val.clone(),
+            };
+            if !clears(&val) {
+                return self.transaction(&change, |tx| {
+                    tx.tx_update(key, schema, &val, &BTreeMap::new())
+                });
+            }
+            // Optional items cleared by the update are removed like any other, finding the members
+            // of their collections with a scan
+            self.scanned_transaction(key, &change, |tx, entries| {
+                tx.tx_update(key, schema, &val, entries)
             })
         })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
+    pub fn remove(&self, key: &Ref) -> Result<(), ServerError> {
+        slow::timed(self.slow_threshold, "remove", key, || {
+            let current = self.schema();
+            let schema = resolve(&current, key)?;
+            if let SchemaItem::Presence = schema {
+                return Err(ServerError::PresenceWrite(key.clone()));
+            }
+            if current.is_ephemeral(&key.0) {
+                let prefix = current.encode_ref(&key.0);
+                return self.memory_transaction(|tx, memory| {
+                    tx.tx_remove(key, schema, &memory.scan(&prefix))
+                });
+            }
+            let change = ChangeOp::Remove { path: key.clone() };
+            // The members of any collections being removed are found by a scan
+            self.scanned_transaction(key, &change, |tx, entries| {
+                tx.tx_remove(key, schema, entries)
+            })?;
+            self.forget(key);
+            Ok(())
+        })
+    }
 
-    use futures_util::StreamExt;
-    use serde_json::{Map, Value};
-    use sled::Config;
+    pub fn subscribe(&self, key: &Ref) -> SubscriptionStream {
+        let encoded_ref = self.schema().encode_ref(&key.0);
+        SubscriptionStream {
+            sub: self.dispatcher.subscribe(encoded_ref),
+            schema: self.schema.clone(),
+            store: self.store.clone(),
+            blobs: self.blobs.clone(),
+            prefix_len: 0,
+            expiring: HashSet::new(),
+        }
+    }
 
-    use crate::{
-        message::Ref,
-        schema::{Schema, SchemaItem},
-        server::Event,
-    };
+    /// The value an `Event::Insert` at `key` carries, as subscribers are sent it: string scalars
+    /// as they are, other scalars decoded by their codec and written as JSON, and `{}` for the
+    /// marker that says a document or collection is there
+    pub fn event_value(&self, key: &Ref, value: &[u8]) -> Result<String, ServerError> {
+        let current = self.schema();
+        let schema = resolve(&current, key)?;
+        let value = match schema {
+            SchemaItem::Collection(_) | SchemaItem::Document(_) => return Ok("{}".to_string()),
+            SchemaItem::Presence if !current.is_presence_member(&key.0) => {
+                return Ok("{}".to_string())
+            }
+            _ => decode_scalar(&current, &self.codecs, key, schema, value)?,
+        };
+        Ok(match value {
+            Value::String(value) => value,
+            value => value.to_string(),
+        })
+    }
+
+    /// Receive the events for every item matching `pattern`, where a `*` component stands for
+    /// any member of a collection. The pattern is checked against the schema first.
+    pub fn subscribe_pattern(
+        &self,
+        pattern: &Ref,
+    ) -> Result<impl Stream<Item = Event> + Send + Unpin, ServerError> {
+        resolve(&self.schema(), pattern)?;
+        // Everything matching starts with the components before the first wildcard
+        let fixed = pattern.0.iter().take_while(|component| *component != "*");
+        let pattern = pattern.clone();
+        Ok(self
+            .subscribe(&Ref(fixed.cloned().collect()))
+            .filter(move |event| {
+                let key = match event {
+                    Event::Insert { key, .. } | Event::Remove { key } | Event::Expire { key } => {
+                        key
+                    }
+                };
+                future::ready(key.matches(&pattern))
+            }))
+    }
 
-    use super::Server;
+    /// Set the member of a presence collection at `key` on behalf of a connection, until the
+    /// connection leaves it or closes. Subscribers see it as if it had been written.
+    pub fn join(&self, connection: u64, key: &Ref, val: Value) -> Result<(), ServerError> {
+        let current = self.schema();
+        let schema = resolve(&current, key)?;
+        if !matches!(schema, SchemaItem::Presence) || !current.is_presence_member(&key.0) {
+            return Err(ServerError::NotPresence(key.clone()));
+        }
+        let value = IVec::from(encode_scalar(&current, &self.codecs, key, schema, &val)?);
+        let encoded_ref = current.encode_ref(&key.0);
+        self.presence
+            .join(encoded_ref.clone(), connection, value.clone());
+        self.dispatcher.publish(sled::Event::Insert {
+            key: encoded_ref.into(),
+            value,
+        });
+        Ok(())
+    }
 
-    #[test]
-    fn values() {
-        let server = document_server();
+    /// Remove a presence member that the connection joined
+    pub fn leave(&self, connection: u64, key: &Ref) -> Result<(), ServerError> {
+        let encoded_ref = self.schema().encode_ref(&key.0);
+        if !self.presence.leave(&encoded_ref, connection) {
+            return Err(ServerError::KeyNotFound(key.clone()));
+        }
+        self.dispatcher.publish(sled::Event::Remove {
+            key: encoded_ref.into(),
+        });
+        Ok(())
+    }
+
+    /// Remove every presence member a connection joined, once it has closed
+    pub fn leave_all(&self, connection: u64) {
+        for key in self.presence.leave_all(connection) {
+            self.dispatcher
+                .publish(sled::Event::Remove { key: key.into() });
+        }
+    }
+
+    /// A handle where every key is relative to `prefix`, for handing a component only the part of
+    /// the tree it owns
+    pub fn scoped(&self, prefix: Ref) -> ScopedServer {
+        ScopedServer {
+            server: self.clone(),
+            prefix,
+        }
+    }
+
+    /// Up to `limit` committed changes, oldest first, starting at sequence number `from`. Changes
+    /// are read only as far as the first that's still being made, so one reading on from the
+    /// last change it was given never misses any. Reading from before the oldest change that
+    /// hasn't been trimmed fails.
+    pub fn changes(&self, from: u64, limit: usize) -> Result<Vec<Change>, ServerError> {
+        let changes = changes::read(&self.changes, from, self.sequencer.horizon(), limit)?;
+        let oldest = changes::trimmed(&self.changes)?;
+        if from < oldest {
+            return Err(ServerError::ChangesTrimmed { from, oldest });
+        }
+        Ok(changes)
+    }
+
+    /// The sequence number of the first change `changes` hasn't read yet: the one the next change
+    /// to commit will be given, or a lower one
+    pub fn next_change(&self) -> Result<u64, ServerError> {
+        Ok(self.sequencer.horizon())
+    }
+
+    /// The sequence number the change log can be read from, as older changes have been trimmed
+    pub fn oldest_change(&self) -> Result<u64, ServerError> {
+        Ok(changes::trimmed(&self.changes)?)
+    }
+
+    /// Remove all but the `keep` most recent changes from the change log, leaving any numbered
+    /// `pinned` or later, e.g. because an integration hasn't sent them yet. Returns how many were
+    /// removed.
+    pub fn trim_changes(&self, keep: usize, pinned: Option<u64>) -> Result<usize, ServerError> {
+        let pinned = pinned.unwrap_or(u64::MAX).min(self.sequencer.horizon());
+        Ok(changes::trim(&self.changes, keep, pinned)?)
+    }
+
+    /// Changes whenever there are more changes to read. It starts out seen, so a change committed
+    /// between watching and reading isn't missed.
+    pub fn watch_changes(&self) -> watch::Receiver<()> {
+        self.sequencer.watch()
+    }
+
+    /// Make several writes in one transaction, as long as nothing else has been written since
+    /// change number `from`, returning whether they were made. Each is logged as its own change.
+    /// Ephemeral items are never part of a transaction, so they're written once the rest are.
+    pub fn write_all(&self, from: u64, writes: &[ChangeOp]) -> Result<bool, ServerError> {
+        let current = self.schema();
+        let (ephemeral, stored): (Vec<&ChangeOp>, Vec<&ChangeOp>) = writes
+            .iter()
+            .partition(|write| current.is_ephemeral(&write.path().0));
+        let mut prepared = Vec::new();
+        for &write in stored.iter() {
+            let key = write.path();
+            let schema = resolve(&current, key)?;
+            match (write, schema) {
+                (ChangeOp::Insert { .. }, SchemaItem::Document(_) | SchemaItem::Collection(_))
+                | (ChangeOp::Update { .. }, _) => {}
+                (ChangeOp::Insert { .. } | ChangeOp::Remove { .. }, SchemaItem::Presence) => {
+                    return Err(ServerError::PresenceWrite(key.clone()))
+                }
+                (ChangeOp::Insert { .. }, _) => {
+                    return Err(ServerError::NonDocumentInsert(key.clone()))
+                }
+                (ChangeOp::Remove { .. }, _) => {}
+                (ChangeOp::Restore { .. }, _) => {
+                    unreachable!("restores replace everything, so they're never made together")
+                }
+            }
+            let entries = match write {
+                ChangeOp::Remove { .. } => self.scan(key)?,
+                ChangeOp::Update { value, .. } if clears(value) => self.scan(key)?,
+                _ => BTreeMap::new(),
+            };
+            prepared.push((write, schema, entries));
+        }
+
+        let logged: Vec<ChangeOp> = stored.into_iter().cloned().collect();
+        let written = self.transaction_from(from, &logged, |tx| {
+            for (write, schema, entries) in prepared.iter() {
+                match write {
+                    ChangeOp::Insert { path, value } => tx.tx_insert(path, schema, value)?,
+                    ChangeOp::Update { path, value } => {
+                        tx.tx_update(path, schema, value, entries)?
+                    }
+                    ChangeOp::Remove { path } => tx.tx_remove(path, schema, entries)?,
+                    ChangeOp::Restore { .. } => unreachable!("checked above"),
+                }
+            }
+            Ok(())
+        })?;
+        if !written {
+            return Ok(false);
+        }
+        for write in logged.iter() {
+            if let ChangeOp::Remove { path } = write {
+                self.forget(path);
+            }
+        }
+        for write in ephemeral {
+            match write {
+                ChangeOp::Insert { path, value } => self.insert(path, value.clone())?,
+                ChangeOp::Update { path, value } => self.update(path, value.clone())?,
+                ChangeOp::Remove { path } => self.remove(path)?,
+                ChangeOp::Restore { .. } => unreachable!("restores are never ephemeral"),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Make a change read from another server's change log, even if this one is read-only, and
+    /// record along with it that `next` is the number of the next change to read from that log.
+    ///
+    /// Applying the same change twice in a row leaves the store as applying it once would, so a
+    /// follower that stops part way through can safely repeat the last change it applied.
+    pub fn apply(&self, op: &ChangeOp, next: u64) -> Result<(), ServerError> {
+        let writable = Server {
+            read_only: false,
+            replicated: Some(next),
+            ..self.clone()
+        };
+        match op {
+            ChangeOp::Insert { path, value } => writable.insert(path, value.clone()),
+            ChangeOp::Update { path, value } => writable.update(path, value.clone()),
+            ChangeOp::Remove { path } => match writable.remove(path) {
+                Err(ServerError::KeyNotFound(_)) => {
+                    Ok(changes::record_replicated(&self.changes, next)?)
+                }
+                result => result,
+            },
+            ChangeOp::Restore { snapshot } => writable.restore(snapshot),
+        }
+    }
+
+    /// The number of the next change to `apply` from the other server's change log, if any have
+    /// been applied
+    pub fn replicated(&self) -> Result<Option<u64>, ServerError> {
+        Ok(changes::replicated(&self.changes)?)
+    }
+
+    /// Run `tx` as a transaction, recording `change` in the change log if it commits
+    fn transaction(
+        &self,
+        change: &ChangeOp,
+        tx: impl Fn(TransactionHandler) -> Result<(), ConflictableTransactionError<ServerError>>,
+    ) -> Result<(), ServerError> {
+        let _write = self.write_gate.read().unwrap();
+        self.commit(slice::from_ref(change), tx)
+    }
+
+    /// Like `transaction`, for writes that need the entries under `key`, which transactions can't
+    /// scan for. The scan is made before the transaction, and nothing else is written until it
+    /// commits.
+    fn scanned_transaction(
+        &self,
+        key: &Ref,
+        change: &ChangeOp,
+        tx: impl Fn(
+            TransactionHandler,
+            &BTreeMap<IVec, IVec>,
+        ) -> Result<(), ConflictableTransactionError<ServerError>>,
+    ) -> Result<(), ServerError> {
+        let _writes = self.write_gate.write().unwrap();
+        let entries = self.scan(key)?;
+        self.commit(slice::from_ref(change), |handler| tx(handler, &entries))
+    }
+
+    /// Like `transaction`, but `tx` only runs if nothing has been numbered since change number
+    /// `from`, returning whether it ran. This lets one rely on reads made beforehand, as nothing
+    /// else is written between the check and the commit. Every one of `logged` is recorded in the
+    /// change log.
+    fn transaction_from(
+        &self,
+        from: u64,
+        logged: &[ChangeOp],
+        tx: impl Fn(TransactionHandler) -> Result<(), ConflictableTransactionError<ServerError>>,
+    ) -> Result<bool, ServerError> {
+        let _writes = self.write_gate.write().unwrap();
+        if self.sequencer.next() != from {
+            return Ok(false);
+        }
+        self.commit(logged, tx).map(|()| true)
+    }
+
+    /// Run `tx` as a transaction, recording every one of `logged` in the change log if it
+    /// commits. The caller holds the write gate.
+    fn commit(
+        &self,
+        logged: &[ChangeOp],
+        tx: impl Fn(TransactionHandler) -> Result<(), ConflictableTransactionError<ServerError>>,
+    ) -> Result<(), ServerError> {
+        if self.read_only {
+            return Err(ServerError::ReadOnly);
+        }
+        if let Some(Maintenance { reason }) = self.maintenance() {
+            return Err(ServerError::Maintenance(reason));
+        }
+        let _span = tracing::trace_span!("transaction").entered();
+        // Held until the transaction has committed or aborted. Dry runs log nothing, so they
+        // aren't numbered.
+        let numbered = (self.sequencer).number(if self.dry_run { &[] } else { logged })?;
+        self.pending_transactions.fetch_add(1, Ordering::Relaxed);
+        let now = now_millis();
+        let written = RefCell::new(Vec::new());
+        let result = dry_run_result(
+            (
+                &self.store,
+                &self.changes,
+                &self.meta,
+                &self.search,
+                &self.blobs,
+            )
+                .transaction(|(tx_db, tx_changes, tx_meta, tx_search, tx_blobs)| {
+                    written.borrow_mut().clear();
+                    let handler = TransactionHandler {
+                        store: tx_db,
+                        meta: Some(tx_meta),
+                        search: Some(tx_search),
+                        blobs: Some(tx_blobs),
+                        chunk_size: self.chunk_size,
+                        written: &written,
+                        now,
+                        schema: &self.schema(),
+                        codecs: &self.codecs,
+                        ephemeral: false,
+                    };
+                    tx(handler).map_err(optional_abort)?;
+                    if self.dry_run {
+                        return abort(None);
+                    }
+                    for (&seq, change) in numbered.seqs().iter().zip(logged) {
+                        changes::write(tx_changes, seq, change)?;
+                    }
+                    if let Some(next) = self.replicated {
+                        changes::write_replicated(tx_changes, next)?;
+                    }
+                    Ok(true)
+                }),
+        );
+        self.pending_transactions.fetch_sub(1, Ordering::Relaxed);
+        drop(numbered);
+        if let (Ok(true), Some(history)) = (&result, &self.history) {
+            self.record_history(history, written.into_inner(), now);
+        }
+        if let (Ok(true), true) = (&result, self.sync_writes) {
+            self.db.flush()?;
+        }
+        result.map(|_| ())
+    }
+
+    /// Add a revision of each document a committed write touched, as it is now
+    fn record_history(&self, history: &History, written: Vec<Ref>, at: u64) {
+        let mut recorded = HashSet::new();
+        for key in written {
+            if !recorded.insert(key.clone()) {
+                continue;
+            }
+            let value = match self.get_item(&key) {
+                Ok(value) => Ok(value),
+                // Removed documents are recorded as null
+                Err(ServerError::KeyNotFound(_)) => Ok(Value::Null),
+                Err(e) => Err(e),
+            };
+            let result = value.and_then(|value| {
+                Ok(history.record(&self.schema().encode_ref(&key.0), at, value)?)
+            });
+            if let Err(e) = result {
+                tracing::error!(path = %key, "could not record a revision: {e}");
+            }
+        }
+    }
+
+    /// Run `tx` against the ephemeral items in memory. It's neither logged nor replicated, so
+    /// followers can take it too.
+    fn memory_transaction(
+        &self,
+        tx: impl FnOnce(
+            TransactionHandler,
+            &MemoryTransaction,
+        ) -> Result<(), ConflictableTransactionError<ServerError>>,
+    ) -> Result<(), ServerError> {
+        let result = self.memory.transaction(
+            |memory| {
+                let handler = TransactionHandler {
+                    store: memory,
+                    meta: None,
+                    search: None,
+                    blobs: None,
+                    chunk_size: self.chunk_size,
+                    written: &RefCell::default(),
+                    now: now_millis(),
+                    schema: &self.schema(),
+                    codecs: &self.codecs,
+                    ephemeral: true,
+                };
+                tx(handler, memory).map_err(optional_abort)?;
+                if self.dry_run {
+                    return abort(None);
+                }
+                Ok(())
+            },
+            |event| self.dispatcher.publish(event),
+        );
+        match result {
+            Ok(()) | Err(ConflictableTransactionError::Abort(None)) => Ok(()),
+            Err(ConflictableTransactionError::Abort(Some(e))) => Err(e),
+            Err(_) => unreachable!("memory transactions only fail by aborting"),
+        }
+    }
+
+    /// Drop the ephemeral items at or under `key`, once whatever contained them is removed
+    fn forget(&self, key: &Ref) {
+        if self.dry_run {
+            return;
+        }
+        self.memory
+            .remove_prefix(&self.schema().encode_ref(&key.0), |event| {
+                self.dispatcher.publish(event)
+            });
+    }
+}
+
+/// When a document was created and last written, in milliseconds since the unix epoch by the
+/// clock of the server that wrote it. Both are set in the same transaction as the write, so
+/// clients never have to trust each other's clocks. A follower keeps its own, from when it
+/// applied each change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Metadata {
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Metadata {
+    fn encode(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.created_at.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.updated_at.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Metadata {
+        let (created_at, updated_at) = bytes.split_at(8);
+        Metadata {
+            created_at: u64::from_be_bytes(created_at.try_into().expect("metadata is 16 bytes")),
+            updated_at: u64::from_be_bytes(updated_at.try_into().expect("metadata is 16 bytes")),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Whether an update to `value` clears anything, by setting an optional item to null
+fn clears(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(items) => items.iter().any(clears),
+        Value::Object(entries) => entries.values().any(clears),
+        _ => false,
+    }
+}
+
+/// What `Server::assemble` does with a scalar that has nothing stored in it
+#[derive(Clone, Copy)]
+enum Missing {
+    Error,
+    Null,
+}
+
+/// The keys of the members of the collection at `key`, among entries read by `Server::scan`.
+/// Each member is stored under its own key, directly beneath the collection's.
+fn collection_members(schema: &Schema, key: &Ref, entries: &BTreeMap<IVec, IVec>) -> Vec<String> {
+    let collection = schema.encode_ref(&key.0);
+    entries
+        .range::<[u8], _>((Bound::Included(&collection[..]), Bound::Unbounded))
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(&collection))
+        .filter_map(|key| schema.decode_member(&collection, key))
+        .collect()
+}
+
+/// The outcome of `Server::migrate_legacy`
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// How many legacy keys were converted
+    pub migrated: usize,
+    /// Legacy keys or subtrees that couldn't be converted, and why
+    pub rejected: Vec<(String, String)>,
+}
+
+/// A key laid out wrongly in the store, found by `Server::check_integrity`
+#[derive(Debug, PartialEq)]
+pub enum IntegrityProblem {
+    /// A key that isn't an encoded ref
+    Undecodable(Vec<u8>),
+    /// An item whose document or collection isn't stored, or is an orphan itself
+    Orphaned(Ref),
+    /// A collection that still lists its members in a bincoded set, as collections did before
+    /// `KEY_FORMAT` 3, along with the members it lists that aren't stored
+    StaleMembers {
+        collection: Ref,
+        missing: Vec<String>,
+    },
+}
+
+impl Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityProblem::Undecodable(key) => {
+                write!(f, "undecodable key {}", String::from_utf8_lossy(key))
+            }
+            IntegrityProblem::Orphaned(path) => {
+                write!(f, "orphaned item {path}, whose container isn't stored")
+            }
+            IntegrityProblem::StaleMembers {
+                collection,
+                missing,
+            } if missing.is_empty() => {
+                write!(f, "collection {collection} still lists its members")
+            }
+            IntegrityProblem::StaleMembers {
+                collection,
+                missing,
+            } => write!(
+                f,
+                "collection {collection} still lists its members, including ones that aren't stored: {}",
+                missing.join(", ")
+            ),
+        }
+    }
+}
+
+/// Place `value` at `path` inside a tree of JSON objects, returning false if something else is
+/// already in the way. A value at the tree's own root is stored under the empty key.
+fn insert_at_path(tree: &mut Value, path: &[String], value: Value) -> bool {
+    let Value::Object(obj) = tree else {
+        return false;
+    };
+    match path {
+        [] => obj.insert(String::new(), value).is_none(),
+        [last] => match obj.get(last) {
+            Some(_) => false,
+            None => {
+                obj.insert(last.clone(), value);
+                true
+            }
+        },
+        [first, rest @ ..] => insert_at_path(
+            obj.entry(first.clone())
+                .or_insert_with(|| Value::Object(Map::new())),
+            rest,
+            value,
+        ),
+    }
+}
+
+#[derive(Clone)]
+pub struct ScopedServer {
+    server: Server,
+    prefix: Ref,
+}
+
+impl ScopedServer {
+    pub fn prefix(&self) -> &Ref {
+        &self.prefix
+    }
+
+    pub fn scoped(&self, prefix: Ref) -> ScopedServer {
+        ScopedServer {
+            server: self.server.clone(),
+            prefix: self.absolute(&prefix),
+        }
+    }
+
+    pub fn get(&self, key: &Ref) -> Result<Value, ServerError> {
+        self.server.get(&self.absolute(key))
+    }
+
+    pub fn insert(&self, key: &Ref, val: Value) -> Result<(), ServerError> {
+        self.server.insert(&self.absolute(key), val)
+    }
+
+    pub fn update(&self, key: &Ref, val: Value) -> Result<(), ServerError> {
+        self.server.update(&self.absolute(key), val)
+    }
+
+    pub fn remove(&self, key: &Ref) -> Result<(), ServerError> {
+        self.server.remove(&self.absolute(key))
+    }
+
+    /// Subscribe to a key under the prefix; event keys are relative to the prefix
+    pub fn subscribe(&self, key: &Ref) -> SubscriptionStream {
+        let mut stream = self.server.subscribe(&self.absolute(key));
+        stream.prefix_len = self.prefix.0.len();
+        stream
+    }
+
+    fn absolute(&self, key: &Ref) -> Ref {
+        let mut absolute = self.prefix.clone();
+        absolute.0.extend(key.0.iter().cloned());
+        absolute
+    }
+}
+
+/// Where a `TransactionHandler` reads and writes: the store, in a sled transaction, or the
+/// ephemeral items in memory
+trait TxStore {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError>;
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), UnabortableTransactionError>;
+    fn remove(&self, key: &[u8]) -> Result<(), UnabortableTransactionError>;
+}
+
+impl TxStore for TransactionalTree {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError> {
+        TransactionalTree::get(self, key)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), UnabortableTransactionError> {
+        TransactionalTree::insert(self, key, value).map(|_| ())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), UnabortableTransactionError> {
+        TransactionalTree::remove(self, key).map(|_| ())
+    }
+}
+
+impl TxStore for MemoryTransaction<'_> {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>, UnabortableTransactionError> {
+        Ok(MemoryTransaction::get(self, key))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), UnabortableTransactionError> {
+        MemoryTransaction::insert(self, key, value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), UnabortableTransactionError> {
+        MemoryTransaction::remove(self, key);
+        Ok(())
+    }
+}
+
+struct TransactionHandler<'a> {
+    store: &'a dyn TxStore,
+    /// Where the metadata of documents written to `store` is kept, unless it's in memory
+    meta: Option<&'a TransactionalTree>,
+    /// Where the words in searchable items written to `store` are indexed, unless it's in memory
+    search: Option<&'a TransactionalTree>,
+    /// Where long scalars written to `store` are split into chunks, unless it's in memory
+    blobs: Option<&'a TransactionalTree>,
+    chunk_size: usize,
+    /// The documents written or removed so far, whose history is recorded once they commit
+    written: &'a RefCell<Vec<Ref>>,
+    /// The time of the write, in milliseconds since the unix epoch
+    now: u64,
+    schema: &'a Schema,
+    codecs: &'a Codecs,
+    /// Whether this writes the ephemeral items in memory. Those in the store only write what
+    /// isn't ephemeral.
+    ephemeral: bool,
+}
+
+impl TransactionHandler<'_> {
+    fn tx_insert(
+        &self,
+        key: &Ref,
+        schema: &SchemaItem,
+        val: &Value,
+    ) -> Result<(), ConflictableTransactionError<ServerError>> {
+        // Keys are checked before anything is written beneath them
+        self.tx_check_key(key)?;
+
+        // TODO: transactional
+        match schema {
+            SchemaItem::Collection(collection) => {
+                let Value::Object(obj) = val else {
+                    return abort(ServerError::SchemaMismatch(key.clone()));
+                };
+                let encoded_ref = self.schema.encode_ref(&key.0);
+                self.store.insert(&encoded_ref[..], &[1])?;
+                for (primary_key, value) in obj {
+                    let mut sub_key = key.clone();
+                    sub_key.0.push(primary_key.clone());
+                    self.tx_insert(&sub_key, &collection.items, value)?;
+                }
+            }
+            SchemaItem::Document(fields) => {
+                // TODO: optimize # of loops
+                let Value::Object(obj) = val else {
+                    return abort(ServerError::SchemaMismatch(key.clone()));
+                };
+                for entry in obj.keys() {
+                    if !fields.contains_key(entry) {
+                        return abort(ServerError::ExtraKeyFound(key.child(entry)));
+                    }
+                }
+                for (field, schema) in fields {
+                    let optional = matches!(
+                        schema,
+                        SchemaItem::Presence | SchemaItem::Ephemeral(_) | SchemaItem::Optional(_)
+                    );
+                    if !obj.contains_key(field) && !optional {
+                        return abort(ServerError::KeyNotFound(key.child(field)));
+                    }
+                }
+                let encoded_ref = self.schema.encode_ref(&key.0);
+                self.store.insert(&encoded_ref[..], &[1])?;
+                self.tx_touch(key)?;
+                for (obj_key, obj_value) in obj {
+                    let field = &fields[obj_key];
+                    let mut sub_key = key.clone();
+                    sub_key.0.push(obj_key.clone());
+                    self.tx_insert(&sub_key, field, obj_value)?;
+                }
+            }
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_) => {
+                let encoded = match encode_scalar(self.schema, self.codecs, key, schema, val) {
+                    Ok(encoded) => encoded,
+                    Err(e) => return abort(e),
+                };
+                self.tx_reindex(key, schema, Some(val))?;
+                self.tx_write_scalar(&self.schema.encode_ref(&key.0), &encoded)?;
+            }
+            SchemaItem::Presence => return abort(ServerError::PresenceWrite(key.clone())),
+            SchemaItem::Sensitive(inner) => return self.tx_insert(key, inner, val),
+            SchemaItem::Ephemeral(inner) if self.ephemeral => {
+                return self.tx_insert(key, inner, val)
+            }
+            SchemaItem::Ephemeral(_) => return abort(ServerError::EphemeralWrite(key.clone())),
+            // Null leaves an optional item out, as if it hadn't been given
+            SchemaItem::Optional(_) if val.is_null() => return Ok(()),
+            SchemaItem::Optional(inner) => return self.tx_insert(key, inner, val),
+            SchemaItem::Searchable(inner) => return self.tx_insert(key, inner, val),
+        }
+
+        self.tx_add_to_parent(key)
+    }
+
+    /// Like `tx_insert`, but for snapshots taken by `Server::dump`, in which any item may be
+    /// null because nothing was stored there
+    fn tx_restore(
+        &self,
+        key: &Ref,
+        schema: &SchemaItem,
+        val: &Value,
+    ) -> Result<(), ConflictableTransactionError<ServerError>> {
+        match (schema, val) {
+            // Dumps never hold ephemeral items, and presence in one belonged to connections that
+            // are gone
+            (_, Value::Null) | (SchemaItem::Presence | SchemaItem::Ephemeral(_), _) => Ok(()),
+            (
+                SchemaItem::Sensitive(inner)
+                | SchemaItem::Optional(inner)
+                | SchemaItem::Searchable(inner),
+                _,
+            ) => self.tx_restore(key, inner, val),
+            (SchemaItem::Collection(collection), Value::Object(members)) => {
+                self.tx_check_key(key)?;
+                self.store
+                    .insert(&self.schema.encode_ref(&key.0)[..], &[1])?;
+                self.tx_add_to_parent(key)?;
+                for (member, value) in members {
+                    self.tx_restore(&key.child(member), &collection.items, value)?;
+                }
+                Ok(())
+            }
+            (SchemaItem::Document(fields), Value::Object(entries)) => {
+                self.tx_check_key(key)?;
+                self.store
+                    .insert(&self.schema.encode_ref(&key.0)[..], &[1])?;
+                self.tx_touch(key)?;
+                self.tx_add_to_parent(key)?;
+                for (entry, value) in entries {
+                    let Some(field) = fields.get(entry) else {
+                        return abort(ServerError::ExtraKeyFound(key.child(entry)));
+                    };
+                    self.tx_restore(&key.child(entry), field, value)?;
+                }
+                Ok(())
+            }
+            (SchemaItem::Collection(_) | SchemaItem::Document(_), _) => {
+                abort(ServerError::SchemaMismatch(key.clone()))
+            }
+            (
+                SchemaItem::Scalar
+                | SchemaItem::Custom(_)
+                | SchemaItem::Reference
+                | SchemaItem::ReferenceTo(_)
+                | SchemaItem::Enum(_),
+                _,
+            ) => self.tx_insert(key, schema, val),
+        }
+    }
+
+    /// Reject a key that its parent collection doesn't allow
+    fn tx_check_key(&self, key: &Ref) -> Result<(), ConflictableTransactionError<ServerError>> {
+        if let Some((child, parent)) = key.0.split_last() {
+            if let Ok(SchemaItem::Collection(collection)) = self.schema.resolve(parent) {
+                if !collection.allows_key(child) {
+                    return abort(ServerError::InvalidKey {
+                        path: key.clone(),
+                        expected: collection.keys.clone().unwrap(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that the document at `key` was written, keeping when it was created if it already
+    /// had been
+    fn tx_touch(&self, key: &Ref) -> Result<(), ConflictableTransactionError<ServerError>> {
+        let Some(meta) = self.meta else {
+            return Ok(());
+        };
+        let encoded_ref = self.schema.encode_ref(&key.0);
+        let created_at = match meta.get(&encoded_ref)? {
+            Some(existing) => Metadata::decode(&existing).created_at,
+            None => self.now,
+        };
+        let metadata = Metadata {
+            created_at,
+            updated_at: self.now,
+        };
+        meta.insert(&encoded_ref[..], &metadata.encode())?;
+        self.written.borrow_mut().push(key.clone());
+        Ok(())
+    }
+
+    /// Record that the document containing the item at `key` was written, if there is one
+    fn tx_touch_container(
+        &self,
+        key: &Ref,
+    ) -> Result<(), ConflictableTransactionError<ServerError>> {
+        for len in (0..key.0.len()).rev() {
+            if let Ok(SchemaItem::Document(_)) = self.schema.resolve(&key.0[..len]) {
+                return self.tx_touch(&Ref(key.0[..len].to_vec()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the search index up to date with the scalar at `key` becoming `new`, or being
+    /// removed if it's None. Call before writing it, as the terms to drop are read from what's
+    /// stored there now.
+    fn tx_reindex(
+        &self,
+        key: &Ref,
+        schema: &SchemaItem,
+        new: Option<&Value>,
+    ) -> Result<(), ConflictableTransactionError<ServerError>> {
+        let Some(index) = self.search else {
+            return Ok(());
+        };
+        if !self.schema.is_searchable(&key.0) {
+            return Ok(());
+        }
+        let encoded_ref = self.schema.encode_ref(&key.0);
+        let old = match self.tx_read_scalar(&encoded_ref)? {
+            Some(old) => match decode_scalar(self.schema, self.codecs, key, schema, &old) {
+                Ok(old) => search::terms(&old),
+                Err(e) => return abort(e),
+            },
+            None => BTreeSet::new(),
+        };
+        let new = new.map(search::terms).unwrap_or_default();
+        for term in old.difference(&new) {
+            index.remove(search::index_key(term, &encoded_ref))?;
+        }
+        for term in new.difference(&old) {
+            index.insert(search::index_key(term, &encoded_ref), &[])?;
+        }
+        Ok(())
+    }
+
+    /// The scalar stored at `encoded_ref`, put back together if it was split into chunks
+    fn tx_read_scalar(
+        &self,
+        encoded_ref: &[u8],
+    ) -> Result<Option<IVec>, UnabortableTransactionError> {
+        match (self.store.get(encoded_ref)?, self.blobs) {
+            (Some(stored), Some(blobs)) => Ok(Some(blob::tx_join(blobs, stored)?)),
+            (stored, _) => Ok(stored),
+        }
+    }
+
+    /// Store an encoded scalar at `encoded_ref`, splitting it into chunks if it's long and
+    /// dropping the chunks of whatever it replaces
+    fn tx_write_scalar(
+        &self,
+        encoded_ref: &[u8],
+        encoded: &[u8],
+    ) -> Result<(), UnabortableTransactionError> {
+        let Some(blobs) = self.blobs else {
+            return self.store.insert(encoded_ref, encoded);
+        };
+        if let Some(old) = self.store.get(encoded_ref)? {
+            blob::tx_drop(blobs, &old)?;
+        }
+        let stored = blob::tx_split(blobs, encoded, self.chunk_size)?;
+        self.store.insert(encoded_ref, &stored)
+    }
+
+    fn tx_remove_scalar(&self, encoded_ref: &[u8]) -> Result<(), UnabortableTransactionError> {
+        if let (Some(old), Some(blobs)) = (self.store.get(encoded_ref)?, self.blobs) {
+            blob::tx_drop(blobs, &old)?;
+        }
+        self.store.remove(encoded_ref)
+    }
+
+    /// Reject writing an item into a document that isn't there
+    fn tx_check_parent(&self, key: &Ref) -> Result<(), ConflictableTransactionError<ServerError>> {
+        let Some((_, parent)) = key.0.split_last() else {
+            return Ok(());
+        };
+        let parent = Ref(parent.to_vec());
+        if let Ok(SchemaItem::Document(_)) = self.schema.resolve(&parent.0) {
+            if self
+                .store
+                .get(&self.schema.encode_ref(&parent.0))?
+                .is_none()
+            {
+                return abort(ServerError::KeyNotFound(parent));
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the parent of a newly written item as present, if it's a collection that the item
+    /// was written into directly. The item's own key is what records it as a member.
+    fn tx_add_to_parent(&self, key: &Ref) -> Result<(), ConflictableTransactionError<ServerError>> {
+        let Some((_, parent_ref)) = key.0.split_last() else {
+            return Ok(());
+        };
+        let parent_schema = match resolve(self.schema, &Ref(parent_ref.to_vec())) {
+            Ok(schema) => schema,
+            Err(err) => return abort(err),
+        };
+        if let SchemaItem::Collection(_) = parent_schema {
+            let encoded_collection_key = self.schema.encode_ref(parent_ref);
+            self.store.insert(&encoded_collection_key[..], &[1])?;
+        }
+        Ok(())
+    }
+
+    /// Update the item at `key`, removing any optional items set to null; the members of their
+    /// collections are found among `entries`, which `Server::scan` read from beneath it
+    fn tx_update(
+        &self,
+        key: &Ref,
+        schema: &SchemaItem,
+        val: &Value,
+        entries: &BTreeMap<IVec, IVec>,
+    ) -> Result<(), ConflictableTransactionError<ServerError>> {
+        if self.schema.is_optional(&key.0) {
+            if val.is_null() {
+                self.tx_touch_container(key)?;
+                return self.tx_remove(key, schema, entries);
+            }
+            // An optional item that isn't set yet is written whole, as if it were inserted
+            if self.store.get(&self.schema.encode_ref(&key.0))?.is_none() {
+                self.tx_check_parent(key)?;
+                self.tx_touch_container(key)?;
+                return self.tx_insert(key, schema, val);
+            }
+        }
+        match schema {
+            SchemaItem::Collection(collection) => {
+                let Value::Object(obj) = val else {
+                    return abort(ServerError::SchemaMismatch(key.clone()));
+                };
+                let encoded_ref = self.schema.encode_ref(&key.0);
+                if self.store.get(&encoded_ref)?.is_none() {
+                    return abort(ServerError::KeyNotFound(key.clone()));
+                }
+                for (primary_key, value) in obj {
+                    let mut sub_key = key.clone();
+                    sub_key.0.push(primary_key.clone());
+                    self.tx_update(&sub_key, &collection.items, value, entries)?;
+                }
+            }
+            SchemaItem::Document(fields) => {
+                let Value::Object(obj) = val else {
+                    return abort(ServerError::SchemaMismatch(key.clone()));
+                };
+                let encoded_ref = self.schema.encode_ref(&key.0);
+                if self.store.get(&encoded_ref)?.is_none() {
+                    return abort(ServerError::KeyNotFound(key.clone()));
+                }
+                for (obj_key, obj_value) in obj {
+                    let Some(field) = fields.get(obj_key) else {
+                        return abort(ServerError::ExtraKeyFound(key.child(obj_key)));
+                    };
+                    let mut sub_key = key.clone();
+                    sub_key.0.push(obj_key.clone());
+                    self.tx_update(&sub_key, field, obj_value, entries)?;
+                }
+            }
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_) => {
+                let encoded = match encode_scalar(self.schema, self.codecs, key, schema, val) {
+                    Ok(encoded) => encoded,
+                    Err(e) => return abort(e),
+                };
+                let encoded_ref = self.schema.encode_ref(&key.0);
+                if self.store.get(&encoded_ref)?.is_none() {
+                    return abort(ServerError::KeyNotFound(key.clone()));
+                }
+                self.tx_reindex(key, schema, Some(val))?;
+                self.tx_write_scalar(&encoded_ref, &encoded)?;
+                self.tx_touch_container(key)?;
+            }
+            SchemaItem::Presence => return abort(ServerError::PresenceWrite(key.clone())),
+            SchemaItem::Sensitive(inner) => self.tx_update(key, inner, val, entries)?,
+            SchemaItem::Ephemeral(inner) if self.ephemeral => {
+                self.tx_update(key, inner, val, entries)?
+            }
+            SchemaItem::Ephemeral(_) => return abort(ServerError::EphemeralWrite(key.clone())),
+            SchemaItem::Optional(inner) | SchemaItem::Searchable(inner) => {
+                self.tx_update(key, inner, val, entries)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the item at `key`, finding the members of collections among `entries`, which
+    /// `Server::scan` read from beneath it
+    fn tx_remove(
+        &self,
+        key: &Ref,
+        schema: &SchemaItem,
+        entries: &BTreeMap<IVec, IVec>,
+    ) -> Result<(), ConflictableTransactionError<ServerError>> {
+        match schema {
+            SchemaItem::Collection(collection) => {
+                let encoded_ref = self.schema.encode_ref(&key.0);
+                if self.store.get(&encoded_ref)?.is_none() {
+                    return abort(ServerError::KeyNotFound(key.clone()));
+                }
+                for child in collection_members(self.schema, key, entries) {
+                    self.tx_remove(&key.child(&child), &collection.items, entries)?;
+                }
+                self.store.remove(&encoded_ref[..])?;
+            }
+            SchemaItem::Document(fields) => {
+                let encoded_ref = self.schema.encode_ref(&key.0);
+                self.store.remove(&encoded_ref[..])?;
+                if let Some(meta) = self.meta {
+                    meta.remove(&encoded_ref[..])?;
+                    self.written.borrow_mut().push(key.clone());
+                }
+                for (field, ty) in fields {
+                    let mut sub_key = key.clone();
+                    sub_key.0.push(field.clone());
+                    self.tx_remove(&sub_key, ty, entries)?;
+                }
+            }
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_) => {
+                self.tx_reindex(key, schema, None)?;
+                self.tx_remove_scalar(&self.schema.encode_ref(&key.0))?;
+            }
+            // Members stay until their connections leave, whatever contains them
+            SchemaItem::Presence => {}
+            SchemaItem::Sensitive(inner) => return self.tx_remove(key, inner, entries),
+            SchemaItem::Ephemeral(inner) if self.ephemeral => {
+                return self.tx_remove(key, inner, entries)
+            }
+            // `Server::remove` forgets these once what contains them is gone
+            SchemaItem::Ephemeral(_) => {}
+            SchemaItem::Optional(inner) | SchemaItem::Searchable(inner) => {
+                return self.tx_remove(key, inner, entries)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The items stored at the top of a tree with the schema `root`: each field of a root document,
+/// or else the root itself
+fn top_level(root: &SchemaItem) -> Vec<(Ref, &SchemaItem)> {
+    match root {
+        SchemaItem::Document(fields) => (fields.iter())
+            .map(|(field, item)| (Ref(vec![field.clone()]), item))
+            .collect(),
+        item => vec![(Ref(Vec::new()), item)],
+    }
+}
+
+/// The layout of keys written by `Schema::encode_ref` and of collection membership, recorded for
+/// each tree of data so trees in an older layout are upgraded when they're opened. Format 2
+/// encoded refs compactly, and format 3 gave each collection member its own key rather than
+/// listing them all in a bincoded set stored under the collection. Format 4 numbered the fields
+/// inside optional and searchable items too, which format 3 stored as strings.
+const KEY_FORMAT: u8 = 4;
+
+/// Number the schema's fields for encoding refs, and bring `store` up to date with that encoding
+fn prepare_schema(db: &Db, store: &Tree, mut schema: Schema) -> Result<Schema, ServerError> {
+    schema.set_field_ids(field_ids(db, &schema, true)?);
+    upgrade_keys(db, store, &schema)?;
+    Ok(schema)
+}
+
+/// The number of every field that any schema in the database has ever had, numbering any of
+/// `schema`'s fields that are new, and recording their numbers if `record` is set. Numbers are
+/// never reused, so data stored under a field removed from the schema can still be read back.
+fn field_ids(db: &Db, schema: &Schema, record: bool) -> Result<HashMap<String, u64>, ServerError> {
+    let fields = db.open_tree("system/fields")?;
+    let mut ids = HashMap::new();
+    for entry in fields.iter() {
+        let (name, id) = entry?;
+        ids.insert(
+            String::from_utf8(name.to_vec()).expect("field names are UTF-8"),
+            u64::from_be_bytes(id.as_ref().try_into().expect("field numbers are 8 bytes")),
+        );
+    }
+    let next = ids.values().max().map_or(0, |max| max + 1);
+    let mut new_names: Vec<String> = schema
+        .field_names()
+        .into_iter()
+        .filter(|name| !ids.contains_key(name))
+        .collect();
+    new_names.sort_unstable();
+    for (id, name) in (next..).zip(new_names) {
+        if record {
+            fields.insert(name.as_bytes(), &id.to_be_bytes())?;
+        }
+        ids.insert(name, id);
+    }
+    Ok(ids)
+}
+
+/// Bring a tree written in an older `KEY_FORMAT` up to date. Keys that weren't encoded refs at
+/// all, like those of the legacy server, are left for `migrate_legacy`.
+fn upgrade_keys(db: &Db, store: &Tree, schema: &Schema) -> Result<(), ServerError> {
+    let formats = db.open_tree("system/key-formats")?;
+    // Trees from before formats were recorded have length-prefixed keys
+    let format = formats.get(store.name())?.map_or(1, |format| format[0]);
+    if format >= KEY_FORMAT {
+        return Ok(());
+    }
+    let mut removed = Vec::new();
+    let mut written = BTreeMap::new();
+    let mut members = Vec::new();
+    for entry in store.iter() {
+        let (key, mut value) = entry?;
+        let mut changed = format < 2;
+        let refs = if format < 2 {
+            let Some(refs) = decode_length_prefixed_ref(&key) else {
+                continue;
+            };
+            refs
+        } else {
+            let Some(refs) = schema.try_decode_ref(&key) else {
+                continue;
+            };
+            refs
+        };
+        if format < 3 {
+            let key = Ref(refs.clone());
+            if let Ok(SchemaItem::Collection(_)) = resolve(schema, &key) {
+                let listed: HashSet<String> =
+                    bincode::deserialize(&value).expect("collections are encoded via bincode");
+                members.extend(
+                    listed
+                        .iter()
+                        .map(|member| schema.encode_ref(&key.child(member).0)),
+                );
+                value = IVec::from(&[1]);
+                changed = true;
+            }
+        }
+        let encoded = schema.encode_ref(&refs);
+        if encoded[..] != key[..] {
+            removed.push(key);
+            changed = true;
+        }
+        if changed {
+            written.insert(encoded, value);
+        }
+    }
+    // Empty collections were only listed in their parents, and need keys of their own now
+    for member in members {
+        if !written.contains_key(&member) && (format < 2 || !store.contains_key(&member)?) {
+            written.insert(member, IVec::from(&[1]));
+        }
+    }
+    // All the old keys are removed before any new ones are written, so a new key can't be
+    // clobbered by an old one that happens to have the same bytes
+    tx_result((store, &formats).transaction(|(tx_store, tx_formats)| {
+        for old in &removed {
+            tx_store.remove(old)?;
+        }
+        for (new, value) in &written {
+            tx_store.insert(&new[..], value)?;
+        }
+        tx_formats.insert(store.name(), &[KEY_FORMAT])?;
+        Ok(())
+    }))?;
+    if !written.is_empty() {
+        tracing::info!(
+            tree = %String::from_utf8_lossy(&store.name()),
+            from = format,
+            keys = written.len(),
+            "upgraded keys to the current layout"
+        );
+    }
+    Ok(())
+}
+
+/// Decode a key in the original layout, where each component was its length as 8 little-endian
+/// bytes followed by its UTF-8 bytes
+fn decode_length_prefixed_ref(mut key: &[u8]) -> Option<Vec<String>> {
+    let mut decoded = Vec::new();
+    while !key.is_empty() {
+        let len = u64::from_le_bytes(key.get(..8)?.try_into().unwrap());
+        let bytes = key.get(8..8usize.checked_add(usize::try_from(len).ok()?)?)?;
+        decoded.push(String::from_utf8(bytes.to_vec()).ok()?);
+        key = &key[8 + bytes.len()..];
+    }
+    Some(decoded)
+}
+
+fn tx_result<T>(result: TransactionResult<T, ServerError>) -> Result<T, ServerError> {
+    match result {
+        Ok(val) => Ok(val),
+        Err(TransactionError::Abort(e)) => Err(e),
+        Err(TransactionError::Storage(e)) => Err(e.into()),
+    }
+}
+
+/// A transaction's error, in the form taken by a transaction that can also abort without one
+fn optional_abort(
+    e: ConflictableTransactionError<ServerError>,
+) -> ConflictableTransactionError<Option<ServerError>> {
+    match e {
+        ConflictableTransactionError::Abort(e) => ConflictableTransactionError::Abort(Some(e)),
+        ConflictableTransactionError::Storage(e) => ConflictableTransactionError::Storage(e),
+        ConflictableTransactionError::Conflict => ConflictableTransactionError::Conflict,
+    }
+}
+
+/// Like `tx_result`, for a transaction that aborts without an error once a dry run has checked
+/// it, which is reported as though it committed
+fn dry_run_result(
+    result: TransactionResult<bool, Option<ServerError>>,
+) -> Result<bool, ServerError> {
+    match result {
+        Ok(val) => Ok(val),
+        Err(TransactionError::Abort(None)) => Ok(true),
+        Err(TransactionError::Abort(Some(e))) => Err(e),
+        Err(TransactionError::Storage(e)) => Err(e.into()),
+    }
+}
+
+/// The result of `Server::get_chunked`
+pub enum Chunked {
+    /// A scalar, or a document with nothing stored in it, which is never split
+    Whole(Value),
+    Pieces(ValueChunks),
+}
+
+/// The pieces of a collection or document read by `Server::get_chunked`. Each is an object
+/// holding some of its members or fields, and merging them all gives the whole value. There's
+/// always at least one, even if it's empty.
+pub struct ValueChunks {
+    members: Box<dyn Iterator<Item = Result<(String, Value), ServerError>> + Send>,
+    chunk_size: usize,
+    started: bool,
+}
+
+impl Iterator for ValueChunks {
+    type Item = Result<Map<String, Value>, ServerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = Map::new();
+        let mut size = 0;
+        while size < self.chunk_size {
+            match self.members.next() {
+                Some(Ok((member, value))) => {
+                    size += member.len() + value.to_string().len();
+                    chunk.insert(member, value);
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None => break,
+            }
+        }
+        if chunk.is_empty() && self.started {
+            return None;
+        }
+        self.started = true;
+        Some(Ok(chunk))
+    }
+}
+
+/// The members of a collection, each assembled from the entries beneath it as a scan of the
+/// collection reaches them
+struct CollectionMembers {
+    server: Server,
+    key: Ref,
+    prefix: Vec<u8>,
+    iter: sled::Iter,
+    /// The first entry of the next member, read while looking for the end of the last one
+    next: Option<(IVec, IVec)>,
+}
+
+impl CollectionMembers {
+    fn next_member(&mut self) -> Result<Option<(String, Value)>, ServerError> {
+        let mut member: Option<(String, Vec<u8>)> = None;
+        let mut entries = BTreeMap::new();
+        loop {
+            let entry = match self.next.take() {
+                Some(entry) => entry,
+                None => match self.iter.next().transpose()? {
+                    Some(entry) => entry,
+                    None => break,
+                },
+            };
+            let Some((key, prefix)) = self
+                .server
+                .schema()
+                .decode_member_prefix(&self.prefix, &entry.0)
+            else {
+                // The collection's own key
+                continue;
+            };
+            match &member {
+                None => member = Some((key, prefix.to_vec())),
+                Some((_, current)) if entry.0.starts_with(current) => {}
+                Some(_) => {
+                    self.next = Some(entry);
+                    break;
+                }
+            }
+            entries.insert(entry.0, entry.1);
+        }
+        let Some((member, _)) = member else {
+            return Ok(None);
+        };
+        let schema = self.server.schema();
+        let SchemaItem::Collection(collection) = resolve(&schema, &self.key)? else {
+            unreachable!("members are only read from collections");
+        };
+        let value = self.server.assemble(
+            &self.key.child(&member),
+            &collection.items,
+            &entries,
+            Missing::Error,
+        )?;
+        Ok(Some((member, value)))
+    }
+}
+
+impl Iterator for CollectionMembers {
+    type Item = Result<(String, Value), ServerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_member().transpose()
+    }
+}
+
+pub struct SubscriptionStream {
+    sub: Subscription,
+    schema: SharedSchema,
+    /// Where the chunks of split values are read back from, as events only carry the manifest
+    store: Tree,
+    blobs: Tree,
+    // Number of leading components to strip from event keys, for scoped subscriptions
+    prefix_len: usize,
+    /// Keys the server said it was about to remove, whose removal is reported as an expiry
+    expiring: HashSet<IVec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A new complete (key, value) pair
+    Insert {
+        /// The key that has been set
+        key: Ref,
+        /// The value that has been set
+        value: IVec,
+    },
+    /// A deleted key
+    Remove {
+        /// The key that has been removed
+        key: Ref,
+    },
+    /// A key the server removed itself, rather than a client, like an orphan cleaned up by
+    /// `Server::collect_orphans`
+    Expire {
+        /// The key that has been removed
+        key: Ref,
+    },
+}
+
+impl Stream for SubscriptionStream {
+    type Item = Event;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let prefix_len = self.prefix_len;
+        let relative = |mut key: Vec<String>| {
+            key.drain(..prefix_len.min(key.len()));
+            Ref(key)
+        };
+        loop {
+            let Some(evt) = std::task::ready!(self.sub.poll_next_unpin(cx)) else {
+                return std::task::Poll::Ready(None);
+            };
+            let event = match evt {
+                Delivery::Expiring(key) => {
+                    self.expiring.insert(key);
+                    continue;
+                }
+                Delivery::Event(sled::Event::Insert { key, value }) => {
+                    // If it was about to be removed, it wasn't after all
+                    self.expiring.remove(&key);
+                    let path = self.schema.current().decode_ref(&key);
+                    // Ephemeral items are never split, whatever they start with
+                    if !blob::is_manifest(&value) || self.schema.current().is_ephemeral(&path) {
+                        Event::Insert {
+                            key: relative(path),
+                            value,
+                        }
+                    } else {
+                        match unsplit(&self.store, &self.blobs, &key, value) {
+                            Ok(Some(value)) => Event::Insert {
+                                key: relative(path),
+                                value,
+                            },
+                            // A later event says what became of it
+                            Ok(None) => continue,
+                            Err(e) => {
+                                tracing::error!("could not read a split value: {e}");
+                                continue;
+                            }
+                        }
+                    }
+                }
+                Delivery::Event(sled::Event::Remove { key }) => {
+                    let path = relative(self.schema.current().decode_ref(&key));
+                    if self.expiring.remove(&key) {
+                        Event::Expire { key: path }
+                    } else {
+                        Event::Remove { key: path }
+                    }
+                }
+            };
+            return std::task::Poll::Ready(Some(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use serde_json::{json, Map, Value};
+    use sled::Config;
+
+    use crate::{
+        changes::{Change, ChangeOp},
+        codec::{CodecError, ScalarCodec, SerdeCodec},
+        error::ErrorKind,
+        message::{Aggregation, Ref},
+        schema::{CollectionSchema, KeyFormat, Schema, SchemaItem},
+        server::Event,
+    };
+
+    use super::{Chunked, IntegrityProblem, Server, ServerError};
+
+    #[test]
+    fn values() {
+        let server = document_server();
+        server
+            .insert(
+                &create_ref(&["hello"]),
+                map(&[("world", "value"), ("new york", "value 2")]),
+            )
+            .unwrap();
+        assert_eq!(
+            server
+                .get(&create_ref(&["hello", "world"]))
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "value"
+        );
+    }
+
+    #[test]
+    fn describing_schemas() {
+        let server = document_server();
+        let all = &mut |targets: &[Ref]| vec![true; targets.len()];
+        assert_eq!(
+            server.describe(&create_ref(&[]), all).unwrap(),
+            json!({ "Document": { "hello": { "Document": { "world": "Scalar", "new york": "Scalar" } } } })
+        );
+        assert_eq!(
+            server
+                .describe(&create_ref(&["hello", "world"]), all)
+                .unwrap(),
+            json!("Scalar")
+        );
+
+        let hidden = create_ref(&["hello", "new york"]);
+        let mut readable =
+            |targets: &[Ref]| targets.iter().map(|target| *target != hidden).collect();
+        assert_eq!(
+            server
+                .describe(&create_ref(&["hello"]), &mut readable)
+                .unwrap(),
+            json!({ "Document": { "world": "Scalar" } })
+        );
+
+        let err = server.describe(&create_ref(&["goodbye"]), all).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidPath);
+    }
+
+    #[test]
+    fn consistent_reads() {
+        let server = document_server();
+        let hello = create_ref(&["hello"]);
+        let fields = [
+            create_ref(&["hello", "world"]),
+            create_ref(&["hello", "new york"]),
+        ];
+        server
+            .insert(&hello, map(&[("world", "0"), ("new york", "0")]))
+            .unwrap();
+
+        // Both fields are always written together, so no read may see them differ
+        let writer = std::thread::spawn({
+            let server = server.clone();
+            let hello = hello.clone();
+            move || {
+                for i in 1..200 {
+                    let i = i.to_string();
+                    let value = map(&[("world", i.as_str()), ("new york", i.as_str())]);
+                    server.insert(&hello, value).unwrap();
+                }
+            }
+        });
+        while !writer.is_finished() {
+            let document = server.get(&hello).unwrap();
+            assert_eq!(document["world"], document["new york"]);
+            let values = server.get_many(&fields).unwrap();
+            assert_eq!(values[0], values[1]);
+        }
+        writer.join().unwrap();
+        assert_eq!(
+            server.get_many(&fields).unwrap(),
+            [json!("199"), json!("199")]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscription() {
+        let server = document_server();
+        server
+            .insert(
+                &create_ref(&["hello"]),
+                map(&[("world", "value"), ("new york", "value 2")]),
+            )
+            .unwrap();
+        let r = create_ref(&["hello", "world"]);
+
+        let mut subscription = server.subscribe(&r);
+
+        let count_up_to = 5;
+
+        let write_server = server.clone();
+        let r_ = r.clone();
+        let handle = tokio::spawn(async move {
+            for i in 0..count_up_to {
+                write_server
+                    .update(&r_, Value::String(i.to_string()))
+                    .unwrap();
+            }
+        });
+
+        let mut expected = 0;
+        while let Some(event) = subscription.next().await {
+            let Event::Insert { key, value } = event else {
+                panic!("expected insert event");
+            };
+            assert_eq!(&key, &r);
+            assert_eq!(String::from_utf8(value.to_vec()), Ok(expected.to_string()));
+
+            expected += 1;
+            if expected >= count_up_to {
+                break;
+            }
+        }
+
+        handle.await.unwrap();
+    }
+
+    #[test]
+    fn set_object() {
+        let server = document_server();
+        let r = Ref(vec!["hello".to_string()]);
+        let obj = map(&[("world", "1"), ("new york", "2")]);
+        server.insert(&r, obj).unwrap();
+
+        assert_eq!(
+            server.get(&create_ref(&["hello", "world"])).unwrap(),
+            Value::String("1".to_string()),
+        );
+        assert_eq!(
+            server.get(&create_ref(&["hello", "new york"])).unwrap(),
+            Value::String("2".to_string()),
+        );
+    }
+
+    #[test]
+    fn update_object() {
+        let server = document_server();
+        let r = create_ref(&["hello"]);
+        assert!(matches!(
+            server.update(&r, map(&[("world", "1")])),
+            Err(ServerError::KeyNotFound(_))
+        ));
+        server
+            .insert(&r, map(&[("world", "1"), ("new york", "2")]))
+            .unwrap();
+        server.update(&r, map(&[("world", "3")])).unwrap();
+
+        assert_eq!(
+            server.get(&r).unwrap(),
+            map(&[("world", "3"), ("new york", "2")])
+        );
+    }
+
+    #[test]
+    fn get_object() {
+        let server = document_server();
+        let r = Ref(vec!["hello".to_string()]);
+        let obj = map(&[("world", "1"), ("new york", "2")]);
+        server.insert(&r, obj.clone()).unwrap();
+        let result_obj = server.get(&Ref(vec!["hello".to_string()])).unwrap();
+
+        assert_eq!(obj, result_obj);
+    }
+
+    #[test]
+    fn get_collection() {
+        let server = collection_server();
+
+        server
+            .insert(&create_ref(&["fruits", "apple"]), map(&[("color", "red")]))
+            .unwrap();
+        server
+            .insert(
+                &create_ref(&["fruits", "banana"]),
+                map(&[("color", "yellow")]),
+            )
+            .unwrap();
+        server
+            .insert(
+                &create_ref(&["fruits", "blueberry"]),
+                map(&[("color", "purple")]),
+            )
+            .unwrap();
+        let all_fruits = server.get(&create_ref(&["fruits"])).unwrap();
+        assert_eq!(
+            all_fruits,
+            map(&[
+                ("apple", map(&[("color", "red")])),
+                ("banana", map(&[("color", "yellow")])),
+                ("blueberry", map(&[("color", "purple")])),
+            ])
+        );
+    }
+
+    #[test]
+    fn get_nested_collections() {
+        let db = Config::new()
+            .temporary(true)
+            .flush_every_ms(None)
+            .open()
+            .unwrap();
+        let schema: Schema = serde_json::from_value(json!({
+            "Document": {
+                "users": { "Collection": { "Document": {
+                    "name": "Scalar",
+                    "pin": { "Sensitive": "Scalar" },
+                    "posts": { "Collection": { "Document": { "title": "Scalar" } } }
+                } } }
+            }
+        }))
+        .unwrap();
+        let server = Server::new(db, schema).unwrap();
+        let root = json!({ "users": {
+            "ada": {
+                "name": "Ada",
+                "pin": "1234",
+                "posts": { "engines": { "title": "On engines" }, "notes": { "title": "Notes" } }
+            },
+            "alan": { "name": "Alan", "pin": "0000", "posts": {} },
+        } });
+        server.insert(&create_ref(&[]), root.clone()).unwrap();
+
+        assert_eq!(server.get(&create_ref(&[])).unwrap(), root);
+        assert_eq!(
+            server.get(&create_ref(&["users", "ada", "posts"])).unwrap(),
+            root["users"]["ada"]["posts"]
+        );
+        assert_eq!(
+            server.get(&create_ref(&["users", "alan"])).unwrap(),
+            root["users"]["alan"]
+        );
+        assert_eq!(server.dump().unwrap(), root);
+    }
+
+    #[test]
+    fn get_chunked() {
+        let server = collection_server();
+        let fruits: Map<String, Value> = (0..50)
+            .map(|i| (format!("fruit{i:02}"), map(&[("color", "red")])))
+            .collect();
+        server
+            .insert(&create_ref(&[]), json!({ "fruits": fruits.clone() }))
+            .unwrap();
+
+        let Chunked::Pieces(chunks) = server.get_chunked(&create_ref(&["fruits"]), 100).unwrap()
+        else {
+            panic!("collections are sent in pieces");
+        };
+        let chunks: Vec<_> = chunks.map(Result::unwrap).collect();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.into_iter().flatten().collect::<Map<_, _>>(), fruits);
+
+        let Chunked::Pieces(chunks) = server.get_chunked(&create_ref(&[]), 100).unwrap() else {
+            panic!("documents are sent in pieces");
+        };
+        let chunks: Vec<_> = chunks.map(Result::unwrap).collect();
+        assert_eq!(
+            chunks,
+            vec![Map::from_iter([("fruits".into(), fruits.into())])]
+        );
+
+        server.remove(&create_ref(&["fruits"])).unwrap();
+        let Chunked::Pieces(chunks) = server.get_chunked(&create_ref(&["fruits"]), 100).unwrap()
+        else {
+            panic!("collections are sent in pieces");
+        };
+        // An empty collection still comes in a piece
+        assert_eq!(
+            chunks.map(Result::unwrap).collect::<Vec<_>>(),
+            vec![Map::new()]
+        );
+    }
+
+    #[test]
+    fn delete_document() {
+        let server = collection_server();
+
+        server
+            .insert(&create_ref(&["fruits", "apple"]), map(&[("color", "red")]))
+            .unwrap();
+
+        server.remove(&create_ref(&["fruits", "apple"])).unwrap();
+
+        let all_fruits = server.get(&create_ref(&["fruits"])).unwrap();
+        assert_eq!(all_fruits, Value::Object(Map::new()));
+    }
+
+    #[test]
+    fn delete_collection() {
+        let server = collection_server();
+
+        server
+            .insert(&create_ref(&["fruits", "apple"]), map(&[("color", "red")]))
+            .unwrap();
+        server
+            .insert(
+                &create_ref(&["fruits", "banana"]),
+                map(&[("color", "yellow")]),
+            )
+            .unwrap();
+
+        server.remove(&create_ref(&["fruits"])).unwrap();
+
+        let all_fruits = server.get(&create_ref(&["fruits"])).unwrap();
+        assert_eq!(all_fruits, Value::Object(Map::new()));
+    }
+
+    #[test]
+    fn fork() {
+        let server = document_server();
+        server
+            .insert(
+                &create_ref(&["hello"]),
+                map(&[("world", "1"), ("new york", "2")]),
+            )
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let fork_path = dir.path().join("fork");
+        let fork = server.fork(fork_path.to_str().unwrap()).unwrap();
+        fork.update(&create_ref(&["hello", "world"]), "3".into())
+            .unwrap();
+
+        assert_eq!(
+            server.get(&create_ref(&["hello", "world"])).unwrap(),
+            Value::String("1".to_string())
+        );
+        assert_eq!(
+            fork.get(&create_ref(&["hello", "world"])).unwrap(),
+            Value::String("3".to_string())
+        );
+        assert!(server.fork(fork_path.to_str().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn scoped() {
+        let server = document_server();
+        let scoped = server.scoped(create_ref(&["hello"]));
+        scoped
+            .insert(&create_ref(&[]), map(&[("world", "1"), ("new york", "2")]))
+            .unwrap();
+        assert_eq!(
+            server.get(&create_ref(&["hello", "world"])).unwrap(),
+            Value::String("1".to_string())
+        );
+
+        let mut subscription = scoped.subscribe(&create_ref(&["world"]));
+        scoped.update(&create_ref(&["world"]), "3".into()).unwrap();
+        let Some(Event::Insert { key, .. }) = subscription.next().await else {
+            panic!("expected insert event");
+        };
+        assert_eq!(key, create_ref(&["world"]));
+    }
+
+    #[test]
+    fn database_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let _server = Server::open(path, Schema::new(SchemaItem::Scalar)).unwrap();
+
+        let Err(ServerError::DatabaseLocked { holder_pid, .. }) =
+            Server::open(path, Schema::new(SchemaItem::Scalar))
+        else {
+            panic!("expected the database to be locked");
+        };
+        assert_eq!(holder_pid, Some(std::process::id()));
+    }
+
+    #[test]
+    fn migrate_legacy() {
+        let server = collection_server();
+        server.store.insert("fruits/apple/color", "red").unwrap();
+        server
+            .store
+            .insert("fruits/banana/color", "yellow")
+            .unwrap();
+        server.store.insert("fruits/banana/shape", "long").unwrap();
+        assert!(server.has_legacy_keys().unwrap());
+
+        let report = server.migrate_legacy('/').unwrap();
+        assert_eq!(report.migrated, 0);
+        assert_eq!(report.rejected.len(), 1);
+        assert!(server.has_legacy_keys().unwrap());
+
+        server.store.remove("fruits/banana/shape").unwrap();
+        let report = server.migrate_legacy('/').unwrap();
+        assert_eq!(report.migrated, 2);
+        assert!(report.rejected.is_empty());
+        assert!(!server.has_legacy_keys().unwrap());
+        assert_eq!(
+            server.get(&create_ref(&["fruits"])).unwrap(),
+            map(&[
+                ("apple", map(&[("color", "red")])),
+                ("banana", map(&[("color", "yellow")])),
+            ])
+        );
+    }
+
+    #[test]
+    fn integrity() {
+        let server = collection_server();
+        let fruits = create_ref(&["fruits"]);
+        server
+            .insert(&fruits.child("apple"), map(&[("color", "red")]))
+            .unwrap();
+        assert_eq!(server.check_integrity(false).unwrap(), Vec::new());
+
+        let key = |components: &[&str]| server.schema().encode_ref(&create_ref(components).0);
+        server
+            .store
+            .insert(key(&["fruits", "banana", "color"]), "yellow")
+            .unwrap();
+        let listed: std::collections::HashSet<_> = ["apple", "cherry"].into_iter().collect();
+        server
+            .store
+            .insert(key(&["fruits"]), bincode::serialize(&listed).unwrap())
+            .unwrap();
+        server.store.insert("fruits/cherry", "red").unwrap();
+        let problems = vec![
+            IntegrityProblem::StaleMembers {
+                collection: fruits.clone(),
+                missing: vec!["cherry".to_string()],
+            },
+            IntegrityProblem::Orphaned(create_ref(&["fruits", "banana", "color"])),
+            IntegrityProblem::Undecodable(b"fruits/cherry".to_vec()),
+        ];
+        assert_eq!(server.check_integrity(false).unwrap(), problems);
+        assert_eq!(server.check_integrity(true).unwrap(), problems);
+        assert_eq!(server.check_integrity(false).unwrap(), Vec::new());
+        assert_eq!(
+            server.get(&fruits).unwrap(),
+            map(&[("apple", map(&[("color", "red")]))])
+        );
+    }
+
+    #[test]
+    fn collecting_orphans() {
+        let server = collection_server();
+        let fruits = create_ref(&["fruits"]);
+        server
+            .insert(&fruits.child("apple"), map(&[("color", "red")]))
+            .unwrap();
+        assert_eq!(server.collect_orphans().unwrap(), 0);
+
+        let key = |components: &[&str]| server.schema().encode_ref(&create_ref(components).0);
+        server
+            .store
+            .insert(key(&["fruits", "banana", "color"]), "yellow")
+            .unwrap();
+        server.store.insert("fruits/cherry", "red").unwrap();
+        assert_eq!(server.clone().dry_run().collect_orphans().unwrap(), 0);
+        assert_eq!(server.collect_orphans().unwrap(), 1);
+        assert_eq!(server.collect_orphans().unwrap(), 0);
+        // Keys that aren't encoded refs may be the legacy server's, waiting to be migrated
+        assert!(server.has_legacy_keys().unwrap());
+        assert_eq!(
+            server.get(&fruits).unwrap(),
+            map(&[("apple", map(&[("color", "red")]))])
+        );
+        assert!(matches!(
+            server.read_only().collect_orphans(),
+            Err(ServerError::ReadOnly)
+        ));
+    }
+
+    #[tokio::test]
+    async fn expiring_orphans() {
+        let server = collection_server();
+        let fruits = create_ref(&["fruits"]);
+        let banana = create_ref(&["fruits", "banana", "color"]);
+        server
+            .insert(&fruits.child("apple"), map(&[("color", "red")]))
+            .unwrap();
+        let mut subscription = server.subscribe(&fruits);
+        server
+            .store
+            .insert(server.schema().encode_ref(&banana.0), "yellow")
+            .unwrap();
+        let Some(Event::Insert { key, .. }) = subscription.next().await else {
+            panic!("expected an insert");
+        };
+        assert_eq!(key, banana);
+
+        assert_eq!(server.collect_orphans().unwrap(), 1);
+        assert_eq!(
+            subscription.next().await,
+            Some(Event::Expire { key: banana })
+        );
+        // Removals by clients are still removals
+        server.remove(&fruits.child("apple")).unwrap();
+        let Some(Event::Remove { .. }) = subscription.next().await else {
+            panic!("expected a removal");
+        };
+    }
+
+    #[test]
+    fn custom_codec() {
+        let test_schema = || {
+            Schema::new(SchemaItem::Document(
+                [(
+                    "counter".to_string(),
+                    SchemaItem::Document(
+                        [("count".to_string(), SchemaItem::Custom("u32".to_string()))]
+                            .into_iter()
+                            .collect(),
+                    ),
+                )]
+                .into_iter()
+                .collect(),
+            ))
+        };
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema()).unwrap();
+        let r = create_ref(&["counter"]);
+
+        let err = server.insert(&r, map(&[("count", 5)])).unwrap_err();
+        assert!(matches!(err, ServerError::UnknownCodec { .. }));
+
+        let server = server.with_codec("u32", SerdeCodec::<u32>::new());
+        server.insert(&r, map(&[("count", 5)])).unwrap();
+        assert_eq!(server.get(&r).unwrap(), map(&[("count", 5)]));
+
+        let err = server.update(&r.child("count"), (-1).into()).unwrap_err();
+        assert!(matches!(err, ServerError::InvalidScalar { .. }));
+
+        // Tenants share the codecs of the server they're opened from
+        let tenant = server.tenant("other", test_schema()).unwrap();
+        tenant.insert(&r, map(&[("count", 6)])).unwrap();
+        assert_eq!(tenant.get(&r).unwrap(), map(&[("count", 6)]));
+    }
+
+    #[tokio::test]
+    async fn event_values() {
+        /// Stores numbers as their bytes, which needn't be UTF-8
+        struct Bytes;
+
+        impl ScalarCodec for Bytes {
+            fn encode(&self, value: &Value) -> Result<Vec<u8>, CodecError> {
+                let number = value
+                    .as_u64()
+                    .ok_or(CodecError("not a number".to_string()))?;
+                Ok((number as u32).to_be_bytes().to_vec())
+            }
+
+            fn decode(&self, bytes: &[u8]) -> Result<Value, CodecError> {
+                let bytes = bytes
+                    .try_into()
+                    .map_err(|_| CodecError("not 4 bytes".to_string()))?;
+                Ok(u32::from_be_bytes(bytes).into())
+            }
+        }
+
+        let test_schema = Schema::new(SchemaItem::Document(
+            [
+                ("count".to_string(), SchemaItem::Custom("bytes".to_string())),
+                ("name".to_string(), SchemaItem::Scalar),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        let server = Server::new(Config::new().temporary(true).open().unwrap(), test_schema)
+            .unwrap()
+            .with_codec("bytes", Bytes);
+        let mut subscription = server.subscribe(&create_ref(&[]));
+        server
+            .insert(
+                &create_ref(&[]),
+                json!({ "count": 0xffff_fffe_u32, "name": "hi" }),
+            )
+            .unwrap();
+
+        let mut values = Map::new();
+        for _ in 0..3 {
+            let Some(Event::Insert { key, value }) = subscription.next().await else {
+                panic!("expected an insert");
+            };
+            let name = key.0.first().cloned().unwrap_or_default();
+            values.insert(name, server.event_value(&key, &value).unwrap().into());
+        }
+        assert_eq!(
+            Value::Object(values),
+            json!({ "": "{}", "count": "4294967294", "name": "hi" })
+        );
+
+        let err = server
+            .event_value(&create_ref(&["count"]), b"hi")
+            .unwrap_err();
+        assert!(matches!(err, ServerError::InvalidScalar { .. }));
+    }
+
+    #[test]
+    fn collection_keys() {
+        let test_schema = Schema::new(SchemaItem::Collection(
+            CollectionSchema::new(SchemaItem::Document(
+                [("title".to_string(), SchemaItem::Scalar)]
+                    .into_iter()
+                    .collect(),
+            ))
+            .with_keys(KeyFormat::Slug),
+        ));
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema).unwrap();
+        let post = json!({ "title": "Hello" });
+
+        server
+            .insert(&create_ref(&["hello-world"]), post.clone())
+            .unwrap();
+        let err = server
+            .insert(&create_ref(&["Hello World"]), post.clone())
+            .unwrap_err();
+        assert!(matches!(err, ServerError::InvalidKey { .. }));
+        assert_eq!(err.path(), Some(&create_ref(&["Hello World"])));
+
+        // Keys nested in an inserted object are checked too, and nothing is written if one fails
+        let err = server
+            .insert(
+                &create_ref(&[]),
+                json!({ "second-post": post.clone(), "third post": post.clone() }),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        assert_eq!(
+            server.get(&create_ref(&["second-post"])).unwrap(),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn references() {
+        let test_schema = Schema::new(SchemaItem::Document(
+            [
+                ("title".to_string(), SchemaItem::Scalar),
+                ("author".to_string(), SchemaItem::Reference),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema).unwrap();
+        let author = create_ref(&["author"]);
+
+        assert_eq!(server.reference(&author).unwrap(), None);
+        server
+            .insert(
+                &create_ref(&[]),
+                json!({ "title": "Hello", "author": ["users", "alice"] }),
+            )
+            .unwrap();
+        assert_eq!(
+            server.reference(&author).unwrap(),
+            Some(create_ref(&["users", "alice"]))
+        );
+
+        // Only refs can be stored, and only reference fields can be followed
+        let err = server.update(&author, json!("alice")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        let err = server.reference(&create_ref(&["title"])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+    }
+
+    #[test]
+    fn reference_targets() {
+        let test_schema = Schema::new(SchemaItem::Document(
+            [(
+                "author".to_string(),
+                SchemaItem::ReferenceTo(vec!["users".to_string(), "*".to_string()]),
+            )]
+            .into_iter()
+            .collect(),
+        ));
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema).unwrap();
+        let author = create_ref(&["author"]);
+
+        server
+            .insert(&create_ref(&[]), json!({ "author": ["users", "alice"] }))
+            .unwrap();
+        assert_eq!(
+            server.reference(&author).unwrap(),
+            Some(create_ref(&["users", "alice"]))
+        );
+        for target in [json!(["posts", "alice"]), json!(["users"])] {
+            let err = server.update(&author, target.clone()).unwrap_err();
+            assert!(matches!(err, ServerError::InvalidScalar { .. }), "{err}");
+            let err = server
+                .insert(&create_ref(&[]), json!({ "author": target }))
+                .unwrap_err();
+            assert!(matches!(err, ServerError::InvalidScalar { .. }), "{err}");
+        }
+        assert_eq!(
+            server.reference(&author).unwrap(),
+            Some(create_ref(&["users", "alice"]))
+        );
+    }
+
+    #[test]
+    fn enums() {
+        let test_schema = Schema::new(SchemaItem::Document(
+            [(
+                "status".to_string(),
+                SchemaItem::Enum(vec!["draft".to_string(), "published".to_string()]),
+            )]
+            .into_iter()
+            .collect(),
+        ));
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema).unwrap();
+        let status = create_ref(&["status"]);
+
+        server
+            .insert(&create_ref(&[]), json!({ "status": "draft" }))
+            .unwrap();
+        server.update(&status, json!("published")).unwrap();
+        assert_eq!(server.get(&status).unwrap(), "published");
+
+        let err = server.update(&status, json!("archived")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        assert_eq!(
+            err.to_string(),
+            "schema mismatch at /status: expected one of draft, published"
+        );
+        let err = server
+            .insert(&create_ref(&[]), json!({ "status": "Draft" }))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        assert_eq!(server.get(&status).unwrap(), "published");
+    }
+
+    #[test]
+    fn optional_fields() {
+        let test_schema = Schema::new(SchemaItem::Document(
+            [
+                ("title".to_string(), SchemaItem::Scalar),
+                (
+                    "subtitle".to_string(),
+                    SchemaItem::Optional(Box::new(SchemaItem::Scalar)),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema).unwrap();
+        let subtitle = create_ref(&["subtitle"]);
+
+        // Optional fields can be left out, but others can't
+        let err = server
+            .insert(&create_ref(&[]), json!({ "subtitle": "Hi" }))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        server
+            .insert(&create_ref(&[]), json!({ "title": "Hello" }))
+            .unwrap();
+        assert_eq!(server.get(&subtitle).unwrap(), Value::Null);
+        assert_eq!(
+            server.get(&create_ref(&[])).unwrap(),
+            json!({ "title": "Hello", "subtitle": null })
+        );
+
+        server.update(&subtitle, json!("World")).unwrap();
+        assert_eq!(server.get(&subtitle).unwrap(), "World");
+        server.update(&subtitle, Value::Null).unwrap();
+        assert_eq!(server.get(&subtitle).unwrap(), Value::Null);
+
+        // Null is the same as leaving it out, and only optional fields can be cleared
+        server
+            .insert(
+                &create_ref(&[]),
+                json!({ "title": "Hello", "subtitle": null }),
+            )
+            .unwrap();
+        assert_eq!(server.get(&subtitle).unwrap(), Value::Null);
+        let err = server
+            .update(&create_ref(&["title"]), Value::Null)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+    }
+
+    #[test]
+    fn metadata() {
+        let server = collection_server();
+        let apple = create_ref(&["fruits", "apple"]);
+        server
+            .insert(
+                &create_ref(&[]),
+                json!({ "fruits": { "apple": { "color": "red" } } }),
+            )
+            .unwrap();
+        let created = server.metadata(&apple).unwrap().unwrap();
+        assert_eq!(created.created_at, created.updated_at);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        server
+            .update(&create_ref(&["fruits", "apple", "color"]), json!("green"))
+            .unwrap();
+        let updated = server.metadata(&apple).unwrap().unwrap();
+        assert_eq!(updated.created_at, created.created_at);
+        assert!(updated.updated_at > created.updated_at);
+
+        let err = server.metadata(&create_ref(&["fruits"])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        server.remove(&apple).unwrap();
+        let err = server.metadata(&apple).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn search() {
+        let post = SchemaItem::Document(
+            [
+                (
+                    "title".to_string(),
+                    SchemaItem::Searchable(Box::new(SchemaItem::Scalar)),
+                ),
+                ("author".to_string(), SchemaItem::Scalar),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let test_schema = Schema::new(SchemaItem::Document(
+            [(
+                "posts".to_string(),
+                SchemaItem::Collection(CollectionSchema::new(post)),
+            )]
+            .into_iter()
+            .collect(),
+        ));
+        let server = Server::open_temporary(test_schema, Config::new()).unwrap();
+        server
+            .insert(
+                &create_ref(&["posts"]),
+                json!({
+                    "a": { "title": "Hello, World", "author": "ada" },
+                    "b": { "title": "hello again", "author": "world" },
+                }),
+            )
+            .unwrap();
+        // The keys of the posts found
+        let search = |query: &str| -> Vec<String> {
+            let found = server.search(&create_ref(&["posts"]), query).unwrap();
+            found.into_iter().map(|post| post.0[1].clone()).collect()
+        };
+
+        assert_eq!(search("HELLO"), ["a", "b"]);
+        // Only searchable fields are indexed
+        assert_eq!(search("hello world"), ["a"]);
+        assert!(search("ada").is_empty());
+        assert!(search("").is_empty());
+
+        // The index follows updates and removals
+        server
+            .update(&create_ref(&["posts", "a", "title"]), json!("goodbye"))
+            .unwrap();
+        assert_eq!(search("hello"), ["b"]);
+        assert_eq!(search("goodbye"), ["a"]);
+        server.remove(&create_ref(&["posts", "a"])).unwrap();
+        assert!(search("goodbye").is_empty());
+        let err = server.search(&create_ref(&["nope"]), "x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidPath);
+    }
+
+    #[test]
+    fn replacing_schemas() {
+        // The posts collection, with each post's fields
+        let posts = |fields: Vec<(&str, SchemaItem)>| {
+            let post = SchemaItem::Document(
+                fields
+                    .into_iter()
+                    .map(|(name, item)| (name.to_string(), item))
+                    .collect(),
+            );
+            Schema::new(SchemaItem::Document(
+                [(
+                    "posts".to_string(),
+                    SchemaItem::Collection(CollectionSchema::new(post)),
+                )]
+                .into_iter()
+                .collect(),
+            ))
+        };
+        let title = || ("title", SchemaItem::Scalar);
+        let author = || ("author", SchemaItem::Scalar);
+        let server = Server::open_temporary(posts(vec![title(), author()]), Config::new()).unwrap();
+        let changes = server.schema_changes();
+        server
+            .insert(
+                &create_ref(&["posts", "a"]),
+                json!({ "title": "Hello, World", "author": "ada" }),
+            )
+            .unwrap();
+
+        // Existing posts have no votes to give a required field, or anywhere to keep authors
+        let votes = ("votes", SchemaItem::Scalar);
+        let err = (server.replace_schema(posts(vec![title(), author(), votes]))).unwrap_err();
+        assert!(
+            matches!(err, ServerError::KeyNotFound(path) if path == create_ref(&["posts", "a", "votes"]))
+        );
+        let err = server.replace_schema(posts(vec![title()])).unwrap_err();
+        server.check_schema(posts(vec![title()])).unwrap_err();
+        assert!(
+            matches!(err, ServerError::ExtraKeyFound(path) if path == create_ref(&["posts", "a", "author"]))
+        );
+        assert!(!changes.has_changed().unwrap());
+
+        let votes = || ("votes", SchemaItem::Optional(Box::new(SchemaItem::Scalar)));
+        let title = || {
+            (
+                "title",
+                SchemaItem::Searchable(Box::new(SchemaItem::Scalar)),
+            )
+        };
+        let replacement = || posts(vec![title(), author(), votes()]);
+        server.check_schema(replacement()).unwrap();
+        assert!(!changes.has_changed().unwrap());
+        server.replace_schema(replacement()).unwrap();
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(
+            server.get(&create_ref(&["posts", "a"])).unwrap(),
+            json!({ "title": "Hello, World", "author": "ada", "votes": null })
+        );
+        server
+            .update(&create_ref(&["posts", "a", "votes"]), json!("3"))
+            .unwrap();
+        // Titles written before they were searchable are indexed along the way
+        assert_eq!(
+            server.search(&create_ref(&["posts"]), "hello").unwrap(),
+            [create_ref(&["posts", "a"])]
+        );
+    }
+
+    #[test]
+    fn aggregate() {
+        let post = SchemaItem::Document(
+            [
+                ("title".to_string(), SchemaItem::Scalar),
+                (
+                    "votes".to_string(),
+                    SchemaItem::Optional(Box::new(SchemaItem::Custom("number".to_string()))),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let test_schema = Schema::new(SchemaItem::Document(
+            [(
+                "posts".to_string(),
+                SchemaItem::Collection(CollectionSchema::new(post)),
+            )]
+            .into_iter()
+            .collect(),
+        ));
+        let server = Server::open_temporary(test_schema, Config::new())
+            .unwrap()
+            .with_codec("number", SerdeCodec::<serde_json::Number>::new());
+        let posts = create_ref(&["posts"]);
+        let votes = || vec!["votes".to_string()];
+        assert_eq!(server.aggregate(&posts, &Aggregation::Count).unwrap(), 0);
+        assert_eq!(
+            server
+                .aggregate(&posts, &Aggregation::Max(votes()))
+                .unwrap(),
+            Value::Null
+        );
+
+        server
+            .insert(
+                &posts,
+                json!({
+                    "a": { "title": "Hello", "votes": 3 },
+                    "b": { "title": "Again", "votes": -1 },
+                    "c": { "title": "Unvoted" },
+                }),
+            )
+            .unwrap();
+        assert_eq!(server.aggregate(&posts, &Aggregation::Count).unwrap(), 3);
+        assert_eq!(
+            server
+                .aggregate(&posts, &Aggregation::Sum(votes()))
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            server
+                .aggregate(&posts, &Aggregation::Min(votes()))
+                .unwrap(),
+            -1
+        );
+        assert_eq!(
+            server
+                .aggregate(&posts, &Aggregation::Max(votes()))
+                .unwrap(),
+            3
+        );
+        server
+            .update(&create_ref(&["posts", "c", "votes"]), json!(0.5))
+            .unwrap();
+        assert_eq!(
+            server
+                .aggregate(&posts, &Aggregation::Sum(votes()))
+                .unwrap(),
+            2.5
+        );
+
+        let err = server
+            .aggregate(&posts, &Aggregation::Sum(vec!["score".to_string()]))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        let err = server
+            .aggregate(&create_ref(&["posts", "a"]), &Aggregation::Count)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+    }
+
+    #[test]
+    fn get_shallow() {
+        let post = SchemaItem::Document(
+            [
+                ("title".to_string(), SchemaItem::Scalar),
+                (
+                    "comments".to_string(),
+                    SchemaItem::Collection(CollectionSchema::new(SchemaItem::Scalar)),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let blog = SchemaItem::Document(
+            [
+                ("name".to_string(), SchemaItem::Scalar),
+                (
+                    "posts".to_string(),
+                    SchemaItem::Collection(CollectionSchema::new(post)),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let test_schema = Schema::new(SchemaItem::Document(
+            [("blog".to_string(), blog)].into_iter().collect(),
+        ));
+        let server = Server::open_temporary(test_schema, Config::new()).unwrap();
+        let blog = create_ref(&["blog"]);
+        server
+            .insert(
+                &blog,
+                json!({
+                    "name": "Notes",
+                    "posts": { "first": { "title": "Hello", "comments": { "a": "Nice" } } },
+                }),
+            )
+            .unwrap();
+
+        assert_eq!(
+            server.get_shallow(&blog, 0).unwrap(),
+            json!({ "name": "Notes", "posts": { "$ref": ["blog", "posts"] } })
+        );
+        assert_eq!(
+            server.get_shallow(&blog, 1).unwrap(),
+            json!({
+                "name": "Notes",
+                "posts": {
+                    "first": { "title": "Hello", "comments": { "$ref": ["blog", "posts", "first", "comments"] } },
+                },
+            })
+        );
+        assert_eq!(
+            server.get_shallow(&blog, 2).unwrap(),
+            server.get(&blog).unwrap()
+        );
+        // Members of a collection are at the same level as the collection
+        assert_eq!(
+            server
+                .get_shallow(&create_ref(&["blog", "posts"]), 0)
+                .unwrap(),
+            json!({ "first": { "title": "Hello", "comments": { "$ref": ["blog", "posts", "first", "comments"] } } })
+        );
+    }
+
+    #[test]
+    fn get_fields() {
+        let server = collection_server();
+        let root = create_ref(&[]);
+        server
+            .insert(
+                &root,
+                json!({ "fruits": { "apple": { "color": "red" }, "kiwi": { "color": "brown" } } }),
+            )
+            .unwrap();
+        let field = |path: &str| path.split('/').map(String::from).collect::<Vec<_>>();
+
+        assert_eq!(
+            server
+                .get_fields(&root, &[field("fruits/apple/color"), field("fruits/kiwi")])
+                .unwrap(),
+            json!({ "fruits": { "apple": { "color": "red" }, "kiwi": { "color": "brown" } } })
+        );
+        assert_eq!(
+            server
+                .get_fields(&create_ref(&["fruits", "kiwi"]), &[field("color")])
+                .unwrap(),
+            json!({ "color": "brown" })
+        );
+        assert_eq!(server.get_fields(&root, &[]).unwrap(), json!({}));
+        let err = server
+            .get_fields(&root, &[field("fruits/apple/size")])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidPath);
+        let err = server
+            .get_fields(&root, &[field("fruits/pear/color")])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn pattern_subscription() {
+        let server = collection_server();
+        let pattern = create_ref(&["fruits", "*", "color"]);
+        let mut subscription = server.subscribe_pattern(&pattern).unwrap();
+        server
+            .insert(
+                &create_ref(&["fruits"]),
+                json!({ "apple": { "color": "red" } }),
+            )
+            .unwrap();
+        // The document itself is written too, but doesn't match
+        let Some(Event::Insert { key, value }) = subscription.next().await else {
+            panic!("expected insert event");
+        };
+        assert_eq!(key, create_ref(&["fruits", "apple", "color"]));
+        assert_eq!(&value[..], b"red");
+
+        let err = server
+            .subscribe_pattern(&create_ref(&["fruits", "*", "size"]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidPath);
+    }
+
+    #[test]
+    fn list_keys() {
+        let server = collection_server();
+        let fruits = create_ref(&["fruits"]);
+        assert!(server.list_keys(&fruits).unwrap().is_empty());
+        server
+            .insert(
+                &fruits,
+                json!({ "apple": { "color": "red" }, "kiwi": { "color": "brown" } }),
+            )
+            .unwrap();
+        assert_eq!(server.list_keys(&fruits).unwrap(), ["kiwi", "apple"]);
+        let err = server
+            .list_keys(&create_ref(&["fruits", "apple"]))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+    }
+
+    #[test]
+    fn history() {
+        let server = collection_server().with_history(2).unwrap();
+        let apple = create_ref(&["fruits", "apple"]);
         server
             .insert(
-                &create_ref(&["hello"]),
-                map(&[("world", "value"), ("new york", "value 2")]),
+                &create_ref(&[]),
+                json!({ "fruits": { "apple": { "color": "red" } } }),
             )
             .unwrap();
-        assert_eq!(
+        for color in ["green", "yellow"] {
             server
-                .get(&create_ref(&["hello", "world"]))
-                .unwrap()
-                .as_str()
-                .unwrap(),
-            "value"
+                .update(&create_ref(&["fruits", "apple", "color"]), json!(color))
+                .unwrap();
+        }
+
+        let revisions = server.history(&apple).unwrap();
+        let kept: Vec<_> = revisions.iter().map(|r| (r.revision, &r.value)).collect();
+        assert_eq!(
+            kept,
+            [
+                (2, &json!({ "color": "green" })),
+                (3, &json!({ "color": "yellow" })),
+            ]
         );
+        assert_eq!(server.revision(&apple, 3).unwrap(), revisions[1]);
+        let err = server.revision(&apple, 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // Removing the document is a revision too, and the history outlives it
+        server.remove(&apple).unwrap();
+        assert_eq!(server.revision(&apple, 4).unwrap().value, Value::Null);
+        let err = server.history(&create_ref(&["fruits"])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
     }
 
-    #[tokio::test]
-    async fn subscription() {
-        let server = document_server();
+    #[test]
+    fn expand_references() {
+        let person = SchemaItem::Document(
+            [
+                ("name".to_string(), SchemaItem::Scalar),
+                ("friend".to_string(), SchemaItem::Reference),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let test_schema = Schema::new(SchemaItem::Collection(CollectionSchema::new(person)));
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema).unwrap();
         server
             .insert(
-                &create_ref(&["hello"]),
-                map(&[("world", "value"), ("new york", "value 2")]),
+                &create_ref(&[]),
+                json!({
+                    "alice": { "name": "Alice", "friend": ["bob"] },
+                    "bob": { "name": "Bob", "friend": ["carol"] },
+                    "carol": { "name": "Carol", "friend": ["dave"] },
+                }),
             )
             .unwrap();
-        let r = create_ref(&["hello", "world"]);
+        let alice = create_ref(&["alice"]);
+        let mut allow_all = |targets: &[Ref]| vec![true; targets.len()];
 
-        let mut subscription = server.subscribe(&r);
+        assert_eq!(
+            server.get_expanded(&alice, 0, &mut allow_all).unwrap(),
+            json!({ "name": "Alice", "friend": ["bob"] })
+        );
+        // Dave doesn't exist
+        assert_eq!(
+            server.get_expanded(&alice, 3, &mut allow_all).unwrap(),
+            json!({ "name": "Alice", "friend": {
+                "name": "Bob", "friend": { "name": "Carol", "friend": null },
+            } })
+        );
 
-        let count_up_to = 5;
+        // Denied targets are hidden, and each item's targets are checked together
+        let mut checked = Vec::new();
+        let value = server
+            .get_expanded(&alice, 2, &mut |targets| {
+                checked.push(targets.to_vec());
+                targets
+                    .iter()
+                    .map(|target| target.0[0] != "carol")
+                    .collect()
+            })
+            .unwrap();
+        assert_eq!(
+            value,
+            json!({ "name": "Alice", "friend": { "name": "Bob", "friend": null } })
+        );
+        assert_eq!(
+            checked,
+            vec![vec![create_ref(&["bob"])], vec![create_ref(&["carol"])]]
+        );
+    }
 
-        let write_server = server.clone();
-        let r_ = r.clone();
-        let handle = tokio::spawn(async move {
-            for i in 0..count_up_to {
-                write_server
-                    .update(&r_, Value::String(i.to_string()))
-                    .unwrap();
-            }
-        });
+    #[test]
+    fn dump_and_restore() {
+        let server = document_server();
+        assert_eq!(server.dump().unwrap(), Value::Null);
+        let snapshot = json!({ "hello": { "world": "earth", "new york": "city" } });
+        server.insert(&create_ref(&[]), snapshot.clone()).unwrap();
+        assert_eq!(server.dump().unwrap(), snapshot);
 
-        let mut expected = 0;
-        while let Some(event) = subscription.next().await {
-            let Event::Insert { key, value } = event else {
-                panic!("expected insert event");
-            };
-            assert_eq!(&key, &r);
-            assert_eq!(String::from_utf8(value.to_vec()), Ok(expected.to_string()));
+        let copy = document_server();
+        copy.restore(&snapshot).unwrap();
+        assert_eq!(copy.get(&create_ref(&[])).unwrap(), snapshot);
 
-            expected += 1;
-            if expected >= count_up_to {
-                break;
-            }
-        }
+        // A snapshot that doesn't match the schema changes nothing
+        let err = copy
+            .restore(&json!({ "hello": { "world": "earth", "mars": "planet" } }))
+            .unwrap_err();
+        assert!(matches!(err, ServerError::ExtraKeyFound(_)));
+        assert_eq!(copy.dump().unwrap(), snapshot);
 
-        handle.await.unwrap();
+        // Missing items round trip as null, and restoring replaces what was there
+        copy.remove(&create_ref(&["hello", "world"])).unwrap();
+        let partial = json!({ "hello": { "world": null, "new york": "city" } });
+        assert_eq!(copy.dump().unwrap(), partial);
+        server.restore(&partial).unwrap();
+        assert_eq!(server.dump().unwrap(), partial);
+
+        // Members of a collection at the root are listed too
+        let server = Server::new(
+            Config::new().temporary(true).open().unwrap(),
+            Schema::new(SchemaItem::Collection(CollectionSchema::new(
+                SchemaItem::Scalar,
+            ))),
+        )
+        .unwrap();
+        let snapshot = json!({ "apple": "red", "banana": "yellow" });
+        server.restore(&snapshot).unwrap();
+        assert_eq!(server.dump().unwrap(), snapshot);
+        assert_eq!(server.get(&create_ref(&[])).unwrap(), snapshot);
     }
 
     #[test]
-    fn set_object() {
+    fn change_log() {
         let server = document_server();
-        let r = Ref(vec!["hello".to_string()]);
-        let obj = map(&[("world", "1"), ("new york", "2")]);
-        server.insert(&r, obj).unwrap();
+        let root = json!({ "hello": { "world": "earth", "new york": "city" } });
+        server.insert(&create_ref(&[]), root.clone()).unwrap();
+        server
+            .update(&create_ref(&["hello", "world"]), json!("mars"))
+            .unwrap();
+        // Writes that fail aren't recorded
+        assert!(server
+            .update(&create_ref(&["hello", "moon"]), json!("cheese"))
+            .is_err());
+        server.remove(&create_ref(&["hello"])).unwrap();
 
+        let changes = server.changes(0, 10).unwrap();
+        let seqs: Vec<u64> = changes.iter().map(|change| change.seq).collect();
+        assert_eq!(seqs.len(), 3);
+        assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
         assert_eq!(
-            server.get(&create_ref(&["hello", "world"])).unwrap(),
-            Value::String("1".to_string()),
+            changes[0].op,
+            ChangeOp::Insert {
+                path: create_ref(&[]),
+                value: root,
+            }
         );
         assert_eq!(
-            server.get(&create_ref(&["hello", "new york"])).unwrap(),
-            Value::String("2".to_string()),
+            changes[1].op,
+            ChangeOp::Update {
+                path: create_ref(&["hello", "world"]),
+                value: json!("mars"),
+            }
+        );
+        assert_eq!(
+            changes[2].op,
+            ChangeOp::Remove {
+                path: create_ref(&["hello"]),
+            }
         );
+
+        // Reading resumes from any offset
+        assert_eq!(server.changes(seqs[1], 1).unwrap(), changes[1..2]);
+        assert_eq!(server.changes(seqs[2], 10).unwrap(), changes[2..]);
+        assert!(server.changes(seqs[2] + 1, 10).unwrap().is_empty());
     }
 
     #[test]
-    fn get_object() {
+    fn changes_committed_out_of_order() {
         let server = document_server();
-        let r = Ref(vec!["hello".to_string()]);
-        let obj = map(&[("world", "1"), ("new york", "2")]);
-        server.insert(&r, obj.clone()).unwrap();
-        let result_obj = server.get(&Ref(vec!["hello".to_string()])).unwrap();
+        // A change that's been numbered but hasn't committed holds back the ones numbered after it
+        let pending = [ChangeOp::Remove {
+            path: create_ref(&["hello", "world"]),
+        }];
+        let numbered = server.sequencer.number(&pending).unwrap();
+        let held = numbered.seqs()[0];
+        let root = json!({ "hello": { "world": "earth", "new york": "city" } });
+        server.insert(&create_ref(&[]), root).unwrap();
+        assert!(server.changes(0, 10).unwrap().is_empty());
+        assert_eq!(server.next_change().unwrap(), held);
+        assert!(server
+            .written_since(held, &[create_ref(&["hello", "world"])])
+            .unwrap());
 
-        assert_eq!(obj, result_obj);
+        drop(numbered);
+        let changes = server.changes(held, 10).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].seq > held);
+        assert!(server.next_change().unwrap() > changes[0].seq);
     }
 
     #[test]
-    fn get_collection() {
-        let server = collection_server();
+    fn trimming_changes() {
+        let server = document_server();
+        let root = json!({ "hello": { "world": "earth", "new york": "city" } });
+        server.insert(&create_ref(&[]), root).unwrap();
+        for planet in ["mars", "venus", "jupiter"] {
+            server
+                .update(&create_ref(&["hello", "world"]), json!(planet))
+                .unwrap();
+        }
+        let changes = server.changes(0, 10).unwrap();
+        assert_eq!(changes.len(), 4);
 
-        server
-            .insert(&create_ref(&["fruits", "apple"]), map(&[("color", "red")]))
+        // Changes from the pinned one on are kept, however many there are
+        assert_eq!(server.trim_changes(1, Some(changes[1].seq)).unwrap(), 1);
+        assert_eq!(server.changes(changes[1].seq, 10).unwrap(), changes[1..]);
+        assert_eq!(server.trim_changes(1, None).unwrap(), 2);
+        assert_eq!(server.trim_changes(1, None).unwrap(), 0);
+        assert_eq!(server.changes(changes[3].seq, 10).unwrap(), changes[3..]);
+
+        let oldest = changes[2].seq + 1;
+        assert_eq!(server.oldest_change().unwrap(), oldest);
+        let Err(ServerError::ChangesTrimmed {
+            from,
+            oldest: trimmed,
+        }) = server.changes(0, 10)
+        else {
+            panic!("expected the log to have been trimmed");
+        };
+        assert_eq!((from, trimmed), (0, oldest));
+    }
+
+    #[test]
+    fn numbering_changes_after_an_old_log() {
+        let db = Config::new().temporary(true).open().unwrap();
+        // Logs used to be numbered from 0, keeping the next number under the empty key
+        let log = db.open_tree("system/changes").unwrap();
+        let old = Change {
+            seq: 1_000_000,
+            op: ChangeOp::Remove {
+                path: create_ref(&["hello"]),
+            },
+        };
+        log.insert(old.seq.to_be_bytes(), serde_json::to_vec(&old).unwrap())
             .unwrap();
-        server
-            .insert(
-                &create_ref(&["fruits", "banana"]),
-                map(&[("color", "yellow")]),
-            )
+        log.insert(b"", &(old.seq + 1).to_be_bytes()).unwrap();
+
+        let server = Server::new(db, document_schema()).unwrap();
+        let root = json!({ "hello": { "world": "earth", "new york": "city" } });
+        server.insert(&create_ref(&[]), root).unwrap();
+        let changes = server.changes(0, 10).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], old);
+        assert!(changes[1].seq > old.seq);
+    }
+
+    #[test]
+    fn upgrading_length_prefixed_keys() {
+        let db = Config::new()
+            .temporary(true)
+            .flush_every_ms(None)
+            .open()
             .unwrap();
-        server
-            .insert(
-                &create_ref(&["fruits", "blueberry"]),
-                map(&[("color", "purple")]),
+        let length_prefixed = |components: &[&str]| {
+            let mut key = Vec::new();
+            for component in components {
+                key.extend((component.len() as u64).to_le_bytes());
+                key.extend(component.as_bytes());
+            }
+            key
+        };
+        db.insert(length_prefixed(&[]), &[1]).unwrap();
+        db.insert(length_prefixed(&["hello"]), &[1]).unwrap();
+        db.insert(length_prefixed(&["hello", "world"]), "earth")
+            .unwrap();
+        db.insert("legacy/key", "value").unwrap();
+
+        let server = Server::new(db, document_schema()).unwrap();
+        assert_eq!(
+            server.get(&create_ref(&["hello", "world"])).unwrap(),
+            json!("earth")
+        );
+        // Keys that weren't encoded refs are left for the legacy migration
+        assert!(server.has_legacy_keys().unwrap());
+    }
+
+    #[test]
+    fn upgrading_collection_sets() {
+        let db = Config::new()
+            .temporary(true)
+            .flush_every_ms(None)
+            .open()
+            .unwrap();
+        let schema = || -> Schema {
+            serde_json::from_value(json!({
+            "Document": {
+                "fruits": { "Collection": { "Document": { "color": "Scalar" } } },
+                "baskets": { "Collection": { "Collection": "Scalar" } }
+            }
+            }))
+            .unwrap()
+        };
+        let server = Server::new(db.clone(), schema()).unwrap();
+        let key = |components: &[&str]| server.schema().encode_ref(&create_ref(components).0);
+        let set = |members: &[&str]| {
+            bincode::serialize(
+                &members
+                    .iter()
+                    .copied()
+                    .collect::<std::collections::HashSet<_>>(),
             )
+            .unwrap()
+        };
+        db.insert(key(&[]), &[1]).unwrap();
+        db.insert(key(&["fruits"]), set(&["apple", "banana"]))
             .unwrap();
-        let all_fruits = server.get(&create_ref(&["fruits"])).unwrap();
+        db.insert(key(&["fruits", "apple"]), &[1]).unwrap();
+        db.insert(key(&["fruits", "apple", "color"]), "red")
+            .unwrap();
+        db.insert(key(&["fruits", "banana"]), &[1]).unwrap();
+        db.insert(key(&["fruits", "banana", "color"]), "yellow")
+            .unwrap();
+        // An empty basket was only listed in the set of baskets
+        db.insert(key(&["baskets"]), set(&["empty", "full"]))
+            .unwrap();
+        db.insert(key(&["baskets", "full"]), set(&["a"])).unwrap();
+        db.insert(key(&["baskets", "full", "a"]), "apple").unwrap();
+        db.open_tree("system/key-formats")
+            .unwrap()
+            .insert(db.name(), &[2])
+            .unwrap();
+
+        let server = Server::new(db, schema()).unwrap();
         assert_eq!(
-            all_fruits,
-            map(&[
-                ("apple", map(&[("color", "red")])),
-                ("banana", map(&[("color", "yellow")])),
-                ("blueberry", map(&[("color", "purple")])),
-            ])
+            server.get(&create_ref(&[])).unwrap(),
+            json!({
+                "fruits": { "apple": { "color": "red" }, "banana": { "color": "yellow" } },
+                "baskets": { "empty": {}, "full": { "a": "apple" } },
+            })
+        );
+        server.remove(&create_ref(&["fruits", "apple"])).unwrap();
+        server.remove(&create_ref(&["baskets", "empty"])).unwrap();
+        assert_eq!(
+            server.get(&create_ref(&[])).unwrap(),
+            json!({
+                "fruits": { "banana": { "color": "yellow" } },
+                "baskets": { "full": { "a": "apple" } },
+            })
         );
     }
 
     #[test]
-    fn delete_document() {
-        let server = collection_server();
+    fn upgrading_fields_under_wrappers() {
+        let db = Config::new()
+            .temporary(true)
+            .flush_every_ms(None)
+            .open()
+            .unwrap();
+        let schema = || -> Schema {
+            serde_json::from_value(json!({ "Document": {
+                "profile": { "Optional": { "Document": { "bio": "Scalar" } } }
+            } }))
+            .unwrap()
+        };
+        let server = Server::new(db.clone(), schema()).unwrap();
+        let profile = server.schema().encode_ref(&create_ref(&["profile"]).0);
+        // Format 3 stored fields under optional items like collection keys
+        let bio = [&profile[..], &[("bio".len() as u8) << 1 | 1], b"bio"].concat();
+        db.insert(server.schema().encode_ref(&[]), &[1]).unwrap();
+        db.insert(&profile, &[1]).unwrap();
+        db.insert(&bio, "hello").unwrap();
+        db.open_tree("system/key-formats")
+            .unwrap()
+            .insert(db.name(), &[3])
+            .unwrap();
+        drop(server);
 
-        server
-            .insert(&create_ref(&["fruits", "apple"]), map(&[("color", "red")]))
+        let server = Server::new(db.clone(), schema()).unwrap();
+        assert_eq!(
+            server.get(&create_ref(&["profile", "bio"])).unwrap(),
+            json!("hello")
+        );
+        assert!(!db.contains_key(&bio).unwrap());
+    }
+
+    #[test]
+    fn replicating_to_a_follower() {
+        let leader = document_server();
+        leader
+            .insert(
+                &create_ref(&[]),
+                json!({ "hello": { "world": "earth", "new york": "city" } }),
+            )
             .unwrap();
+        let (next, snapshot) = leader.snapshot().unwrap();
+        assert!(leader.changes(next, 10).unwrap().is_empty());
+        leader
+            .update(&create_ref(&["hello", "world"]), json!("mars"))
+            .unwrap();
+        leader.remove(&create_ref(&["hello"])).unwrap();
 
-        server.remove(&create_ref(&["fruits", "apple"])).unwrap();
+        let follower = document_server().read_only();
+        let err = follower
+            .insert(&create_ref(&[]), json!({ "hello": null }))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ReadOnly);
 
-        let all_fruits = server.get(&create_ref(&["fruits"])).unwrap();
-        assert_eq!(all_fruits, Value::Object(Map::new()));
+        follower
+            .apply(&ChangeOp::Restore { snapshot }, next)
+            .unwrap();
+        assert_eq!(follower.replicated().unwrap(), Some(next));
+        assert_eq!(
+            follower.get(&create_ref(&["hello", "world"])).unwrap(),
+            json!("earth")
+        );
+        let changes = leader.changes(next, 10).unwrap();
+        for change in &changes {
+            follower.apply(&change.op, change.seq + 1).unwrap();
+            // Repeating a change is harmless
+            follower.apply(&change.op, change.seq + 1).unwrap();
+        }
+        assert_eq!(follower.dump().unwrap(), leader.dump().unwrap());
+        let last = changes.last().unwrap().seq;
+        assert_eq!(follower.replicated().unwrap(), Some(last + 1));
     }
 
     #[test]
-    fn delete_collection() {
-        let server = collection_server();
+    fn dry_runs() {
+        let server = document_server();
+        let rehearsal = server.clone().dry_run();
+        let hello = create_ref(&["hello"]);
 
-        server
-            .insert(&create_ref(&["fruits", "apple"]), map(&[("color", "red")]))
+        rehearsal
+            .insert(&hello, json!({ "world": "earth", "new york": "city" }))
             .unwrap();
+        assert_eq!(server.get(&hello).unwrap(), Value::Null);
+        let err = rehearsal
+            .insert(&hello, json!({ "world": "earth", "mars": "red" }))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+
         server
-            .insert(
-                &create_ref(&["fruits", "banana"]),
-                map(&[("color", "yellow")]),
-            )
+            .insert(&hello, json!({ "world": "earth", "new york": "city" }))
             .unwrap();
+        let next = server.next_change().unwrap();
+        rehearsal
+            .update(&create_ref(&["hello", "world"]), json!("mars"))
+            .unwrap();
+        rehearsal.remove(&hello).unwrap();
+        assert_eq!(
+            server.get(&hello).unwrap(),
+            json!({ "world": "earth", "new york": "city" })
+        );
+        // Nothing is logged either
+        assert_eq!(server.next_change().unwrap(), next);
+    }
 
-        server.remove(&create_ref(&["fruits"])).unwrap();
+    #[test]
+    fn sensitive_errors() {
+        let test_schema = Schema::new(SchemaItem::Document(
+            [
+                ("age".to_string(), SchemaItem::Custom("u32".to_string())),
+                (
+                    "pin".to_string(),
+                    SchemaItem::Sensitive(Box::new(SchemaItem::Custom("u32".to_string()))),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        let server = Server::new(Config::new().temporary(true).open().unwrap(), test_schema)
+            .unwrap()
+            .with_codec("u32", SerdeCodec::<u32>::new());
 
-        let all_fruits = server.get(&create_ref(&["fruits"])).unwrap();
-        assert_eq!(all_fruits, Value::Object(Map::new()));
+        let err = server
+            .insert(&create_ref(&[]), json!({ "age": "forty", "pin": 1234 }))
+            .unwrap_err();
+        assert!(err.to_string().contains("forty"), "{err}");
+
+        let err = server
+            .insert(&create_ref(&[]), json!({ "age": 40, "pin": "1234" }))
+            .unwrap_err();
+        assert!(!err.to_string().contains("1234"), "{err}");
+        assert_eq!(err.path(), Some(&create_ref(&["pin"])));
     }
 
     #[test]
@@ -575,6 +4919,183 @@ mod tests {
         assert_eq!(value, Value::Null);
     }
 
+    #[test]
+    fn error_context() {
+        let server = document_server();
+
+        let err = server.get(&create_ref(&["hello", "world"])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.path(), Some(&create_ref(&["hello", "world"])));
+
+        let err = server
+            .insert(
+                &create_ref(&["hello"]),
+                map(&[("world", "1"), ("mars", "2")]),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SchemaMismatch);
+        assert_eq!(err.path(), Some(&create_ref(&["hello", "mars"])));
+
+        let err = server.get(&create_ref(&["goodbye"])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidPath);
+    }
+
+    #[tokio::test]
+    async fn presence() {
+        let test_schema = Schema::new(SchemaItem::Collection(CollectionSchema::new(
+            SchemaItem::Document(
+                [
+                    ("topic".to_string(), SchemaItem::Scalar),
+                    ("online".to_string(), SchemaItem::Presence),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        )));
+        let server =
+            Server::new(Config::new().temporary(true).open().unwrap(), test_schema).unwrap();
+        // Presence fields can't be written, so they don't have to be inserted either
+        server
+            .insert(&create_ref(&["lobby"]), json!({ "topic": "general" }))
+            .unwrap();
+        let err = server
+            .update(&create_ref(&["lobby", "online", "ada"]), json!("here"))
+            .unwrap_err();
+        assert!(matches!(err, ServerError::PresenceWrite(_)));
+
+        let mut subscription = server.subscribe(&create_ref(&["lobby"]));
+        server
+            .join(1, &create_ref(&["lobby", "online", "ada"]), json!("here"))
+            .unwrap();
+        server
+            .join(2, &create_ref(&["lobby", "online", "grace"]), json!("away"))
+            .unwrap();
+        let Some(Event::Insert { key, value }) = subscription.next().await else {
+            panic!("expected an insert");
+        };
+        assert_eq!(key, create_ref(&["lobby", "online", "ada"]));
+        assert_eq!(value, "here");
+        assert_eq!(
+            server.get(&create_ref(&["lobby"])).unwrap(),
+            json!({ "topic": "general", "online": { "ada": "here", "grace": "away" } })
+        );
+        let err = server
+            .join(1, &create_ref(&["lobby", "online"]), json!("here"))
+            .unwrap_err();
+        assert!(matches!(err, ServerError::NotPresence(_)));
+
+        // Only the connection that joined can leave, and closing leaves everything
+        let err = server
+            .leave(2, &create_ref(&["lobby", "online", "ada"]))
+            .unwrap_err();
+        assert!(matches!(err, ServerError::KeyNotFound(_)));
+        server.leave_all(1);
+        assert_eq!(
+            server.get(&create_ref(&["lobby", "online"])).unwrap(),
+            json!({ "grace": "away" })
+        );
+    }
+
+    #[tokio::test]
+    async fn ephemeral() {
+        let test_schema = Schema::new(SchemaItem::Collection(CollectionSchema::new(
+            SchemaItem::Document(
+                [
+                    ("title".to_string(), SchemaItem::Scalar),
+                    (
+                        "cursors".to_string(),
+                        SchemaItem::Ephemeral(Box::new(SchemaItem::Collection(
+                            CollectionSchema::new(SchemaItem::Scalar),
+                        ))),
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        )));
+        let db = Config::new().temporary(true).open().unwrap();
+        let server = Server::new(db.clone(), test_schema).unwrap();
+        server
+            .insert(&create_ref(&["notes"]), json!({ "title": "Notes" }))
+            .unwrap();
+        let err = server
+            .insert(
+                &create_ref(&["todo"]),
+                json!({ "title": "Todo", "cursors": { "ada": "12" } }),
+            )
+            .unwrap_err();
+        assert!(matches!(err, ServerError::EphemeralWrite(_)));
+        assert_eq!(
+            server.get(&create_ref(&["notes"])).unwrap(),
+            json!({ "title": "Notes", "cursors": {} })
+        );
+
+        let mut subscription = server.subscribe(&create_ref(&["notes", "cursors"]));
+        let keys_stored = db.len();
+        let changes = server.next_change().unwrap();
+        server
+            .insert(&create_ref(&["notes", "cursors"]), json!({ "ada": "12" }))
+            .unwrap();
+        server
+            .update(&create_ref(&["notes", "cursors", "ada"]), json!("14"))
+            .unwrap();
+        assert_eq!(
+            server.get(&create_ref(&["notes"])).unwrap(),
+            json!({ "title": "Notes", "cursors": { "ada": "14" } })
+        );
+        let Some(Event::Insert { key, .. }) = subscription.next().await else {
+            panic!("expected an insert");
+        };
+        assert_eq!(key, create_ref(&["notes", "cursors"]));
+        assert_eq!(db.len(), keys_stored);
+        assert_eq!(server.next_change().unwrap(), changes);
+        assert_eq!(server.dump().unwrap()["notes"]["cursors"], json!({}));
+
+        // Ephemeral items go with whatever contains them
+        server.remove(&create_ref(&["notes"])).unwrap();
+        server
+            .insert(&create_ref(&["notes"]), json!({ "title": "Notes" }))
+            .unwrap();
+        assert_eq!(
+            server.get(&create_ref(&["notes", "cursors"])).unwrap(),
+            json!({})
+        );
+    }
+
+    #[tokio::test]
+    async fn split_values() {
+        let server = collection_server().with_chunk_size(4);
+        let apple = create_ref(&["fruits", "apple"]);
+        let color = create_ref(&["fruits", "apple", "color"]);
+        let mut subscription = server.subscribe(&color);
+
+        server
+            .insert(&apple, json!({ "color": "a deep, glossy red" }))
+            .unwrap();
+        // 18 bytes, in chunks of 4
+        assert_eq!(server.blobs.len(), 5);
+        assert_eq!(server.get(&color).unwrap(), json!("a deep, glossy red"));
+        assert_eq!(
+            server.get(&create_ref(&["fruits"])).unwrap(),
+            json!({ "apple": { "color": "a deep, glossy red" } })
+        );
+        let Some(Event::Insert { value, .. }) = subscription.next().await else {
+            panic!("expected insert event");
+        };
+        assert_eq!(&value[..], b"a deep, glossy red");
+
+        // Overwriting a split value drops its chunks, whether or not the new one is split
+        server.update(&color, json!("green")).unwrap();
+        assert_eq!(server.blobs.len(), 2);
+        server.update(&color, json!("red")).unwrap();
+        assert!(server.blobs.is_empty());
+        assert_eq!(server.get(&color).unwrap(), json!("red"));
+
+        server.update(&color, json!("yellowish")).unwrap();
+        server.remove(&apple).unwrap();
+        assert!(server.blobs.is_empty());
+    }
+
     fn collection_server() -> Server {
         let db = Config::new()
             .temporary(true)
@@ -585,7 +5106,7 @@ mod tests {
         let test_schema = Schema::new(SchemaItem::Document(
             [(
                 "fruits".to_string(),
-                SchemaItem::Collection(Box::new(SchemaItem::Document(
+                SchemaItem::Collection(CollectionSchema::new(SchemaItem::Document(
                     [("color".to_string(), SchemaItem::Scalar)]
                         .into_iter()
                         .collect(),
@@ -595,10 +5116,7 @@ mod tests {
             .collect(),
         ));
 
-        Server {
-            store: db,
-            schema: Arc::new(test_schema),
-        }
+        Server::new(db, test_schema).unwrap()
     }
 
     fn document_server() -> Server {
@@ -607,8 +5125,11 @@ mod tests {
             .flush_every_ms(None)
             .open()
             .unwrap();
+        Server::new(db, document_schema()).unwrap()
+    }
 
-        let test_schema = Schema::new(SchemaItem::Document(
+    fn document_schema() -> Schema {
+        Schema::new(SchemaItem::Document(
             [(
                 "hello".to_string(),
                 SchemaItem::Document(
@@ -622,12 +5143,7 @@ mod tests {
             )]
             .into_iter()
             .collect(),
-        ));
-
-        Server {
-            store: db,
-            schema: Arc::new(test_schema),
-        }
+        ))
     }
 
     fn create_ref(components: &[&str]) -> Ref {
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+
+use crate::{accounts::SignedIn, message::ClientMessage, oidc::Verified};
+
+/// The sessions of connections that dropped recently, kept for a grace period so their clients
+/// can reconnect and carry on where they left off
+pub struct Sessions {
+    parked: Mutex<HashMap<String, Parked>>,
+    grace: Duration,
+}
+
+/// What's needed to restore a connection's session
+pub struct Session {
+    /// The tenant the client connected to, or None for the default tenant
+    pub tenant: Option<String>,
+    /// The message that started each of the client's subscriptions
+    pub subscriptions: Vec<ClientMessage>,
+    /// The first change the client may not have been sent updates for
+    pub cursor: u64,
+    /// Who the client had signed in or authenticated as
+    pub identity: Option<Identity>,
+}
+
+/// Who a client is to the permission rules
+#[derive(Clone, Debug, PartialEq)]
+pub enum Identity {
+    /// Signed in to one of the server's own accounts
+    Account(SignedIn),
+    /// Authenticated with an ID token from an OpenID Connect provider
+    IdToken(Verified),
+}
+
+impl Identity {
+    /// The user the rules are checked for, which an ID token only names until it expires
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Identity::Account(signed_in) => Some(&signed_in.user),
+            Identity::IdToken(verified) => (!expired(verified)).then_some(verified.user.as_str()),
+        }
+    }
+
+    /// What the ID token claimed about the user, until it expires
+    pub fn claims(&self) -> Option<&Value> {
+        match self {
+            Identity::Account(_) => None,
+            Identity::IdToken(verified) => (!expired(verified)).then_some(&verified.claims),
+        }
+    }
+}
+
+fn expired(verified: &Verified) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    verified.expires_at <= now.as_secs()
+}
+
+struct Parked {
+    session: Session,
+    at: Instant,
+}
+
+impl Sessions {
+    /// Keep sessions for `grace` after their connection drops; with no grace period, sessions
+    /// can't be resumed at all
+    pub fn new(grace: Duration) -> Sessions {
+        Sessions {
+            parked: Mutex::new(HashMap::new()),
+            grace,
+        }
+    }
+
+    /// A new session token, which is hard to guess so that only the client it's given to can
+    /// resume the session
+    pub fn issue() -> String {
+        let mut bytes = [0; 16];
+        getrandom::getrandom(&mut bytes).expect("the OS has no source of randomness");
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Keep the session of a connection that dropped, until it's resumed or the grace period
+    /// runs out
+    pub fn park(&self, token: String, session: Session) {
+        if self.grace.is_zero() {
+            return;
+        }
+        let mut parked = self.parked.lock().unwrap();
+        self.expire(&mut parked);
+        let at = Instant::now();
+        parked.insert(token, Parked { session, at });
+    }
+
+    /// Take the session `token` names, if it's still kept and belongs to `tenant`. Each session
+    /// can only be resumed once.
+    pub fn resume(&self, token: &str, tenant: Option<&str>) -> Option<Session> {
+        let mut parked = self.parked.lock().unwrap();
+        self.expire(&mut parked);
+        let Parked { session, .. } = parked.remove(token)?;
+        (session.tenant.as_deref() == tenant).then_some(session)
+    }
+
+    fn expire(&self, parked: &mut HashMap<String, Parked>) {
+        parked.retain(|_, parked| parked.at.elapsed() < self.grace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::Ref;
+
+    use super::*;
+
+    fn session(tenant: Option<&str>) -> Session {
+        Session {
+            tenant: tenant.map(String::from),
+            subscriptions: vec![ClientMessage::Subscribe(Ref(vec!["hello".to_string()]))],
+            cursor: 3,
+            identity: None,
+        }
+    }
+
+    #[test]
+    fn resuming_sessions() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let token = Sessions::issue();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, Sessions::issue());
+
+        sessions.park(token.clone(), session(None));
+        assert!(sessions.resume("guess", None).is_none());
+        let resumed = sessions.resume(&token, None).unwrap();
+        assert_eq!(resumed.cursor, 3);
+        assert!(sessions.resume(&token, None).is_none());
+
+        // Sessions only resume in the tenant they were parked from
+        sessions.park(token.clone(), session(Some("blog")));
+        assert!(sessions.resume(&token, None).is_none());
+
+        let expired = Sessions::new(Duration::ZERO);
+        expired.park(token.clone(), session(None));
+        assert!(expired.resume(&token, None).is_none());
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::BTreeSet;
+
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc::{self, UnboundedReceiver},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+
+use iceload::{
+    message::{
+        Aggregation, ClientMessage, Encoding, ErrorCode, Ref, ServerMessage, PROTOCOL_VERSION,
+    },
+    server::{Event, Server},
+};
+
+const HELP: &str = "\
+commands:
+  get <path> [depth]    print the value at a path, with references expanded depth levels deep
+  shallow <path> [n]    print the value at a path, leaving out what's nested over n deep
+  pick <path> <fields>  print only some fields of a path, space-separated (title author/name)
+  set <path> <json>     insert an object, or update any other value
+  rm <path>             remove the value at a path
+  ls <path>             list the keys of a document or collection
+  keys <path>           list the keys of a collection without reading its members
+  meta <path>           print when a document was created and last written
+  history <path> [rev]  list the kept revisions of a document, or print one of them
+  search <path> <words> list the documents under a path containing every word
+  query <path> <filter> print the members of a collection matching a JSON filter
+  count <path>          print how many members a collection has
+  subscribe <path>      print updates to a path as they happen, where * matches any member
+  follow <path>         print the item a reference field points at whenever it changes
+  unsubscribe <path>    stop printing updates to a path
+  help                  show this message
+  exit                  leave the shell
+paths are slash-separated (hello/world) or a JSON array ([\"hello\", \"new york\"])";
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Where the shell sends its requests
+pub enum Backend {
+    /// Operate on a database directory directly, bypassing permissions
+    Direct {
+        server: Server,
+        subscriptions: Vec<(Ref, JoinHandle<()>)>,
+    },
+    /// Talk to a running server over its WebSocket protocol
+    Remote {
+        send: SplitSink<WsStream, tungstenite::Message>,
+        responses: UnboundedReceiver<ServerMessage>,
+    },
+}
+
+impl Backend {
+    pub fn direct(server: Server) -> Backend {
+        Backend::Direct {
+            server,
+            subscriptions: Vec::new(),
+        }
+    }
+
+    pub async fn connect(url: &str) -> anyhow::Result<Backend> {
+        let (ws_stream, _) = connect_async(url).await?;
+        let (mut send, mut recv) = ws_stream.split();
+
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            features: BTreeSet::new(),
+            encoding: Encoding::Json,
+            session: None,
+        };
+        send.send(tungstenite::Message::Text(serde_json::to_string(&hello)?))
+            .await?;
+        let Some(welcome) = recv.next().await else {
+            anyhow::bail!("the server closed the connection during the handshake");
+        };
+        match serde_json::from_str(welcome?.to_text()?)? {
+            ServerMessage::Welcome { .. } => {}
+            ServerMessage::Error(e) => anyhow::bail!("the server refused the connection: {e}"),
+            msg => anyhow::bail!("unexpected response to the handshake: {msg:?}"),
+        }
+
+        let (send_resp, responses) = mpsc::unbounded_channel();
+        tokio::spawn(print_remote_updates(recv, send_resp));
+
+        Ok(Backend::Remote { send, responses })
+    }
+
+    async fn request(&mut self, msg: ClientMessage) -> anyhow::Result<Option<ServerMessage>> {
+        match self {
+            Backend::Direct {
+                server,
+                subscriptions,
+            } => Ok(Some(match msg {
+                ClientMessage::Get(key) | ClientMessage::GetChunked(key) => {
+                    match server.get(&key) {
+                        Ok(value) => ServerMessage::Value(value),
+                        Err(e) => ServerMessage::from(&e),
+                    }
+                }
+                ClientMessage::GetMany(keys) => match server.get_many(&keys) {
+                    Ok(values) => ServerMessage::Value(Value::Array(values)),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                // Opening the database directly bypasses the permission rules anyway
+                ClientMessage::GetExpanded(key, depth) => {
+                    match server.get_expanded(&key, depth, &mut |targets| vec![true; targets.len()])
+                    {
+                        Ok(value) => ServerMessage::Value(value),
+                        Err(e) => ServerMessage::from(&e),
+                    }
+                }
+                ClientMessage::DescribeSchema(key) => {
+                    match server.describe(&key, &mut |targets| vec![true; targets.len()]) {
+                        Ok(described) => ServerMessage::Value(described),
+                        Err(e) => ServerMessage::from(&e),
+                    }
+                }
+                ClientMessage::GetShallow(key, depth) => match server.get_shallow(&key, depth) {
+                    Ok(value) => ServerMessage::Value(value),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::GetFields(key, fields) => match server.get_fields(&key, &fields) {
+                    Ok(value) => ServerMessage::Value(value),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::GetMetadata(key) => match server.metadata(&key) {
+                    Ok(metadata) => ServerMessage::Value(serde_json::to_value(metadata).unwrap()),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::GetHistory(key) => match server.history(&key) {
+                    Ok(revisions) => ServerMessage::Value(serde_json::to_value(revisions).unwrap()),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::GetAt(key, revision) => match server.revision(&key, revision) {
+                    Ok(revision) => ServerMessage::Value(serde_json::to_value(revision).unwrap()),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::Search(key, query) => match server.search(&key, &query) {
+                    Ok(found) => ServerMessage::Value(serde_json::to_value(found).unwrap()),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::Query(key, filter) => match server.query(&key, &filter) {
+                    Ok(members) => ServerMessage::Value(members),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::ListKeys(key) => match server.list_keys(&key) {
+                    Ok(keys) => ServerMessage::Value(serde_json::to_value(keys).unwrap()),
+                    Err(e) => ServerMessage::from(&e),
+                },
+                ClientMessage::Aggregate(key, aggregation) => {
+                    match server.aggregate(&key, &aggregation) {
+                        Ok(result) => ServerMessage::Value(result),
+                        Err(e) => ServerMessage::from(&e),
+                    }
+                }
+                ClientMessage::Insert(key, value) => write_result(server.insert(&key, value)),
+                ClientMessage::Update(key, value) => write_result(server.update(&key, value)),
+                ClientMessage::Remove(key) => write_result(server.remove(&key)),
+                // Updates are printed as they come, so there's nothing to gain from debouncing
+                ClientMessage::Subscribe(key) | ClientMessage::SubscribeDebounced(key, _) => {
+                    let mut subscriber = server.subscribe(&key);
+                    let key_ = key.clone();
+                    let handle = tokio::spawn(async move {
+                        while let Some(event) = subscriber.next().await {
+                            match event {
+                                Event::Insert { key, value } => println!(
+                                    "update {:?} (watching {:?}): {}",
+                                    key.0,
+                                    key_.0,
+                                    String::from_utf8_lossy(&value)
+                                ),
+                                Event::Remove { key } => {
+                                    println!("removed {:?} (watching {:?})", key.0, key_.0)
+                                }
+                                Event::Expire { key } => {
+                                    println!("expired {:?} (watching {:?})", key.0, key_.0)
+                                }
+                            }
+                        }
+                    });
+                    subscriptions.push((key, handle));
+                    return Ok(None);
+                }
+                ClientMessage::SubscribePattern(pattern) => {
+                    let mut subscriber = match server.subscribe_pattern(&pattern) {
+                        Ok(subscriber) => subscriber,
+                        Err(e) => return Ok(Some(ServerMessage::from(&e))),
+                    };
+                    let pattern_ = pattern.clone();
+                    let handle = tokio::spawn(async move {
+                        while let Some(event) = subscriber.next().await {
+                            match event {
+                                Event::Insert { key, value } => println!(
+                                    "update {:?} (watching {:?}): {}",
+                                    key.0,
+                                    pattern_.0,
+                                    String::from_utf8_lossy(&value)
+                                ),
+                                Event::Remove { key } => {
+                                    println!("removed {:?} (watching {:?})", key.0, pattern_.0)
+                                }
+                                Event::Expire { key } => {
+                                    println!("expired {:?} (watching {:?})", key.0, pattern_.0)
+                                }
+                            }
+                        }
+                    });
+                    subscriptions.push((pattern, handle));
+                    return Ok(None);
+                }
+                ClientMessage::Unsubscribe(key) => {
+                    subscriptions.retain(|(sub_key, handle)| {
+                        if sub_key == &key {
+                            handle.abort();
+                        }
+                        sub_key != &key
+                    });
+                    return Ok(None);
+                }
+                ClientMessage::Follow(_) => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "references can only be followed over the network",
+                ),
+                ClientMessage::SubscribeFrom { .. } => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "subscriptions can only be resumed over the network",
+                ),
+                ClientMessage::Join(..) | ClientMessage::Leave(_) => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "presence is only joined over the network",
+                ),
+                ClientMessage::Call { .. } => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "functions can only be called over the network",
+                ),
+                // The shell reads and writes the store directly, without permission rules
+                ClientMessage::CanI(..) => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "the permission rules are only checked over the network",
+                ),
+                ClientMessage::SignUp { .. }
+                | ClientMessage::SignIn(_)
+                | ClientMessage::SignOut
+                | ClientMessage::Auth(_) => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "accounts are only signed in to over the network",
+                ),
+                ClientMessage::Hello { .. } => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "handshakes are only needed over the network",
+                ),
+                ClientMessage::Envelope(_) => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "envelopes are only accepted over the network",
+                ),
+                // Everything is flushed when the shell exits
+                ClientMessage::Durable(_) => ServerMessage::error(
+                    ErrorCode::InvalidRequest,
+                    "durable writes are only made over the network",
+                ),
+                ClientMessage::Validate(write) => {
+                    let server = server.clone().dry_run();
+                    match *write {
+                        ClientMessage::Insert(key, value) => {
+                            write_result(server.insert(&key, value))
+                        }
+                        ClientMessage::Update(key, value) => {
+                            write_result(server.update(&key, value))
+                        }
+                        ClientMessage::Remove(key) => write_result(server.remove(&key)),
+                        _ => ServerMessage::error(
+                            ErrorCode::InvalidRequest,
+                            "only Insert, Update and Remove can be validated",
+                        ),
+                    }
+                }
+            })),
+            Backend::Remote { send, responses } => {
+                let expects_response = !matches!(
+                    msg,
+                    ClientMessage::Subscribe(_)
+                        | ClientMessage::SubscribeDebounced(..)
+                        | ClientMessage::SubscribeFrom { .. }
+                        | ClientMessage::Unsubscribe(_)
+                        | ClientMessage::Follow(_)
+                );
+                send.send(tungstenite::Message::Text(serde_json::to_string(&msg)?))
+                    .await?;
+                if expects_response {
+                    Ok(responses.recv().await)
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+fn write_result(result: Result<(), iceload::server::ServerError>) -> ServerMessage {
+    match result {
+        Ok(()) => ServerMessage::Value(Value::Null),
+        Err(e) => ServerMessage::from(&e),
+    }
+}
+
+async fn print_remote_updates(
+    mut recv: SplitStream<WsStream>,
+    send_resp: mpsc::UnboundedSender<ServerMessage>,
+) {
+    while let Some(Ok(msg)) = recv.next().await {
+        let Ok(text) = msg.to_text() else {
+            continue;
+        };
+        let Ok(msg) = serde_json::from_str::<ServerMessage>(text) else {
+            eprintln!("unrecognized message from server: {text}");
+            continue;
+        };
+        match msg {
+            ServerMessage::SubscriptionUpdate(key, Some(value)) => {
+                println!("update {:?}: {value}", key.0)
+            }
+            ServerMessage::SubscriptionUpdate(key, None) => println!("removed {:?}", key.0),
+            ServerMessage::PatternUpdate {
+                pattern,
+                key,
+                value: Some(value),
+            } => println!("update {:?} (watching {:?}): {value}", key.0, pattern.0),
+            ServerMessage::PatternUpdate {
+                pattern,
+                key,
+                value: None,
+            } => println!("removed {:?} (watching {:?})", key.0, pattern.0),
+            ServerMessage::SubscriptionExpired(key) => println!("expired {:?}", key.0),
+            ServerMessage::PatternExpired { pattern, key } => {
+                println!("expired {:?} (watching {:?})", key.0, pattern.0)
+            }
+            ServerMessage::ServerShutdown => println!("server is shutting down"),
+            ServerMessage::SchemaChanged => println!("the schema was replaced"),
+            ServerMessage::Explain(explanation) => {
+                println!("matched {}", explanation.schema);
+                if let Some(rule) = explanation.rule {
+                    let verdict = if rule.allowed { "allowed" } else { "denied" };
+                    println!("rules {verdict} {} on {}", rule.op, rule.path);
+                }
+            }
+            msg => {
+                if send_resp.send(msg).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    println!("connection closed");
+}
+
+pub async fn run(mut backend: Backend) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+
+    loop {
+        stdout.write_all(b"iceload> ").await?;
+        stdout.flush().await?;
+        let Some(line) = lines.next_line().await? else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let msg = match command {
+            "exit" | "quit" => break,
+            "help" => {
+                println!("{HELP}");
+                continue;
+            }
+            "get" | "shallow" | "pick" | "ls" | "keys" | "meta" | "history" | "search"
+            | "query" | "count" | "rm" | "subscribe" | "follow" | "unsubscribe" | "set" => {
+                match parse_path(args) {
+                    Ok((path, rest)) => match (command, rest.trim()) {
+                        ("get" | "ls", "") => ClientMessage::Get(path),
+                        ("get", depth) if depth.parse::<u32>().is_ok() => {
+                            ClientMessage::GetExpanded(path, depth.parse().unwrap())
+                        }
+                        ("shallow", "") => ClientMessage::GetShallow(path, 0),
+                        ("shallow", depth) if depth.parse::<u32>().is_ok() => {
+                            ClientMessage::GetShallow(path, depth.parse().unwrap())
+                        }
+                        ("pick", fields) if !fields.is_empty() => ClientMessage::GetFields(
+                            path,
+                            fields
+                                .split_whitespace()
+                                .map(|field| field.split('/').map(String::from).collect())
+                                .collect(),
+                        ),
+                        ("meta", "") => ClientMessage::GetMetadata(path),
+                        ("history", "") => ClientMessage::GetHistory(path),
+                        ("history", revision) if revision.parse::<u64>().is_ok() => {
+                            ClientMessage::GetAt(path, revision.parse().unwrap())
+                        }
+                        ("search", query) if !query.is_empty() => {
+                            ClientMessage::Search(path, query.to_string())
+                        }
+                        ("query", filter) if !filter.is_empty() => {
+                            match serde_json::from_str(filter) {
+                                Ok(filter) => ClientMessage::Query(path, filter),
+                                Err(e) => {
+                                    println!("invalid filter: {e}");
+                                    continue;
+                                }
+                            }
+                        }
+                        ("keys", "") => ClientMessage::ListKeys(path),
+                        ("count", "") => ClientMessage::Aggregate(path, Aggregation::Count),
+                        ("rm", "") => ClientMessage::Remove(path),
+                        ("subscribe", "") if path.0.iter().any(|component| component == "*") => {
+                            ClientMessage::SubscribePattern(path)
+                        }
+                        ("subscribe", "") => ClientMessage::Subscribe(path),
+                        ("follow", "") => ClientMessage::Follow(path),
+                        ("unsubscribe", "") => ClientMessage::Unsubscribe(path),
+                        ("set", value) if !value.is_empty() => {
+                            match serde_json::from_str::<Value>(value) {
+                                Ok(value @ Value::Object(_)) => ClientMessage::Insert(path, value),
+                                Ok(value) => ClientMessage::Update(path, value),
+                                Err(e) => {
+                                    println!("invalid JSON value: {e}");
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => {
+                            println!("wrong arguments for {command}; try `help`");
+                            continue;
+                        }
+                    },
+                    Err(e) => {
+                        println!("{e}");
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                println!("unknown command {command}; try `help`");
+                continue;
+            }
+        };
+
+        match backend.request(msg).await? {
+            Some(ServerMessage::Value(value)) if command == "ls" => match value {
+                Value::Object(obj) => {
+                    for key in obj.keys() {
+                        println!("{key}");
+                    }
+                }
+                value => println!("{}", serde_json::to_string_pretty(&value)?),
+            },
+            Some(ServerMessage::Value(Value::Array(keys))) if command == "keys" => {
+                for key in keys {
+                    println!("{}", key.as_str().unwrap_or_default());
+                }
+            }
+            Some(ServerMessage::Value(value)) => {
+                println!("{}", serde_json::to_string_pretty(&value)?)
+            }
+            Some(ServerMessage::Error(e)) => println!("error: {e}"),
+            Some(msg) => println!("{msg:?}"),
+            None if !matches!(command, "subscribe" | "unsubscribe") => {
+                println!("connection closed");
+                break;
+            }
+            None => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a path off the front of the arguments, returning the rest
+fn parse_path(args: &str) -> anyhow::Result<(Ref, &str)> {
+    let args = args.trim_start();
+    if args.starts_with('[') {
+        let mut stream = serde_json::Deserializer::from_str(args).into_iter::<Vec<String>>();
+        let path = stream
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("missing path"))??;
+        Ok((Ref(path), &args[stream.byte_offset()..]))
+    } else {
+        let (path, rest) = args.split_once(' ').unwrap_or((args, ""));
+        let components = path
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(str::to_string)
+            .collect();
+        Ok((Ref(components), rest))
+    }
+}
@@ -0,0 +1,31 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::message::Ref;
+
+/// Run `f`, logging a warning if it takes longer than `threshold`, so operators can find the
+/// paths that are expensive to read, write or check. Nothing is timed without a threshold.
+pub fn timed<T>(
+    threshold: Option<Duration>,
+    op: impl Display,
+    path: &Ref,
+    f: impl FnOnce() -> T,
+) -> T {
+    let Some(threshold) = threshold else {
+        return f();
+    };
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    if elapsed > threshold {
+        tracing::warn!(
+            op = %op,
+            r#ref = %path,
+            duration_ms = elapsed.as_secs_f64() * 1000.0,
+            "slow operation"
+        );
+    }
+    result
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+use serde_json::{Map, Value};
+
+use iceload::{
+    message::Ref,
+    schema::{CollectionSchema, Schema, SchemaItem},
+    server::Server,
+};
+
+/// The column holding each row's key in the collection
+const KEY_COLUMN: &str = "_key";
+
+/// A scalar field of the documents in a collection, which is a column of their CSV
+struct Column<'a> {
+    /// The path to the field from the document, which is in a document of its own if it has
+    /// more than one component
+    path: Vec<String>,
+    item: &'a SchemaItem,
+}
+
+impl Column<'_> {
+    /// The column's header, with the fields of documents inside documents named by their path
+    fn name(&self) -> String {
+        self.path.join(".")
+    }
+}
+
+/// The rows of a CSV file that couldn't be imported, and why
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub rejected: Vec<(usize, String)>,
+}
+
+/// Parse a path given on the command line, e.g. `teams/core/members`
+pub fn parse_path(path: &str) -> Ref {
+    Ref(path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Write the documents of the collection at `path` to `out` as CSV, one row each. The first
+/// column is the key of each document, and the rest are its scalar fields, including those of
+/// any documents inside it; collections inside it are left out.
+///
+/// Returns how many rows were written.
+pub fn export(server: &Server, path: &Ref, out: impl Write) -> anyhow::Result<usize> {
+    let schema = server.schema();
+    let collection = collection(&schema, path)?;
+    let columns = columns(&collection.items)?;
+    let mut writer = csv::Writer::from_writer(out);
+    let header = std::iter::once(KEY_COLUMN.to_string()).chain(columns.iter().map(Column::name));
+    writer.write_record(header)?;
+
+    let Value::Object(members) = server.get(path)? else {
+        anyhow::bail!("{path} isn't a collection");
+    };
+    for (key, document) in members.iter() {
+        let mut row = vec![key.clone()];
+        for column in columns.iter() {
+            let value = (column.path.iter()).try_fold(document, |value, field| value.get(field));
+            row.push(cell(value.unwrap_or(&Value::Null)));
+        }
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(members.len())
+}
+
+/// Write each row of the CSV in `input` to the collection at `path`, as `export` writes them.
+/// Rows for documents that are already stored update the fields they have columns for, and the
+/// rest insert new documents, with any collections inside them empty. Empty cells leave optional
+/// fields unset.
+pub fn import(server: &Server, path: &Ref, input: impl Read) -> anyhow::Result<ImportReport> {
+    let schema = server.schema();
+    let collection = collection(&schema, path)?;
+    let columns = columns(&collection.items)?;
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.headers()?.clone();
+    let mut by_name: HashMap<String, &Column> = (columns.iter())
+        .map(|column| (column.name(), column))
+        .collect();
+    let mut key_index = None;
+    let mut row_columns = Vec::new();
+    for (index, header) in headers.iter().enumerate() {
+        if header == KEY_COLUMN {
+            key_index = Some(index);
+            row_columns.push(None);
+            continue;
+        }
+        let Some(column) = by_name.remove(header) else {
+            anyhow::bail!("{header:?} isn't a scalar field of the documents in {path}");
+        };
+        row_columns.push(Some(column));
+    }
+    let Some(key_index) = key_index else {
+        anyhow::bail!("there's no {KEY_COLUMN} column holding the key of each document");
+    };
+
+    let mut report = ImportReport::default();
+    // Row numbers count the header, as spreadsheets do
+    for (row_number, row) in reader.records().enumerate().map(|(i, row)| (i + 2, row)) {
+        let row = row?;
+        let key = path.child(&row[key_index]);
+        let mut document = Value::Object(Map::new());
+        for (cell, column) in row.iter().zip(row_columns.iter()) {
+            if let Some(column) = column {
+                set(&mut document, &column.path, parse_cell(column.item, cell));
+            }
+        }
+        // Documents that aren't stored read as null
+        let result = match server.get(&key) {
+            Ok(Value::Null) => {
+                fill_collections(&collection.items, &mut document);
+                server.insert(&key, document)
+            }
+            Ok(_) => server.update(&key, document),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => report.imported += 1,
+            Err(e) => report.rejected.push((row_number, e.to_string())),
+        }
+    }
+    Ok(report)
+}
+
+/// The collection at `path`, which has to hold documents
+fn collection<'a>(schema: &'a Schema, path: &Ref) -> anyhow::Result<&'a CollectionSchema> {
+    match schema.resolve(&path.0)? {
+        SchemaItem::Collection(collection) => Ok(collection),
+        _ => anyhow::bail!("{path} isn't a collection"),
+    }
+}
+
+/// The scalar fields of the documents `items` describes, sorted by name
+fn columns(items: &SchemaItem) -> anyhow::Result<Vec<Column<'_>>> {
+    let SchemaItem::Document(fields) = unwrapped(items) else {
+        anyhow::bail!("only collections of documents have fields to make columns of");
+    };
+    let mut found = Vec::new();
+    add_columns(fields, &mut Vec::new(), &mut found);
+    found.sort_by_key(Column::name);
+    Ok(found)
+}
+
+fn add_columns<'a>(
+    fields: &'a HashMap<String, SchemaItem>,
+    path: &mut Vec<String>,
+    found: &mut Vec<Column<'a>>,
+) {
+    for (name, item) in fields {
+        path.push(name.clone());
+        match unwrapped(item) {
+            SchemaItem::Document(fields) => add_columns(fields, path, found),
+            SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_) => found.push(Column {
+                path: path.clone(),
+                item,
+            }),
+            // Optional items are read as null when they aren't set, which an empty cell stands
+            // for, but that doesn't extend to the fields of an optional document
+            SchemaItem::Optional(inner) if is_scalar(inner) => found.push(Column {
+                path: path.clone(),
+                item,
+            }),
+            _ => {}
+        }
+        path.pop();
+    }
+}
+
+/// A value as it's written to a cell: strings as they are, references as slash-separated paths
+/// and anything else as JSON, with null left empty
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(string) => string.clone(),
+        Value::Array(components) if components.iter().all(Value::is_string) => (components.iter())
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("/"),
+        value => value.to_string(),
+    }
+}
+
+/// The value a cell of a column for `item` holds, the reverse of `cell`
+fn parse_cell(item: &SchemaItem, cell: &str) -> Value {
+    match item {
+        SchemaItem::Optional(_) if cell.is_empty() => Value::Null,
+        SchemaItem::Optional(inner)
+        | SchemaItem::Sensitive(inner)
+        | SchemaItem::Searchable(inner) => parse_cell(inner, cell),
+        SchemaItem::Reference | SchemaItem::ReferenceTo(_) => {
+            parse_path(cell).0.into_iter().map(Value::String).collect()
+        }
+        // Custom codecs take any JSON, so cells that aren't are taken as strings
+        SchemaItem::Custom(_) => {
+            serde_json::from_str(cell).unwrap_or_else(|_| Value::String(cell.to_string()))
+        }
+        _ => Value::String(cell.to_string()),
+    }
+}
+
+/// Place `value` at `path` inside `document`, making any documents on the way
+fn set(document: &mut Value, path: &[String], value: Value) {
+    let Some((last, parents)) = path.split_last() else {
+        return;
+    };
+    let mut target = document;
+    for parent in parents {
+        target = (target.as_object_mut().unwrap())
+            .entry(parent.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    target.as_object_mut().unwrap().insert(last.clone(), value);
+}
+
+/// Give a new document the collections it has to have, which have no columns, as empty ones
+fn fill_collections(item: &SchemaItem, document: &mut Value) {
+    let (SchemaItem::Document(fields), Value::Object(object)) = (unwrapped(item), document) else {
+        return;
+    };
+    for (name, item) in fields {
+        match unwrapped(item) {
+            SchemaItem::Collection(_) => {
+                object.insert(name.clone(), Value::Object(Map::new()));
+            }
+            SchemaItem::Document(_) => {
+                let inner = object
+                    .entry(name.clone())
+                    .or_insert_with(|| Value::Object(Map::new()));
+                fill_collections(item, inner);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The item, without the wrappers that don't change what it holds
+fn unwrapped(item: &SchemaItem) -> &SchemaItem {
+    match item {
+        SchemaItem::Sensitive(inner) | SchemaItem::Searchable(inner) => unwrapped(inner),
+        item => item,
+    }
+}
+
+fn is_scalar(item: &SchemaItem) -> bool {
+    matches!(
+        unwrapped(item),
+        SchemaItem::Scalar
+            | SchemaItem::Custom(_)
+            | SchemaItem::Reference
+            | SchemaItem::ReferenceTo(_)
+            | SchemaItem::Enum(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use iceload::Schema;
+    use serde_json::json;
+
+    use super::*;
+
+    fn server() -> Server {
+        let (schema, _) = Schema::check(
+            r#"{ "Document": { "users": { "Collection": { "Document": {
+                "name": "Scalar",
+                "nickname": { "Optional": "Scalar" },
+                "friend": { "Optional": "Reference" },
+                "address": { "Document": { "city": "Scalar" } },
+                "posts": { "Collection": "Scalar" }
+            } } } } }"#,
+        )
+        .unwrap();
+        Server::open_temporary(schema, sled::Config::new()).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let server = server();
+        let users = parse_path("/users");
+        server.insert(&users, json!({})).unwrap();
+        server
+            .insert(
+                &users.child("ada"),
+                json!({
+                    "name": "Ada, Countess of Lovelace",
+                    "friend": ["users", "charles"],
+                    "address": { "city": "London" },
+                    "posts": { "first": "Hello" }
+                }),
+            )
+            .unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(export(&server, &users, &mut out).unwrap(), 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "_key,address.city,friend,name,nickname\n\
+             ada,London,users/charles,\"Ada, Countess of Lovelace\",\n"
+        );
+
+        let csv = "_key,name,address.city,nickname\n\
+                   ada,Ada Lovelace,London,\n\
+                   grace,Grace Hopper,Arlington,Amazing Grace\n";
+        let report = import(&server, &users, csv.as_bytes()).unwrap();
+        assert_eq!(report.imported, 2);
+        assert!(report.rejected.is_empty());
+        assert_eq!(
+            server.get(&users.child("ada")).unwrap(),
+            json!({
+                "name": "Ada Lovelace",
+                "nickname": null,
+                "friend": ["users", "charles"],
+                "address": { "city": "London" },
+                "posts": { "first": "Hello" }
+            })
+        );
+        assert_eq!(
+            server.get(&users.child("grace")).unwrap(),
+            json!({
+                "name": "Grace Hopper",
+                "nickname": "Amazing Grace",
+                "friend": null,
+                "address": { "city": "Arlington" },
+                "posts": {}
+            })
+        );
+
+        let err = import(&server, &users, "name\nAda\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("_key"));
+        let err = import(&server, &users, "_key,posts\nada,\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("posts"));
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use crate::{
+    schema::Schema,
+    server::{Server, ServerError},
+};
+
+/// An app hosted by the server, with its own data, schema, and permission rules
+#[derive(Clone)]
+pub struct Tenant {
+    /// None for the default tenant
+    pub name: Option<String>,
+    pub server: Server,
+    pub permission_bytecode: &'static [u8],
+    pub function_bytecode: &'static [u8],
+}
+
+/// Every tenant the server hosts. The default tenant keeps its data in the database's default
+/// tree, so a server with no other tenants reads and writes exactly what it always has.
+pub struct Tenants {
+    default: Tenant,
+    named: HashMap<String, Tenant>,
+}
+
+impl Tenants {
+    pub fn new(
+        server: Server,
+        permission_bytecode: &'static [u8],
+        function_bytecode: &'static [u8],
+    ) -> Tenants {
+        Tenants {
+            default: Tenant {
+                name: None,
+                server,
+                permission_bytecode,
+                function_bytecode,
+            },
+            named: HashMap::new(),
+        }
+    }
+
+    /// Host another tenant in the same database as the default one
+    pub fn add(
+        &mut self,
+        name: &str,
+        schema: Schema,
+        permission_bytecode: &'static [u8],
+        function_bytecode: &'static [u8],
+    ) -> Result<(), ServerError> {
+        let server = self.default.server.tenant(name, schema)?;
+        self.named.insert(
+            name.to_string(),
+            Tenant {
+                name: Some(name.to_string()),
+                server,
+                permission_bytecode,
+                function_bytecode,
+            },
+        );
+        Ok(())
+    }
+
+    /// The tenant a WebSocket client selected with the path of its handshake request: `/` for
+    /// the default tenant, or `/<name>` for a named one
+    pub fn for_path(&self, path: &str) -> Option<&Tenant> {
+        match path.trim_matches('/') {
+            "" => self.get(None),
+            name => self.get(Some(name)),
+        }
+    }
+
+    /// The tenant called `name`, or the default tenant for None
+    pub fn get(&self, name: Option<&str>) -> Option<&Tenant> {
+        match name {
+            None => Some(&self.default),
+            Some(name) => self.named.get(name),
+        }
+    }
+
+    /// The default tenant, followed by the named ones
+    pub fn iter(&self) -> impl Iterator<Item = &Tenant> {
+        std::iter::once(&self.default).chain(self.named.values())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{message::Ref, schema::SchemaItem};
+
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::new(SchemaItem::Document(
+            [("title".to_string(), SchemaItem::Scalar)]
+                .into_iter()
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn isolated_tenants() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::open(dir.path().to_str().unwrap(), schema()).unwrap();
+        let mut tenants = Tenants::new(server, &[], &[]);
+        tenants.add("blog", schema(), &[], &[]).unwrap();
+
+        let default = tenants.for_path("/").unwrap();
+        let blog = tenants.for_path("/blog").unwrap();
+        assert_eq!(blog.name.as_deref(), Some("blog"));
+        assert!(tenants.for_path("/shop").is_none());
+
+        let title = Ref(vec!["title".into()]);
+        default
+            .server
+            .insert(&Ref(Vec::new()), json!({ "title": "Home" }))
+            .unwrap();
+        assert_eq!(blog.server.get(&Ref(Vec::new())).unwrap(), json!(null));
+        blog.server
+            .insert(&Ref(Vec::new()), json!({ "title": "Posts" }))
+            .unwrap();
+        assert_eq!(default.server.get(&title).unwrap(), json!("Home"));
+        assert_eq!(blog.server.get(&title).unwrap(), json!("Posts"));
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use thiserror::Error;
+use tokio_rustls::{
+    rustls::{self, crypto::ring, ClientConfig, RootCertStore, ServerConfig},
+    TlsAcceptor, TlsConnector,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TlsError {
+    #[error("could not read {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("no certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("could not load the system's root certificates")]
+    NativeRoots(#[source] io::Error),
+    #[error("invalid TLS configuration")]
+    Config(#[from] rustls::Error),
+}
+
+/// Build a TLS acceptor from a PEM certificate chain and private key, for terminating wss://
+/// connections on the listener itself
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, TlsError> {
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| TlsError::Io { path, source }
+    };
+
+    let mut cert_reader = BufReader::new(File::open(cert_path).map_err(io_error(cert_path))?);
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_error(cert_path))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(cert_path.to_path_buf()));
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path).map_err(io_error(key_path))?);
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(io_error(key_path))?
+        .ok_or_else(|| TlsError::NoPrivateKey(key_path.to_path_buf()))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build a TLS connector that trusts the system's root certificates, for making https://
+/// requests to other services
+pub fn connector() -> Result<TlsConnector, TlsError> {
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(
+        rustls_native_certs::load_native_certs().map_err(TlsError::NativeRoots)?,
+    );
+    if ignored > 0 {
+        tracing::debug!(added, ignored, "skipped unparseable root certificates");
+    }
+
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        acceptor(&cert_path, &key_path).unwrap();
+
+        // Swapping the files around leaves each without what it should contain
+        assert!(matches!(
+            acceptor(&key_path, &key_path),
+            Err(TlsError::NoCertificates(_))
+        ));
+        assert!(matches!(
+            acceptor(&cert_path, &cert_path),
+            Err(TlsError::NoPrivateKey(_))
+        ));
+        assert!(matches!(
+            acceptor(&dir.path().join("missing.pem"), &key_path),
+            Err(TlsError::Io { .. })
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+use crate::{delivery::Sink, tls::TlsError};
+
+/// How long a receiver has to respond before the delivery counts as failed
+const TIMEOUT: Duration = Duration::from_secs(10);
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum WebhookError {
+    #[error("invalid webhook URL {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("{0}")]
+    Tls(#[from] TlsError),
+}
+
+/// An HTTP endpoint that payloads are POSTed to as JSON. Any 2xx response counts as delivered.
+pub struct Webhook {
+    url: Uri,
+    tls: TlsConnector,
+}
+
+impl Webhook {
+    pub fn new(url: &str, tls: TlsConnector) -> Result<Webhook, WebhookError> {
+        let invalid = |reason: &str| WebhookError::InvalidUrl {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        };
+        let parsed: Uri = url.parse().map_err(|e| invalid(&format!("{e}")))?;
+        if !matches!(parsed.scheme_str(), Some("http" | "https")) {
+            return Err(invalid("only http and https are supported"));
+        }
+        if parsed.host().is_none() {
+            return Err(invalid("there's no host"));
+        }
+        Ok(Webhook { url: parsed, tls })
+    }
+
+    async fn post(&self, payload: &Value) -> Result<StatusCode, String> {
+        let host = self
+            .url
+            .host()
+            .expect("webhook URLs are checked for a host");
+        let https = self.url.scheme_str() == Some("https");
+        let port = self.url.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let authority = self
+            .url
+            .authority()
+            .expect("URLs with a host have an authority");
+        let request = Request::post(
+            self.url
+                .path_and_query()
+                .map_or("/", |path_and_query| path_and_query.as_str()),
+        )
+        .header(header::HOST, authority.as_str())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::USER_AGENT, "iceload")
+        .body(Full::new(Bytes::from(serde_json::to_vec(payload).unwrap())))
+        .unwrap();
+
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(|e| format!("could not connect: {e}"))?;
+        if https {
+            let name = ServerName::try_from(host.to_string()).map_err(|e| format!("{e}"))?;
+            let stream = self
+                .tls
+                .connect(name, stream)
+                .await
+                .map_err(|e| format!("TLS handshake failed: {e}"))?;
+            send(stream, request).await
+        } else {
+            send(stream, request).await
+        }
+    }
+}
+
+impl Sink for Webhook {
+    fn deliver<'a>(&'a self, payload: &'a Value) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let status = tokio::time::timeout(TIMEOUT, self.post(payload))
+                .await
+                .map_err(|_| "timed out".to_string())??;
+            if status.is_success() {
+                Ok(())
+            } else {
+                Err(format!("responded {status}"))
+            }
+        })
+    }
+}
+
+async fn send(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    request: Request<Full<Bytes>>,
+) -> Result<StatusCode, String> {
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| format!("{e}"))?;
+    // Drives the connection until the response has been read
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|e| format!("{e}"))?;
+    Ok(response.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls;
+
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let url = |url: &str| Webhook::new(url, tls::connector().unwrap());
+        assert!(url("https://example.com/hooks?app=blog").is_ok());
+        assert!(url("ftp://example.com").is_err());
+        assert!(url("/hooks").is_err());
+    }
+}
@@ -0,0 +1,1884 @@
+mod testkit;
+
+use std::time::Duration;
+
+use iceload::{message::Ref, schema::Schema, server::Server};
+use serde_json::json;
+use testkit::{
+    http_request, http_request_with_body, http_request_with_headers, open_event_stream, Fixtures,
+    HttpReceiver, TestServer,
+};
+
+#[tokio::test]
+async fn read_and_write() {
+    let server = TestServer::start();
+    let mut client = server.connect().await;
+
+    let response = client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    assert_eq!(response, json!({ "Value": null }));
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "earth" }));
+}
+
+#[tokio::test]
+async fn handshake() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[features]\ncoalesce_updates = 1.0\n".into()),
+        ..Fixtures::default()
+    });
+
+    // Only the features the client supports are enabled for it
+    let mut client = server.open("/").await.unwrap();
+    let welcome = client
+        .request(json!({ "Hello": {
+            "protocol_version": 1,
+            "features": ["coalesce_updates", "telepathy"],
+        } }))
+        .await;
+    assert_eq!(welcome["Welcome"]["protocol_version"], 1);
+    assert_eq!(welcome["Welcome"]["features"], json!(["coalesce_updates"]));
+    assert_eq!(welcome["Welcome"]["resumed"], false);
+    let response = client
+        .request(json!({ "Hello": { "protocol_version": 1, "features": [] } }))
+        .await;
+    assert_eq!(response["Error"]["code"], "InvalidRequest");
+
+    let mut client = server.open("/").await.unwrap();
+    let response = client
+        .request(json!({ "Hello": { "protocol_version": 2, "features": [] } }))
+        .await;
+    assert_eq!(response["Error"]["code"], "IncompatibleProtocol");
+    client.expect_closed().await;
+
+    let mut client = server.open("/").await.unwrap();
+    let response = client.request(json!({ "Get": ["hello"] })).await;
+    assert_eq!(response["Error"]["code"], "IncompatibleProtocol");
+    client.expect_closed().await;
+}
+
+#[tokio::test]
+async fn message_pack() {
+    let server = TestServer::start();
+    let mut client = server.open("/").await.unwrap();
+    let welcome = client
+        .request(json!({ "Hello": {
+            "protocol_version": 1,
+            "features": [],
+            "encoding": "MessagePack",
+        } }))
+        .await;
+    assert!(welcome.get("Welcome").is_some(), "{welcome}");
+
+    client
+        .send_binary(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    assert_eq!(client.receive_binary().await, json!({ "Value": null }));
+    // Requests may still be sent as JSON, but responses stay binary
+    client.send(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(client.receive_binary().await, json!({ "Value": "earth" }));
+}
+
+#[tokio::test]
+async fn compression() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[connections]\ncompression = true\n".into()),
+        ..Fixtures::default()
+    });
+
+    // Only clients that ask for it get compressed messages
+    let mut client = server.connect().await;
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response["Error"]["code"], "KeyNotFound");
+
+    let mut client = server.open("/").await.unwrap();
+    let welcome = client
+        .request(json!({ "Hello": { "protocol_version": 1, "features": ["deflate"] } }))
+        .await;
+    assert_eq!(welcome["Welcome"]["features"], json!(["deflate"]));
+    client.send(json!({ "Subscribe": ["hello"] })).await;
+    client
+        .send(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let mut received = Vec::new();
+    while !received.contains(&json!({ "Value": null })) {
+        received.push(client.receive_deflated().await);
+    }
+    client.send(json!({ "Get": ["hello", "world"] })).await;
+    loop {
+        let message = client.receive_deflated().await;
+        if message.get("SubscriptionUpdate").is_none() {
+            assert_eq!(message, json!({ "Value": "earth" }));
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn permissions() {
+    let server = TestServer::start();
+    let mut client = server.connect().await;
+
+    // The default rules only allow reads and inserts
+    let response = client
+        .request(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+    assert_eq!(
+        response,
+        json!({ "Error": { "code": "PermissionDenied", "path": ["hello", "world"], "message": null } })
+    );
+    let response = client.request(json!({ "Remove": ["hello"] })).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+    assert_eq!(response["Error"]["path"], json!(["hello"]));
+
+    // Reading a missing scalar says which key is missing
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response["Error"]["code"], "KeyNotFound");
+    assert_eq!(response["Error"]["path"], json!(["hello", "world"]));
+}
+
+#[tokio::test]
+async fn permissive_rules() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let response = client
+        .request(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+    assert_eq!(response, json!({ "Value": null }));
+    let response = client.request(json!({ "Remove": ["hello"] })).await;
+    assert_eq!(response, json!({ "Value": null }));
+}
+
+#[tokio::test]
+async fn subscriptions() {
+    let server = TestServer::start();
+    let mut watcher = server.connect().await;
+    let mut writer = server.connect().await;
+
+    watcher.send(json!({ "Subscribe": ["hello"] })).await;
+    // Responses on a connection are ordered, so this confirms the subscription is in place
+    watcher.request(json!({ "Get": ["hello"] })).await;
+
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let update = watcher.receive().await;
+    assert_eq!(update["SubscriptionUpdate"][0], json!(["hello"]));
+}
+
+#[tokio::test]
+async fn pattern_subscriptions() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("search.json"),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut watcher = server.connect().await;
+    let mut writer = server.connect().await;
+
+    // Patterns are checked against the schema
+    let response = watcher
+        .request(json!({ "SubscribePattern": ["posts", "*", "votes"] }))
+        .await;
+    assert_eq!(response["Error"]["code"], "InvalidPath");
+
+    watcher
+        .send(json!({ "SubscribePattern": ["posts", "*", "author"] }))
+        .await;
+    watcher.request(json!({ "Get": ["posts"] })).await;
+    for (post, author) in [("first", "ada"), ("second", "grace")] {
+        writer
+            .request(json!({ "Insert": [["posts", post], {
+                "title": "Hello", "body": "", "author": author,
+            }] }))
+            .await;
+        let update = watcher.receive().await;
+        assert_eq!(
+            update,
+            json!({ "PatternUpdate": {
+                "pattern": ["posts", "*", "author"],
+                "key": ["posts", post, "author"],
+                "value": author,
+            } })
+        );
+    }
+
+    // Removing a whole post removes its author along with it
+    let response = writer
+        .request(json!({ "Remove": ["posts", "first"] }))
+        .await;
+    assert_eq!(response, json!({ "Value": null }));
+    assert_eq!(
+        watcher.receive().await,
+        json!({ "PatternUpdate": {
+            "pattern": ["posts", "*", "author"],
+            "key": ["posts", "first", "author"],
+            "value": null,
+        } })
+    );
+    let response = watcher
+        .request(json!({ "Get": ["posts", "second", "author"] }))
+        .await;
+    assert_eq!(response, json!({ "Value": "grace" }));
+}
+
+#[tokio::test]
+async fn reconnect() {
+    let server = TestServer::start();
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    client.send(json!({ "Subscribe": ["hello"] })).await;
+    client.close().await;
+
+    // Data outlives the connection, and a new one starts from a clean slate
+    let mut client = server.connect().await;
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "earth" }));
+}
+
+#[tokio::test]
+async fn graceful_shutdown() {
+    let mut server = TestServer::start();
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+
+    assert!(server.terminate().success());
+    assert_eq!(client.receive().await, json!("ServerShutdown"));
+}
+
+#[tokio::test]
+async fn connection_limit() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[connections]\nmax = 1\n".into()),
+        ..Fixtures::default()
+    });
+    let client = server.connect().await;
+    assert!(server.try_connect().await.is_err());
+
+    // The slot frees up once the first client leaves
+    client.close().await;
+    for _ in 0..50 {
+        if server.try_connect().await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("the connection slot was never released");
+}
+
+#[tokio::test]
+async fn idle_timeout() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[connections]\nping_interval_secs = 1\nidle_timeout_secs = 2\n".into()),
+        ..Fixtures::default()
+    });
+    let mut listening = server.connect().await;
+    let mut pinging = server.connect().await;
+    let mut silent = server.connect().await;
+
+    // Waiting for messages answers the server's pings, and pinging counts as being heard from
+    let wait = Duration::from_secs(3);
+    let pings = async {
+        for _ in 0..6 {
+            pinging.ping().await;
+            tokio::time::sleep(wait / 6).await;
+        }
+    };
+    let (listened, ()) = tokio::join!(tokio::time::timeout(wait, listening.receive()), pings);
+    assert!(listened.is_err());
+    for client in [&mut listening, &mut pinging] {
+        let response = client.request(json!({ "Get": ["hello"] })).await;
+        assert_eq!(response, json!({ "Value": null }));
+    }
+
+    // A client that never reads doesn't answer pings
+    silent.expect_closed().await;
+}
+
+#[tokio::test]
+async fn message_limits() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some(
+            "[limits]\nmax_message_bytes = 1024\nmax_ref_depth = 3\n\
+             max_subscriptions_per_connection = 1\n"
+                .into(),
+        ),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+
+    // Requests that break a limit are refused without closing the connection
+    let response = client
+        .request(json!({ "Get": ["hello", "world", "and", "beyond"] }))
+        .await;
+    assert_eq!(response["Error"]["code"], "LimitExceeded");
+    assert_eq!(
+        response["Error"]["path"],
+        json!(["hello", "world", "and", "beyond"])
+    );
+    let response = client.request(json!({ "Get": ["hello"] })).await;
+    assert_eq!(response, json!({ "Value": null }));
+
+    // Subscribing to the same key again doesn't count against the quota
+    client.send(json!({ "Subscribe": ["hello"] })).await;
+    client.send(json!({ "Subscribe": ["hello"] })).await;
+    let response = client.request(json!({ "Subscribe": ["planets"] })).await;
+    assert_eq!(response["Error"]["code"], "LimitExceeded");
+    assert_eq!(response["Error"]["path"], json!(["planets"]));
+
+    // An oversized message can't be skipped safely, so the connection is closed
+    let response = client
+        .request(json!({ "Update": [["hello", "world"], "x".repeat(2048)] }))
+        .await;
+    assert_eq!(response["Error"]["code"], "LimitExceeded");
+    client.expect_closed().await;
+}
+
+#[tokio::test]
+async fn large_values() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[connections]\nmax_frame_bytes = 16\n[sled]\nchunk_size = 8\n".into()),
+        ..Fixtures::default()
+    });
+    let mut writer = server.connect().await;
+    let mut client = server.connect().await;
+    let long = "x".repeat(1000);
+
+    // Values are split to be stored and sent, and put back together on the way
+    client
+        .send(json!({ "Subscribe": ["hello", "world"] }))
+        .await;
+    client.request(json!({ "Get": ["hello"] })).await;
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": long, "new york": "city" }] }))
+        .await;
+    assert_eq!(
+        client.receive().await,
+        json!({ "SubscriptionUpdate": [["hello", "world"], long] })
+    );
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": long }));
+}
+
+#[tokio::test]
+async fn kick_client() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n".into()),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let mut client = server.connect().await;
+    client.send(json!({ "Subscribe": ["hello"] })).await;
+    client.request(json!({ "Get": ["hello"] })).await;
+
+    let (status, body) = http_request(admin, "GET", "/connections").await;
+    assert_eq!(status, 200);
+    let clients: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(clients[0]["subscriptions"], json!([["hello"]]));
+    let id = clients[0]["id"].as_u64().unwrap();
+
+    let (status, _) = http_request(admin, "DELETE", &format!("/connections/{id}")).await;
+    assert_eq!(status, 204);
+    assert_eq!(
+        client.receive().await,
+        json!({ "Error": {
+            "code": "Disconnected",
+            "path": null,
+            "message": "disconnected by an administrator",
+        } })
+    );
+
+    // The client is forgotten once its connection has wound down
+    for _ in 0..50 {
+        let (status, _) = http_request(admin, "GET", &format!("/connections/{id}")).await;
+        if status == 404 {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("the kicked client was never unregistered");
+}
+
+#[tokio::test]
+async fn event_streams() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n[limits]\nmax_subscriptions = 1\n".into()),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let (status, mut events) = open_event_stream(&server.http_url, "/events/hello").await;
+    assert_eq!(status, 200);
+
+    // The stream's subscription counts towards the quota like any other
+    let (status, _) = open_event_stream(&server.http_url, "/events/hello/world").await;
+    assert_eq!(status, 429);
+    let mut client = server.connect().await;
+    let response = client.request(json!({ "Subscribe": ["hello"] })).await;
+    assert_eq!(response["Error"]["code"], "LimitExceeded");
+
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let update = events.next().await.unwrap();
+    assert_eq!(update["SubscriptionUpdate"][0], json!(["hello"]));
+
+    // The stream is a client of its own, which can be kicked
+    let (_, body) = http_request(admin, "GET", "/connections").await;
+    let clients: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let stream = clients
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|client| client["subscriptions"] == json!([["hello"]]))
+        .unwrap();
+    let id = stream["id"].as_u64().unwrap();
+    let (status, _) = http_request(admin, "DELETE", &format!("/connections/{id}")).await;
+    assert_eq!(status, 204);
+    while events.next().await.is_some() {}
+    client.send(json!({ "Subscribe": ["hello"] })).await;
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "earth" }));
+}
+
+#[tokio::test]
+async fn durable_writes() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n".into()),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let mut client = server.connect().await;
+
+    let response = client
+        .request(json!({ "Durable": { "Insert": [["hello"], { "world": "earth", "new york": "city" }] } }))
+        .await;
+    assert_eq!(response, json!({ "Value": null }));
+    let response = client
+        .request(json!({ "Durable": { "Get": ["hello"] } }))
+        .await;
+    assert_eq!(response["Error"]["code"], "InvalidRequest");
+
+    let (status, _) = http_request(admin, "POST", "/flush").await;
+    assert_eq!(status, 204);
+}
+
+#[tokio::test]
+async fn forking() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n".into()),
+        seed: Some(Fixtures::path("seed.json")),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let fork = format!("/fork?to={}", dir.path().join("fork").display());
+
+    let (status, _) = http_request(admin, "POST", &fork).await;
+    assert_eq!(status, 204);
+    // The copy has to be somewhere new
+    let (status, _) = http_request(admin, "POST", &fork).await;
+    assert_eq!(status, 500);
+    // The server carries on as it was
+    let mut client = server.connect().await;
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "earth" }));
+
+    let schema = Schema::load(&Fixtures::default().schema).unwrap();
+    let forked = Server::open(dir.path().join("fork").to_str().unwrap(), schema).unwrap();
+    assert_eq!(
+        forked.get(&Ref::from(["hello", "world"])).unwrap(),
+        json!("earth")
+    );
+}
+
+#[tokio::test]
+async fn seed_data() {
+    let server = TestServer::with_fixtures(Fixtures {
+        seed: Some(Fixtures::path("seed.json")),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+
+    let response = client.request(json!({ "Get": ["hello"] })).await;
+    assert_eq!(
+        response,
+        json!({ "Value": { "world": "earth", "new york": "city" } })
+    );
+}
+
+#[tokio::test]
+async fn validated_writes() {
+    let server = TestServer::start();
+    let mut client = server.connect().await;
+
+    let response = client
+        .request(json!({ "Validate": { "Insert": [["hello"], { "world": "earth", "new york": "city" }] } }))
+        .await;
+    assert_eq!(response, json!({ "Value": null }));
+    let response = client
+        .request(
+            json!({ "Validate": { "Insert": [["hello"], { "world": "earth", "mars": "red" }] } }),
+        )
+        .await;
+    assert!(response.get("Error").is_some());
+    // The default rules don't allow updates
+    let response = client
+        .request(json!({ "Validate": { "Update": [["hello", "world"], "mars"] } }))
+        .await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+    let response = client
+        .request(json!({ "Validate": { "Get": ["hello"] } }))
+        .await;
+    assert_eq!(response["Error"]["code"], "InvalidRequest");
+
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response["Error"]["code"], "KeyNotFound");
+}
+
+#[tokio::test]
+async fn maintenance_mode() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n".into()),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let mut client = server.connect().await;
+    let insert = json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] });
+
+    let (status, _) = http_request(admin, "PUT", "/maintenance").await;
+    assert_eq!(status, 204);
+    let (_, body) = http_request(admin, "GET", "/maintenance").await;
+    assert_eq!(body, r#"{"reason":null}"#);
+    let response = client.request(insert.clone()).await;
+    assert_eq!(response["Error"]["code"], "Maintenance");
+    // Reads carry on while writes are paused
+    let response = client.request(json!({ "Get": ["hello"] })).await;
+    assert_eq!(response, json!({ "Value": null }));
+
+    let (status, _) = http_request(admin, "DELETE", "/maintenance").await;
+    assert_eq!(status, 204);
+    let (_, body) = http_request(admin, "GET", "/maintenance").await;
+    assert_eq!(body, "null");
+    let response = client.request(insert).await;
+    assert!(response.get("Error").is_none(), "{response}");
+}
+
+#[tokio::test]
+async fn storage_usage() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n".into()),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    client
+        .send(json!({ "Subscribe": ["hello", "world"] }))
+        .await;
+    client.request(json!({ "Get": ["hello"] })).await;
+
+    let (status, body) = http_request(admin, "GET", "/storage").await;
+    assert_eq!(status, 200);
+    let usage: serde_json::Value = serde_json::from_str(&body).unwrap();
+    // The document has a key of its own as well as one for each field
+    assert_eq!(usage["keys"]["hello"], 3);
+    assert_eq!(usage["subscriptions"]["hello"], 1);
+    assert!(usage["size_on_disk"].as_u64().unwrap() > 0);
+
+    // Key counts are reused until they're refreshed, but subscriptions are always current
+    client.request(json!({ "Remove": ["hello"] })).await;
+    client
+        .send(json!({ "Unsubscribe": ["hello", "world"] }))
+        .await;
+    client.request(json!({ "Get": ["hello"] })).await;
+    let (_, body) = http_request(admin, "GET", "/storage").await;
+    let usage: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(usage["keys"]["hello"], 3);
+    assert_eq!(usage["subscriptions"], json!({}));
+    let (_, body) = http_request(admin, "GET", "/storage?refresh=true").await;
+    let usage: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(usage["keys"]["hello"], 0);
+    assert_eq!(usage["age_secs"], 0);
+}
+
+#[tokio::test]
+async fn replace_schema() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\nadmin_token = \"hunter2\"\n".into()),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let credentials = [("Authorization", "Bearer hunter2")];
+    let replace = |schema: String| async move {
+        http_request_with_headers(admin, "PUT", "/schema", &credentials, &schema).await
+    };
+
+    let (status, _) = http_request_with_body(admin, "PUT", "/schema", "{").await;
+    assert_eq!(status, 401);
+    let (status, _) = replace("{".to_string()).await;
+    assert_eq!(status, 400);
+    // The stored document has a field the new schema drops
+    let dropped = json!({ "Document": { "hello": { "Document": { "world": "Scalar" } } } });
+    let (status, body) = replace(dropped.to_string()).await;
+    assert_eq!(status, 409, "{body}");
+
+    let added = json!({ "Document": { "hello": { "Document": {
+        "world": "Scalar",
+        "new york": "Scalar",
+        "mars": { "Optional": "Scalar" },
+    } } } });
+    let (status, body) = replace(added.to_string()).await;
+    assert_eq!(status, 204, "{body}");
+    assert_eq!(client.receive().await, json!("SchemaChanged"));
+    let response = client.request(json!({ "Get": ["hello"] })).await;
+    assert_eq!(
+        response,
+        json!({ "Value": { "world": "earth", "new york": "city", "mars": null } })
+    );
+}
+
+#[tokio::test]
+async fn replacing_schemas_needs_a_token() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n".into()),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let schema = std::fs::read_to_string(Fixtures::default().schema).unwrap();
+    let (status, body) = http_request_with_body(admin, "PUT", "/schema", &schema).await;
+    assert_eq!(status, 403, "{body}");
+}
+
+#[tokio::test]
+async fn replicating_needs_a_token() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\n".into()),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    for path in ["/replication/snapshot", "/replication/changes"] {
+        let (status, body) = http_request(admin, "GET", path).await;
+        assert_eq!(status, 403, "{body}");
+    }
+}
+
+#[tokio::test]
+async fn slow_operation_logging() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[log]\nslow_ms = 0\n".into()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    client.request(json!({ "Get": ["hello", "world"] })).await;
+
+    let log = std::fs::read_to_string(server.dir().join("server.log")).unwrap();
+    let slow: Vec<_> = (log.lines())
+        .filter(|line| line.contains("slow operation"))
+        .collect();
+    assert!(slow.iter().any(|line| line.contains("op=insert")));
+    assert!(slow.iter().any(|line| line.contains("op=check insert")));
+    assert!(slow.iter().any(|line| line.contains("op=get")));
+}
+
+#[tokio::test]
+async fn get_many() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let response = client
+        .request(json!({ "GetMany": [["hello", "new york"], ["hello", "world"], ["hello"]] }))
+        .await;
+    assert_eq!(
+        response,
+        json!({ "Value": ["city", "earth", { "world": "earth", "new york": "city" }] })
+    );
+
+    // Like any other read, it sees the writes sent before it without waiting for their answers
+    client
+        .send(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+    client
+        .send(json!({ "GetMany": [["hello", "world"], ["hello", "new york"]] }))
+        .await;
+    assert_eq!(client.receive().await, json!({ "Value": null }));
+    assert_eq!(client.receive().await, json!({ "Value": ["mars", "city"] }));
+}
+
+#[tokio::test]
+async fn describe_schema() {
+    let server = TestServer::start();
+    let mut client = server.connect().await;
+    let response = client.request(json!({ "DescribeSchema": ["hello"] })).await;
+    assert_eq!(
+        response,
+        json!({ "Value": { "Document": { "world": "Scalar", "new york": "Scalar" } } })
+    );
+    let response = client
+        .request(json!({ "DescribeSchema": ["goodbye"] }))
+        .await;
+    assert_eq!(response["Error"]["code"], "InvalidPath");
+}
+
+#[tokio::test]
+async fn pipelined_requests() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    // Requests sent without waiting for answers are answered in order, and each sees the writes
+    // sent before it
+    let requests = [
+        json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }),
+        json!({ "Get": ["hello", "world"] }),
+        json!({ "Update": [["hello", "world"], "mars"] }),
+        json!({ "Get": ["hello", "new york"] }),
+        json!({ "Get": ["hello", "world"] }),
+        json!({ "Remove": ["hello"] }),
+        json!({ "Get": ["hello"] }),
+    ];
+    for request in requests {
+        client.send(request).await;
+    }
+    let mut responses = Vec::new();
+    for _ in 0..7 {
+        responses.push(client.receive().await["Value"].clone());
+    }
+    assert_eq!(
+        responses,
+        [
+            json!(null),
+            json!("earth"),
+            json!(null),
+            json!("city"),
+            json!("mars"),
+            json!(null),
+            json!(null),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn full_text_search() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("search.json"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["posts"], {
+            "first": { "title": "Launch day", "body": "We shipped it!", "author": "ada" },
+            "second": { "title": "Postmortem", "body": "What we shipped, and why", "author": "grace" },
+        }] }))
+        .await;
+
+    let response = client
+        .request(json!({ "Search": [["posts"], "shipped WE"] }))
+        .await;
+    assert_eq!(
+        response,
+        json!({ "Value": [["posts", "first"], ["posts", "second"]] })
+    );
+    let response = client
+        .request(json!({ "Search": [["posts"], "why"] }))
+        .await;
+    assert_eq!(response, json!({ "Value": [["posts", "second"]] }));
+    // Authors aren't searchable
+    let response = client
+        .request(json!({ "Search": [["posts"], "ada"] }))
+        .await;
+    assert_eq!(response, json!({ "Value": [] }));
+}
+
+#[tokio::test]
+async fn query_filters() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("search.json"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["posts"], {
+            "first": { "title": "Launch day", "body": "", "author": "ada" },
+            "second": { "title": "Re: Launch day", "body": "", "author": "grace" },
+            "third": { "title": "Roadmap", "body": "", "author": "alan" },
+        }] }))
+        .await;
+
+    let response = client
+        .request(json!({ "Query": [["posts"], { "And": [
+            { "Compare": { "field": ["author"], "op": "Lt", "value": "b" } },
+            { "Not": { "Compare": { "field": ["title"], "op": "Prefix", "value": "Launch" } } },
+        ] }] }))
+        .await;
+    assert_eq!(
+        response,
+        json!({ "Value": { "third": { "title": "Roadmap", "body": "", "author": "alan" } } })
+    );
+    let response = client
+        .request(json!({ "Query": [["posts"], { "Or": [
+            { "Compare": { "field": ["author"], "op": "Eq", "value": "grace" } },
+            { "Compare": { "field": ["title"], "op": "Ge", "value": "Roadmap" } },
+        ] }] }))
+        .await;
+    let keys: Vec<_> = response["Value"].as_object().unwrap().keys().collect();
+    assert_eq!(keys, ["second", "third"]);
+
+    // Fields are checked against the schema
+    let response = client
+        .request(json!({ "Query": [["posts"],
+            { "Compare": { "field": ["votes"], "op": "Gt", "value": 1 } }
+        ] }))
+        .await;
+    assert_eq!(response["Error"]["code"], "SchemaMismatch");
+    assert_eq!(response["Error"]["path"], json!(["posts"]));
+}
+
+#[tokio::test]
+async fn aggregate_count() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("search.json"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    let count = json!({ "Aggregate": [["posts"], "Count"] });
+    assert_eq!(client.request(count.clone()).await, json!({ "Value": 0 }));
+    client
+        .request(json!({ "Insert": [["posts"], {
+            "first": { "title": "Launch day", "body": "", "author": "ada" },
+            "second": { "title": "Roadmap", "body": "", "author": "alan" },
+        }] }))
+        .await;
+    assert_eq!(client.request(count).await, json!({ "Value": 2 }));
+
+    // Only numbers are summed, and plain scalars are strings
+    let response = client
+        .request(json!({ "Aggregate": [["posts"], { "Sum": ["author"] }] }))
+        .await;
+    assert_eq!(response, json!({ "Value": 0 }));
+}
+
+#[tokio::test]
+async fn audit_log() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some(
+            "admin_listen = \"127.0.0.1:0\"\n[audit]\nenabled = true\ndenied = true\n".into(),
+        ),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    // The default rules don't allow updates
+    client
+        .request(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+    client.request(json!({ "Get": ["hello"] })).await;
+
+    let (status, body) = http_request(admin, "GET", "/audit?path=hello/world").await;
+    assert_eq!(status, 200);
+    let entries: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["op"], "insert");
+    assert_eq!(entries[0]["path"], json!(["hello"]));
+    assert_eq!(entries[0]["allowed"], true);
+    assert_eq!(entries[0]["source"], "web_socket");
+    assert_eq!(entries[1]["op"], "update");
+    assert_eq!(entries[1]["allowed"], false);
+
+    let (_, body) = http_request(admin, "GET", "/audit?path=elsewhere").await;
+    assert_eq!(body, "[]");
+}
+
+#[tokio::test]
+async fn follow_references() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("references.json"),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut watcher = server.connect().await;
+    let mut writer = server.connect().await;
+    writer
+        .request(json!({ "Insert": [[], {
+            "users": { "alice": { "name": "Alice" }, "bob": { "name": "Bob" } },
+            "pinned": ["users", "alice"],
+        }] }))
+        .await;
+
+    // Following sends the current target straight away
+    watcher.send(json!({ "Follow": ["pinned"] })).await;
+    let update = watcher.receive().await;
+    assert_eq!(update["SubscriptionUpdate"][0], json!(["pinned"]));
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(
+            update["SubscriptionUpdate"][1].as_str().unwrap()
+        )
+        .unwrap(),
+        json!({ "name": "Alice" })
+    );
+
+    // Changes to the target are sent, and so is the new target when the reference moves
+    writer
+        .request(json!({ "Update": [["users", "alice", "name"], "Alicia"] }))
+        .await;
+    let update = watcher.receive().await;
+    assert!(update["SubscriptionUpdate"][1]
+        .as_str()
+        .unwrap()
+        .contains("Alicia"));
+    writer
+        .request(json!({ "Update": [["pinned"], ["users", "bob"]] }))
+        .await;
+    let update = watcher.receive().await;
+    assert!(update["SubscriptionUpdate"][1]
+        .as_str()
+        .unwrap()
+        .contains("Bob"));
+}
+
+#[tokio::test]
+async fn tenants() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some(format!(
+            "[tenants.blog]\nschema = \"schema.json\"\nrules = {:?}\n",
+            Fixtures::path("allow_all.luau"),
+        )),
+        ..Fixtures::default()
+    });
+    let mut app = server.connect().await;
+    let mut blog = server.try_connect_to("/blog").await.unwrap();
+    assert!(server.try_connect_to("/shop").await.is_err());
+
+    // Each tenant has its own data and rules
+    blog.request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let response = app.request(json!({ "Get": ["hello"] })).await;
+    assert_eq!(response, json!({ "Value": null }));
+    let response = app.request(json!({ "Remove": ["hello"] })).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+    let response = blog.request(json!({ "Remove": ["hello"] })).await;
+    assert_eq!(response, json!({ "Value": null }));
+}
+
+#[tokio::test]
+async fn expand_references() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("references.json"),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [[], {
+            "users": { "alice": { "name": "Alice" } },
+            "pinned": ["users", "alice"],
+        }] }))
+        .await;
+
+    let response = client
+        .request(json!({ "GetExpanded": [["pinned"], 1] }))
+        .await;
+    assert_eq!(response, json!({ "Value": { "name": "Alice" } }));
+    let (status, body) = http_request(&server.http_url, "GET", "/v1/pinned?expand=1").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+        json!({ "name": "Alice" })
+    );
+}
+
+#[tokio::test]
+async fn scheduled_backups() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[backups]\ndir = \"backups\"\ninterval_secs = 1\n".into()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [[], { "hello": { "world": "earth", "new york": "city" } }] }))
+        .await;
+
+    for _ in 0..100 {
+        let (_, metrics) = http_request(&server.http_url, "GET", "/metrics").await;
+        let last_success = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("iceload_backup_last_success_timestamp_seconds "))
+            .unwrap();
+        if last_success != "0" {
+            let backup = std::fs::read_dir(server.dir().join("backups"))
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .path();
+            let data: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(backup.join("data.json")).unwrap())
+                    .unwrap();
+            assert_eq!(data["hello"]["world"], json!("earth"));
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("no backup was taken");
+}
+
+#[tokio::test]
+async fn dev_mode_explanations() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("dev_mode = true\n".into()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+
+    client.send(json!({ "Remove": ["hello"] })).await;
+    assert_eq!(
+        client.receive().await,
+        json!({ "Explain": {
+            "schema": "document { new york, world }",
+            "rule": { "op": "remove", "path": ["hello"], "user": null, "allowed": false },
+        } })
+    );
+    assert_eq!(client.receive().await["Error"]["code"], "PermissionDenied");
+
+    client.send(json!({ "Remove": ["hello", "moon"] })).await;
+    let explanation = client.receive().await;
+    assert!(explanation["Explain"]["schema"]
+        .as_str()
+        .unwrap()
+        .starts_with("nothing matches /hello/moon"));
+}
+
+#[tokio::test]
+async fn webhooks() {
+    let receiver = HttpReceiver::start().await;
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some(format!(
+            "[[webhooks]]\npath = [\"hello\", \"world\"]\nurl = \"{}/hook\"\n\n\
+             [deliveries]\ninitial_backoff_ms = 10\nper_second = 1000.0\n",
+            receiver.url
+        )),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    // Writes elsewhere aren't sent
+    client
+        .request(json!({ "Update": [["hello", "new york"], "town"] }))
+        .await;
+    client
+        .request(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+
+    let (path, event) = receiver.receive(200).await;
+    assert_eq!(path, "/hook");
+    assert_eq!(event["op"], json!("insert"));
+    assert_eq!(event["path"], json!(["hello"]));
+    // A receiver that fails is retried
+    let (_, event) = receiver.receive(503).await;
+    assert_eq!(event["op"], json!("update"));
+    let (_, retried) = receiver.receive(204).await;
+    assert_eq!(retried, event);
+    assert_eq!(event["value"], json!("mars"));
+}
+
+#[tokio::test]
+async fn replication() {
+    let leader = TestServer::with_fixtures(Fixtures {
+        config: Some("admin_listen = \"127.0.0.1:0\"\nadmin_token = \"hunter2\"\n".into()),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut writer = leader.connect().await;
+    writer
+        .request(json!({ "Insert": [[], { "hello": { "world": "earth", "new york": "city" } }] }))
+        .await;
+
+    let follower = TestServer::with_fixtures(Fixtures {
+        config: Some(format!(
+            "[replication]\nleader = \"{}\"\ntoken = \"hunter2\"\n",
+            leader.admin_url.as_ref().unwrap()
+        )),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut reader = follower.connect().await;
+    let response = reader
+        .request(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+    assert_eq!(response["Error"]["code"], "ReadOnly");
+
+    // Writes keep being streamed to the follower once it has a snapshot
+    writer
+        .request(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+    for _ in 0..100 {
+        let response = reader.request(json!({ "Get": [] })).await;
+        if response["Value"]["hello"]["world"] == json!("mars") {
+            assert_eq!(response["Value"]["hello"]["new york"], json!("city"));
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("the follower never caught up");
+}
+
+#[tokio::test]
+async fn trimming_the_change_log() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some(
+            "admin_listen = \"127.0.0.1:0\"\nadmin_token = \"hunter2\"\n\
+             [changes]\nkeep = 1\ntrim_interval_secs = 1\n"
+                .into(),
+        ),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let admin = server.admin_url.as_deref().unwrap();
+    let mut writer = server.connect().await;
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    for planet in ["mars", "venus"] {
+        writer
+            .request(json!({ "Update": [["hello", "world"], planet] }))
+            .await;
+    }
+
+    // A follower reading from before the trimmed changes has to start again
+    let credentials = [("Authorization", "Bearer hunter2")];
+    for _ in 0..100 {
+        let (status, _) = http_request_with_headers(
+            admin,
+            "GET",
+            "/replication/changes?from=0",
+            &credentials,
+            "",
+        )
+        .await;
+        if status == 410 {
+            // While browsing the log starts at the oldest change left
+            let (status, body) =
+                http_request_with_headers(admin, "GET", "/changes", &credentials, "").await;
+            assert_eq!(status, 200);
+            let changes: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(changes.as_array().unwrap().len(), 1);
+            assert_eq!(changes[0]["value"], json!("venus"));
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("the change log was never trimmed");
+}
+
+#[tokio::test]
+async fn ephemeral_storage() {
+    let mut server = TestServer::with_fixtures(Fixtures {
+        config: Some("ephemeral = true\n".into()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "earth" }));
+
+    assert!(server.terminate().success());
+    assert!(!server.dir().join("data").exists());
+}
+
+#[tokio::test]
+async fn chunked_get() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("references.json"),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    let users: serde_json::Map<String, serde_json::Value> = (0..200)
+        .map(|i| (format!("user{i}"), json!({ "name": "x".repeat(1000) })))
+        .collect();
+    client
+        .request(
+            json!({ "Insert": [[], { "users": users.clone(), "pinned": ["users", "user0"] }] }),
+        )
+        .await;
+
+    // A request sent after a chunked read is answered after every chunk
+    client.send(json!({ "GetChunked": ["users"] })).await;
+    client.send(json!({ "Get": ["pinned"] })).await;
+    let mut received = serde_json::Map::new();
+    let mut chunks = 0;
+    loop {
+        let response = client.receive().await;
+        let chunk = &response["ValueChunk"];
+        received.extend(chunk["members"].as_object().unwrap().clone());
+        chunks += 1;
+        if chunk["last"] == json!(true) {
+            break;
+        }
+    }
+    assert!(chunks > 1);
+    assert_eq!(received, users);
+    assert_eq!(
+        client.receive().await,
+        json!({ "Value": ["users", "user0"] })
+    );
+
+    // Scalars aren't split
+    let response = client
+        .request(json!({ "GetChunked": ["users", "user1", "name"] }))
+        .await;
+    assert_eq!(response, json!({ "Value": "x".repeat(1000) }));
+}
+
+#[tokio::test]
+async fn debounced_subscriptions() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut watcher = server.connect().await;
+    let mut writer = server.connect().await;
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+
+    watcher
+        .send(json!({ "SubscribeDebounced": [["hello", "world"], 500] }))
+        .await;
+    watcher.request(json!({ "Get": ["hello"] })).await;
+    for planet in ["mars", "venus", "pluto"] {
+        writer
+            .request(json!({ "Update": [["hello", "world"], planet] }))
+            .await;
+    }
+
+    // The writes all land within the window, so only the last one is sent
+    let update = watcher.receive().await;
+    assert_eq!(
+        update,
+        json!({ "SubscriptionUpdate": [["hello", "world"], "pluto"] })
+    );
+    let response = watcher.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "pluto" }));
+}
+
+#[tokio::test]
+async fn resumable_subscriptions() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut writer = server.connect().await;
+    let mut watcher = server.connect().await;
+
+    watcher
+        .send(json!({ "SubscribeFrom": { "key": ["hello"], "token": null } }))
+        .await;
+    let update = watcher.receive().await;
+    assert_eq!(update["ResumableUpdate"]["value"], json!(null));
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+    let update = watcher.receive().await;
+    assert_eq!(
+        update["ResumableUpdate"]["value"],
+        json!({ "world": "earth", "new york": "city" })
+    );
+    let token = update["ResumableUpdate"]["token"].clone();
+    watcher.close().await;
+
+    // Only what was missed while disconnected is sent
+    writer
+        .request(json!({ "Update": [["hello", "world"], "mars"] }))
+        .await;
+    let mut watcher = server.connect().await;
+    watcher
+        .send(json!({ "SubscribeFrom": { "key": ["hello"], "token": token } }))
+        .await;
+    let update = watcher.receive().await;
+    assert_eq!(
+        update["ResumableUpdate"]["value"],
+        json!({ "world": "mars", "new york": "city" })
+    );
+    let token = update["ResumableUpdate"]["token"].clone();
+    watcher.close().await;
+
+    let mut watcher = server.connect().await;
+    watcher
+        .send(json!({ "SubscribeFrom": { "key": ["hello"], "token": token } }))
+        .await;
+    let response = watcher.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "mars" }));
+}
+
+#[tokio::test]
+async fn presence() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("presence.json"),
+        rules: Fixtures::path("allow_all.luau"),
+        ..Fixtures::default()
+    });
+    let mut watcher = server.connect().await;
+    let mut member = server.connect().await;
+    watcher
+        .request(json!({ "Insert": [["lobby"], { "topic": "general" }] }))
+        .await;
+    watcher
+        .send(json!({ "Subscribe": ["lobby", "online"] }))
+        .await;
+    watcher.request(json!({ "Get": ["lobby"] })).await;
+
+    let response = member
+        .request(json!({ "Join": [["lobby", "online", "ada"], "typing"] }))
+        .await;
+    assert_eq!(response, json!({ "Value": null }));
+    let update = watcher.receive().await;
+    assert_eq!(
+        update,
+        json!({ "SubscriptionUpdate": [["lobby", "online"], "typing"] })
+    );
+    let response = watcher.request(json!({ "Get": ["lobby", "online"] })).await;
+    assert_eq!(response, json!({ "Value": { "ada": "typing" } }));
+
+    // Members go away with the connection that joined them
+    member.close().await;
+    let update = watcher.receive().await;
+    assert_eq!(
+        update,
+        json!({ "SubscriptionUpdate": [["lobby", "online"], null] })
+    );
+    let response = watcher.request(json!({ "Get": ["lobby", "online"] })).await;
+    assert_eq!(response, json!({ "Value": {} }));
+}
+
+#[tokio::test]
+async fn call_functions() {
+    let functions = Fixtures::path("functions.luau");
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("allow_all.luau"),
+        config: Some(format!("functions = {:?}", functions.to_str().unwrap())),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+
+    let response = client
+        .request(json!({ "Call": { "name": "swap", "args": null } }))
+        .await;
+    assert_eq!(
+        response,
+        json!({ "Value": { "world": "city", "new york": "earth" } })
+    );
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "city" }));
+
+    // A failing function writes nothing
+    let response = client
+        .request(json!({ "Call": { "name": "fail", "args": { "reason": "thanks" } } }))
+        .await;
+    assert_eq!(response["Error"]["code"], "FunctionFailed");
+    assert!(response["Error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("no thanks"));
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "city" }));
+
+    // Calls sent without waiting for answers see the writes sent before them, and are seen by
+    // the reads sent after them
+    for request in [
+        json!({ "Update": [["hello", "world"], "mars"] }),
+        json!({ "Call": { "name": "swap", "args": null } }),
+        json!({ "Get": ["hello", "world"] }),
+    ] {
+        client.send(request).await;
+    }
+    assert_eq!(client.receive().await, json!({ "Value": null }));
+    assert_eq!(
+        client.receive().await,
+        json!({ "Value": { "world": "earth", "new york": "mars" } })
+    );
+    assert_eq!(client.receive().await, json!({ "Value": "earth" }));
+}
+
+#[tokio::test]
+async fn document_metadata() {
+    let server = TestServer::start();
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+
+    let response = client.request(json!({ "GetMetadata": ["hello"] })).await;
+    let created_at = response["Value"]["created_at"].as_u64().unwrap();
+    assert_eq!(response["Value"]["updated_at"], created_at);
+
+    // Only documents have metadata
+    let response = client
+        .request(json!({ "GetMetadata": ["hello", "world"] }))
+        .await;
+    assert_eq!(response["Error"]["code"], "SchemaMismatch");
+}
+
+#[tokio::test]
+async fn document_history() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[history]\nkeep = 2\n".into()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+
+    let response = client.request(json!({ "GetHistory": ["hello"] })).await;
+    let revisions = response["Value"].as_array().unwrap();
+    assert_eq!(revisions.len(), 1);
+    assert_eq!(revisions[0]["revision"], 1);
+    assert_eq!(revisions[0]["value"]["world"], "earth");
+
+    let response = client.request(json!({ "GetAt": [["hello"], 1] })).await;
+    assert_eq!(response["Value"], revisions[0]);
+    let response = client.request(json!({ "GetAt": [["hello"], 2] })).await;
+    assert_eq!(response["Error"]["code"], "KeyNotFound");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_listener() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[[listeners]]\naddress = \"unix:iceload.sock\"\n".into()),
+        ..Fixtures::default()
+    });
+    // Both listeners serve the same data
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+
+    let stream = tokio::net::UnixStream::connect(server.dir().join("iceload.sock"))
+        .await
+        .unwrap();
+    let (mut socket, _) = tokio_tungstenite::client_async("ws://localhost/", stream)
+        .await
+        .unwrap();
+    let hello = json!({ "Hello": { "protocol_version": 1, "features": [] } });
+    let mut replies = Vec::new();
+    for message in [hello, json!({ "Get": ["hello", "world"] })] {
+        socket
+            .send(Message::Text(message.to_string()))
+            .await
+            .unwrap();
+        let reply = socket.next().await.unwrap().unwrap();
+        replies.push(serde_json::from_str::<serde_json::Value>(reply.to_text().unwrap()).unwrap());
+    }
+    assert!(replies[0].get("Welcome").is_some(), "{}", replies[0]);
+    assert_eq!(replies[1], json!({ "Value": "earth" }));
+}
+
+#[tokio::test]
+async fn origin_and_subprotocol() {
+    use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[connections]\nallowed_origins = [\"https://app.example.com\"]\n".into()),
+        ..Fixtures::default()
+    });
+    let connect = |headers: &[(&'static str, &'static str)]| {
+        let mut request = server.ws_url.as_str().into_client_request().unwrap();
+        for (name, value) in headers {
+            request.headers_mut().insert(*name, value.parse().unwrap());
+        }
+        tokio_tungstenite::connect_async(request)
+    };
+
+    let (_, response) = connect(&[
+        ("Origin", "https://app.example.com"),
+        ("Sec-WebSocket-Protocol", "iceload.v1"),
+    ])
+    .await
+    .unwrap();
+    assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "iceload.v1");
+
+    let Err(tungstenite::Error::Http(response)) =
+        connect(&[("Origin", "https://evil.example.com")]).await
+    else {
+        panic!("a connection from another site was accepted");
+    };
+    assert_eq!(response.status(), 403);
+    let Err(tungstenite::Error::Http(response)) =
+        connect(&[("Sec-WebSocket-Protocol", "mqtt")]).await
+    else {
+        panic!("a connection for another protocol was accepted");
+    };
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn session_resume() {
+    let server = TestServer::start();
+    let mut writer = server.connect().await;
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] }))
+        .await;
+
+    let hello = json!({ "Hello": { "protocol_version": 1, "features": [] } });
+    let mut client = server.open("/").await.unwrap();
+    let welcome = client.request(hello.clone()).await;
+    let session = welcome["Welcome"]["session"].clone();
+    client.send(json!({ "Subscribe": ["hello"] })).await;
+    client.request(json!({ "Get": ["hello"] })).await;
+    drop(client);
+    // Give the server a moment to notice the connection dropped
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": "mars", "new york": "city" }] }))
+        .await;
+
+    let mut resume = hello.clone();
+    resume["Hello"]["session"] = session;
+    let mut client = server.open("/").await.unwrap();
+    let welcome = client.request(resume.clone()).await;
+    assert_eq!(welcome["Welcome"]["resumed"], true);
+    // What was missed arrives straight away, and the subscription carries on
+    assert_eq!(
+        client.receive().await,
+        json!({ "SubscriptionUpdate": [["hello"], r#"{"new york":"city","world":"mars"}"#] })
+    );
+    writer
+        .request(json!({ "Insert": [["hello"], { "world": "venus", "new york": "city" }] }))
+        .await;
+    assert_eq!(
+        client.receive().await["SubscriptionUpdate"][0],
+        json!(["hello"])
+    );
+
+    // Each session can only be resumed once
+    let mut other = server.open("/").await.unwrap();
+    let welcome = other.request(resume).await;
+    assert_eq!(welcome["Welcome"]["resumed"], false);
+}
+
+#[tokio::test]
+async fn accounts() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("signed_in.luau"),
+        config: Some("[accounts]\nenabled = true\n".to_string()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    let insert = json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] });
+    let response = client.request(insert.clone()).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+
+    let sign_up = json!({ "SignUp": { "username": "ada", "password": "engine" } });
+    let response = client.request(sign_up.clone()).await;
+    let user = response["Value"]["user"].as_str().unwrap().to_string();
+    let response = client.request(insert).await;
+    assert_eq!(response, json!({ "Value": null }));
+    let response = client.request(sign_up).await;
+    assert_eq!(response["Error"]["code"], "InvalidRequest");
+
+    let response = client.request(json!("SignOut")).await;
+    assert_eq!(response, json!({ "Value": null }));
+    let update = json!({ "Update": [["hello", "world"], "mars"] });
+    let response = client.request(update.clone()).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+
+    // Another connection signs in to the same account
+    let mut other = server.connect().await;
+    let wrong = json!({ "SignIn": { "Password": { "username": "ada", "password": "wrong" } } });
+    let response = other.request(wrong).await;
+    assert_eq!(response["Error"]["code"], "InvalidCredentials");
+    let right = json!({ "SignIn": { "Password": { "username": "ada", "password": "engine" } } });
+    let response = other.request(right).await;
+    assert_eq!(response["Value"]["user"], user.as_str());
+    let token = response["Value"]["token"].clone();
+    let response = other.request(update).await;
+    assert_eq!(response, json!({ "Value": null }));
+
+    let response = client
+        .request(json!({ "SignIn": { "Token": token } }))
+        .await;
+    assert_eq!(response["Value"]["user"], user.as_str());
+}
+
+#[tokio::test]
+async fn accounts_disabled() {
+    let server = TestServer::start();
+    let mut client = server.connect().await;
+    let sign_up = json!({ "SignUp": { "username": "ada", "password": "engine" } });
+    let response = client.request(sign_up).await;
+    assert_eq!(response["Error"]["code"], "InvalidRequest");
+}
+
+#[tokio::test]
+async fn anonymous_accounts() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("signed_in.luau"),
+        config: Some("[accounts]\nanonymous = true\n".to_string()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    let response = client.request(json!({ "SignIn": "Anonymous" })).await;
+    let user = response["Value"]["user"].clone();
+    let token = response["Value"]["token"].clone();
+    let insert = json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] });
+    let response = client.request(insert).await;
+    assert_eq!(response, json!({ "Value": null }));
+    client.close().await;
+
+    // The token brings the same user back on a later connection
+    let mut client = server.connect().await;
+    let response = client
+        .request(json!({ "SignIn": { "Token": token } }))
+        .await;
+    assert_eq!(response["Value"]["user"], user);
+    let response = client.request(json!({ "SignIn": "Anonymous" })).await;
+    assert_ne!(response["Value"]["user"], user);
+
+    // Only anonymous sign in is turned on
+    let sign_up = json!({ "SignUp": { "username": "ada", "password": "engine" } });
+    let response = client.request(sign_up).await;
+    assert_eq!(response["Error"]["code"], "InvalidRequest");
+}
+
+#[tokio::test]
+async fn http_bearer_tokens() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("signed_in.luau"),
+        config: Some("[accounts]\nanonymous = true\n".to_string()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    let response = client.request(json!({ "SignIn": "Anonymous" })).await;
+    let token = response["Value"]["token"].as_str().unwrap().to_string();
+    let json = "application/json";
+
+    // Writing takes a user, which the token names
+    let body = r#"{ "world": "earth", "new york": "city" }"#;
+    let headers = [("Content-Type", json)];
+    let (status, _) =
+        http_request_with_headers(&server.http_url, "PUT", "/v1/hello", &headers, body).await;
+    assert_eq!(status, 403);
+    let authorization = format!("Bearer {token}");
+    let headers = [
+        ("Content-Type", json),
+        ("Authorization", authorization.as_str()),
+    ];
+    let (status, _) =
+        http_request_with_headers(&server.http_url, "PUT", "/v1/hello", &headers, body).await;
+    assert_eq!(status, 200);
+    let (status, body) = http_request(&server.http_url, "GET", "/v1/hello/world").await;
+    assert_eq!((status, body.as_str()), (200, r#""earth""#));
+
+    let headers = [("Authorization", "Bearer guess")];
+    let (status, body) =
+        http_request_with_headers(&server.http_url, "DELETE", "/v1/hello", &headers, "").await;
+    assert_eq!(status, 401);
+    assert!(body.contains("token is wrong"), "{body}");
+}
+
+#[tokio::test]
+async fn http_writes_when_envelopes_are_required() {
+    let server = TestServer::with_fixtures(Fixtures {
+        config: Some("[replay]\nrequire_envelopes = true\n".to_string()),
+        ..Fixtures::default()
+    });
+
+    // There's no way to envelope a write over HTTP, so there's no way to write at all
+    let headers = [("Content-Type", "application/json")];
+    let (status, body) =
+        http_request_with_headers(&server.http_url, "PUT", "/v1/hello", &headers, "1").await;
+    assert_eq!(status, 403);
+    assert!(body.contains("envelope"), "{body}");
+    let (status, _) = http_request(&server.http_url, "GET", "/v1/hello").await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn user_placeholder() {
+    let server = TestServer::with_fixtures(Fixtures {
+        schema: Fixtures::path("references.json"),
+        rules: Fixtures::path("own_user.luau"),
+        config: Some("[accounts]\nanonymous = true\n".to_string()),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    client
+        .request(json!({ "Insert": [[], { "users": {}, "pinned": ["users", "nobody"] }] }))
+        .await;
+    let response = client.request(json!({ "Get": ["users", "$uid"] })).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+    assert_eq!(response["Error"]["path"], json!(["users", "$uid"]));
+
+    let response = client.request(json!({ "SignIn": "Anonymous" })).await;
+    let user = response["Value"]["user"].as_str().unwrap().to_string();
+    client.send(json!({ "Subscribe": ["users", "$uid"] })).await;
+    client
+        .send(json!({ "Insert": [["users", "$uid"], { "name": "Ada" }] }))
+        .await;
+    // Updates may arrive before or after the insert is answered, one for the document and one
+    // for its field
+    let (mut answered, mut updates) = (false, 0);
+    while !(answered && updates == 2) {
+        let message = client.receive().await;
+        match message.get("SubscriptionUpdate") {
+            Some(update) => {
+                assert_eq!(update[0], json!(["users", user]));
+                updates += 1;
+            }
+            None => {
+                assert_eq!(message, json!({ "Value": null }));
+                answered = true;
+            }
+        }
+    }
+
+    // The placeholder is always the user's own ID, so another user can't reach the document
+    let mut other = server.connect().await;
+    other.request(json!({ "SignIn": "Anonymous" })).await;
+    let response = other.request(json!({ "Get": ["users", user] })).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+    let response = client.request(json!({ "Get": ["users", "$uid"] })).await;
+    assert_eq!(response, json!({ "Value": { "name": "Ada" } }));
+}
+
+#[tokio::test]
+async fn asking_the_rules() {
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("signed_in.luau"),
+        config: Some("[accounts]\nanonymous = true\n".to_string()),
+        seed: Some(Fixtures::path("seed.json")),
+        ..Fixtures::default()
+    });
+    let mut client = server.connect().await;
+    let can_remove = json!({ "CanI": ["remove", ["hello"]] });
+    let response = client.request(can_remove.clone()).await;
+    assert_eq!(
+        response,
+        json!({ "Value": { "op": "remove", "path": ["hello"], "user": null, "allowed": false } })
+    );
+
+    let response = client.request(json!({ "SignIn": "Anonymous" })).await;
+    let user = response["Value"]["user"].clone();
+    let response = client.request(can_remove).await;
+    assert_eq!(response["Value"]["allowed"], true);
+    assert_eq!(response["Value"]["user"], user);
+    // Only asked, not done
+    let response = client.request(json!({ "Get": ["hello", "world"] })).await;
+    assert_eq!(response, json!({ "Value": "earth" }));
+}
+
+#[tokio::test]
+async fn id_tokens() {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+    let provider = HttpReceiver::start().await;
+    let server = TestServer::with_fixtures(Fixtures {
+        rules: Fixtures::path("verified_email.luau"),
+        config: Some(format!(
+            "[[oidc]]\nissuer = \"{}\"\naudience = \"iceload-app\"\nclaims = [\"email_verified\"]\n",
+            provider.url
+        )),
+        ..Fixtures::default()
+    });
+    let signer = rcgen::KeyPair::generate().unwrap();
+    let point = signer.public_key_raw();
+    let (x, y) = point[1..].split_at(32);
+    let jwks = json!({ "keys": [{
+        "kty": "EC",
+        "crv": "P-256",
+        "kid": "first",
+        "x": URL_SAFE_NO_PAD.encode(x),
+        "y": URL_SAFE_NO_PAD.encode(y),
+    }] });
+    let id_token = |sub: &str, audience: &str, email_verified: bool| {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("first".to_string());
+        let claims = json!({
+            "iss": provider.url,
+            "aud": audience,
+            "sub": sub,
+            "exp": jsonwebtoken::get_current_timestamp() + 600,
+            "email_verified": email_verified,
+        });
+        let key = EncodingKey::from_ec_der(&signer.serialize_der());
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    };
+
+    let mut client = server.connect().await;
+    let insert = json!({ "Insert": [["hello"], { "world": "earth", "new york": "city" }] });
+    let response = client.request(insert.clone()).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+
+    // The provider's keys are found through its discovery document
+    let auth = json!({ "Auth": id_token("ada", "iceload-app", true) });
+    let provide = async {
+        let discovery = json!({ "jwks_uri": format!("{}/jwks", provider.url) });
+        let path = provider.serve(&discovery).await;
+        assert_eq!(path, "/.well-known/openid-configuration");
+        assert_eq!(provider.serve(&jwks).await, "/jwks");
+    };
+    let (response, ()) = tokio::join!(client.request(auth), provide);
+    assert_eq!(response, json!({ "Value": { "user": "ada" } }));
+    let response = client.request(insert).await;
+    assert_eq!(response, json!({ "Value": null }));
+
+    // The keys are cached, and the rules see the claims of whoever authenticated last
+    let response = client
+        .request(json!({ "Auth": id_token("grace", "iceload-app", false) }))
+        .await;
+    assert_eq!(response, json!({ "Value": { "user": "grace" } }));
+    let update = json!({ "Update": [["hello", "world"], "mars"] });
+    let response = client.request(update).await;
+    assert_eq!(response["Error"]["code"], "PermissionDenied");
+
+    let response = client
+        .request(json!({ "Auth": id_token("ada", "another-app", true) }))
+        .await;
+    assert_eq!(response["Error"]["code"], "InvalidCredentials");
+}
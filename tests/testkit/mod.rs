@@ -0,0 +1,441 @@
+// Boots the real iceload binary against temporary storage so protocol behaviour can be tested
+// end to end. Each `TestServer` listens on ephemeral ports, so tests can run in parallel.
+
+#![allow(dead_code)]
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The files a server is booted with; by default the ones at the root of the repository
+pub struct Fixtures {
+    pub schema: PathBuf,
+    pub rules: PathBuf,
+    /// Contents of iceload.toml, if any
+    pub config: Option<String>,
+    /// Data to seed the database with
+    pub seed: Option<PathBuf>,
+}
+
+impl Default for Fixtures {
+    fn default() -> Fixtures {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        Fixtures {
+            schema: root.join("schema.json"),
+            rules: root.join("permission.luau"),
+            config: None,
+            seed: None,
+        }
+    }
+}
+
+impl Fixtures {
+    /// A fixture from tests/fixtures
+    pub fn path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(name)
+    }
+}
+
+pub struct TestServer {
+    process: Child,
+    dir: TempDir,
+    pub ws_url: String,
+    pub http_url: String,
+    /// Set when the config enables the admin API
+    pub admin_url: Option<String>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        TestServer::with_fixtures(Fixtures::default())
+    }
+
+    pub fn with_fixtures(fixtures: Fixtures) -> TestServer {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy(&fixtures.schema, dir.path().join("schema.json")).unwrap();
+        std::fs::copy(&fixtures.rules, dir.path().join("permission.luau")).unwrap();
+        if let Some(config) = &fixtures.config {
+            std::fs::write(dir.path().join("iceload.toml"), config).unwrap();
+        }
+        if let Some(seed) = &fixtures.seed {
+            std::fs::copy(seed, dir.path().join("seed.json")).unwrap();
+        }
+
+        let log = File::create(dir.path().join("server.log")).unwrap();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_iceload"));
+        command.args(["--listen", "127.0.0.1:0", "--http-listen", "127.0.0.1:0"]);
+        #[cfg(feature = "grpc")]
+        command.args(["--grpc-listen", "127.0.0.1:0"]);
+        if fixtures.seed.is_some() {
+            command.args(["--seed", "seed.json"]);
+        }
+        let mut process = command
+            .current_dir(dir.path())
+            .stdout(Stdio::piped())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+
+        // The server announces the addresses it actually bound once it's ready
+        let mut ws_url = None;
+        let mut http_url = None;
+        let mut admin_url = None;
+        let mut stdout = BufReader::new(process.stdout.take().unwrap());
+        for line in stdout.by_ref().lines() {
+            let line = line.unwrap();
+            if let Some(url) = line.strip_prefix("HTTP gateway listening on ") {
+                http_url = Some(url.to_string());
+            } else if let Some(url) = line.strip_prefix("admin API listening on ") {
+                admin_url = Some(url.to_string());
+            } else if let Some(url) = line.strip_prefix("listening on ") {
+                ws_url = Some(url.to_string());
+                break;
+            }
+        }
+        let (Some(ws_url), Some(http_url)) = (ws_url, http_url) else {
+            let log = std::fs::read_to_string(dir.path().join("server.log")).unwrap();
+            panic!("server exited before it started listening:\n{log}");
+        };
+        // Keep draining stdout, or the server will fail to write to it
+        std::thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+
+        TestServer {
+            process,
+            dir,
+            ws_url,
+            http_url,
+            admin_url,
+        }
+    }
+
+    /// The directory the server is running in, holding its database and fixtures
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Ask the server to shut down gracefully, and wait for it to exit
+    pub fn terminate(&mut self) -> ExitStatus {
+        let status = Command::new("kill")
+            .args(["-TERM", &self.process.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        self.process.wait().unwrap()
+    }
+
+    /// Connect a client and consume the handshake
+    pub async fn connect(&self) -> TestClient {
+        self.try_connect().await.unwrap()
+    }
+
+    pub async fn try_connect(&self) -> Result<TestClient, tungstenite::Error> {
+        self.try_connect_to("/").await
+    }
+
+    /// Connect a client to the tenant served at `path`, and complete the handshake
+    pub async fn try_connect_to(&self, path: &str) -> Result<TestClient, tungstenite::Error> {
+        let mut client = self.open(path).await?;
+        let welcome = client
+            .request(json!({ "Hello": { "protocol_version": 1, "features": [] } }))
+            .await;
+        assert!(welcome.get("Welcome").is_some(), "{welcome}");
+        Ok(client)
+    }
+
+    /// Open a connection to the tenant served at `path`, leaving the handshake to the test
+    pub async fn open(&self, path: &str) -> Result<TestClient, tungstenite::Error> {
+        let (socket, _) = tokio::time::timeout(
+            TIMEOUT,
+            tokio_tungstenite::connect_async(format!("{}{path}", self.ws_url)),
+        )
+        .await
+        .expect("timed out connecting")?;
+        Ok(TestClient { socket })
+    }
+}
+
+/// Make a bodyless HTTP/1.1 request, returning the status code and response body
+pub async fn http_request(base_url: &str, method: &str, path: &str) -> (u16, String) {
+    http_request_with_body(base_url, method, path, "").await
+}
+
+/// Make an HTTP/1.1 request with `body`, returning the status code and response body
+pub async fn http_request_with_body(
+    base_url: &str,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, String) {
+    http_request_with_headers(base_url, method, path, &[], body).await
+}
+
+/// Make an HTTP/1.1 request with `headers` and `body`, returning the status code and response body
+pub async fn http_request_with_headers(
+    base_url: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> (u16, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let host = base_url.strip_prefix("http://").unwrap();
+    let mut stream = TcpStream::connect(host).await.unwrap();
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}\r\n"))
+        .collect();
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\nContent-Length: {}\r\n{headers}\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("timed out waiting for an HTTP response")
+        .unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+/// A stream of Server-Sent Events from the HTTP gateway
+pub struct EventStream {
+    reader: tokio::io::BufReader<TcpStream>,
+}
+
+/// Start streaming events from `path`, returning the status code and the stream
+pub async fn open_event_stream(base_url: &str, path: &str) -> (u16, EventStream) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let host = base_url.strip_prefix("http://").unwrap();
+    let mut stream = TcpStream::connect(host).await.unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut reader = tokio::io::BufReader::new(stream);
+    let mut status_line = String::new();
+    tokio::time::timeout(TIMEOUT, reader.read_line(&mut status_line))
+        .await
+        .expect("timed out waiting for an HTTP response")
+        .unwrap();
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut events = EventStream { reader };
+    while !events.read_line().await.unwrap().is_empty() {}
+    (status, events)
+}
+
+impl EventStream {
+    /// The data of the next event, or None once the stream has ended
+    pub async fn next(&mut self) -> Option<Value> {
+        loop {
+            let line = self.read_line().await?;
+            if let Some(data) = line.strip_prefix("data: ") {
+                return Some(serde_json::from_str(data).unwrap());
+            }
+        }
+    }
+
+    /// The next line, without its line ending, or None at the end of the stream
+    async fn read_line(&mut self) -> Option<String> {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut line = String::new();
+        let read = tokio::time::timeout(TIMEOUT, self.reader.read_line(&mut line))
+            .await
+            .expect("timed out waiting for an event")
+            .unwrap();
+        (read > 0).then(|| line.trim_end().to_string())
+    }
+}
+
+/// Accepts HTTP requests on an ephemeral port, standing in for an external service the server
+/// calls out to
+pub struct HttpReceiver {
+    listener: tokio::net::TcpListener,
+    pub url: String,
+}
+
+impl HttpReceiver {
+    pub async fn start() -> HttpReceiver {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        HttpReceiver { listener, url }
+    }
+
+    /// Wait for the next request, answer it with `status`, and return its path and JSON body
+    pub async fn receive(&self, status: u16) -> (String, Value) {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut stream, path, body) = self.accept().await;
+        let response = format!("HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+        (path, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Wait for the next request, answer it with `document` as JSON, and return its path
+    pub async fn serve(&self, document: &Value) -> String {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut stream, path, _) = self.accept().await;
+        let body = document.to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        path
+    }
+
+    /// Read the next request, returning the stream to answer it on, its path and its body
+    async fn accept(&self) -> (tokio::io::BufReader<TcpStream>, String, Vec<u8>) {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+        let (stream, _) = tokio::time::timeout(TIMEOUT, self.listener.accept())
+            .await
+            .expect("timed out waiting for a request")
+            .unwrap();
+        let mut stream = tokio::io::BufReader::new(stream);
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await.unwrap();
+        let path = request_line.split(' ').nth(1).unwrap().to_string();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await.unwrap();
+        (stream, path, body)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Speaks the wire protocol directly, as JSON, so tests pin down the format clients rely on
+pub struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, message: Value) {
+        self.socket
+            .send(Message::Text(message.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// Send a message encoded as MessagePack, in a binary frame
+    pub async fn send_binary(&mut self, message: Value) {
+        self.socket
+            .send(Message::Binary(rmp_serde::to_vec_named(&message).unwrap()))
+            .await
+            .unwrap();
+    }
+
+    /// Wait for the next message from the server, in whichever encoding it uses
+    pub async fn receive(&mut self) -> Value {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.socket.next())
+                .await
+                .expect("timed out waiting for the server")
+                .expect("the server closed the connection")
+                .unwrap();
+            match message {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                Message::Binary(bytes) => return rmp_serde::from_slice(&bytes).unwrap(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Wait for the next message from the server, failing unless it's JSON compressed with raw
+    /// deflate, as sent with the `deflate` feature
+    pub async fn receive_deflated(&mut self) -> Value {
+        let message = tokio::time::timeout(TIMEOUT, self.socket.next())
+            .await
+            .expect("timed out waiting for the server")
+            .expect("the server closed the connection")
+            .unwrap();
+        match message {
+            Message::Binary(bytes) => {
+                serde_json::from_reader(flate2::read::DeflateDecoder::new(&bytes[..])).unwrap()
+            }
+            message => panic!("expected a binary frame, got {message:?}"),
+        }
+    }
+
+    /// Wait for the next message from the server, failing unless it's MessagePack
+    pub async fn receive_binary(&mut self) -> Value {
+        let message = tokio::time::timeout(TIMEOUT, self.socket.next())
+            .await
+            .expect("timed out waiting for the server")
+            .expect("the server closed the connection")
+            .unwrap();
+        match message {
+            Message::Binary(bytes) => rmp_serde::from_slice(&bytes).unwrap(),
+            message => panic!("expected a binary frame, got {message:?}"),
+        }
+    }
+
+    /// Send a request and wait for its response
+    pub async fn request(&mut self, message: Value) -> Value {
+        self.send(message).await;
+        self.receive().await
+    }
+
+    /// Wait for the server to close the connection, failing if it sends a message first
+    pub async fn expect_closed(&mut self) {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.socket.next())
+                .await
+                .expect("timed out waiting for the server to close the connection");
+            match message {
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                None | Some(Ok(Message::Close(_))) | Some(Err(_)) => return,
+                message => panic!("expected the connection to close, got {message:?}"),
+            }
+        }
+    }
+
+    /// Send a WebSocket ping, which the server answers without a message
+    pub async fn ping(&mut self) {
+        self.socket.send(Message::Ping(Vec::new())).await.unwrap();
+    }
+
+    pub async fn close(mut self) {
+        self.socket.close(None).await.unwrap();
+    }
+}